@@ -0,0 +1,28 @@
+//! Run with `cargo bench`. Pass value sizes and key counts to narrow it
+//! down, such as `cargo bench --bench fsdb -- --sizes 16,1024 --keys 1000`
+
+use fsdb::bench::{run, BenchOptions};
+
+fn main() {
+    let mut opts = BenchOptions::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let list = |args: &mut dyn Iterator<Item = String>| -> Vec<usize> {
+            let value = args.next().unwrap_or_default();
+            value.split(',').filter_map(|n| n.parse().ok()).collect()
+        };
+        match arg.as_str() {
+            "--sizes" => opts.value_sizes = list(&mut args),
+            "--keys" => opts.key_counts = list(&mut args),
+            // such as --bench, from cargo
+            _ => (),
+        }
+    }
+    match run(&opts) {
+        Ok(results) => results.iter().for_each(|r| println!("{}", r)),
+        Err(e) => {
+            eprintln!("benchmark failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Op, Result};
+use rmp_serde::{decode, encode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// File (inside a bucket) holding the time each key was last read, once enabled
+pub(crate) const ACCESS: &str = ".access";
+
+// reads are recorded in memory, and written out at most this often
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+/// When each key in a bucket was last read, kept in memory and flushed to the
+/// access file every `FLUSH_EVERY`, when the bucket is closed, and when the last
+/// handle to it is dropped. Flushes merge with what other processes have
+/// written, keeping the later time for each key
+pub(crate) struct AccessLog {
+    path: PathBuf,
+    // nanoseconds since the epoch
+    last_read: HashMap<String, u64>,
+    // keys removed since the last flush, so merging doesn't bring them back
+    forgotten: HashSet<String>,
+    dirty: bool,
+    flushed: Instant,
+}
+
+impl AccessLog {
+    pub(crate) fn exists(dir: &Path) -> bool {
+        dir.join(ACCESS).is_file()
+    }
+
+    /// Load the access file, creating it if it's missing
+    pub(crate) fn open(dir: &Path) -> io::Result<Self> {
+        let mut log = Self {
+            path: dir.join(ACCESS),
+            last_read: HashMap::new(),
+            forgotten: HashSet::new(),
+            dirty: false,
+            flushed: Instant::now(),
+        };
+        match log.read() {
+            Ok(last_read) => log.last_read = last_read,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => log.write()?,
+            Err(e) => return Err(e),
+        }
+        Ok(log)
+    }
+
+    pub(crate) fn last_read(&self, name: &str) -> Option<SystemTime> {
+        let nanos = *self.last_read.get(name)?;
+        Some(UNIX_EPOCH + Duration::from_nanos(nanos))
+    }
+
+    /// Note a read of `name`. Only errors if a periodic flush fails
+    pub(crate) fn record(&mut self, name: &str) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        self.last_read.insert(name.to_owned(), now);
+        self.forgotten.remove(name);
+        self.dirty = true;
+        if self.flushed.elapsed() >= FLUSH_EVERY {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Drop a removed key
+    pub(crate) fn forget(&mut self, name: &str) {
+        if self.last_read.remove(name).is_some() {
+            self.forgotten.insert(name.to_owned());
+            self.dirty = true;
+        }
+    }
+
+    /// Drop every key, for a cleared bucket
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        self.last_read.clear();
+        self.forgotten.clear();
+        self.write()
+    }
+
+    /// Write out reads recorded since the last flush
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let on_disk = match self.read() {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        for (name, nanos) in on_disk {
+            if self.forgotten.contains(&name) {
+                continue;
+            }
+            let t = self.last_read.entry(name).or_default();
+            *t = (*t).max(nanos);
+        }
+        self.write()?;
+        self.forgotten.clear();
+        Ok(())
+    }
+
+    fn read(&self) -> io::Result<HashMap<String, u64>> {
+        let bytes = fs::read(&self.path)?;
+        decode::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write(&mut self) -> io::Result<()> {
+        let buf = encode::to_vec(&self.last_read)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp = self
+            .path
+            .with_file_name(format!("{}.{}.tmp", ACCESS, std::process::id()));
+        fs::write(&tmp, buf)?;
+        fs::rename(tmp, &self.path)?;
+        self.dirty = false;
+        self.flushed = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // best effort, Fsdb::close reports errors
+        let _ = self.flush();
+    }
+}
+
+// access tracking
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Track when each key was last read (in a `.access` file), for
+    /// `last_read` and `list_least_recently_used`. Once enabled, tracking is
+    /// loaded whenever the bucket is opened.
+    ///
+    /// Reads are recorded in memory and written out about once a second, so a
+    /// crash can lose the last second of them. Failing to write them out never
+    /// fails a read
+    pub fn enable_access_tracking(&self) -> Result<()> {
+        let mut access = self.state.access();
+        if access.is_none() {
+            let log = AccessLog::open(&self.dir).ctx(|| Context::new(Op::Open, &self.dir, None))?;
+            *access = Some(log);
+        }
+        Ok(())
+    }
+    /// When a key was last read through any handle, or `None` if it hasn't
+    /// been read since tracking was enabled (or tracking is off)
+    pub fn last_read(&self, key: &str) -> Option<SystemTime> {
+        self.state.access().as_ref()?.last_read(&self.maxify(key))
+    }
+    /// Up to `n` keys, least recently used first. A key's last use is its last
+    /// read, or if it hasn't been read since tracking was enabled, its last write.
+    /// Without access tracking, that's just the least recently written keys
+    pub fn list_least_recently_used(&self, n: usize) -> Result<Vec<String>> {
+        let ctx = || Context::new(Op::List, &self.dir, None);
+        let mut keys = Vec::new();
+        for name in self.fs_keys(&self.dir)? {
+            let used = match self
+                .state
+                .access()
+                .as_ref()
+                .and_then(|a| a.last_read(&name))
+            {
+                Some(t) => t,
+                None => match fs::metadata(self.dir.join(&name)) {
+                    Ok(meta) => meta.modified().ctx(ctx)?,
+                    // removed since listing
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e).ctx(ctx),
+                },
+            };
+            keys.push((used, name));
+        }
+        keys.sort();
+        Ok(keys.into_iter().take(n).map(|(_, name)| name).collect())
+    }
+    // note a successful read of a top-level key
+    pub(crate) fn record_read(&self, dir: &Path, name: &str) {
+        if dir != self.dir {
+            return;
+        }
+        if let Some(access) = self.state.access().as_mut() {
+            let _ = access.record(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use std::time::Duration;
+
+    #[test]
+    fn test_least_recently_used() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("lru").expect("fail bucket");
+        b.enable_access_tracking()
+            .expect("fail enable_access_tracking");
+        for (i, k) in ["a", "b", "c", "d"].iter().enumerate() {
+            b.put(k, i as u8).expect("failed to save");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        b.get("c").expect("fail get");
+        std::thread::sleep(Duration::from_millis(10));
+        b.get("a").expect("fail get");
+        assert!(b.last_read("a").is_some());
+        assert!(b.last_read("b").is_none());
+
+        // b and d were never read, so go by when they were written
+        let lru = b.list_least_recently_used(10).expect("fail lru");
+        assert_eq!(lru, vec!["b", "d", "c", "a"]);
+        assert_eq!(b.list_least_recently_used(1).expect("fail lru"), vec!["b"]);
+
+        b.remove("a").expect("fail remove");
+        assert!(b.last_read("a").is_none());
+        assert_eq!(
+            b.list_least_recently_used(10).expect("fail lru"),
+            vec!["b", "d", "c"]
+        );
+    }
+
+    #[test]
+    fn test_access_persists() {
+        let tmp = Fsdb::temp().expect("fail Fsdb::temp");
+        let path = tmp.path().to_str().expect("non-utf8 temp dir").to_owned();
+        {
+            let db = Fsdb::new(&path).expect("fail Fsdb::new");
+            let b = db.bucket::<u8>("lru").expect("fail bucket");
+            b.enable_access_tracking()
+                .expect("fail enable_access_tracking");
+            b.put("a", 1).expect("failed to save");
+            b.get("a").expect("fail get");
+            db.close().expect("fail close");
+        }
+        let db = Fsdb::new(&path).expect("fail Fsdb::new");
+        let b = db.bucket::<u8>("lru").expect("fail bucket");
+        assert!(b.last_read("a").is_some());
+        // fsdb's own file isn't a key
+        assert_eq!(b.list().expect("fail list"), vec!["a"]);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::error::WithContext;
+use crate::lockfile::with_lock_file;
+use crate::{generation, Context, Error, Fsdb, Op, Result};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Directory (inside the database) holding a file per alias, naming its bucket
+const ALIASES: &str = ".aliases";
+
+impl Fsdb {
+    /// Point `alias` at bucket `target`, so `bucket(alias)` (and every other
+    /// way of opening a bucket) opens `target`. An existing alias is repointed,
+    /// atomically, as with `swap_alias`. Handles opened before that keep using
+    /// the bucket they opened; open the alias again to follow it
+    pub fn alias(&self, alias: &str, target: &str) -> Result<()> {
+        self.point_alias(alias, target, false).map(|_| ())
+    }
+    /// Repoint an existing alias at `target` in one step, returning the bucket
+    /// it pointed at before, so a new dataset can be built in a shadow bucket
+    /// and then promoted with no moment where the alias is missing
+    pub fn swap_alias(&self, alias: &str, target: &str) -> Result<String> {
+        let old = self.point_alias(alias, target, true)?;
+        Ok(old.unwrap_or_default())
+    }
+    /// The bucket an alias points at, or `None` if it isn't an alias
+    pub fn resolve_alias(&self, alias: &str) -> Result<Option<String>> {
+        target(&self.dir, alias).ctx(|| Context::new(Op::Bucket, &self.dir, Some(alias)))
+    }
+    /// Remove an alias, leaving the bucket it pointed at alone
+    pub fn remove_alias(&self, alias: &str) -> Result<()> {
+        fs::remove_file(self.dir.join(ALIASES).join(alias))
+            .ctx(|| Context::new(Op::Bucket, &self.dir, Some(alias)))
+    }
+    /// Every alias, sorted, with the bucket it points at
+    pub fn aliases(&self) -> Result<Vec<(String, String)>> {
+        let ctx = || Context::new(Op::List, &self.dir, None);
+        let entries = match fs::read_dir(self.dir.join(ALIASES)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        let mut aliases = Vec::new();
+        for entry in entries.flatten() {
+            let Ok(alias) = entry.file_name().into_string() else {
+                continue;
+            };
+            if alias.starts_with('.') {
+                continue;
+            }
+            // removed since listing
+            if let Some(target) = target(&self.dir, &alias).ctx(ctx)? {
+                aliases.push((alias, target));
+            }
+        }
+        aliases.sort();
+        Ok(aliases)
+    }
+    // write the alias file, returning what it pointed at before, or failing if
+    // there wasn't one and it `must_exist`
+    fn point_alias(&self, alias: &str, target: &str, must_exist: bool) -> Result<Option<String>> {
+        let ctx = || Context::new(Op::Bucket, &self.dir, Some(alias));
+        if !valid_name(alias) {
+            return Err(self.alias_error(
+                alias,
+                io::ErrorKind::InvalidInput,
+                "isn't a valid alias",
+            ));
+        }
+        // resolved inside the database only
+        if !valid_name(target) {
+            return Err(self.alias_error(
+                target,
+                io::ErrorKind::InvalidInput,
+                "isn't a valid alias target",
+            ));
+        }
+        // the alias would hide it
+        if self.dir.join(alias).exists() {
+            return Err(self.alias_error(alias, io::ErrorKind::AlreadyExists, "is a bucket"));
+        }
+        if !self.dir.join(target).is_dir() {
+            return Err(self.alias_error(target, io::ErrorKind::NotFound, "isn't a bucket"));
+        }
+        let dir = self.dir.join(ALIASES);
+        fs::create_dir_all(&dir).ctx(ctx)?;
+        with_lock_file(&dir.join(format!(".{}", alias)), || {
+            let old = target_in(&dir, alias)?;
+            if must_exist && old.is_none() {
+                let msg = format!("{:?} isn't an alias", alias);
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+            }
+            let tmp = crate::write_temp(&dir, alias, target.as_bytes(), true, false)?;
+            fs::rename(tmp, dir.join(alias))?;
+            // what the alias opens has been replaced, as far as its users can tell
+            if let Some(old) = old.as_deref().filter(|old| *old != target) {
+                if self.dir.join(old).is_dir() {
+                    generation::bump(&self.dir.join(old))?;
+                }
+                generation::bump(&self.dir.join(target))?;
+            }
+            Ok(old)
+        })
+        .ctx(ctx)
+    }
+    fn alias_error(&self, name: &str, kind: io::ErrorKind, problem: &str) -> Error {
+        Error::Io {
+            ctx: Context::new(Op::Bucket, &self.dir, Some(name)),
+            source: io::Error::new(kind, format!("{:?} {}", name, problem)),
+        }
+    }
+}
+
+// whether `name` can be an alias or its target: a top-level bucket, not one of
+// fsdb's own directories, nor a path out of the database
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\'])
+}
+
+/// The bucket `name` points at in database `dir`, or None if it isn't an alias
+pub(crate) fn target(dir: &Path, name: &str) -> io::Result<Option<String>> {
+    if !valid_name(name) {
+        return Ok(None);
+    }
+    target_in(&dir.join(ALIASES), name)
+}
+
+fn target_in(aliases: &Path, name: &str) -> io::Result<Option<String>> {
+    match fs::read_to_string(aliases.join(name)) {
+        Ok(target) if valid_name(&target) => Ok(Some(target)),
+        // written by hand, or by an older version
+        Ok(target) => {
+            let msg = format!(
+                "alias {:?} points at {:?}, which isn't a bucket name",
+                name, target
+            );
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ErrorKind, Fsdb};
+
+    #[test]
+    fn test_alias() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let v1 = db.bucket::<u32>("data-v1").expect("fail bucket");
+        v1.put("n", 1).expect("failed to save");
+        db.alias("current", "data-v1").expect("fail alias");
+        let current = db.bucket::<u32>("current").expect("fail bucket");
+        assert_eq!(current.get("n").expect("fail get"), 1);
+
+        // build the next version in a shadow bucket, then promote it
+        let v2 = db.bucket::<u32>("data-v2").expect("fail bucket");
+        v2.put("n", 2).expect("failed to save");
+        let old = db
+            .swap_alias("current", "data-v2")
+            .expect("fail swap_alias");
+        assert_eq!(old, "data-v1");
+        let current = db.bucket::<u32>("current").expect("fail bucket");
+        assert_eq!(current.get("n").expect("fail get"), 2);
+        assert_eq!(
+            db.aliases().expect("fail aliases"),
+            vec![("current".to_owned(), "data-v2".to_owned())]
+        );
+
+        assert!(db.swap_alias("latest", "data-v2").is_err());
+        assert_eq!(db.resolve_alias("latest").expect("fail resolve"), None);
+        assert!(db.alias("data-v1", "data-v2").is_err());
+        assert!(db.alias("next", "data-v3").is_err());
+        // targets stay inside the database
+        db.bucket::<u32>("data-v1/sub").expect("fail bucket");
+        for target in ["../other", "data-v1/sub", ".aliases", ""] {
+            let e = db.alias("next", target).expect_err("bad target");
+            assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(db.resolve_alias("next").expect("fail resolve"), None);
+        let planted = db.path().join(".aliases/next");
+        std::fs::write(planted, "../other").expect("fail write");
+        assert!(db.bucket::<u32>("next").is_err());
+        db.remove_alias("next").expect("fail remove_alias");
+
+        db.remove_alias("current").expect("fail remove_alias");
+        assert!(db.aliases().expect("fail aliases").is_empty());
+    }
+}
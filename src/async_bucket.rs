@@ -0,0 +1,384 @@
+use crate::pool::{Blocking, IoPool, Priority};
+use crate::{Bucket, Error, Fsdb, Result};
+use futures_core::Stream;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::VecDeque;
+use std::future::{self, Future};
+use std::io;
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::Poll;
+
+// reading one key for `Export`: None if it was skipped
+type Read<V> = Blocking<Result<Option<(String, V)>>>;
+
+/// A bucket whose operations are futures, from `Bucket::to_async`. They run on
+/// an `IoPool` (the `Fsdb`'s own, or one shared by the process) and wake the
+/// task when done, so they work under any executor (tokio, async-std,
+/// smol, or `futures::executor`) without fsdb depending on one.
+///
+/// Operations are cancellation-safe: dropping a future (on a timeout, say)
+/// doesn't stop its operation, which still finishes on the pool. A put is
+/// written to a temporary file and renamed over the key, so however it ends
+/// the key holds either the old value or the new one, never part of a value,
+/// and no temporary file is left behind
+pub struct AsyncBucket<V> {
+    inner: Bucket<V>,
+    pool: Arc<IoPool>,
+    priority: Priority,
+}
+
+impl<V> Clone for AsyncBucket<V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            pool: self.pool.clone(),
+            priority: self.priority,
+        }
+    }
+}
+
+/// Stream of every key and value in a bucket, from `AsyncBucket::export_stream`
+pub struct Export<V> {
+    inner: Bucket<V>,
+    pool: Arc<IoPool>,
+    priority: Priority,
+    listing: Option<Blocking<Result<Vec<String>>>>,
+    keys: VecDeque<String>,
+    reading: Option<Read<V>>,
+}
+
+impl Fsdb {
+    /// The thread pool async operations on this Fsdb's buckets run on, from
+    /// `FsdbOptions::io_threads`, or else the one shared by the process
+    pub fn io_pool(&self) -> Arc<IoPool> {
+        self.io_pool.clone().unwrap_or_else(IoPool::shared)
+    }
+}
+
+// async operations
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// An async handle to this bucket. See `AsyncBucket`
+    pub fn to_async(&self) -> AsyncBucket<V> {
+        AsyncBucket {
+            inner: self.clone(),
+            pool: self.io_pool.clone().unwrap_or_else(IoPool::shared),
+            priority: Priority::Interactive,
+        }
+    }
+}
+
+impl<V> AsyncBucket<V>
+where
+    V: Serialize + DeserializeOwned + Send + 'static,
+{
+    /// The blocking bucket underneath
+    pub fn bucket(&self) -> &Bucket<V> {
+        &self.inner
+    }
+    /// The thread pool operations run on
+    pub fn pool(&self) -> &Arc<IoPool> {
+        &self.pool
+    }
+    /// A handle whose operations wait in the pool's `priority` queue. Handles
+    /// are `Priority::Interactive` by default; use `Priority::Background` for
+    /// maintenance, like `b.with_priority(Priority::Background).run(|b| b.compact())`
+    pub fn with_priority(&self, priority: Priority) -> Self {
+        Self {
+            priority,
+            ..self.clone()
+        }
+    }
+    /// Get a key
+    pub fn get(&self, key: &str) -> Blocking<Result<V>> {
+        let (b, key) = (self.inner.clone(), key.to_owned());
+        self.pool.spawn(self.priority, move || b.get(&key))
+    }
+    /// Create a key
+    pub fn put(&self, key: &str, value: V) -> Blocking<Result<()>> {
+        let (b, key) = (self.inner.clone(), key.to_owned());
+        self.pool.spawn(self.priority, move || b.put(&key, value))
+    }
+    /// Delete a key
+    pub fn remove(&self, key: &str) -> Blocking<Result<()>> {
+        let (b, key) = (self.inner.clone(), key.to_owned());
+        self.pool.spawn(self.priority, move || b.remove(&key))
+    }
+    /// Check if a key exists
+    pub fn exists(&self, key: &str) -> Blocking<bool> {
+        let (b, key) = (self.inner.clone(), key.to_owned());
+        self.pool.spawn(self.priority, move || b.exists(&key))
+    }
+    /// List keys in this bucket (or sub-buckets in this bucket)
+    pub fn list(&self) -> Blocking<Result<Vec<String>>> {
+        let b = self.inner.clone();
+        self.pool.spawn(self.priority, move || b.list())
+    }
+    /// Run any blocking operation on the bucket, such as a transaction, on the
+    /// thread pool
+    pub fn run<T, F>(&self, f: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Bucket<V>) -> T + Send + 'static,
+    {
+        let b = self.inner.clone();
+        self.pool.spawn(self.priority, move || f(&b))
+    }
+    /// Put every key and value in `items`, with at most `max_in_flight` (at least
+    /// one) running at a time. Returns each put's result, in the order of `items`
+    pub async fn put_many_concurrent<I>(&self, items: I, max_in_flight: usize) -> Vec<Result<()>>
+    where
+        I: IntoIterator<Item = (String, V)>,
+    {
+        let mut items = items.into_iter().enumerate();
+        let mut results: Vec<Option<Result<()>>> = Vec::new();
+        let mut in_flight: Vec<(usize, Blocking<Result<()>>)> = Vec::new();
+        loop {
+            while in_flight.len() < max_in_flight.max(1) {
+                let Some((i, (key, value))) = items.next() else {
+                    break;
+                };
+                results.push(None);
+                in_flight.push((i, self.put(&key, value)));
+            }
+            if in_flight.is_empty() {
+                break;
+            }
+            // wait for at least one to finish
+            future::poll_fn(|cx| {
+                let before = in_flight.len();
+                in_flight.retain_mut(|(i, put)| match Pin::new(put).poll(cx) {
+                    Poll::Ready(r) => {
+                        results[*i] = Some(r);
+                        false
+                    }
+                    Poll::Pending => true,
+                });
+                match in_flight.len() < before {
+                    true => Poll::Ready(()),
+                    false => Poll::Pending,
+                }
+            })
+            .await;
+        }
+        results
+            .into_iter()
+            .map(|r| r.expect("every put finished"))
+            .collect()
+    }
+    /// Stream every key and value in the bucket (not sub-buckets). Keys are
+    /// listed first, then each value is read only when the stream is polled for
+    /// it, so a slow consumer holds back reading. Keys removed since listing are
+    /// skipped
+    pub fn export_stream(&self) -> Export<V> {
+        let b = self.inner.clone();
+        Export {
+            inner: self.inner.clone(),
+            pool: self.pool.clone(),
+            priority: self.priority,
+            listing: Some(self.pool.spawn(self.priority, move || b.list())),
+            keys: VecDeque::new(),
+            reading: None,
+        }
+    }
+    /// Put every key and value from `stream`, such as another bucket's
+    /// `export_stream`. The next item is only taken once the last is saved.
+    /// Returns the number saved, stopping at the first error
+    pub async fn import_stream<S>(&self, stream: S) -> Result<usize>
+    where
+        S: Stream<Item = (String, V)>,
+    {
+        let mut stream = pin!(stream);
+        let mut saved = 0;
+        while let Some((key, value)) = future::poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+            self.put(&key, value).await?;
+            saved += 1;
+        }
+        Ok(saved)
+    }
+}
+
+// nothing in it is pinned in place
+impl<V> Unpin for Export<V> {}
+
+impl<V> Stream for Export<V>
+where
+    V: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Item = Result<(String, V)>;
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(listing) = &mut this.listing {
+                let keys = match Pin::new(listing).poll(cx) {
+                    Poll::Ready(keys) => keys,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.listing = None;
+                match keys {
+                    Ok(keys) => this.keys = keys.into(),
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+            }
+            if let Some(reading) = &mut this.reading {
+                let read = match Pin::new(reading).poll(cx) {
+                    Poll::Ready(read) => read,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.reading = None;
+                match read {
+                    Ok(Some(item)) => return Poll::Ready(Some(Ok(item))),
+                    Ok(None) => {}
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+            }
+            let key = match this.keys.pop_front() {
+                Some(key) => key,
+                None => return Poll::Ready(None),
+            };
+            let b = this.inner.clone();
+            this.reading = Some(this.pool.spawn(this.priority, move || read_item(&b, key)));
+        }
+    }
+}
+
+// a key's value, or None if it's a sub-bucket or has been removed
+fn read_item<V: Serialize + DeserializeOwned>(
+    b: &Bucket<V>,
+    key: String,
+) -> Result<Option<(String, V)>> {
+    if b.dir.join(&key).is_dir() {
+        return Ok(None);
+    }
+    match b.get(&key) {
+        Ok(value) => Ok(Some((key, value))),
+        Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Fsdb, FsdbOptions, IoPool};
+    use futures_core::Stream;
+    use futures_executor::block_on;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Waker};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // the values of a stream of results, which must all be Ok
+    struct Oks<S>(S);
+
+    impl<T, S: Stream<Item = crate::Result<T>> + Unpin> Stream for Oks<S> {
+        type Item = T;
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+            Pin::new(&mut self.0)
+                .poll_next(cx)
+                .map(|item| item.map(|r| r.expect("fail export")))
+        }
+    }
+
+    #[test]
+    fn test_async_bucket() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("async").expect("fail bucket").to_async();
+        block_on(async {
+            b.put("a", 1).await.expect("failed to save");
+            b.put("b", 2).await.expect("failed to save");
+            assert_eq!(b.get("a").await.expect("fail get"), 1);
+            assert!(b.exists("b").await);
+            b.remove("b").await.expect("fail remove");
+            assert_eq!(b.list().await.expect("fail list"), vec!["a"]);
+            let n = b.run(|b| b.len()).await.expect("fail len");
+            assert_eq!(n, 1);
+            assert!(b.get("b").await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_own_pool() {
+        let tmp = Fsdb::temp().expect("fail Fsdb::temp");
+        let options = FsdbOptions {
+            io_threads: Some(3),
+            ..Default::default()
+        };
+        let path = tmp.path().to_str().expect("non-utf8 temp dir");
+        let db = Fsdb::with_options(path, options).expect("fail Fsdb::with_options");
+        let b = db.bucket::<u32>("pooled").expect("fail bucket").to_async();
+        assert!(Arc::ptr_eq(b.pool(), &db.io_pool()));
+        assert!(!Arc::ptr_eq(b.pool(), &IoPool::shared()));
+        block_on(b.put("a", 1)).expect("failed to save");
+        let stats = db.io_pool().stats();
+        assert_eq!((stats.threads, stats.completed), (3, 1));
+    }
+
+    #[test]
+    fn test_dropped_put() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db
+            .bucket::<Vec<u8>>("dropped")
+            .expect("fail bucket")
+            .to_async();
+        for i in 0..20u8 {
+            // some dropped before they're polled, some after
+            let mut put = b.put(&format!("k{}", i), vec![i; 100_000]);
+            if i % 2 == 0 {
+                let _ = Pin::new(&mut put).poll(&mut Context::from_waker(Waker::noop()));
+            }
+            drop(put);
+        }
+        // they still finish
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while b.bucket().len().expect("fail len") < 20 {
+            assert!(Instant::now() < deadline, "dropped puts didn't finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+        for i in 0..20u8 {
+            let value = b.bucket().get(&format!("k{}", i)).expect("fail get");
+            assert_eq!(value, vec![i; 100_000]);
+        }
+        let dir = db.path().join("dropped");
+        let leftover = std::fs::read_dir(dir)
+            .expect("fail read_dir")
+            .filter_map(|e| e.ok())
+            .any(|e| e.file_name().to_string_lossy().ends_with(".tmp"));
+        assert!(!leftover);
+    }
+
+    #[test]
+    fn test_put_many_concurrent() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("many").expect("fail bucket").to_async();
+        let mut items: Vec<(String, u32)> = (0..50).map(|i| (format!("k{}", i), i)).collect();
+        items.insert(10, ("bad/key".into(), 0));
+        let results = block_on(b.put_many_concurrent(items, 4));
+        assert_eq!(results.len(), 51);
+        assert!(results[10].is_err());
+        let ok = results.iter().filter(|r| r.is_ok()).count();
+        assert_eq!(ok, 50);
+        assert_eq!(b.bucket().get("k49").expect("fail get"), 49);
+    }
+
+    #[test]
+    fn test_export_import_stream() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let from = db.bucket::<u32>("from").expect("fail bucket");
+        for i in 0..20 {
+            from.put(&format!("k{}", i), i).expect("failed to save");
+        }
+        from.put_within("x", 99, "sub").expect("failed to save");
+        let to = db.bucket::<u32>("to").expect("fail bucket").to_async();
+        // piped straight from one bucket to the other
+        let exported = Oks(from.to_async().export_stream());
+        let saved = block_on(to.import_stream(exported)).expect("fail import");
+        assert_eq!(saved, 20);
+        assert_eq!(to.bucket().len().expect("fail len"), 20);
+        assert_eq!(to.bucket().get("k7").expect("fail get"), 7);
+    }
+}
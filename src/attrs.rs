@@ -0,0 +1,212 @@
+use crate::error::WithContext;
+use crate::{header, tags, Bucket, Context, Op, Result};
+use rmp_serde::{decode, encode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Extended attribute holding a value's `Metadata`
+#[cfg(target_os = "linux")]
+const XATTR: &str = "user.fsdb.attrs";
+
+/// Metadata stored with a value, from `Bucket::put_with_meta`, such as the
+/// content type to serve it with. fsdb keeps it for you, but doesn't act on it.
+///
+/// It's kept in the value file's extended attributes if the bucket uses them
+/// (see `Bucket::set_xattrs`), or in the value's header otherwise. Either way it
+/// belongs to the value: writing the key again without metadata clears it
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    /// When the value should be considered stale
+    pub expires_at: Option<SystemTime>,
+    /// A checksum of the value, in whatever scheme the application uses
+    pub checksum: Option<Vec<u8>>,
+    /// The MIME type of the value, for serving values that hold files or documents
+    pub content_type: Option<String>,
+    /// Free-form labels, see `Bucket::list_by_tag`
+    pub tags: Vec<String>,
+}
+
+// per-key metadata
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Keep metadata in extended attributes (xattrs) rather than in each value's
+    /// header, where the filesystem supports them, so reading it doesn't mean
+    /// reading the value. Returns whether they're supported: if not, this handle
+    /// keeps using headers. Metadata is read from either place regardless
+    pub fn set_xattrs(&mut self, on: bool) -> bool {
+        self.xattrs = on && xattrs_supported(&self.dir);
+        self.xattrs
+    }
+    /// Create a key, with metadata
+    pub fn put_with_meta(&self, key: &str, value: V, attrs: Metadata) -> Result<()> {
+        self.put_with_meta_ref(key, &value, attrs)
+    }
+    /// Create a key, with metadata, from a borrowed value
+    pub fn put_with_meta_ref(&self, key: &str, value: &V, attrs: Metadata) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.dir, Some(key));
+        let payload = self.payload(value, ctx)?;
+        if !attrs.tags.is_empty() {
+            tags::start_indexing(&self.dir).ctx(ctx)?;
+        }
+        if self.xattrs {
+            let xattr = encode::to_vec(&attrs).ctx(ctx)?;
+            let bytes = self.seal_value(Some(key), payload);
+            self.fs_put_bytes_with(&self.dir, key, &bytes, Some(&xattr))?;
+        } else {
+            let bytes = self.seal_with(Some(key), payload, Some(attrs));
+            self.fs_put_bytes(&self.dir, key, &bytes)?;
+        }
+        self.publish_put(key, value);
+        Ok(())
+    }
+    /// Get a key's metadata (empty if it has none)
+    pub fn get_meta(&self, key: &str) -> Result<Metadata> {
+        let ctx = || Context::new(Op::Get, &self.dir, Some(key));
+        let path = self.dir.join(self.maxify(key));
+        if let Some(xattr) = get_xattr(&path).ctx(ctx)? {
+            return decode::from_slice(&xattr).ctx(ctx);
+        }
+        let bytes = self.read_file(&self.dir, &self.maxify(key)).ctx(ctx)?;
+        let (header, _) = header::split(&bytes)
+            .ok_or_else(|| decode::Error::Syntax("corrupt value header".into()))
+            .ctx(ctx)?;
+        Ok(header.attrs.unwrap_or_default())
+    }
+}
+
+// try setting an attribute on a scratch file
+fn xattrs_supported(dir: &Path) -> bool {
+    let probe = dir.join(".xattr-probe");
+    let ok = fs::write(&probe, b"").is_ok() && set_xattr(&probe, b"").is_ok();
+    let _ = fs::remove_file(&probe);
+    ok
+}
+
+#[cfg(target_os = "linux")]
+fn c_string(s: &[u8]) -> io::Result<std::ffi::CString> {
+    std::ffi::CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Store encoded `Metadata` in a file's extended attributes
+#[cfg(target_os = "linux")]
+pub(crate) fn set_xattr(path: &Path, value: &[u8]) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(XATTR.as_bytes())?;
+    // SAFETY: both strings are valid C strings, and value is valid for its length
+    let res = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Read encoded `Metadata` from a file's extended attributes. `None` if it
+/// doesn't have any or doesn't exist (a packed value), or the filesystem
+/// doesn't support them
+#[cfg(target_os = "linux")]
+pub(crate) fn get_xattr(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(XATTR.as_bytes())?;
+    let absent = |e: &io::Error| {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::ENODATA) | Some(libc::EOPNOTSUPP) | Some(libc::ENOENT)
+        )
+    };
+    // SAFETY: a null buffer of length 0 asks for the attribute's size
+    let len = unsafe { libc::getxattr(c_path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    if len < 0 {
+        let e = io::Error::last_os_error();
+        return if absent(&e) { Ok(None) } else { Err(e) };
+    }
+    let mut buf = vec![0u8; len as usize];
+    // SAFETY: buf is valid for writes of its length
+    let len = unsafe {
+        libc::getxattr(
+            c_path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        let e = io::Error::last_os_error();
+        return if absent(&e) { Ok(None) } else { Err(e) };
+    }
+    buf.truncate(len as usize);
+    Ok(Some(buf))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_xattr(_path: &Path, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are only used on linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn get_xattr(_path: &Path) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metadata;
+    use crate::{header, Fsdb};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn meta() -> Metadata {
+        Metadata {
+            expires_at: Some(UNIX_EPOCH + Duration::from_secs(2_000_000_000)),
+            checksum: Some(vec![1, 2, 3]),
+            content_type: Some("text/plain".into()),
+            tags: vec!["greeting".into()],
+        }
+    }
+
+    #[test]
+    fn test_header_meta() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<String>("docs").expect("fail bucket");
+        b.put_with_meta("a", "hello".into(), meta())
+            .expect("failed to save");
+        assert_eq!(b.get("a").expect("fail get"), "hello");
+        assert_eq!(b.get_meta("a").expect("fail get_meta"), meta());
+        // a plain put replaces them
+        b.put("a", "bye".into()).expect("failed to save");
+        assert_eq!(b.get_meta("a").expect("fail get_meta"), Metadata::default());
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<String>("docs").expect("fail bucket");
+        let supported = b.set_xattrs(true);
+        b.put_with_meta("a", "hello".into(), meta())
+            .expect("failed to save");
+        assert_eq!(b.get("a").expect("fail get"), "hello");
+        assert_eq!(b.get_meta("a").expect("fail get_meta"), meta());
+        let bytes = std::fs::read(db.path().join("docs/a")).expect("fail read");
+        let (header, _) = header::split(&bytes).expect("corrupt header");
+        // falls back to the header where xattrs aren't supported
+        assert_eq!(header.attrs.is_none(), supported);
+
+        // a handle without xattrs turned on still reads them
+        let plain = db.bucket::<String>("docs").expect("fail bucket");
+        assert_eq!(plain.get_meta("a").expect("fail get_meta"), meta());
+    }
+}
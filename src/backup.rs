@@ -0,0 +1,164 @@
+use crate::changelog::CHANGES_DIR;
+use crate::copy;
+use crate::error::WithContext;
+use crate::{Context, Fsdb, Op, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Marks the point a backup was taken from, to pass to the next `backup_incremental`.
+/// It can be serialized and stored alongside the backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BackupMarker(u64);
+
+impl BackupMarker {
+    fn now() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Self(nanos)
+    }
+    fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.0)
+    }
+}
+
+impl Fsdb {
+    /// Bring a backup copy of this database at `dest` up to date.
+    ///
+    /// Only values written since `since` (the marker returned by the previous backup)
+    /// are copied, based on file modification times; pass `None` for a full copy.
+    /// Keys and buckets that no longer exist are removed from the backup. fsdb's
+    /// own files (settings, versions, tags, packed values and so on) are backed
+    /// up with them, but not snapshots, the change log or writes in progress.
+    /// Writes that happen while the backup runs are picked up by the next one.
+    pub fn backup_incremental(
+        &self,
+        dest: &str,
+        since: Option<BackupMarker>,
+    ) -> Result<BackupMarker> {
+        let marker = BackupMarker::now();
+        let ctx = || Context::new(Op::Backup, &self.dir, None);
+        let since = since.map(|m| m.time());
+        copy_changed(&self.dir, Path::new(dest), since).ctx(ctx)?;
+        remove_deleted(&self.dir, Path::new(dest)).ctx(ctx)?;
+        Ok(marker)
+    }
+}
+
+// what a backup leaves out: fsdb's files that copies of a bucket leave out,
+// and the change log
+fn skipped(name: &std::ffi::OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with('.') && (!copy::copied(&name) || name == CHANGES_DIR)
+}
+
+fn copy_changed(src: &Path, dst: &Path, since: Option<SystemTime>) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)?.flatten() {
+        let name = entry.file_name();
+        if skipped(&name) {
+            continue;
+        }
+        let ty = entry.file_type()?;
+        let target = dst.join(&name);
+        if ty.is_dir() {
+            copy_changed(&entry.path(), &target, since)?;
+        } else if ty.is_file() {
+            let modified = entry.metadata()?.modified()?;
+            let changed = match since {
+                Some(since) => modified >= since || !target.exists(),
+                None => true,
+            };
+            if changed {
+                let tmp = dst.join(format!(".{}.backup.tmp", name.to_string_lossy()));
+                fs::copy(entry.path(), &tmp)?;
+                fs::rename(&tmp, &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remove_deleted(src: &Path, dst: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dst)?.flatten() {
+        let name = entry.file_name();
+        if skipped(&name) {
+            continue;
+        }
+        let source = src.join(&name);
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            if source.is_dir() {
+                remove_deleted(&source, &entry.path())?;
+            } else {
+                fs::remove_dir_all(entry.path())?;
+            }
+        } else if !source.is_file() {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pack::PACK;
+    use crate::{Fsdb, Metadata, Version};
+
+    #[test]
+    fn test_backup_incremental() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let backup = Fsdb::temp().expect("fail Fsdb::temp");
+        let dest = backup.path().to_str().expect("non-utf8 temp dir");
+        let b = db.bucket::<u64>("nums").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.put("a", 1).expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        let marker = db.backup_incremental(dest, None).expect("fail backup");
+        b.put("a", 10).expect("failed to save");
+        b.remove("b").expect("fail remove");
+        b.put("c", 3).expect("failed to save");
+        let next = db
+            .backup_incremental(dest, Some(marker))
+            .expect("fail backup");
+        assert!(next > marker);
+
+        let bb = backup.bucket::<u64>("nums").expect("fail bucket");
+        let mut keys = bb.list().expect("fail list");
+        keys.sort();
+        assert_eq!(keys, vec!["a", "c"]);
+        assert_eq!(bb.get("a").expect("fail get"), 10);
+    }
+
+    #[test]
+    fn test_backup_own_files() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let backup = Fsdb::temp().expect("fail Fsdb::temp");
+        let dest = backup.path().to_str().expect("non-utf8 temp dir");
+        let b = db.bucket::<u64>("nums").expect("fail bucket");
+        b.enable_packed().expect("fail enable_packed");
+        b.put("a", 1).expect("failed to save");
+        let version = b
+            .put_versioned("v", 2, Version::default())
+            .expect("fail put");
+        let attrs = Metadata {
+            tags: vec!["red".into()],
+            ..Metadata::default()
+        };
+        b.put_with_meta("t", 3, attrs).expect("failed to save");
+        let marker = db.backup_incremental(dest, None).expect("fail backup");
+        b.remove("a").expect("fail remove");
+        db.backup_incremental(dest, Some(marker))
+            .expect("fail backup");
+
+        let bb = backup.bucket::<u64>("nums").expect("fail bucket");
+        assert!(!bb.exists("a"));
+        assert_eq!(bb.get_versioned("v").expect("fail get"), (2, version));
+        assert_eq!(bb.list_by_tag("red").expect("fail list_by_tag"), vec!["t"]);
+        assert!(backup.path().join("nums").join(PACK).is_dir());
+    }
+}
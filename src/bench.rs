@@ -0,0 +1,173 @@
+//! Timing fsdb's basic operations across value sizes, key counts and storage
+//! modes, for a baseline to check the performance of new features against.
+//! `cargo bench` runs `run` with the default options and prints the results
+
+use crate::{Bucket, BufferOptions, Fsdb, Result};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How the benchmarked bucket stores its values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// One file per key
+    Plain,
+    /// Puts go through a write-behind `BufferedBucket`, flushed at the end
+    Buffered,
+    /// Values are packed into segment files, see `Bucket::enable_packed`
+    Packed,
+}
+
+/// An operation that's timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchOp {
+    /// `put` every key
+    Put,
+    /// `get` every key
+    Get,
+    /// `list` the bucket once
+    List,
+    /// Read every key and value with `snapshot_iter`
+    Iter,
+}
+
+/// What `run` times: every combination of value size, key count and mode
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Sizes of the values stored, in bytes
+    pub value_sizes: Vec<usize>,
+    /// Numbers of keys in the bucket
+    pub key_counts: Vec<usize>,
+    pub modes: Vec<Mode>,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            value_sizes: vec![16, 1024, 64 << 10],
+            key_counts: vec![100, 1000],
+            modes: vec![Mode::Plain, Mode::Buffered, Mode::Packed],
+        }
+    }
+}
+
+/// How long an operation took, on a bucket of `keys` values of `value_size` bytes
+#[derive(Debug, Clone, Copy)]
+pub struct BenchResult {
+    pub op: BenchOp,
+    pub mode: Mode,
+    pub value_size: usize,
+    pub keys: usize,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    /// Time per key
+    pub fn per_key(&self) -> Duration {
+        self.elapsed / self.keys.max(1) as u32
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} {}B x{}: {:?} ({:?}/key)",
+            self.mode,
+            self.op,
+            self.value_size,
+            self.keys,
+            self.elapsed,
+            self.per_key()
+        )
+    }
+}
+
+/// Time put, get, list and iteration for every combination in `opts`, each in a
+/// fresh temporary database
+pub fn run(opts: &BenchOptions) -> Result<Vec<BenchResult>> {
+    let mut results = Vec::new();
+    for &mode in &opts.modes {
+        for &value_size in &opts.value_sizes {
+            for &keys in &opts.key_counts {
+                let db = Fsdb::temp()?;
+                let timings = run_one(&db, mode, value_size, keys)?;
+                results.extend(timings.into_iter().map(|(op, elapsed)| BenchResult {
+                    op,
+                    mode,
+                    value_size,
+                    keys,
+                    elapsed,
+                }));
+            }
+        }
+    }
+    Ok(results)
+}
+
+fn run_one(
+    db: &Fsdb,
+    mode: Mode,
+    value_size: usize,
+    keys: usize,
+) -> Result<Vec<(BenchOp, Duration)>> {
+    let b: Bucket<String> = db.bucket("bench")?;
+    if mode == Mode::Packed {
+        b.enable_packed()?;
+    }
+    let names: Vec<String> = (0..keys).map(|i| format!("key{:08}", i)).collect();
+    let value = "x".repeat(value_size);
+    let mut timings = Vec::new();
+
+    let start = Instant::now();
+    match mode {
+        Mode::Buffered => {
+            let buffered = b.buffered(BufferOptions::default());
+            for name in &names {
+                buffered.put(name, value.clone())?;
+            }
+            buffered.flush()?;
+        }
+        Mode::Plain | Mode::Packed => {
+            for name in &names {
+                b.put(name, value.clone())?;
+            }
+        }
+    }
+    timings.push((BenchOp::Put, start.elapsed()));
+
+    let start = Instant::now();
+    for name in &names {
+        b.get(name)?;
+    }
+    timings.push((BenchOp::Get, start.elapsed()));
+
+    let start = Instant::now();
+    b.list()?;
+    timings.push((BenchOp::List, start.elapsed()));
+
+    let start = Instant::now();
+    for item in b.snapshot_iter()? {
+        item?;
+    }
+    timings.push((BenchOp::Iter, start.elapsed()));
+    Ok(timings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, BenchOp, BenchOptions, Mode};
+
+    #[test]
+    fn test_run() {
+        let opts = BenchOptions {
+            value_sizes: vec![8],
+            key_counts: vec![5],
+            modes: vec![Mode::Plain, Mode::Buffered, Mode::Packed],
+        };
+        let results = run(&opts).expect("fail run");
+        assert_eq!(results.len(), 12);
+        assert_eq!(results[0].op, BenchOp::Put);
+        assert_eq!(results[11].mode, Mode::Packed);
+        assert!(results[0].to_string().starts_with("Plain Put 8B x5"));
+    }
+}
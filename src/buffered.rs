@@ -0,0 +1,318 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Error, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// When a `BufferedBucket` writes its buffered changes to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferOptions {
+    /// Flush at least this often
+    pub interval: Duration,
+    /// Flush early once this many keys have unflushed changes
+    pub max_dirty: usize,
+}
+
+impl Default for BufferOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            max_dirty: 1000,
+        }
+    }
+}
+
+/// A write-behind view of a bucket, from `Bucket::buffered`. Puts and removes
+/// land in memory and return at once, and a background thread writes them to
+/// the bucket every `interval`, or sooner once `max_dirty` keys have changed.
+/// Reads through this handle see the buffered changes; other handles see them
+/// once they're flushed.
+///
+/// Dropping it flushes whatever is still buffered. Errors from background
+/// flushes are kept and returned by the next `flush`, so call `flush` before
+/// shutting down to find out whether everything was written.
+pub struct BufferedBucket<V> {
+    shared: Arc<Shared<V>>,
+    max_dirty: usize,
+    wake: Option<Sender<()>>,
+    flusher: Option<JoinHandle<()>>,
+}
+
+struct Shared<V> {
+    bucket: Bucket<V>,
+    buffers: Mutex<Buffers<V>>,
+    // one flush at a time
+    flushing: Mutex<()>,
+    // the first error from a background flush, since the last `flush`
+    error: Mutex<Option<Error>>,
+    // Shared::write_changes, which Drop and Flush can't name without V's bounds
+    flush_fn: fn(&Shared<V>) -> Result<()>,
+}
+
+// a buffered change is a value to put, or None to remove the key
+struct Buffers<V> {
+    dirty: BTreeMap<String, Option<V>>,
+    // changes being written by the current flush, still visible to reads
+    in_flight: BTreeMap<String, Option<V>>,
+}
+
+impl<V> Default for Buffers<V> {
+    fn default() -> Self {
+        Self {
+            dirty: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V> Bucket<V>
+where
+    V: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    /// Buffer writes to this bucket in memory, see `BufferedBucket`
+    pub fn buffered(&self, opts: BufferOptions) -> BufferedBucket<V> {
+        let shared = Arc::new(Shared {
+            bucket: self.clone(),
+            buffers: Mutex::new(Buffers::default()),
+            flushing: Mutex::new(()),
+            error: Mutex::new(None),
+            flush_fn: Shared::write_changes,
+        });
+        let flush: Weak<dyn Flush> = Arc::downgrade(&shared) as Weak<Shared<V>>;
+        let mut buffers = self
+            .state
+            .buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        buffers.retain(|b| b.strong_count() > 0);
+        buffers.push(flush);
+        drop(buffers);
+        let (wake, woken) = mpsc::channel::<()>();
+        let shared2 = shared.clone();
+        let flusher = std::thread::spawn(move || loop {
+            match woken.recv_timeout(opts.interval) {
+                Ok(()) | Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = shared2.write_changes() {
+                        shared2.error().get_or_insert(e);
+                    }
+                }
+                // the BufferedBucket was dropped, and flushes for itself
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        });
+        BufferedBucket {
+            shared,
+            max_dirty: opts.max_dirty.max(1),
+            wake: Some(wake),
+            flusher: Some(flusher),
+        }
+    }
+}
+
+impl<V> BufferedBucket<V>
+where
+    V: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    /// Buffer a put
+    pub fn put(&self, key: &str, value: V) -> Result<()> {
+        self.change(key, Some(value));
+        Ok(())
+    }
+    /// Get a key, including buffered changes
+    pub fn get(&self, key: &str) -> Result<V> {
+        match self.shared.buffered(key) {
+            Some(Some(value)) => Ok(value),
+            Some(None) => Err(io::Error::from(io::ErrorKind::NotFound))
+                .ctx(|| Context::new(Op::Get, &self.shared.bucket.dir, Some(key))),
+            None => self.shared.bucket.get(key),
+        }
+    }
+    /// Check if a key exists, including buffered changes
+    pub fn exists(&self, key: &str) -> bool {
+        match self.shared.buffered(key) {
+            Some(change) => change.is_some(),
+            None => self.shared.bucket.exists(key),
+        }
+    }
+    /// Buffer a remove. Removing a key that doesn't exist isn't an error
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.change(key, None);
+        Ok(())
+    }
+    /// Number of keys with changes that haven't been flushed
+    pub fn dirty(&self) -> usize {
+        let buffers = self.shared.buffers();
+        buffers.dirty.len() + buffers.in_flight.len()
+    }
+    /// Write every buffered change to the bucket, and wait until it's done. Also
+    /// returns the first error from a background flush since the last call
+    pub fn flush(&self) -> Result<()> {
+        let res = self.shared.write_changes();
+        match self.shared.error().take() {
+            Some(e) => Err(e),
+            None => res,
+        }
+    }
+    /// Ask the background thread to flush now, without waiting for it
+    pub fn flush_async(&self) {
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+    }
+    /// The underlying bucket, which only sees changes once they're flushed
+    pub fn bucket(&self) -> &Bucket<V> {
+        &self.shared.bucket
+    }
+    fn change(&self, key: &str, change: Option<V>) {
+        let dirty = {
+            let mut buffers = self.shared.buffers();
+            buffers.dirty.insert(key.to_owned(), change);
+            buffers.dirty.len()
+        };
+        if dirty >= self.max_dirty {
+            self.flush_async();
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned + Clone> Shared<V> {
+    // the latest buffered change to a key, if it has one
+    fn buffered(&self, key: &str) -> Option<Option<V>> {
+        let buffers = self.buffers();
+        buffers
+            .dirty
+            .get(key)
+            .or_else(|| buffers.in_flight.get(key))
+            .cloned()
+    }
+    // write the changes buffered so far. Any that fail stay buffered for next time
+    fn write_changes(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().expect("flush lock poisoned");
+        let changes = {
+            let mut buffers = self.buffers();
+            let dirty = std::mem::take(&mut buffers.dirty);
+            buffers.in_flight = dirty.clone();
+            dirty
+        };
+        let mut res = Ok(());
+        for (key, change) in &changes {
+            let written = match change {
+                Some(value) => self.bucket.put(key, value.clone()),
+                None => match self.bucket.remove(key) {
+                    Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                        Ok(())
+                    }
+                    r => r,
+                },
+            };
+            if let Err(e) = written {
+                res = Err(e);
+                break;
+            }
+        }
+        let mut buffers = self.buffers();
+        let in_flight = std::mem::take(&mut buffers.in_flight);
+        if res.is_err() {
+            // put back what wasn't written, unless it's changed again since
+            for (key, change) in in_flight {
+                buffers.dirty.entry(key).or_insert(change);
+            }
+        }
+        res
+    }
+}
+
+/// A write-behind buffer, as seen by `Fsdb::close`
+pub(crate) trait Flush: Send + Sync {
+    fn flush(&self) -> Result<()>;
+}
+
+impl<V: Send + Sync> Flush for Shared<V> {
+    fn flush(&self) -> Result<()> {
+        (self.flush_fn)(self)
+    }
+}
+
+impl<V> Shared<V> {
+    fn buffers(&self) -> MutexGuard<'_, Buffers<V>> {
+        self.buffers.lock().expect("write buffer poisoned")
+    }
+    fn error(&self) -> MutexGuard<'_, Option<Error>> {
+        self.error.lock().expect("flush error poisoned")
+    }
+}
+
+impl<V> Drop for BufferedBucket<V> {
+    fn drop(&mut self) {
+        drop(self.wake.take());
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+        // there's no way to report an error from here, see `flush`
+        let _ = (self.shared.flush_fn)(&self.shared);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferOptions;
+    use crate::Fsdb;
+    use std::time::Duration;
+
+    #[test]
+    fn test_buffered() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("buffered").expect("fail bucket");
+        b.put("gone", 0).expect("failed to save");
+        let opts = BufferOptions {
+            interval: Duration::from_secs(60),
+            max_dirty: 100,
+        };
+        let buf = b.buffered(opts);
+        buf.put("a", 1).expect("failed to buffer");
+        buf.remove("gone").expect("failed to buffer");
+        buf.remove("never").expect("failed to buffer");
+        assert_eq!(buf.get("a").expect("fail get"), 1);
+        assert!(buf.get("gone").is_err());
+        assert!(!buf.exists("gone"));
+        // not written yet
+        assert!(!b.exists("a"));
+        assert!(b.exists("gone"));
+        assert_eq!(buf.dirty(), 3);
+
+        buf.flush().expect("fail flush");
+        assert_eq!(buf.dirty(), 0);
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        assert!(!b.exists("gone"));
+
+        // dropping flushes too
+        buf.put("b", 2).expect("failed to buffer");
+        drop(buf);
+        assert_eq!(b.get("b").expect("fail get"), 2);
+    }
+
+    #[test]
+    fn test_background_flush() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("buffered").expect("fail bucket");
+        let opts = BufferOptions {
+            interval: Duration::from_secs(60),
+            max_dirty: 2,
+        };
+        let buf = b.buffered(opts);
+        buf.put("a", 1).expect("failed to buffer");
+        buf.put("b", 2).expect("failed to buffer");
+        // max_dirty reached, so the flusher runs without waiting for the interval
+        let mut waited = 0;
+        while !b.exists("b") && waited < 100 {
+            std::thread::sleep(Duration::from_millis(10));
+            waited += 1;
+        }
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        assert_eq!(b.get("b").expect("fail get"), 2);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::error::WithContext;
+use crate::state::BucketState;
+use crate::{Bucket, Context, Fsdb, Op, Result};
+use rmp_serde::{decode, encode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// A write to a subscribed bucket, from `Subscription`
+#[derive(Debug, Clone, PartialEq)]
+pub enum BucketEvent<V> {
+    Put { key: String, value: V },
+    Remove { key: String },
+    Clear,
+}
+
+// what gets sent to subscribers. Values are plain msgpack (never encrypted),
+// encoded once and shared by every subscriber
+#[derive(Clone)]
+pub(crate) enum Notice {
+    Put(String, Arc<Vec<u8>>),
+    Remove(String),
+    Clear,
+}
+
+/// Subscribers to a bucket's writes, kept in its `BucketState`
+#[derive(Default)]
+pub(crate) struct Subscribers(Mutex<Vec<Sender<Notice>>>);
+
+impl Subscribers {
+    pub(crate) fn active(&self) -> bool {
+        !self
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+    pub(crate) fn publish(&self, notice: Notice) {
+        let mut subs = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        // dropped subscriptions are removed the next time something is published
+        subs.retain(|s| s.send(notice.clone()).is_ok());
+    }
+    fn add(&self) -> Receiver<Notice> {
+        let (tx, rx) = mpsc::channel();
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(tx);
+        rx
+    }
+}
+
+/// Events for writes to one bucket made in this process (through any handle to
+/// it), from `Fsdb::subscribe`. Writes to sub-buckets and from other processes
+/// are not seen. Events queue up until received
+pub struct Subscription<V> {
+    rx: Receiver<Notice>,
+    dir: PathBuf,
+    // keeps the subscriber list alive while no bucket handles are open
+    _state: Arc<BucketState>,
+    _v: PhantomData<V>,
+}
+
+impl Fsdb {
+    /// Subscribe to writes to a bucket (creating it if needed). See `Subscription`
+    pub fn subscribe<V: Serialize + DeserializeOwned>(&self, p: &str) -> Result<Subscription<V>> {
+        let bucket = self.bucket::<V>(p)?;
+        Ok(Subscription {
+            rx: bucket.state.subscribers.add(),
+            dir: bucket.dir.clone(),
+            _state: bucket.state.clone(),
+            _v: PhantomData,
+        })
+    }
+}
+
+impl<V: DeserializeOwned> Subscription<V> {
+    /// Wait for the next event
+    pub fn recv(&self) -> Result<BucketEvent<V>> {
+        let notice = self.rx.recv().expect("bucket state holds a sender");
+        self.event(notice)
+    }
+    /// Get the next event if there is one, without waiting
+    pub fn try_recv(&self) -> Result<Option<BucketEvent<V>>> {
+        match self.rx.try_recv() {
+            Ok(notice) => self.event(notice).map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => unreachable!("bucket state holds a sender"),
+        }
+    }
+    /// Wait up to `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<BucketEvent<V>>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(notice) => self.event(notice).map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => unreachable!("bucket state holds a sender"),
+        }
+    }
+    fn event(&self, notice: Notice) -> Result<BucketEvent<V>> {
+        Ok(match notice {
+            Notice::Put(key, bytes) => {
+                let value = decode::from_slice(&bytes)
+                    .ctx(|| Context::new(Op::Get, &self.dir, Some(&key)))?;
+                BucketEvent::Put { key, value }
+            }
+            Notice::Remove(key) => BucketEvent::Remove { key },
+            Notice::Clear => BucketEvent::Clear,
+        })
+    }
+}
+
+// publishing from write paths
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    pub(crate) fn publish_put(&self, key: &str, value: &V) {
+        if let Some(notice) = put_notice(&self.state, &self.maxify(key), value) {
+            self.state.subscribers.publish(notice);
+        }
+    }
+    pub(crate) fn publish(&self, notice: Notice) {
+        self.state.subscribers.publish(notice);
+    }
+}
+
+/// A notice for a put, or `None` if the bucket has no subscribers
+pub(crate) fn put_notice<V: Serialize>(
+    state: &BucketState,
+    name: &str,
+    value: &V,
+) -> Option<Notice> {
+    if !state.subscribers.active() {
+        return None;
+    }
+    // the value was already encoded once to be stored, so this can't fail
+    let bytes = encode::to_vec(value).ok()?;
+    Some(Notice::Put(name.to_owned(), Arc::new(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Thing {
+        n: u8,
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("hi").expect("fail bucket");
+        b.clear().expect("fail clear");
+        let sub = db.subscribe::<Thing>("hi").expect("fail subscribe");
+        assert_eq!(sub.try_recv().expect("fail recv"), None);
+
+        b.put("a", Thing { n: 1 }).expect("failed to save");
+        b.put_within("x", Thing { n: 9 }, "sub")
+            .expect("failed to save");
+        b.remove("a").expect("fail remove");
+        let mut txn = db.transaction().expect("fail txn");
+        txn.put(&b, "b", Thing { n: 2 }).expect("fail stage");
+        txn.commit().expect("fail commit");
+        b.clear().expect("fail clear");
+
+        let put = |key: &str, n| BucketEvent::Put {
+            key: key.into(),
+            value: Thing { n },
+        };
+        assert_eq!(sub.recv().expect("fail recv"), put("a", 1));
+        assert_eq!(
+            sub.recv().expect("fail recv"),
+            BucketEvent::Remove { key: "a".into() }
+        );
+        assert_eq!(sub.recv().expect("fail recv"), put("b", 2));
+        assert_eq!(sub.recv().expect("fail recv"), BucketEvent::Clear);
+        assert_eq!(sub.try_recv().expect("fail recv"), None);
+    }
+}
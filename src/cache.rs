@@ -0,0 +1,224 @@
+use crate::state::BucketState;
+use crate::Bucket;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Read cache counters for a bucket, from `Bucket::cache_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from the cache
+    pub hits: u64,
+    /// Reads that went to disk, because the key wasn't cached or had changed
+    pub misses: u64,
+    /// Entries dropped to make room for others
+    pub evictions: u64,
+    /// Entries cached now
+    pub entries: usize,
+}
+
+/// An in-process LRU cache of the files of recently read keys. An entry is
+/// only used while the file's identity, length and modification time still
+/// match, so writes by other handles and processes are never missed
+pub(crate) struct ReadCache {
+    max_entries: usize,
+    entries: HashMap<String, Entry>,
+    // entries by last use, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+struct Entry {
+    stamp: Stamp,
+    bytes: Arc<Vec<u8>>,
+    used: u64,
+}
+
+// writes rename a new file into place, so on unix the inode changes with every write
+#[derive(PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    #[cfg(unix)]
+    ino: u64,
+}
+
+impl Stamp {
+    fn of(meta: &fs::Metadata) -> Self {
+        Self {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            #[cfg(unix)]
+            ino: std::os::unix::fs::MetadataExt::ino(meta),
+        }
+    }
+}
+
+impl ReadCache {
+    pub(crate) fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries: max_entries.max(1),
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+
+    // the cached file, if it hasn't changed since
+    fn get(&mut self, name: &str, stamp: &Stamp) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(name) {
+            Some(e) if e.stamp == *stamp => {
+                self.order.remove(&e.used);
+                e.used = tick;
+                self.order.insert(tick, name.to_owned());
+                self.stats.hits += 1;
+                Some(e.bytes.clone())
+            }
+            _ => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, name: &str, stamp: Stamp, bytes: Arc<Vec<u8>>) {
+        self.remove(name);
+        while self.entries.len() >= self.max_entries {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, name.to_owned());
+        let used = self.tick;
+        self.entries
+            .insert(name.to_owned(), Entry { stamp, bytes, used });
+    }
+
+    /// Drop a key that was written or removed
+    pub(crate) fn remove(&mut self, name: &str) {
+        if let Some(e) = self.entries.remove(name) {
+            self.order.remove(&e.used);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+/// Read a top-level key's file, through the bucket's read cache if it has one
+pub(crate) fn read(state: &BucketState, path: &Path, name: &str) -> io::Result<Vec<u8>> {
+    if state.cache().is_none() {
+        return fs::read(path);
+    }
+    let stamp = Stamp::of(&fs::metadata(path)?);
+    if let Some(bytes) = state.cache().as_mut().and_then(|c| c.get(name, &stamp)) {
+        return Ok(bytes.to_vec());
+    }
+    let bytes = fs::read(path)?;
+    // only cache it if it wasn't replaced while we read it
+    let after = Stamp::of(&fs::metadata(path)?);
+    if after == stamp {
+        if let Some(cache) = state.cache().as_mut() {
+            cache.insert(name, stamp, Arc::new(bytes.clone()));
+        }
+    }
+    Ok(bytes)
+}
+
+// read caching
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Keep the last `max_entries` keys read from this bucket in memory, for
+    /// every handle to it in this process. A cached key still costs a `stat` to
+    /// check it hasn't changed, but skips reading the file. Calling this again
+    /// resizes the cache, emptying it
+    pub fn enable_read_cache(&self, max_entries: usize) {
+        *self.state.cache() = Some(ReadCache::new(max_entries));
+    }
+    /// Hit, miss and eviction counts of the read cache, or `None` if it isn't enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.state.cache().as_ref().map(ReadCache::stats)
+    }
+    // read a key's file in `dir`, on the watchdog thread if there's a timeout
+    pub(crate) fn read_file(&self, dir: &Path, name: &str) -> io::Result<Vec<u8>> {
+        let path: PathBuf = dir.join(name);
+        if dir == self.dir {
+            let packed = match self.state.pack().as_ref() {
+                Some(p) => p.get(name)?,
+                None => None,
+            };
+            // values too large to pack are still files
+            if let Some(bytes) = packed {
+                return Ok(bytes);
+            }
+        }
+        if dir != self.dir {
+            return self.timed(move || fs::read(&path));
+        }
+        let state = self.state.clone();
+        let name = name.to_owned();
+        self.timed(move || read(&state, &path, &name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CacheStats;
+    use crate::Fsdb;
+
+    #[test]
+    fn test_read_cache() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("cached").expect("fail bucket");
+        assert!(b.cache_stats().is_none());
+        b.enable_read_cache(2);
+        b.put("a", 1).expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        b.put("c", 3).expect("failed to save");
+
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        assert_eq!(b.get("b").expect("fail get"), 2);
+        // evicts a
+        assert_eq!(b.get("c").expect("fail get"), 3);
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        let stats = b.cache_stats().expect("no cache");
+        assert_eq!(
+            stats,
+            CacheStats {
+                hits: 1,
+                misses: 4,
+                evictions: 2,
+                entries: 2,
+            }
+        );
+
+        // writes through other handles are seen
+        let other = db.bucket::<u32>("cached").expect("fail bucket");
+        other.put("a", 10).expect("failed to save");
+        assert_eq!(b.get("a").expect("fail get"), 10);
+        other.remove("a").expect("fail remove");
+        assert!(b.get("a").is_err());
+        assert_eq!(b.get_or_default("c").expect("fail get"), 3);
+        assert_eq!(b.cache_stats().expect("no cache").hits, 2);
+    }
+}
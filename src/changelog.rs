@@ -0,0 +1,500 @@
+use crate::error::WithContext;
+use crate::pack;
+use crate::snapshot::{link_or_copy, link_tree};
+use crate::{Bucket, Context, Error, Fsdb, Op, Result};
+use rmp_serde::{decode, encode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Directory (inside the database) holding the change log, in segment files
+/// named after the sequence number of their first record
+pub(crate) const CHANGES_DIR: &str = ".changes";
+/// Directory (inside `CHANGES_DIR`) of copies of the whole database, named
+/// after the sequence number they were taken at, with `keep_values`
+pub(crate) const CHECKPOINTS: &str = "checkpoints";
+/// Directory (inside `CHANGES_DIR`) of the values written, named after the
+/// sequence number of their record, with `keep_values`
+pub(crate) const VALUES: &str = "values";
+
+/// Options for the change log, see `Fsdb::enable_change_log`
+#[derive(Debug, Clone)]
+pub struct ChangeLogOptions {
+    /// How long to keep records. They're deleted a segment at a time, so some
+    /// are kept a little longer
+    pub retention: Duration,
+    /// Start a new segment file once the current one reaches this many bytes
+    pub segment_size: u64,
+    /// Keep every value written, and a checkpoint of the database each time a
+    /// segment is started, for `Fsdb::restore_to`. Both are hard links, so they
+    /// take no extra space until keys are overwritten or removed
+    pub keep_values: bool,
+}
+
+impl Default for ChangeLogOptions {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+            segment_size: 4 << 20,
+            keep_values: false,
+        }
+    }
+}
+
+/// A mutation recorded in the change log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    /// Increases with every record, see `Fsdb::changes_since`
+    pub seq: u64,
+    pub at: SystemTime,
+    /// The bucket's directory, relative to the database's
+    pub bucket: PathBuf,
+    /// `None` for `Op::Clear`, which removes every key
+    pub key: Option<String>,
+    /// `Op::Put`, `Op::Remove` or `Op::Clear`
+    pub op: Op,
+    /// A hash of the key's stored file before the change, if it existed
+    pub before: Option<u64>,
+    /// A hash of the key's stored file after the change, if it exists
+    pub after: Option<u64>,
+    /// Who made the change, if it was made through a handle from `Bucket::with_context`
+    #[serde(default)]
+    pub actor: Option<String>,
+}
+
+/// An append-only log of every write to buckets opened through an `Fsdb`
+/// with a change log, shared by their `BucketState`s
+pub(crate) struct ChangeLog {
+    root: PathBuf,
+    options: ChangeLogOptions,
+    writer: Mutex<Writer>,
+}
+
+struct Writer {
+    file: Option<File>,
+    size: u64,
+    last_seq: u64,
+}
+
+impl ChangeLog {
+    pub(crate) fn open(root: &Path, options: ChangeLogOptions) -> io::Result<Self> {
+        let dir = root.join(CHANGES_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut writer = Writer {
+            file: None,
+            size: 0,
+            last_seq: 0,
+        };
+        if let Some((start, path)) = numbered(&dir, ".log")?.pop_back() {
+            let last = read_segment(&path)?.last().map(|r| r.seq);
+            writer.last_seq = last.unwrap_or(start);
+            writer.file = Some(OpenOptions::new().append(true).open(&path)?);
+            writer.size = fs::metadata(&path)?.len();
+        }
+        let log = Self {
+            root: root.to_path_buf(),
+            options,
+            writer: Mutex::new(writer),
+        };
+        // restoring needs somewhere to start from
+        if log.options.keep_values && numbered(&dir.join(CHECKPOINTS), "")?.is_empty() {
+            let mut writer = log.writer.lock().unwrap_or_else(PoisonError::into_inner);
+            let seq = nanos(SystemTime::now()).max(writer.last_seq + 1);
+            log.checkpoint(seq)?;
+            writer.last_seq = seq;
+        }
+        Ok(log)
+    }
+    /// Append a record of `op` on `key` in the bucket at `dir`
+    pub(crate) fn record(
+        &self,
+        dir: &Path,
+        key: Option<&str>,
+        op: Op,
+        before: Option<u64>,
+        after: Option<u64>,
+        actor: Option<&str>,
+    ) -> io::Result<()> {
+        let record = ChangeRecord {
+            seq: 0,
+            at: SystemTime::now(),
+            bucket: dir.strip_prefix(&self.root).unwrap_or(dir).to_path_buf(),
+            key: key.map(str::to_owned),
+            op,
+            before,
+            after,
+            actor: actor.map(str::to_owned),
+        };
+        // the caller holds the key lock, so this is the value just written
+        self.append(record, |kept| match key {
+            Some(key) => link_or_copy(&dir.join(key), kept),
+            None => Ok(()),
+        })
+    }
+    /// Append a record of packed value `bytes` (see `Bucket::enable_packed`)
+    /// being put as `key` in the bucket at `dir`
+    pub(crate) fn record_packed(
+        &self,
+        dir: &Path,
+        key: &str,
+        before: Option<u64>,
+        bytes: &[u8],
+        actor: Option<&str>,
+    ) -> io::Result<()> {
+        let record = ChangeRecord {
+            seq: 0,
+            at: SystemTime::now(),
+            bucket: dir.strip_prefix(&self.root).unwrap_or(dir).to_path_buf(),
+            key: Some(key.to_owned()),
+            op: Op::Put,
+            before,
+            after: Some(hash_bytes(bytes)),
+            actor: actor.map(str::to_owned),
+        };
+        self.append(record, |kept| fs::write(kept, bytes))
+    }
+    // number `record` and append it, with `keep` copying the value put to
+    // where it's kept
+    fn append(
+        &self,
+        mut record: ChangeRecord,
+        keep: impl FnOnce(&Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        // a panic can at worst leave a torn record at the end, which readers skip
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let seq = nanos(SystemTime::now()).max(writer.last_seq + 1);
+        record.seq = seq;
+        record.at = SystemTime::now();
+        let bytes =
+            encode::to_vec(&record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut buf = (bytes.len() as u32).to_le_bytes().to_vec();
+        buf.extend(bytes);
+        if writer.file.is_none() || writer.size >= self.options.segment_size {
+            self.rotate(&mut writer, seq)?;
+        }
+        if let (true, Op::Put, Some(_)) = (self.options.keep_values, record.op, &record.key) {
+            let values = self.root.join(CHANGES_DIR).join(VALUES);
+            fs::create_dir_all(&values)?;
+            keep(&values.join(format!("{:020}", seq)))?;
+        }
+        if let Some(file) = writer.file.as_mut() {
+            // one write, so appends from other processes don't interleave with it
+            file.write_all(&buf)?;
+        }
+        writer.size += buf.len() as u64;
+        writer.last_seq = seq;
+        Ok(())
+    }
+    // start a new segment at `seq`, and delete the ones past their retention
+    fn rotate(&self, writer: &mut Writer, seq: u64) -> io::Result<()> {
+        let dir = self.root.join(CHANGES_DIR);
+        let path = dir.join(format!("{:020}.log", seq));
+        writer.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        writer.size = 0;
+        let mut cutoff =
+            nanos(SystemTime::now()).saturating_sub(self.options.retention.as_nanos() as u64);
+        if self.options.keep_values {
+            // everything before `seq` is already written, so it's in the checkpoint
+            let latest = numbered(&dir.join(CHECKPOINTS), "")?.pop_back();
+            if latest.is_none_or(|(c, _)| c < writer.last_seq) {
+                self.checkpoint(seq - 1)?;
+            }
+            // keep what's needed to restore to any time since the cutoff: the last
+            // checkpoint before it, and what came after that
+            let checkpoints = numbered(&dir.join(CHECKPOINTS), "")?;
+            let Some(keep) = checkpoints.iter().rev().find(|(c, _)| *c <= cutoff) else {
+                return Ok(());
+            };
+            cutoff = keep.0;
+            for (_, path) in checkpoints.iter().take_while(|(c, _)| *c < cutoff) {
+                fs::remove_dir_all(path)?;
+            }
+            for (_, path) in numbered(&dir.join(VALUES), "")?
+                .iter()
+                .take_while(|(s, _)| *s < cutoff)
+            {
+                fs::remove_file(path)?;
+            }
+        }
+        let segments = numbered(&dir, ".log")?;
+        // every record in a segment is older than the start of the next one
+        for ((_, path), (next, _)) in segments.iter().zip(segments.iter().skip(1)) {
+            if *next <= cutoff {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+    // link the whole database's values (but not fsdb's own files) into a
+    // checkpoint at `seq`
+    fn checkpoint(&self, seq: u64) -> io::Result<()> {
+        let dir = self.root.join(CHANGES_DIR).join(CHECKPOINTS);
+        let tmp = dir.join(format!(".{:020}.tmp", seq));
+        match fs::remove_dir_all(&tmp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        link_tree(&self.root, &tmp)?;
+        pack::unpack_tree(&self.root, &tmp)?;
+        fs::rename(tmp, dir.join(format!("{:020}", seq)))
+    }
+}
+
+/// A hash of a stored file, or `None` if it doesn't exist. FNV-1a, which is
+/// stable across processes and versions, unlike `DefaultHasher`
+pub(crate) fn hash_file(path: &Path) -> io::Result<Option<u64>> {
+    let bytes = match fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(hash_bytes(&bytes)))
+}
+
+/// A hash of a stored value, the same as `hash_file` gives for a file holding it
+pub(crate) fn hash_bytes(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub(crate) fn nanos(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// The files (or directories) in `dir` named with a sequence number and `suffix`,
+/// oldest first
+pub(crate) fn numbered(dir: &Path, suffix: &str) -> io::Result<VecDeque<(u64, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => return Err(e),
+    };
+    let mut segments: Vec<(u64, PathBuf)> = entries
+        .flatten()
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            let start = name.strip_suffix(suffix)?.parse().ok()?;
+            Some((start, e.path()))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments.into())
+}
+
+// the records in a segment, up to a torn one at the end (from a crash, or an append in progress)
+fn read_segment(path: &Path) -> io::Result<Vec<ChangeRecord>> {
+    let bytes = match fs::read(path) {
+        Ok(b) => b,
+        // deleted by retention since it was listed
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut records = Vec::new();
+    let mut rest = bytes.as_slice();
+    while let Some((len, after)) = rest.split_first_chunk::<4>() {
+        let Some((record, after)) = after.split_at_checked(u32::from_le_bytes(*len) as usize)
+        else {
+            break;
+        };
+        let record = decode::from_slice(record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        records.push(record);
+        rest = after;
+    }
+    Ok(records)
+}
+
+/// Records from the change log, oldest first, from `Fsdb::changes_since`
+pub struct ChangeIter {
+    dir: PathBuf,
+    since: u64,
+    segments: VecDeque<(u64, PathBuf)>,
+    records: std::vec::IntoIter<ChangeRecord>,
+}
+
+impl Iterator for ChangeIter {
+    type Item = Result<ChangeRecord>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.by_ref().find(|r| r.seq > self.since) {
+                return Some(Ok(record));
+            }
+            let (_, path) = self.segments.pop_front()?;
+            match read_segment(&path) {
+                Ok(records) => self.records = records.into_iter(),
+                Err(source) => {
+                    let ctx = Context::new(Op::Get, &self.dir, None);
+                    return Some(Err(Error::Io { ctx, source }));
+                }
+            }
+        }
+    }
+}
+
+// actor attribution, for auditing
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// A handle that records `actor` (such as `"user:42"`) as who made each
+    /// change through it, in the change log. See `ChangeRecord::actor`
+    pub fn with_context(&self, actor: &str) -> Self {
+        let mut b = self.clone();
+        b.actor = Some(actor.into());
+        b
+    }
+    pub(crate) fn actor(&self) -> Option<&str> {
+        self.actor.as_deref()
+    }
+}
+
+// change data capture
+impl Fsdb {
+    /// Log every write to buckets opened from now on (which key, what kind of
+    /// write, and hashes of the value before and after) to an append-only log
+    /// in the database directory, for auditing or syncing elsewhere. Read it
+    /// back with `changes_since`.
+    ///
+    /// Records are kept for `options.retention`. Processes sharing the database
+    /// should all log their writes, but their records may be out of order
+    /// between them. Like `FsdbOptions::change_log`
+    pub fn enable_change_log(&mut self, options: ChangeLogOptions) -> Result<()> {
+        let log = ChangeLog::open(&self.dir, options.clone())
+            .ctx(|| Context::new(Op::Open, &self.dir, None))?;
+        self.change_log = Some(Arc::new(log));
+        self.options.change_log = Some(options);
+        Ok(())
+    }
+    /// The change log's records with a sequence number greater than `since`
+    /// (so 0 for all of them), oldest first. Pass the last record's `seq` next
+    /// time to pick up where this left off
+    pub fn changes_since(&self, since: u64) -> Result<ChangeIter> {
+        let dir = self.dir.join(CHANGES_DIR);
+        let mut segments = numbered(&dir, ".log").ctx(|| Context::new(Op::Get, &dir, None))?;
+        // skip segments that end before `since`
+        while segments.len() > 1 && segments[1].0 <= since {
+            segments.pop_front();
+        }
+        Ok(ChangeIter {
+            dir,
+            since,
+            segments,
+            records: Vec::new().into_iter(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChangeLogOptions;
+    use crate::{Fsdb, Op};
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn test_changes_since() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        db.enable_change_log(ChangeLogOptions::default())
+            .expect("fail enable_change_log");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        b.put("a", 2).expect("failed to save");
+        b.remove("a").expect("fail remove");
+        b.clear().expect("fail clear");
+
+        let changes: Vec<_> = db
+            .changes_since(0)
+            .expect("fail changes_since")
+            .collect::<Result<_, _>>()
+            .expect("fail read change");
+        let ops: Vec<_> = changes.iter().map(|c| (c.op, c.key.as_deref())).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (Op::Put, Some("a")),
+                (Op::Put, Some("a")),
+                (Op::Remove, Some("a")),
+                (Op::Clear, None),
+            ]
+        );
+        assert!(changes.iter().all(|c| c.bucket == Path::new("things")));
+        assert!(changes.windows(2).all(|w| w[0].seq < w[1].seq));
+        assert_eq!(changes[0].before, None);
+        assert!(changes[0].after.is_some());
+        assert_eq!(changes[1].before, changes[0].after);
+        assert_ne!(changes[1].after, changes[1].before);
+        assert_eq!(changes[2].before, changes[1].after);
+        assert_eq!(changes[2].after, None);
+
+        assert!(changes.iter().all(|c| c.actor.is_none()));
+
+        // picks up where it left off
+        let rest = db
+            .changes_since(changes[1].seq)
+            .expect("fail changes_since");
+        assert_eq!(rest.count(), 2);
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        let options = ChangeLogOptions {
+            retention: Duration::ZERO,
+            segment_size: 1,
+            ..Default::default()
+        };
+        db.enable_change_log(options)
+            .expect("fail enable_change_log");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        for i in 0..5 {
+            b.put("a", i).expect("failed to save");
+        }
+        // every record gets its own segment, and all but the newest have expired
+        let changes: Vec<_> = db.changes_since(0).expect("fail changes_since").collect();
+        assert_eq!(changes.len(), 1);
+
+        // a bucket opened without a change log doesn't log
+        let plain = Fsdb::new(&db.path().to_string_lossy()).expect("fail Fsdb::new");
+        let other = plain.bucket::<u8>("others").expect("fail bucket");
+        other.put("b", 1).expect("failed to save");
+        assert_eq!(db.changes_since(0).expect("fail changes_since").count(), 1);
+    }
+
+    #[test]
+    fn test_actor() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        db.enable_change_log(ChangeLogOptions::default())
+            .expect("fail enable_change_log");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        let alice = b.with_context("user:42");
+        alice.put("a", 1).expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.remove(&alice, "a");
+        tx.put(&b.with_context("job:nightly"), "c", 3)
+            .expect("fail stage");
+        tx.commit().expect("fail commit");
+        alice.clear().expect("fail clear");
+
+        let changes: Vec<_> = db
+            .changes_since(0)
+            .expect("fail changes_since")
+            .collect::<Result<_, _>>()
+            .expect("fail read change");
+        let actors: Vec<_> = changes.iter().map(|c| c.actor.as_deref()).collect();
+        assert_eq!(
+            actors,
+            vec![
+                Some("user:42"),
+                None,
+                Some("user:42"),
+                Some("job:nightly"),
+                Some("user:42"),
+            ]
+        );
+    }
+}
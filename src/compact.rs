@@ -0,0 +1,328 @@
+use crate::config::BucketConfig;
+use crate::error::WithContext;
+use crate::header::{self, Header};
+use crate::{attrs, meta, Bucket, BucketOptions, Context, Error, Metadata, Op, Result};
+use rmp_serde::{decode, encode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What `Bucket::reconfigure` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reconfigured {
+    /// The new settings apply to every value already stored
+    Applied,
+    /// Values already stored are to be rewritten with the new settings by
+    /// `compact`. They can be read meanwhile
+    CompactionPending,
+}
+
+/// File (inside a bucket) recording how far an interrupted `compact` got
+const PROGRESS: &str = ".compact";
+
+// save progress after this many keys, so a resumed compaction redoes at most this many
+const SAVE_EVERY: usize = 64;
+
+// rewriting values in the current format
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Rewrite every value (including in sub-buckets) with this handle's current
+    /// settings: its cipher (reading with the previous one, if set), signer,
+    /// `set_named_fields` and `set_xattrs`, and the current header format.
+    /// Values already stored that way are left alone. Returns the number rewritten.
+    ///
+    /// Keys are done one at a time in order, paced by `set_scan_rate_limit`, and
+    /// progress is saved as it goes, so an interrupted compaction picks up where
+    /// it left off next time
+    pub fn compact(&self) -> Result<usize> {
+        let ctx = || Context::new(Op::Put, &self.dir, None);
+        let progress = self.dir.join(PROGRESS);
+        let resume: Option<(String, String)> = match fs::read(&progress) {
+            Ok(buf) => Some(decode::from_slice(&buf).ctx(ctx)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        // the top level ("") first, then each sub-bucket
+        let mut subs = vec![String::new()];
+        let mut names = self.fs_list(&self.dir)?;
+        names.sort();
+        subs.extend(names.into_iter().filter(|n| self.dir.join(n).is_dir()));
+        let mut rewritten = 0;
+        let mut since_saved = 0;
+        let mut pacer = self.pacer();
+        for sub in subs {
+            let dir = self.dir.join(&sub);
+            let mut keys = self.fs_keys(&dir)?;
+            keys.sort();
+            for name in keys {
+                let here = (sub.clone(), name);
+                if resume.as_ref().is_some_and(|r| here <= *r) {
+                    continue;
+                }
+                pacer.tick();
+                if self.compact_file(&dir, &here.1)? {
+                    rewritten += 1;
+                }
+                since_saved += 1;
+                if since_saved == SAVE_EVERY {
+                    save_progress(&progress, &here).ctx(ctx)?;
+                    since_saved = 0;
+                }
+            }
+        }
+        match fs::remove_file(&progress) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).ctx(ctx),
+            _ => (),
+        }
+        if self.compaction_pending()? {
+            BucketConfig::update(&self.dir, |config| {
+                config.compact_pending = false;
+                Ok(())
+            })
+            .ctx(ctx)?;
+        }
+        Ok(rewritten)
+    }
+    /// Change the settings recorded with this bucket (see `Fsdb::bucket_with`),
+    /// for this handle and handles opened later. Settings that change how values
+    /// are encoded, like `named_fields`, are applied to new writes straight
+    /// away, and the bucket is marked for `compact` to rewrite the rest (see
+    /// `compaction_pending`). `max_file_name` changes which file a key is
+    /// stored in, so it can only be changed while the bucket is empty
+    pub fn reconfigure(&mut self, options: BucketOptions) -> Result<Reconfigured> {
+        let ctx = || Context::new(Op::Bucket, &self.dir, None);
+        let _guard = self.state.write_guard();
+        let empty = self.fs_list(&self.dir)?.is_empty();
+        // `None` if the change is refused, which leaves the settings as they were
+        let pending = BucketConfig::update(&self.dir, |config| {
+            if config.max_file_name != options.max_file_name && !empty {
+                return Ok(None);
+            }
+            let reencode = config.named_fields != options.named_fields;
+            config.apply(&options);
+            if reencode && !empty {
+                config.compact_pending = true;
+            }
+            Ok(Some(config.compact_pending))
+        })
+        .ctx(ctx)?;
+        let Some(pending) = pending else {
+            return Err(Error::ConfigMismatch {
+                ctx: ctx(),
+                reason: "max_file_name can't change while the bucket holds values".into(),
+            });
+        };
+        self.max_file_name = options.max_file_name;
+        self.named_fields = options.named_fields;
+        Ok(match pending {
+            true => Reconfigured::CompactionPending,
+            false => Reconfigured::Applied,
+        })
+    }
+    /// Whether `reconfigure` changed settings that values already stored are
+    /// yet to be rewritten with by `compact`
+    pub fn compaction_pending(&self) -> Result<bool> {
+        let config =
+            BucketConfig::load(&self.dir).ctx(|| Context::new(Op::Bucket, &self.dir, None))?;
+        Ok(config.is_some_and(|c| c.compact_pending))
+    }
+    // rewrite one value, if it isn't stored the current way. Returns whether it was rewritten
+    fn compact_file(&self, dir: &Path, name: &str) -> Result<bool> {
+        let ctx = || Context::new(Op::Put, dir, Some(name));
+        let path = dir.join(name);
+        let _lock = self.state.key_lock(&path);
+        let data = match self.retrying(|| self.read_file(dir, name)) {
+            Ok(data) => data,
+            // removed since listing
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        let value: V = match self.decode(&data, ctx) {
+            Ok(value) => value,
+            // expired, for `purge_expired` to remove
+            Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        };
+        let (old, _) = header::split(&data)
+            .ok_or_else(|| decode::Error::Syntax("corrupt value header".into()))
+            .ctx(ctx)?;
+        let old_xattr = attrs::get_xattr(&path).ctx(ctx)?;
+        let attrs: Option<Metadata> = match (&old.attrs, &old_xattr) {
+            (Some(a), _) => Some(a.clone()),
+            (None, Some(x)) => Some(decode::from_slice(x).ctx(ctx)?),
+            (None, None) => None,
+        };
+        let payload = self.payload(&value, ctx)?;
+        let (attrs, xattr) = match self.xattrs {
+            true => (
+                None,
+                attrs.map(|a| encode::to_vec(&a)).transpose().ctx(ctx)?,
+            ),
+            false => (attrs, None),
+        };
+        let header = Header {
+            signature: self.signer.as_ref().map(|s| s.sign(&payload)),
+            // keep what it was stored as, for `PolyBucket`
+            type_tag: old.type_tag,
+            attrs,
+            key: old.key,
+            expires_at: old.expires_at,
+        };
+        let sealed = header::wrap(&header, payload);
+        if sealed == data && xattr == old_xattr {
+            return Ok(false);
+        }
+        // rewriting in place is not a logical change, so the version stays the same
+        let top_level = dir == self.dir;
+        let version = match meta::tracking(dir) {
+            true => Some(self.state.current_version(dir, name, top_level).ctx(ctx)?),
+            false => None,
+        };
+        if top_level && xattr.is_none() {
+            let packed = self
+                .state
+                .install_packed(dir, name, &sealed, version, self.actor())
+                .ctx(ctx)?;
+            if packed {
+                return Ok(true);
+            }
+        }
+        let tmp = self
+            .retrying(|| {
+                crate::write_temp(
+                    dir,
+                    name,
+                    &sealed,
+                    self.state.network_fs(),
+                    self.preallocate,
+                )
+            })
+            .ctx(ctx)?;
+        if let Some(xattr) = &xattr {
+            attrs::set_xattr(&tmp, xattr).ctx(ctx)?;
+        }
+        self.state
+            .install(dir, name, &tmp, version, false, self.actor())
+            .ctx(ctx)?;
+        // a packed value it's grown too large for
+        if top_level {
+            self.state.unpack(name).ctx(ctx)?;
+        }
+        Ok(true)
+    }
+}
+
+fn save_progress(path: &Path, at: &(String, String)) -> io::Result<()> {
+    let buf = encode::to_vec(at).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, buf)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{save_progress, Reconfigured, PROGRESS};
+    use crate::{BucketOptions, Cipher, Fsdb};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        name: String,
+        count: u32,
+    }
+
+    fn item(count: u32) -> Item {
+        Item {
+            name: "widget".into(),
+            count,
+        }
+    }
+
+    // not real encryption: xor with a key byte, tagged so the wrong key is detected
+    struct Xor(u8);
+
+    impl Cipher for Xor {
+        fn encrypt(&self, plain: &[u8]) -> Vec<u8> {
+            let mut out = vec![self.0];
+            out.extend(plain.iter().map(|b| b ^ self.0));
+            out
+        }
+        fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+            let (tag, rest) = data.split_first()?;
+            (*tag == self.0).then(|| rest.iter().map(|b| b ^ self.0).collect())
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<Item>("items").expect("fail bucket");
+        b.put("a", item(1)).expect("failed to save");
+        b.put_within("b", item(2), "sub").expect("failed to save");
+        assert_eq!(b.compact().expect("fail compact"), 0);
+
+        b.set_named_fields(true).expect("fail set_named_fields");
+        assert_eq!(b.compact().expect("fail compact"), 2);
+        let stored = std::fs::read(db.path().join("items/a")).expect("fail read");
+        // a fixmap of two fields
+        assert_eq!(stored[0], 0x82);
+        assert_eq!(b.compact().expect("fail compact"), 0);
+        assert_eq!(b.get("a").expect("fail get"), item(1));
+        assert_eq!(b.get_within("b", "sub").expect("fail get"), item(2));
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<Item>("items").expect("fail bucket");
+        b.put("a", item(1)).expect("failed to save");
+        let named = BucketOptions {
+            named_fields: true,
+            ..Default::default()
+        };
+        let done = b.reconfigure(named.clone()).expect("fail reconfigure");
+        assert_eq!(done, Reconfigured::CompactionPending);
+        assert!(b.compaction_pending().expect("fail compaction_pending"));
+        assert_eq!(b.compact().expect("fail compact"), 1);
+        assert!(!b.compaction_pending().expect("fail compaction_pending"));
+        // recorded for later handles
+        db.bucket_with::<Item>("items", named)
+            .expect("fail bucket_with");
+
+        let truncate = BucketOptions {
+            max_file_name: Some(4),
+            named_fields: true,
+        };
+        assert!(b.reconfigure(truncate.clone()).is_err());
+        b.clear().expect("fail clear");
+        let done = b.reconfigure(truncate).expect("fail reconfigure");
+        assert_eq!(done, Reconfigured::Applied);
+    }
+
+    #[test]
+    fn test_compact_new_key() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut old = db.bucket::<Item>("items").expect("fail bucket");
+        old.set_cipher(Xor(1));
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            old.put(key, item(i as u32)).expect("failed to save");
+        }
+        let mut b = db.bucket::<Item>("items").expect("fail bucket");
+        b.set_cipher(Xor(2));
+        b.set_previous_cipher(Xor(1));
+        // pick up after "a", as though interrupted there
+        let progress = db.path().join("items").join(PROGRESS);
+        save_progress(&progress, &(String::new(), "a".into())).expect("fail save");
+        assert_eq!(b.compact().expect("fail compact"), 2);
+        assert!(!progress.exists());
+
+        let mut new = db.bucket::<Item>("items").expect("fail bucket");
+        new.set_cipher(Xor(2));
+        assert!(new.get("a").is_err());
+        assert_eq!(new.get("c").expect("fail get"), item(2));
+        assert_eq!(b.compact().expect("fail compact"), 1);
+        assert_eq!(new.get("a").expect("fail get"), item(0));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Result of `Bucket::check_compat`
+#[derive(Debug, Default)]
+pub struct CompatReport {
+    /// Number of values that were trial-decoded
+    pub checked: usize,
+    /// Keys that failed to decode (as "sub/key" for sub-buckets), with the error
+    pub failures: Vec<(String, String)>,
+}
+
+impl CompatReport {
+    /// Check if every checked value decoded
+    pub fn is_compatible(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+// schema evolution checks
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Trial-decode stored values (including in sub-buckets) as `W`, and report
+    /// which ones fail, so an incompatible change to a value type can be caught
+    /// before deploying it. Checks at most `limit` values, or all of them if `None`.
+    pub fn check_compat<W: DeserializeOwned>(&self, limit: Option<usize>) -> Result<CompatReport> {
+        let mut report = CompatReport::default();
+        let limit = limit.unwrap_or(usize::MAX);
+        self.check_dir::<W>(&self.dir, "", limit, &mut report)?;
+        for sub in self.fs_list(&self.dir)? {
+            let dir = self.dir.join(&sub);
+            if dir.is_dir() {
+                self.check_dir::<W>(&dir, &format!("{}/", sub), limit, &mut report)?;
+            }
+        }
+        Ok(report)
+    }
+    fn check_dir<W: DeserializeOwned>(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        limit: usize,
+        report: &mut CompatReport,
+    ) -> Result<()> {
+        let mut pacer = self.pacer();
+        for key in self.fs_keys(dir)? {
+            if report.checked >= limit {
+                break;
+            }
+            pacer.tick();
+            let ctx = || Context::new(Op::Get, dir, Some(&key));
+            let bytes = match self.retrying(|| fs::read(dir.join(&key))) {
+                Ok(b) => b,
+                // removed since listing
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).ctx(ctx),
+            };
+            report.checked += 1;
+            if let Err(e) = self.decode_as::<W>(&bytes, ctx) {
+                report
+                    .failures
+                    .push((format!("{}{}", prefix, key), e.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct V1 {
+        n: u8,
+    }
+
+    #[derive(Deserialize)]
+    struct V2 {
+        #[allow(dead_code)]
+        n: u8,
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    #[test]
+    fn test_check_compat() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<V1>("things").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.put("a", V1 { n: 1 }).expect("failed to save");
+        b.put_within("b", V1 { n: 2 }, "sub")
+            .expect("failed to save");
+        assert!(b
+            .check_compat::<V1>(None)
+            .expect("fail check")
+            .is_compatible());
+        let report = b.check_compat::<V2>(None).expect("fail check");
+        assert_eq!(report.checked, 2);
+        let mut keys: Vec<&str> = report.failures.iter().map(|(k, _)| k.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "sub/b"]);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::lockfile::with_lock_file;
+use crate::BucketOptions;
+use rmp_serde::{decode, encode};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// File (inside a bucket) holding its persistent settings
+pub(crate) const CONFIG: &str = ".config";
+
+/// Settings recorded when a bucket is first opened, and checked on later opens
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct BucketConfig {
+    /// `std::any::type_name` of the value type
+    pub type_name: Option<String>,
+    /// files are named after a hash of their key, see `Bucket::enable_hashed_keys`
+    pub hashed_keys: bool,
+    /// from `BucketOptions`
+    pub max_file_name: Option<usize>,
+    pub named_fields: bool,
+    /// values are to be rewritten with changed settings, see `Bucket::reconfigure`
+    pub compact_pending: bool,
+    /// how long puts are kept for, see `Bucket::set_default_ttl`
+    pub default_ttl: Option<Duration>,
+    /// writes are refused, see `Bucket::seal`
+    pub sealed: bool,
+    /// bumped when the contents are replaced wholesale, see `Bucket::generation`
+    pub generation: u64,
+}
+
+impl BucketConfig {
+    pub(crate) fn load(dir: &Path) -> io::Result<Option<Self>> {
+        match fs::read(dir.join(CONFIG)) {
+            Ok(buf) => decode::from_slice(&buf)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Record `options`
+    pub(crate) fn apply(&mut self, options: &BucketOptions) {
+        self.max_file_name = options.max_file_name;
+        self.named_fields = options.named_fields;
+    }
+
+    /// Check `options` match those recorded, or say which doesn't
+    pub(crate) fn check(&self, options: &BucketOptions) -> Result<(), String> {
+        if self.max_file_name != options.max_file_name {
+            return Err(format!(
+                "max_file_name is {:?}, opened with {:?}",
+                self.max_file_name, options.max_file_name
+            ));
+        }
+        if self.named_fields != options.named_fields {
+            return Err(format!(
+                "named_fields is {}, opened with {}",
+                self.named_fields, options.named_fields
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save(&self, dir: &Path) -> io::Result<()> {
+        let buf =
+            encode::to_vec(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp = dir.join(format!("{}.tmp", CONFIG));
+        fs::write(&tmp, buf)?;
+        fs::rename(tmp, dir.join(CONFIG))
+    }
+
+    /// Load the settings in `dir` (the defaults if there are none), change them
+    /// with `f` and save them, holding the lock file throughout so changes
+    /// from other processes aren't lost
+    pub(crate) fn update<T>(
+        dir: &Path,
+        f: impl FnOnce(&mut Self) -> io::Result<T>,
+    ) -> io::Result<T> {
+        with_lock_file(&dir.join(CONFIG), || {
+            let mut config = Self::load(dir)?.unwrap_or_default();
+            let out = f(&mut config)?;
+            config.save(dir)?;
+            Ok(out)
+        })
+    }
+}
@@ -0,0 +1,230 @@
+use crate::error::WithContext;
+use crate::state::BucketState;
+use crate::{tags, Bucket, Context, Error, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+// fsdb's own directories that belong to one copy of a bucket
+const NOT_COPIED: [&str; 5] = [".snapshots", ".uploads", ".txn", ".leases", ".elections"];
+
+// copying buckets
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Replace the contents of `other` with a copy of this bucket's keys and
+    /// sub-buckets. `other` keeps its own settings (manifest, tags, access
+    /// tracking and so on), while version numbers start over.
+    ///
+    /// Files are cloned with a reflink where the filesystem supports it, and
+    /// otherwise hard-linked (fsdb never modifies a value file in place, so the
+    /// buckets can't see each other's writes) or copied. Values are copied as
+    /// stored, so both buckets need the same cipher and signing keys
+    pub fn copy_to(&self, other: &Bucket<V>) -> Result<()> {
+        let ctx = || Context::new(Op::Copy, &other.dir, None);
+        let src = self.dir.canonicalize().ctx(ctx)?;
+        let dst = other.dir.canonicalize().ctx(ctx)?;
+        if src.starts_with(&dst) || dst.starts_with(&src) {
+            let source = io::Error::new(io::ErrorKind::InvalidInput, "buckets overlap");
+            return Err(Error::Io { ctx: ctx(), source });
+        }
+        // lock both buckets in a fixed order, so copies in opposite directions can't deadlock
+        let (_first, _second) = match src < dst {
+            true => (self.state.exclusive_guard(), other.state.exclusive_guard()),
+            false => (other.state.exclusive_guard(), self.state.exclusive_guard()),
+        };
+        other.clear_locked(&other.dir)?;
+        copy_tree(&self.dir, &other.dir, false).ctx(ctx)?;
+        self.copy_packed(&other.dir).ctx(ctx)?;
+        let tagging = tags::indexing(&other.dir);
+        for key in other.fs_list(&other.dir)? {
+            if let Some(m) = other.state.manifest().as_mut() {
+                m.insert(&key).ctx(ctx)?;
+            }
+            if tagging {
+                let new = tags::file_tags(&other.dir.join(&key)).ctx(ctx)?;
+                tags::retag(&other.dir, &key, &[], &new).ctx(ctx)?;
+            }
+        }
+        other.rebuild_indexes_locked()?;
+        other.fill_views()?;
+        other.state.bump_seq(&other.dir).ctx(ctx)
+    }
+}
+
+impl Fsdb {
+    /// Create bucket `dst` as a copy of bucket `src`, including its settings
+    /// and fsdb's files (type, manifest, versions, tags and so on), but not its
+    /// snapshots, leases or writes in progress. `dst` must not exist yet, and
+    /// only appears once fully copied.
+    ///
+    /// Files are cloned the same way as by `Bucket::copy_to`
+    pub fn clone_bucket(&self, src: &str, dst: &str) -> Result<()> {
+        let from = self.dir.join(src);
+        let to = self.dir.join(dst);
+        let ctx = || Context::new(Op::Copy, &to, None);
+        if !from.is_dir() {
+            return Err(Error::NoSuchBucket {
+                ctx: Context::new(Op::Copy, &from, None),
+            });
+        }
+        if to.exists() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists)).ctx(ctx);
+        }
+        let name = to.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = to.with_file_name(format!(".{}.{}.clone.tmp", name, std::process::id()));
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).ctx(ctx)?;
+        }
+        let state = BucketState::get(&from);
+        let _guard = state.exclusive_guard();
+        if let Err(e) = copy_tree(&from, &tmp, true) {
+            let _ = fs::remove_dir_all(&tmp);
+            return Err(e).ctx(ctx);
+        }
+        fs::rename(&tmp, &to).ctx(ctx)
+    }
+}
+
+// copy the keys and sub-buckets under `src` into `dst`, and with `all` fsdb's
+// own files too (apart from those in NOT_COPIED, and temp files)
+fn copy_tree(src: &Path, dst: &Path, all: bool) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)?.flatten() {
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        let hidden = name_str.starts_with('.');
+        if hidden && (!all || !copied(&name_str)) {
+            continue;
+        }
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            copy_tree(&entry.path(), &dst.join(&name), all)?;
+        } else if ty.is_file() {
+            // fsdb's own files can be updated in place, so they're never linked
+            clone_file(&entry.path(), &dst.join(&name), !hidden)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether copies of a bucket take fsdb's own file or directory `name` along:
+/// all of them but those in NOT_COPIED, and lock and temp files
+pub(crate) fn copied(name: &str) -> bool {
+    !NOT_COPIED.contains(&name) && !name.ends_with(".tmp") && !name.ends_with(".guard")
+}
+
+// reflink, hard link (if `link`) or copy a file
+fn clone_file(src: &Path, dst: &Path, link: bool) -> io::Result<()> {
+    if reflink(src, dst).is_ok() || (link && fs::hard_link(src, dst).is_ok()) {
+        return Ok(());
+    }
+    fs::copy(src, dst)?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let from = File::open(src)?;
+    let to = File::create_new(dst)?;
+    // SAFETY: both are open file descriptors, which outlive the call
+    let res = unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) };
+    if res == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    drop(to);
+    let _ = fs::remove_file(dst);
+    Err(e)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Fsdb};
+
+    #[test]
+    fn test_copy_to() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let blue = db.bucket::<u32>("blue").expect("fail bucket");
+        let green = db.bucket::<u32>("green").expect("fail bucket");
+        green.enable_manifest().expect("fail manifest");
+        blue.put("a", 1).expect("failed to save");
+        blue.put_within("x", 2, "sub").expect("failed to save");
+        green.put("stale", 0).expect("failed to save");
+
+        blue.copy_to(&green).expect("fail copy_to");
+        let mut keys = green.list().expect("fail list");
+        keys.sort();
+        assert_eq!(keys, vec!["a", "sub"]);
+        assert_eq!(green.get_within("x", "sub").expect("fail get"), 2);
+
+        // the copies are independent
+        green.put("a", 10).expect("failed to save");
+        assert_eq!(blue.get("a").expect("fail get"), 1);
+        assert!(blue.copy_to(&blue).is_err());
+
+        // packed values come along too
+        let packed = db.bucket::<u32>("packed").expect("fail bucket");
+        packed.enable_packed().expect("fail enable_packed");
+        packed.put("p", 3).expect("failed to save");
+        packed.copy_to(&blue).expect("fail copy_to");
+        assert_eq!(blue.list().expect("fail list"), vec!["p"]);
+        assert_eq!(blue.get("p").expect("fail get"), 3);
+    }
+
+    #[test]
+    fn test_copy_to_indexes_and_views() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let blue = db.bucket::<u64>("blue").expect("fail bucket");
+        let green = db.bucket::<u64>("green").expect("fail bucket");
+        green
+            .create_sorted_index("value", |v| *v)
+            .expect("fail create_sorted_index");
+        let big = db.bucket::<u64>("big").expect("fail bucket");
+        green
+            .create_view(&big, |v| *v > 1, |v| *v)
+            .expect("fail create_view");
+        green.put("stale", 9).expect("failed to save");
+        blue.put("a", 1).expect("failed to save");
+        blue.put("b", 2).expect("failed to save");
+
+        blue.copy_to(&green).expect("fail copy_to");
+        assert_eq!(green.top_n("value", 2).expect("fail top_n"), vec!["b", "a"]);
+        assert_eq!(big.list().expect("fail list"), vec!["b"]);
+    }
+
+    #[test]
+    fn test_clone_bucket() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let src = db.bucket::<u32>("src").expect("fail bucket");
+        src.enable_manifest().expect("fail manifest");
+        src.put("a", 1).expect("failed to save");
+        src.snapshot("before").expect("fail snapshot");
+
+        db.clone_bucket("src", "dst").expect("fail clone_bucket");
+        // the bucket's type came along
+        assert!(matches!(
+            db.bucket::<String>("dst"),
+            Err(Error::TypeMismatch { .. })
+        ));
+        let dst = db.bucket::<u32>("dst").expect("fail bucket");
+        assert_eq!(dst.get("a").expect("fail get"), 1);
+        assert!(dst.list_snapshots().expect("fail list").is_empty());
+        dst.put("b", 2).expect("failed to save");
+        assert_eq!(src.list().expect("fail list"), vec!["a"]);
+
+        assert!(db.clone_bucket("src", "dst").is_err());
+        assert!(matches!(
+            db.clone_bucket("nope", "other"),
+            Err(Error::NoSuchBucket { .. })
+        ));
+    }
+}
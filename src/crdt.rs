@@ -0,0 +1,204 @@
+use crate::error::WithContext;
+use crate::lockfile::with_lock_file;
+use crate::{meta, Bucket, Context, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::hash::Hash;
+
+/// A value that replicas can update independently and then combine without
+/// conflicts, for `CrdtBucket`. `merge` must be commutative, associative and
+/// idempotent, so every replica ends up the same whatever order merges happen in
+pub trait Crdt {
+    /// Fold another replica's state into this one
+    fn merge(&mut self, other: Self);
+}
+
+// grow-only sets
+impl<T: Ord> Crdt for BTreeSet<T> {
+    fn merge(&mut self, other: Self) {
+        self.extend(other);
+    }
+}
+
+impl<T: Eq + Hash> Crdt for HashSet<T> {
+    fn merge(&mut self, other: Self) {
+        self.extend(other);
+    }
+}
+
+// a map of CRDTs merges key by key
+impl<K: Ord, V: Crdt> Crdt for BTreeMap<K, V> {
+    fn merge(&mut self, other: Self) {
+        for (k, v) in other {
+            match self.get_mut(&k) {
+                Some(mine) => mine.merge(v),
+                None => {
+                    self.insert(k, v);
+                }
+            }
+        }
+    }
+}
+
+/// A bucket of CRDT values, from `Fsdb::crdt_bucket`. `put` merges the new value
+/// into the one on disk instead of replacing it, so any number of threads and
+/// processes can write the same key without losing each other's updates, and
+/// replicas that were written separately (say, offline) can be combined with
+/// `merge_from`.
+///
+/// It holds the same data as a `Bucket<V>` opened on the same directory, whose
+/// puts do overwrite. There's no `remove`: a removed key would come back with the
+/// next merge from a replica that still has it, so record removals in `V` instead
+pub struct CrdtBucket<V> {
+    inner: Bucket<V>,
+}
+
+impl<V> Clone for CrdtBucket<V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Fsdb {
+    /// Create or open a bucket of CRDT values. See `CrdtBucket`
+    pub fn crdt_bucket<V: Crdt + Serialize + DeserializeOwned>(
+        &self,
+        p: &str,
+    ) -> Result<CrdtBucket<V>> {
+        Ok(CrdtBucket {
+            inner: self.bucket(p)?,
+        })
+    }
+}
+
+impl<V: Crdt + Serialize + DeserializeOwned> CrdtBucket<V> {
+    /// Merge `value` into a key (or create it)
+    pub fn put(&self, key: &str, value: V) -> Result<()> {
+        let b = &self.inner;
+        let name = b.maxify(key);
+        let _lock = b.state.key_lock(&b.dir.join(&name));
+        // other processes merging into the same key wait on this
+        let guard = b.dir.join(format!(".{}", name));
+        with_lock_file(&guard, || Ok(self.merge_locked(key, value)))
+            .ctx(|| Context::new(Op::Put, &b.dir, Some(key)))?
+    }
+    /// Get a key
+    pub fn get(&self, key: &str) -> Result<V> {
+        self.inner.get(key)
+    }
+    /// Check if a key exists
+    pub fn exists(&self, key: &str) -> bool {
+        self.inner.exists(key)
+    }
+    /// List keys in this bucket
+    pub fn list(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+    /// Merge every key of another replica into this one, such as a copy of this
+    /// bucket that was written to separately. Returns the number of keys merged
+    pub fn merge_from(&self, other: &Bucket<V>) -> Result<usize> {
+        let mut merged = 0;
+        for key in other.fs_keys(&other.dir)? {
+            // removed since listing
+            let Some(value) = other.fs_get_opt(&other.dir, &key)? else {
+                continue;
+            };
+            self.put(&key, value)?;
+            merged += 1;
+        }
+        Ok(merged)
+    }
+    /// The underlying bucket, whose puts overwrite rather than merge
+    pub fn bucket(&self) -> &Bucket<V> {
+        &self.inner
+    }
+    // merge into the value on disk, holding the key lock and lock file
+    fn merge_locked(&self, key: &str, value: V) -> Result<()> {
+        let b = &self.inner;
+        let merged = match b.fs_get_opt(&b.dir, key)? {
+            Some(mut current) => {
+                current.merge(value);
+                current
+            }
+            None => value,
+        };
+        let version = if meta::tracking(&b.dir) {
+            Some(b.current_version(&b.dir, key)?.next())
+        } else {
+            None
+        };
+        b.fs_put_locked(&b.dir, key, &merged, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Crdt;
+    use crate::Fsdb;
+    use serde::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    // a grow-only counter, with a count per replica
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    struct GCounter(BTreeMap<String, u64>);
+
+    impl GCounter {
+        fn one(replica: &str, n: u64) -> Self {
+            Self(BTreeMap::from([(replica.to_owned(), n)]))
+        }
+        fn total(&self) -> u64 {
+            self.0.values().sum()
+        }
+    }
+
+    impl Crdt for GCounter {
+        fn merge(&mut self, other: Self) {
+            for (replica, n) in other.0 {
+                let mine = self.0.entry(replica).or_default();
+                *mine = (*mine).max(n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_crdt_put_merges() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db
+            .crdt_bucket::<BTreeSet<u32>>("sets")
+            .expect("fail bucket");
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let b = b.clone();
+                std::thread::spawn(move || b.put("s", BTreeSet::from([i])).expect("fail put"))
+            })
+            .collect();
+        writers.into_iter().for_each(|w| w.join().unwrap());
+        assert_eq!(b.get("s").expect("fail get").len(), 8);
+        // the lock files are fsdb's own, not keys
+        assert_eq!(b.list().expect("fail list"), vec!["s"]);
+    }
+
+    #[test]
+    fn test_crdt_merge_from() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let here = db.crdt_bucket::<GCounter>("here").expect("fail bucket");
+        let there = db.crdt_bucket::<GCounter>("there").expect("fail bucket");
+        here.put("hits", GCounter::one("here", 3))
+            .expect("fail put");
+        there
+            .put("hits", GCounter::one("there", 2))
+            .expect("fail put");
+        there
+            .put("misses", GCounter::one("there", 1))
+            .expect("fail put");
+
+        assert_eq!(here.merge_from(there.bucket()).expect("fail merge"), 2);
+        assert_eq!(here.get("hits").expect("fail get").total(), 5);
+        // merging again changes nothing
+        here.merge_from(there.bucket()).expect("fail merge");
+        assert_eq!(here.get("hits").expect("fail get").total(), 5);
+        assert_eq!(here.get("misses").expect("fail get").total(), 1);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Fsdb, Op, Result};
+use rmp_serde::encode;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How two buckets (or databases) differ, from `diff`, `diff_structural` or
+/// `diff_databases`. Keys are sorted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiffReport {
+    /// Keys only the first has
+    pub only_in_a: Vec<String>,
+    /// Keys only the second has
+    pub only_in_b: Vec<String>,
+    /// Keys both have, with different values
+    pub differing: Vec<ValueDiff>,
+}
+
+impl DiffReport {
+    /// True if nothing differs
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// A key whose value differs
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueDiff {
+    /// The key (`bucket/key` from `diff_databases`)
+    pub key: String,
+    /// The fields that differ, from `diff_structural` (empty otherwise)
+    pub changes: Vec<FieldChange>,
+}
+
+/// A field that differs between two values, as JSON
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    /// Where in the value, like `items[2].name`. Empty for the whole value
+    pub path: String,
+    /// The field in the first value, or `None` if it's missing
+    pub a: Option<Value>,
+    /// The field in the second value, or `None` if it's missing
+    pub b: Option<Value>,
+}
+
+/// Compare the keys of two buckets (not their sub-buckets), decoding each
+/// value, so buckets with different ciphers or signing keys can be compared
+pub fn diff<V: Serialize + DeserializeOwned>(a: &Bucket<V>, b: &Bucket<V>) -> Result<DiffReport> {
+    compare(a, b, |key, x, y| {
+        let ctx = || Context::new(Op::Get, &a.dir, Some(key));
+        let same = encode::to_vec(x).ctx(ctx)? == encode::to_vec(y).ctx(ctx)?;
+        Ok((!same).then(Vec::new))
+    })
+}
+
+/// Like `diff`, but also lists the fields of each value that differ
+pub fn diff_structural<V: Serialize + DeserializeOwned>(
+    a: &Bucket<V>,
+    b: &Bucket<V>,
+) -> Result<DiffReport> {
+    compare(a, b, |key, x, y| {
+        let ctx = || Context::new(Op::Get, &a.dir, Some(key));
+        let x = serde_json::to_value(x).map_err(io::Error::from).ctx(ctx)?;
+        let y = serde_json::to_value(y).map_err(io::Error::from).ctx(ctx)?;
+        let mut changes = Vec::new();
+        changes_between(String::new(), Some(&x), Some(&y), &mut changes);
+        Ok((!changes.is_empty()).then_some(changes))
+    })
+}
+
+/// Compare every key in every bucket of two databases, byte for byte as
+/// stored, to check a replica or backup. Keys are named `bucket/key`
+pub fn diff_databases(a: &Fsdb, b: &Fsdb) -> Result<DiffReport> {
+    let ctx = || Context::new(Op::List, &a.dir, None);
+    let mut xs = BTreeSet::new();
+    let mut ys = BTreeSet::new();
+    files_under(&a.dir, "", &mut xs).ctx(ctx)?;
+    files_under(&b.dir, "", &mut ys).ctx(ctx)?;
+    let mut report = DiffReport {
+        only_in_a: xs.difference(&ys).cloned().collect(),
+        only_in_b: ys.difference(&xs).cloned().collect(),
+        differing: Vec::new(),
+    };
+    for key in xs.intersection(&ys) {
+        let ctx = || Context::new(Op::Get, &a.dir, Some(key));
+        if fs::read(a.dir.join(key)).ctx(ctx)? != fs::read(b.dir.join(key)).ctx(ctx)? {
+            report.differing.push(ValueDiff {
+                key: key.clone(),
+                changes: Vec::new(),
+            });
+        }
+    }
+    Ok(report)
+}
+
+// diff two buckets, with `differ` returning the changes between two values, if they differ
+fn compare<V, F>(a: &Bucket<V>, b: &Bucket<V>, mut differ: F) -> Result<DiffReport>
+where
+    V: Serialize + DeserializeOwned,
+    F: FnMut(&str, &V, &V) -> Result<Option<Vec<FieldChange>>>,
+{
+    let xs: BTreeSet<String> = a.fs_keys(&a.dir)?.into_iter().collect();
+    let ys: BTreeSet<String> = b.fs_keys(&b.dir)?.into_iter().collect();
+    let mut report = DiffReport {
+        only_in_a: xs.difference(&ys).cloned().collect(),
+        only_in_b: ys.difference(&xs).cloned().collect(),
+        differing: Vec::new(),
+    };
+    for key in xs.intersection(&ys) {
+        // removed since listing
+        let (Some(x), Some(y)) = (a.fs_get_opt(&a.dir, key)?, b.fs_get_opt(&b.dir, key)?) else {
+            continue;
+        };
+        if let Some(changes) = differ(key, &x, &y)? {
+            report.differing.push(ValueDiff {
+                key: key.clone(),
+                changes,
+            });
+        }
+    }
+    Ok(report)
+}
+
+fn changes_between(path: String, a: Option<&Value>, b: Option<&Value>, out: &mut Vec<FieldChange>) {
+    match (a, b) {
+        (Some(Value::Object(x)), Some(Value::Object(y))) => {
+            let fields: BTreeSet<&String> = x.keys().chain(y.keys()).collect();
+            for f in fields {
+                let p = match path.is_empty() {
+                    true => f.clone(),
+                    false => format!("{}.{}", path, f),
+                };
+                changes_between(p, x.get(f), y.get(f), out);
+            }
+        }
+        (Some(Value::Array(x)), Some(Value::Array(y))) => {
+            for i in 0..x.len().max(y.len()) {
+                changes_between(format!("{}[{}]", path, i), x.get(i), y.get(i), out);
+            }
+        }
+        _ if a != b => out.push(FieldChange {
+            path,
+            a: a.cloned(),
+            b: b.cloned(),
+        }),
+        _ => (),
+    }
+}
+
+// relative paths of every key file under `dir`, skipping fsdb's own files
+fn files_under(dir: &Path, prefix: &str, out: &mut BTreeSet<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let rel = format!("{}{}", prefix, name);
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            files_under(&entry.path(), &format!("{}/", rel), out)?;
+        } else if ty.is_file() {
+            out.insert(rel);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, diff_databases, diff_structural, FieldChange};
+    use crate::Fsdb;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Order {
+        id: u32,
+        items: Vec<String>,
+    }
+
+    fn order(id: u32, items: &[&str]) -> Order {
+        Order {
+            id,
+            items: items.iter().map(|i| i.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let a = db.bucket::<Order>("a").expect("fail bucket");
+        let b = db.bucket::<Order>("b").expect("fail bucket");
+        a.put("same", order(1, &["x"])).expect("failed to save");
+        b.put("same", order(1, &["x"])).expect("failed to save");
+        a.put("changed", order(2, &["x", "y"]))
+            .expect("failed to save");
+        b.put("changed", order(2, &["z"])).expect("failed to save");
+        a.put("left", order(3, &[])).expect("failed to save");
+        b.put("right", order(4, &[])).expect("failed to save");
+
+        let report = diff(&a, &b).expect("fail diff");
+        assert_eq!(report.only_in_a, vec!["left"]);
+        assert_eq!(report.only_in_b, vec!["right"]);
+        assert_eq!(report.differing.len(), 1);
+        assert_eq!(report.differing[0].key, "changed");
+        assert!(report.differing[0].changes.is_empty());
+
+        let report = diff_structural(&a, &b).expect("fail diff");
+        assert_eq!(
+            report.differing[0].changes,
+            vec![
+                FieldChange {
+                    path: "items[0]".into(),
+                    a: Some(json!("x")),
+                    b: Some(json!("z")),
+                },
+                FieldChange {
+                    path: "items[1]".into(),
+                    a: Some(json!("y")),
+                    b: None,
+                },
+            ]
+        );
+        assert!(diff(&a, &a).expect("fail diff").is_empty());
+    }
+
+    #[test]
+    fn test_diff_databases() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("nums").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        b.put_within("x", 2, "sub").expect("failed to save");
+        let backup = Fsdb::temp().expect("fail Fsdb::temp");
+        let dest = backup.path().to_str().expect("non-utf8 temp dir");
+        db.backup_incremental(dest, None).expect("fail backup");
+        assert!(diff_databases(&db, &backup).expect("fail diff").is_empty());
+
+        b.put("a", 10).expect("failed to save");
+        b.put("c", 3).expect("failed to save");
+        let report = diff_databases(&db, &backup).expect("fail diff");
+        assert_eq!(report.only_in_a, vec!["nums/c"]);
+        assert_eq!(report.differing[0].key, "nums/a");
+    }
+}
@@ -0,0 +1,155 @@
+use crate::Bucket;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+/// How hard fsdb works to make writes survive a power failure (set in `FsdbOptions`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Leave flushing to the operating system. A crash can lose recent writes,
+    /// but never leaves a partially written value
+    #[default]
+    None,
+    /// Flush each value, then its directory, before the put or remove returns
+    Sync,
+    /// Like `Sync`, but puts and removes in the same bucket within the given window
+    /// of each other share one flush of the directory, so many threads writing at
+    /// once pay for far fewer flushes. Each one still waits for the shared flush
+    GroupCommit(Duration),
+}
+
+/// Batches directory flushes for `Durability::GroupCommit`. One writer at a time
+/// leads a batch: it waits out the window, then flushes every directory written
+/// to so far, while the others wait for a batch that covers their write
+#[derive(Default)]
+pub(crate) struct GroupSync {
+    batch: Mutex<Batch>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct Batch {
+    // writes that asked for a flush, and the last one a finished flush covers
+    requested: u64,
+    synced: u64,
+    // the last write covered by a flush that failed
+    failed: u64,
+    leading: bool,
+    dirs: HashSet<PathBuf>,
+    // number of flushes, for tests
+    flushes: u64,
+}
+
+impl GroupSync {
+    /// Wait until `dir` has been flushed by a batch that started after this call
+    pub(crate) fn sync(&self, dir: &Path, window: Duration) -> io::Result<()> {
+        let mut batch = self.lock();
+        batch.requested += 1;
+        let ticket = batch.requested;
+        batch.dirs.insert(dir.to_owned());
+        loop {
+            if batch.failed >= ticket {
+                return Err(io::Error::other("group commit: directory flush failed"));
+            }
+            if batch.synced >= ticket {
+                return Ok(());
+            }
+            if !batch.leading {
+                break;
+            }
+            batch = self
+                .done
+                .wait(batch)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        batch.leading = true;
+        drop(batch);
+        std::thread::sleep(window);
+        let (upto, dirs) = {
+            let mut batch = self.lock();
+            (batch.requested, std::mem::take(&mut batch.dirs))
+        };
+        let res = dirs.iter().try_for_each(|d| sync_dir(d));
+        let mut batch = self.lock();
+        batch.leading = false;
+        batch.flushes += 1;
+        match &res {
+            Ok(()) => batch.synced = upto,
+            Err(_) => batch.failed = upto,
+        }
+        self.done.notify_all();
+        res
+    }
+    #[cfg(test)]
+    pub(crate) fn flushes(&self) -> u64 {
+        self.lock().flushes
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, Batch> {
+        self.batch.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Flush a directory, so renames and removes in it are on disk
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+impl<V> Bucket<V> {
+    // make a finished put or remove in `dir` durable, as the bucket's `Durability` asks
+    pub(crate) fn make_durable(&self, dir: &Path) -> io::Result<()> {
+        match self.state.durability() {
+            Durability::None => Ok(()),
+            Durability::Sync => sync_dir(dir),
+            Durability::GroupCommit(window) => self.state.group_sync.sync(dir, window),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Durability;
+    use crate::{Fsdb, FsdbOptions};
+    use std::time::Duration;
+
+    #[test]
+    fn test_group_commit() {
+        let tmp = Fsdb::temp().expect("fail Fsdb::temp");
+        let options = FsdbOptions {
+            durability: Durability::GroupCommit(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let path = tmp.path().to_str().expect("non-utf8 temp dir");
+        let db = Fsdb::with_options(path, options).expect("fail Fsdb::with_options");
+        let b = db.bucket::<u32>("durable").expect("fail bucket");
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let b = b.clone();
+                std::thread::spawn(move || b.put(&format!("k{}", i), i).expect("failed to save"))
+            })
+            .collect();
+        writers.into_iter().for_each(|w| w.join().unwrap());
+        assert_eq!(b.list().expect("fail list").len(), 8);
+        // the writers overlapped, so they shared flushes
+        assert!(b.state.group_sync.flushes() < 8);
+
+        b.remove("k0").expect("fail remove");
+        assert!(!b.exists("k0"));
+    }
+
+    #[test]
+    fn test_sync() {
+        let tmp = Fsdb::temp().expect("fail Fsdb::temp");
+        let options = FsdbOptions {
+            durability: Durability::Sync,
+            ..Default::default()
+        };
+        let path = tmp.path().to_str().expect("non-utf8 temp dir");
+        let db = Fsdb::with_options(path, options).expect("fail Fsdb::with_options");
+        let b = db.bucket::<u32>("durable").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        assert_eq!(b.get("a").expect("fail get"), 1);
+    }
+}
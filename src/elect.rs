@@ -0,0 +1,110 @@
+use crate::{Bucket, Fsdb, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Directory (inside the database) holding election leases
+const ELECTIONS: &str = ".elections";
+
+/// Leadership of a role, from `Fsdb::elect`. A background thread renews the
+/// underlying lease while this is alive, and dropping it steps down
+pub struct Leadership {
+    role: String,
+    lost: Arc<AtomicBool>,
+    stop: Option<Sender<()>>,
+    renewer: Option<JoinHandle<()>>,
+}
+
+impl Fsdb {
+    /// Wait until `my_id` is the leader for `role`, among every process sharing
+    /// this database that calls `elect` for it. Leadership is a lease with the
+    /// given `ttl`, renewed every third of the ttl, so if the leader dies another
+    /// candidate takes over within about `ttl`. Candidates retry just as often
+    pub fn elect(&self, role: &str, my_id: &str, ttl: Duration) -> Result<Leadership> {
+        let (dir, state) = self.open_bucket_dir(ELECTIONS, std::any::type_name::<Leadership>())?;
+        let bucket: Bucket<()> = Bucket::with_state(dir, state);
+        let every = ttl / 3;
+        let mut lease = loop {
+            if let Some(lease) = bucket.acquire_lease_as(role, my_id, ttl)? {
+                break lease;
+            }
+            std::thread::sleep(every);
+        };
+        let lost = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (done, finished) = mpsc::channel::<()>();
+        self.add_task(stop.clone(), finished);
+        let lost2 = lost.clone();
+        let renewer = std::thread::spawn(move || {
+            // until stepping down, Fsdb::close, or failing to renew
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                if lease.renew(ttl).is_err() {
+                    break;
+                }
+            }
+            lost2.store(true, Ordering::SeqCst);
+            // dropping the lease releases it, before close hears this thread is done
+            drop(lease);
+            drop(done);
+        });
+        Ok(Leadership {
+            role: role.to_owned(),
+            lost,
+            stop: Some(stop),
+            renewer: Some(renewer),
+        })
+    }
+}
+
+impl Leadership {
+    /// The role this is leadership of
+    pub fn role(&self) -> &str {
+        &self.role
+    }
+    /// Check if leadership is still held. It's lost if the lease couldn't be
+    /// renewed in time and another candidate took over, or the `Fsdb` was closed
+    pub fn is_leader(&self) -> bool {
+        !self.lost.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Leadership {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(renewer) = self.renewer.take() {
+            let _ = renewer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use std::time::Duration;
+
+    #[test]
+    fn test_elect() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let ttl = Duration::from_millis(90);
+        let leader = db.elect("scheduler", "a", ttl).expect("fail elect");
+        assert!(leader.is_leader());
+        // outlives the ttl, so it was renewed
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(leader.is_leader());
+
+        let db2 =
+            Fsdb::new(db.path().to_str().expect("non-utf8 temp dir")).expect("fail Fsdb::new");
+        let waiting = std::thread::spawn(move || {
+            let next = db2.elect("scheduler", "b", ttl).expect("fail elect");
+            next.is_leader()
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!waiting.is_finished());
+        drop(leader);
+        assert!(waiting.join().unwrap());
+    }
+}
@@ -0,0 +1,448 @@
+use crate::seal::Sealed;
+use crate::{retry, Version};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+
+/// The operation that was running when an error occurred
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    Open,
+    Bucket,
+    Put,
+    Get,
+    Remove,
+    List,
+    Clear,
+    Commit,
+    Snapshot,
+    Backup,
+    Watch,
+    Lease,
+    Space,
+    Close,
+    Copy,
+    Restore,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Op::Open => "open",
+            Op::Bucket => "bucket",
+            Op::Put => "put",
+            Op::Get => "get",
+            Op::Remove => "remove",
+            Op::List => "list",
+            Op::Clear => "clear",
+            Op::Commit => "commit",
+            Op::Snapshot => "snapshot",
+            Op::Backup => "backup",
+            Op::Watch => "watch",
+            Op::Lease => "lease",
+            Op::Space => "space",
+            Op::Close => "close",
+            Op::Copy => "copy",
+            Op::Restore => "restore",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Where an error happened: the operation, bucket directory and key (if any)
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub op: Op,
+    pub bucket: PathBuf,
+    pub key: Option<String>,
+}
+
+impl Context {
+    pub(crate) fn new(op: Op, bucket: impl Into<PathBuf>, key: Option<&str>) -> Self {
+        Self {
+            op,
+            bucket: bucket.into(),
+            key: key.map(|k| k.to_owned()),
+        }
+    }
+}
+
+// databases opened with `FsdbOptions::redact_paths`. Never removed, so errors
+// still around after their Fsdb is dropped stay redacted
+static REDACTED: RwLock<Vec<PathBuf>> = RwLock::new(Vec::new());
+
+/// Redact the errors of everything under database directory `dir`
+pub(crate) fn redact_under(dir: &Path) {
+    let mut dirs = REDACTED.write().unwrap_or_else(PoisonError::into_inner);
+    if !dirs.iter().any(|d| d == dir) {
+        dirs.push(dir.to_owned());
+    }
+}
+
+impl Context {
+    // whether this is under a database opened with `FsdbOptions::redact_paths`
+    fn redacted(&self) -> bool {
+        let dirs = REDACTED.read().unwrap_or_else(PoisonError::into_inner);
+        dirs.iter().any(|d| self.bucket.starts_with(d))
+    }
+
+    // the `Display` output, leaving out the bucket path and key if `redact`
+    fn write(&self, f: &mut impl fmt::Write, redact: bool) -> fmt::Result {
+        match redact {
+            true => write!(f, "{} in \"...\"", self.op)?,
+            false => write!(f, "{} in {:?}", self.op, self.bucket)?,
+        }
+        match (&self.key, redact) {
+            (Some(_), true) => write!(f, " (key \"...\")")?,
+            (Some(key), false) => write!(f, " (key {:?})", key)?,
+            (None, _) => (),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, self.redacted())
+    }
+}
+
+/// The broad category of an `Error`, from `Error::kind`, for handling errors
+/// the same way whichever operation they came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The key, bucket or file doesn't exist
+    NotFound,
+    /// Something else changed it first: a version mismatch, a lost lease, an
+    /// existing file or a delete restricted by a reference
+    Conflict,
+    /// What's stored can't be read back: it doesn't decode, decrypt or verify
+    Corrupt,
+    /// The key, value or an argument isn't valid, or the bucket holds another type
+    InvalidInput,
+    /// The filesystem (or a sealed bucket) doesn't allow writing
+    ReadOnly,
+    /// Out of disk space or quota
+    QuotaExceeded,
+    /// The operation timed out
+    Timeout,
+    /// The bucket is holding back writes, see `WriteThrottle`
+    Busy,
+    /// Any other filesystem error
+    Io,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("io error: {ctx}: {source}")]
+    Io {
+        ctx: Context,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("disk full: {ctx}: {source}")]
+    DiskFull {
+        ctx: Context,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("timed out: {ctx}: {source}")]
+    Timeout {
+        ctx: Context,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("back pressure: {ctx}: {reason}")]
+    BackPressure { ctx: Context, reason: String },
+    #[error("encode error: {ctx}: {source}")]
+    Encode {
+        ctx: Context,
+        #[source]
+        source: rmp_serde::encode::Error,
+    },
+    #[error("decode error: {ctx}: {source}")]
+    Decode {
+        ctx: Context,
+        #[source]
+        source: rmp_serde::decode::Error,
+    },
+    #[error("decrypt error: {ctx}: wrong key or corrupted value")]
+    Decrypt { ctx: Context },
+    #[error("invalid signature: {ctx}: value is unsigned or has been modified")]
+    SignatureInvalid { ctx: Context },
+    #[error("type mismatch: {ctx}: bucket holds {expected}, opened as {found}")]
+    TypeMismatch {
+        ctx: Context,
+        expected: String,
+        found: String,
+    },
+    #[error("unknown type: {ctx}: {tag} is not registered")]
+    UnknownType { ctx: Context, tag: String },
+    #[error("lease lost: {ctx}: it expired and was taken by another owner")]
+    LeaseLost { ctx: Context },
+    #[error("no such bucket: {ctx}")]
+    NoSuchBucket { ctx: Context },
+    #[error("restricted: {ctx}: still referenced by {referrer}")]
+    Restricted { ctx: Context, referrer: String },
+    #[error("config mismatch: {ctx}: {reason}")]
+    ConfigMismatch { ctx: Context, reason: String },
+    #[error("sealed: {ctx}: the bucket is read-only until unsealed")]
+    Sealed { ctx: Context },
+    #[error("version mismatch: {ctx}: expected {expected:?}, found {actual:?}")]
+    VersionMismatch {
+        ctx: Context,
+        expected: Version,
+        actual: Version,
+    },
+}
+
+impl Error {
+    /// The operation, bucket and key this error happened on
+    pub fn context(&self) -> &Context {
+        match self {
+            Error::Io { ctx, .. } => ctx,
+            Error::DiskFull { ctx, .. } => ctx,
+            Error::Timeout { ctx, .. } => ctx,
+            Error::BackPressure { ctx, .. } => ctx,
+            Error::Encode { ctx, .. } => ctx,
+            Error::Decode { ctx, .. } => ctx,
+            Error::Decrypt { ctx } => ctx,
+            Error::SignatureInvalid { ctx } => ctx,
+            Error::TypeMismatch { ctx, .. } => ctx,
+            Error::UnknownType { ctx, .. } => ctx,
+            Error::LeaseLost { ctx } => ctx,
+            Error::NoSuchBucket { ctx } => ctx,
+            Error::Restricted { ctx, .. } => ctx,
+            Error::ConfigMismatch { ctx, .. } => ctx,
+            Error::Sealed { ctx } => ctx,
+            Error::VersionMismatch { ctx, .. } => ctx,
+        }
+    }
+    /// The category of this error
+    pub fn kind(&self) -> ErrorKind {
+        use std::io::ErrorKind as Io;
+        match self {
+            Error::Io { source, .. } => match source.kind() {
+                Io::NotFound => ErrorKind::NotFound,
+                Io::AlreadyExists => ErrorKind::Conflict,
+                Io::InvalidData => ErrorKind::Corrupt,
+                Io::InvalidInput | Io::InvalidFilename => ErrorKind::InvalidInput,
+                Io::PermissionDenied | Io::ReadOnlyFilesystem => ErrorKind::ReadOnly,
+                _ => ErrorKind::Io,
+            },
+            Error::DiskFull { .. } => ErrorKind::QuotaExceeded,
+            Error::Timeout { .. } => ErrorKind::Timeout,
+            Error::BackPressure { .. } => ErrorKind::Busy,
+            Error::Encode { .. } => ErrorKind::InvalidInput,
+            Error::Decode { .. } => ErrorKind::Corrupt,
+            Error::Decrypt { .. } => ErrorKind::Corrupt,
+            Error::SignatureInvalid { .. } => ErrorKind::Corrupt,
+            Error::TypeMismatch { .. } => ErrorKind::InvalidInput,
+            Error::UnknownType { .. } => ErrorKind::InvalidInput,
+            Error::LeaseLost { .. } => ErrorKind::Conflict,
+            Error::NoSuchBucket { .. } => ErrorKind::NotFound,
+            Error::Restricted { .. } => ErrorKind::Conflict,
+            Error::ConfigMismatch { .. } => ErrorKind::InvalidInput,
+            Error::Sealed { .. } => ErrorKind::ReadOnly,
+            Error::VersionMismatch { .. } => ErrorKind::Conflict,
+        }
+    }
+    /// Whether the same operation might succeed if tried again later: it timed
+    /// out, was held back, or failed with a transient filesystem error (the kind
+    /// `RetryPolicy` retries). Conflicts need the value read again first, so
+    /// aren't counted
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Timeout { .. } | Error::BackPressure { .. } => true,
+            Error::Io { source, .. } => retry::transient(source),
+            _ => false,
+        }
+    }
+    /// A stable identifier for this error, like `fsdb::type_mismatch`, for
+    /// diagnostics and for looking errors up in logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Io { .. } => "fsdb::io",
+            Error::DiskFull { .. } => "fsdb::disk_full",
+            Error::Timeout { .. } => "fsdb::timeout",
+            Error::BackPressure { .. } => "fsdb::back_pressure",
+            Error::Encode { .. } => "fsdb::encode",
+            Error::Decode { .. } => "fsdb::decode",
+            Error::Decrypt { .. } => "fsdb::decrypt",
+            Error::SignatureInvalid { .. } => "fsdb::signature_invalid",
+            Error::TypeMismatch { .. } => "fsdb::type_mismatch",
+            Error::UnknownType { .. } => "fsdb::unknown_type",
+            Error::LeaseLost { .. } => "fsdb::lease_lost",
+            Error::NoSuchBucket { .. } => "fsdb::no_such_bucket",
+            Error::Restricted { .. } => "fsdb::restricted",
+            Error::ConfigMismatch { .. } => "fsdb::config_mismatch",
+            Error::Sealed { .. } => "fsdb::sealed",
+            Error::VersionMismatch { .. } => "fsdb::version_mismatch",
+        }
+    }
+    /// A suggestion for fixing this error, for showing alongside it in a CLI or
+    /// an application's error report. None if there's nothing to add
+    pub fn help(&self) -> Option<&'static str> {
+        let help = match self {
+            Error::Io { source, .. } => match source.kind() {
+                std::io::ErrorKind::NotFound if self.context().key.is_some() => {
+                    "the key doesn't exist: check with `exists`, or treat ErrorKind::NotFound as missing"
+                }
+                std::io::ErrorKind::NotFound => {
+                    "the bucket directory is missing: `Fsdb::bucket` creates it"
+                }
+                std::io::ErrorKind::PermissionDenied => {
+                    "check this process can write to the database directory"
+                }
+                _ => return None,
+            },
+            Error::DiskFull { .. } => {
+                "free up space, or set a `WriteThrottle` to hold writes back before the disk fills"
+            }
+            Error::Timeout { .. } => "try again, or allow longer with `Bucket::set_timeout`",
+            Error::BackPressure { .. } => "the disk is nearly full: try again once space is freed",
+            Error::Decode { .. } => {
+                "the value was stored as another type, or the file is corrupt: see `Bucket::check_compat`"
+            }
+            Error::Decrypt { .. } => {
+                "check the cipher key; `set_previous_cipher` reads values written before a key change"
+            }
+            Error::SignatureInvalid { .. } => {
+                "the value was changed outside fsdb, or signed with another key"
+            }
+            Error::TypeMismatch { .. } => {
+                "open the bucket with the type it was created with; `Bucket::check_compat` tests a new type against the stored values"
+            }
+            Error::UnknownType { .. } => "`PolyBucket::register` the type before reading it",
+            Error::LeaseLost { .. } => "acquire the lease again before writing",
+            Error::NoSuchBucket { .. } => "open the bucket with `Fsdb::bucket`, which creates it",
+            Error::Restricted { .. } => "remove the values referring to it first",
+            Error::ConfigMismatch { .. } => {
+                "open the bucket with the settings it was created with, or with `Fsdb::bucket`"
+            }
+            Error::Sealed { .. } => {
+                "the bucket was sealed with `Bucket::seal`; `Bucket::unseal(true)` makes it writable again"
+            }
+            Error::VersionMismatch { .. } => "read the value again, then retry the update",
+            Error::Encode { .. } => return None,
+        };
+        Some(help)
+    }
+}
+
+/// Reports `Error::code` and `Error::help`, for applications that show errors
+/// with `miette`
+#[cfg(feature = "diagnostics")]
+impl miette::Diagnostic for Error {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Some(Box::new(Error::code(self)))
+    }
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Error::help(self).map(|help| Box::new(help) as Box<dyn fmt::Display>)
+    }
+}
+
+// attach a Context to lower level errors
+pub(crate) trait WithContext<T> {
+    fn ctx(self, ctx: impl FnOnce() -> Context) -> Result<T, Error>;
+}
+
+// running out of space (or quota) and timing out get their own variants,
+// so callers can react to them
+impl<T> WithContext<T> for Result<T, std::io::Error> {
+    fn ctx(self, ctx: impl FnOnce() -> Context) -> Result<T, Error> {
+        use std::io::ErrorKind::{QuotaExceeded, StorageFull, TimedOut};
+        self.map_err(|source| match source.kind() {
+            _ if source.get_ref().is_some_and(|e| e.is::<Sealed>()) => Error::Sealed { ctx: ctx() },
+            StorageFull | QuotaExceeded => Error::DiskFull { ctx: ctx(), source },
+            TimedOut => Error::Timeout { ctx: ctx(), source },
+            _ => Error::Io { ctx: ctx(), source },
+        })
+    }
+}
+
+impl<T> WithContext<T> for Result<T, rmp_serde::encode::Error> {
+    fn ctx(self, ctx: impl FnOnce() -> Context) -> Result<T, Error> {
+        self.map_err(|source| Error::Encode { ctx: ctx(), source })
+    }
+}
+
+impl<T> WithContext<T> for Result<T, rmp_serde::decode::Error> {
+    fn ctx(self, ctx: impl FnOnce() -> Context) -> Result<T, Error> {
+        self.map_err(|source| Error::Decode { ctx: ctx(), source })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Context, ErrorKind};
+    use crate::{Fsdb, Op};
+
+    #[test]
+    fn test_error_kind() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("kinds").expect("fail bucket");
+        let missing = b.get("missing").expect_err("missing key found");
+        assert_eq!(missing.kind(), ErrorKind::NotFound);
+        assert!(!missing.is_retryable());
+        let wrong = db
+            .bucket::<String>("kinds")
+            .err()
+            .expect("opened as another type");
+        assert_eq!(wrong.kind(), ErrorKind::InvalidInput);
+        assert_eq!(wrong.code(), "fsdb::type_mismatch");
+        assert!(wrong.help().expect("no help").contains("check_compat"));
+        assert!(missing.help().expect("no help").contains("exists"));
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn test_diagnostic() {
+        use miette::Diagnostic;
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        db.bucket::<u32>("kinds").expect("fail bucket");
+        let wrong = db
+            .bucket::<String>("kinds")
+            .err()
+            .expect("opened as another type");
+        let diagnostic: &dyn Diagnostic = &wrong;
+        let code = diagnostic.code().expect("no code").to_string();
+        assert_eq!(code, "fsdb::type_mismatch");
+        let help = diagnostic.help().expect("no help").to_string();
+        assert!(help.contains("check_compat"));
+    }
+
+    #[test]
+    fn test_redaction() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        let plain = Fsdb::temp().expect("fail Fsdb::temp");
+        db.enable_redaction();
+        let b = db.bucket::<u32>("tenant-42").expect("fail bucket");
+        let shown = b.get("alice@example.com").expect_err("missing").to_string();
+        assert!(!shown.contains("tenant-42"), "{}", shown);
+        assert!(!shown.contains("alice"), "{}", shown);
+        // only for this database
+        let b = plain.bucket::<u32>("tenant-42").expect("fail bucket");
+        let shown = b.get("alice@example.com").expect_err("missing").to_string();
+        assert!(shown.contains("alice@example.com"), "{}", shown);
+    }
+
+    #[test]
+    fn test_redact_paths() {
+        let shown = |ctx: &Context, redact| {
+            let mut out = String::new();
+            ctx.write(&mut out, redact).expect("fail write");
+            out
+        };
+        let ctx = Context::new(Op::Get, "/home/alice/tenant-42/orders", Some("k"));
+        assert_eq!(
+            shown(&ctx, false),
+            "get in \"/home/alice/tenant-42/orders\" (key \"k\")"
+        );
+        assert_eq!(shown(&ctx, true), "get in \"...\" (key \"...\")");
+        let root = Context::new(Op::Get, "/", None);
+        assert_eq!(shown(&root, true), "get in \"...\"");
+        assert_eq!(ctx.bucket.to_str(), Some("/home/alice/tenant-42/orders"));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::state::BucketState;
+use crate::{Bucket, Context, Error, Op, Result, Txn};
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// What removing a key in a transaction does to values that reference it,
+/// see `Bucket::references`
+#[derive(Debug, Clone, Copy)]
+pub enum OnDelete<V> {
+    /// Fail the commit with `Error::Restricted`
+    Restrict,
+    /// Remove the values that reference it too, in the same transaction
+    Cascade,
+    /// Clear the reference with the given function, and store the values again
+    Nullify(fn(&mut V)),
+}
+
+/// Values in one bucket referencing keys in another, registered with the
+/// referenced bucket's state and checked when a transaction removes its keys
+pub(crate) trait Reference: Send + Sync {
+    /// Stage whatever removing `name` from the referenced bucket calls for
+    fn on_remove(&self, txn: &mut Txn, name: &str) -> Result<()>;
+}
+
+// the key a value references, if any
+type KeyOfFn<V> = dyn Fn(&V) -> Option<String> + Send + Sync;
+
+struct ForeignKey<V> {
+    // the referencing bucket, detached from its state (which it would keep alive)
+    child: Bucket<V>,
+    key_of: Box<KeyOfFn<V>>,
+    on_delete: OnDelete<V>,
+    // the referenced bucket, and the file name length its keys are cut to
+    parent: PathBuf,
+    parent_max: Option<usize>,
+}
+
+impl<V: Serialize + DeserializeOwned + Send + Sync> Reference for ForeignKey<V> {
+    fn on_remove(&self, txn: &mut Txn, name: &str) -> Result<()> {
+        let mut child = self.child.clone();
+        child.state = BucketState::get(&child.dir);
+        for key in child.fs_keys(&child.dir)? {
+            if txn.removes(&child.dir, &key) {
+                continue;
+            }
+            // a value staged in this transaction replaces the one on disk
+            let value = match txn.staged_value(&child, &key)? {
+                Some(v) => v,
+                None => match child.fs_get_opt(&child.dir, &key)? {
+                    Some(v) => v,
+                    None => continue,
+                },
+            };
+            if (self.key_of)(&value)
+                .map(|k| self.parent_name(k))
+                .as_deref()
+                != Some(name)
+            {
+                continue;
+            }
+            match self.on_delete {
+                OnDelete::Restrict => {
+                    return Err(Error::Restricted {
+                        ctx: Context::new(Op::Commit, &self.parent, Some(name)),
+                        referrer: child.dir.join(&key).display().to_string(),
+                    })
+                }
+                OnDelete::Cascade => txn.remove(&child, &key),
+                OnDelete::Nullify(clear) => {
+                    let mut value = value;
+                    clear(&mut value);
+                    txn.put(&child, &key, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<V> ForeignKey<V> {
+    fn parent_name(&self, mut key: String) -> String {
+        if let Some(max) = self.parent_max {
+            key.truncate(max);
+        }
+        key
+    }
+}
+
+// referential integrity
+impl<V: Serialize + DeserializeOwned + Send + Sync + 'static> Bucket<V> {
+    /// Declare that values in this bucket reference keys in `parent`, through
+    /// the key `key_of` returns (if any). When a transaction removes a key in
+    /// `parent`, values referencing it are handled as `on_delete` says when it
+    /// commits, and cascades carry on through references to this bucket.
+    ///
+    /// Only removals through a `Txn` are checked: `Bucket::remove` and `clear`
+    /// aren't. Declarations live in memory, for every handle to `parent` in this
+    /// process, and checking one reads every value in this bucket
+    pub fn references<P>(
+        &self,
+        parent: &Bucket<P>,
+        key_of: impl Fn(&V) -> Option<String> + Send + Sync + 'static,
+        on_delete: OnDelete<V>,
+    ) {
+        let mut child = self.clone();
+        child.state = Arc::new(BucketState::default());
+        let fk = ForeignKey {
+            child,
+            key_of: Box::new(key_of),
+            on_delete,
+            parent: parent.dir.clone(),
+            parent_max: parent.max_file_name,
+        };
+        parent.state.references().push(Arc::new(fk));
+    }
+}
+
+impl BucketState {
+    // what a transaction removing `name` from this bucket needs to do
+    pub(crate) fn on_remove(&self, txn: &mut Txn, name: &str) -> Result<()> {
+        let references = self.references().clone();
+        references.iter().try_for_each(|r| r.on_remove(txn, name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OnDelete;
+    use crate::{Error, Fsdb};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Post {
+        author: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Comment {
+        post: String,
+    }
+
+    fn by(author: &str) -> Post {
+        Post {
+            author: Some(author.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_restrict() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let users = db.bucket::<String>("users").expect("fail bucket");
+        let posts = db.bucket::<Post>("posts").expect("fail bucket");
+        posts.references(&users, |p: &Post| p.author.clone(), OnDelete::Restrict);
+        users.put("ann", "Ann".into()).expect("failed to save");
+        users.put("bob", "Bob".into()).expect("failed to save");
+        posts.put("p1", by("ann")).expect("failed to save");
+
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.remove(&users, "ann");
+        assert!(matches!(tx.commit(), Err(Error::Restricted { .. })));
+        assert!(users.exists("ann"));
+
+        // fine once the post goes in the same transaction
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.remove(&posts, "p1");
+        tx.remove(&users, "ann");
+        tx.remove(&users, "bob");
+        tx.commit().expect("fail commit");
+        assert!(!users.exists("ann"));
+    }
+
+    #[test]
+    fn test_cascade_and_nullify() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let users = db.bucket::<String>("users").expect("fail bucket");
+        let posts = db.bucket::<Post>("posts").expect("fail bucket");
+        let comments = db.bucket::<Comment>("comments").expect("fail bucket");
+        posts.references(&users, |p: &Post| p.author.clone(), OnDelete::Cascade);
+        comments.references(
+            &posts,
+            |c: &Comment| Some(c.post.clone()),
+            OnDelete::Cascade,
+        );
+        users.put("ann", "Ann".into()).expect("failed to save");
+        posts.put("p1", by("ann")).expect("failed to save");
+        posts.put("p2", by("bob")).expect("failed to save");
+        let c = |post: &str| Comment { post: post.into() };
+        comments.put("c1", c("p1")).expect("failed to save");
+        comments.put("c2", c("p2")).expect("failed to save");
+
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.remove(&users, "ann");
+        tx.commit().expect("fail commit");
+        assert_eq!(posts.list().expect("fail list"), vec!["p2"]);
+        assert_eq!(comments.list().expect("fail list"), vec!["c2"]);
+
+        let editors = db.bucket::<Post>("edited").expect("fail bucket");
+        editors.references(
+            &users,
+            |p: &Post| p.author.clone(),
+            OnDelete::Nullify(|p| p.author = None),
+        );
+        users.put("cat", "Cat".into()).expect("failed to save");
+        editors.put("e1", by("cat")).expect("failed to save");
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.remove(&users, "cat");
+        tx.commit().expect("fail commit");
+        assert_eq!(editors.get("e1").expect("fail get"), Post { author: None });
+    }
+}
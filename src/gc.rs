@@ -0,0 +1,99 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+
+// cleaning up empty sub-bucket directories
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Remove a sub-bucket's directory when `remove_within` takes out its last
+    /// key, rather than leaving it empty. See also `Fsdb::gc_empty_dirs`
+    pub fn set_prune_empty_sub_buckets(&mut self, on: bool) {
+        self.prune_empty = on;
+    }
+    // remove a sub-bucket's directory if it's empty, returning whether it was.
+    // A write racing with this either lands first (so the directory isn't
+    // empty) or recreates it, see `put_within`
+    pub(crate) fn prune_sub_dir(&self, sub: &str) -> Result<bool> {
+        let dir = self.sub_dir(sub);
+        let ctx = || Context::new(Op::Remove, &dir, None);
+        let _guard = self.state.write_guard();
+        if fs::remove_dir(&dir).is_err() {
+            // not empty, or already gone
+            return Ok(false);
+        }
+        if let Some(m) = self.state.manifest().as_mut() {
+            m.remove(&self.maxify(sub)).ctx(ctx)?;
+        }
+        Ok(true)
+    }
+}
+
+impl Fsdb {
+    /// Remove every empty sub-bucket directory, in every bucket, and return how
+    /// many there were. Buckets themselves are kept, even if they're empty
+    pub fn gc_empty_dirs(&self) -> Result<usize> {
+        let ctx = || Context::new(Op::Remove, &self.dir, None);
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir).ctx(ctx)?.flatten() {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with('.') || !entry.path().is_dir() {
+                continue;
+            }
+            let dir = entry.path();
+            let bucket = Bucket::<()>::with_state(dir.clone(), self.bucket_state(&dir)?);
+            for sub in fs::read_dir(&dir).ctx(ctx)?.flatten() {
+                let Ok(sub) = sub.file_name().into_string() else {
+                    continue;
+                };
+                if !sub.starts_with('.') && dir.join(&sub).is_dir() && bucket.prune_sub_dir(&sub)? {
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+
+    #[test]
+    fn test_prune_empty_sub_buckets() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<u8>("things").expect("fail bucket");
+        b.put_within("a", 1, "sub").expect("failed to save");
+        b.put_within("b", 2, "sub").expect("failed to save");
+        b.set_prune_empty_sub_buckets(true);
+        b.remove_within("a", "sub").expect("fail remove");
+        assert!(db.path().join("things/sub").is_dir());
+        b.remove_within("b", "sub").expect("fail remove");
+        assert!(!db.path().join("things/sub").exists());
+        // and it comes back when written to again
+        b.put_within("c", 3, "sub").expect("failed to save");
+        assert_eq!(b.get_within("c", "sub").expect("fail get"), 3);
+    }
+
+    #[test]
+    fn test_gc_empty_dirs() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        b.enable_manifest().expect("fail enable_manifest");
+        b.put("top", 0).expect("failed to save");
+        for sub in ["x", "y", "z"] {
+            b.put_within("a", 1, sub).expect("failed to save");
+        }
+        b.remove_within("a", "x").expect("fail remove");
+        b.remove_within("a", "y").expect("fail remove");
+        let empty = db.bucket::<u8>("empty").expect("fail bucket");
+
+        assert_eq!(db.gc_empty_dirs().expect("fail gc"), 2);
+        let mut keys = b.list().expect("fail list");
+        keys.sort();
+        assert_eq!(keys, vec!["top", "z"]);
+        assert!(empty.is_empty().expect("fail is_empty"));
+        assert_eq!(db.gc_empty_dirs().expect("fail gc"), 0);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::config::BucketConfig;
+use crate::error::WithContext;
+use crate::{Bucket, Context, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::path::Path;
+
+// wholesale replacement
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// A counter bumped every time this bucket's contents are replaced
+    /// wholesale: by `clear`, `restore_snapshot`, `copy_to` (into it) and
+    /// `Fsdb::restore_to`, and by `Fsdb::swap_alias` for the buckets an alias
+    /// pointed at before and after. Caches and replicas can note it when they
+    /// fill, and start over once it changes. It's read from disk each time, so
+    /// it sees other processes' changes too. A new bucket starts at 0
+    pub fn generation(&self) -> Result<u64> {
+        let config =
+            BucketConfig::load(&self.dir).ctx(|| Context::new(Op::Get, &self.dir, None))?;
+        Ok(config.map_or(0, |c| c.generation))
+    }
+}
+
+/// Bump the generation of the bucket in `dir`
+pub(crate) fn bump(dir: &Path) -> io::Result<()> {
+    BucketConfig::update(dir, |config| {
+        config.generation += 1;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_generation() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("data").expect("fail bucket");
+        assert_eq!(b.generation().expect("fail generation"), 0);
+        // ordinary writes don't change it
+        b.put("a", 1).expect("failed to save");
+        b.remove("a").expect("fail remove");
+        assert_eq!(b.generation().expect("fail generation"), 0);
+
+        b.put("a", 1).expect("failed to save");
+        b.snapshot("one").expect("fail snapshot");
+        b.clear().expect("fail clear");
+        assert_eq!(b.generation().expect("fail generation"), 1);
+        b.restore_snapshot("one").expect("fail restore_snapshot");
+        let other = db.bucket::<u32>("data").expect("fail bucket");
+        assert_eq!(other.generation().expect("fail generation"), 2);
+
+        let next = db.bucket::<u32>("data-next").expect("fail bucket");
+        db.alias("current", "data").expect("fail alias");
+        db.swap_alias("current", "data-next")
+            .expect("fail swap_alias");
+        assert_eq!(b.generation().expect("fail generation"), 3);
+        assert_eq!(next.generation().expect("fail generation"), 1);
+    }
+
+    #[test]
+    fn test_generation_with_config_writes() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("data").expect("fail bucket");
+        let dir = db.path().join("data");
+        // settings saved alongside don't lose bumps
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..50 {
+                    super::bump(&dir).expect("fail bump");
+                }
+            });
+            for i in 0..50 {
+                b.set_default_ttl(Duration::from_secs(i + 1))
+                    .expect("fail set_default_ttl");
+            }
+        });
+        assert_eq!(b.generation().expect("fail generation"), 50);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::config::BucketConfig;
+use crate::error::WithContext;
+use crate::header;
+use crate::{Bucket, Context, Error, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::path::Path;
+
+// FNV-1a, 128 bit
+const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// The file name a key is stored under in a bucket with hashed keys
+pub(crate) fn hashed_name(key: &str) -> String {
+    // already hashed, such as a name from listing the directory
+    if is_hashed_name(key) {
+        return key.to_owned();
+    }
+    let hash = key
+        .bytes()
+        .fold(OFFSET, |h, b| (h ^ b as u128).wrapping_mul(PRIME));
+    format!("#{:032x}", hash)
+}
+
+fn is_hashed_name(name: &str) -> bool {
+    name.len() == 33
+        && name.starts_with('#')
+        && name[1..]
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+// naming files by key hash
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Name files after a hash of their key, rather than the key itself, so keys
+    /// can be any length and contain any character. The key is kept in each
+    /// value's header, and `list`, `list_within`, `retain` and `remove_where`
+    /// read it back from there. Other listings (queries, indexes and so on)
+    /// return the hashed names, which work as keys too. Sub-bucket names are
+    /// hashed as well, and listed hashed.
+    ///
+    /// The setting is saved with the bucket, so it applies to every later
+    /// handle. It can only be turned on while the bucket is empty
+    pub fn enable_hashed_keys(&self) -> Result<()> {
+        let ctx = || Context::new(Op::Bucket, &self.dir, None);
+        let _guard = self.state.write_guard();
+        if self.state.hashed_keys() {
+            return Ok(());
+        }
+        if !self.fs_list(&self.dir)?.is_empty() {
+            return Err(Error::Io {
+                ctx: ctx(),
+                source: io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "hashed keys can only be enabled on an empty bucket",
+                ),
+            });
+        }
+        BucketConfig::update(&self.dir, |config| {
+            config.hashed_keys = true;
+            Ok(())
+        })
+        .ctx(ctx)?;
+        self.state.set_hashed_keys();
+        Ok(())
+    }
+    // the keys of files (and sub-buckets) listed in `dir`, skipping any removed since
+    pub(crate) fn keys_of(&self, dir: &Path, names: Vec<String>) -> Result<Vec<String>> {
+        if !self.state.hashed_keys() {
+            return Ok(names);
+        }
+        let mut keys = Vec::with_capacity(names.len());
+        for name in names {
+            if let Some(key) = self.key_of(dir, &name)? {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+    // the key stored in `dir/name`'s header, or the name if there isn't one
+    // (such as a sub-bucket, or an upload). None if it's been removed
+    pub(crate) fn key_of(&self, dir: &Path, name: &str) -> Result<Option<String>> {
+        if !self.state.hashed_keys() {
+            return Ok(Some(name.to_owned()));
+        }
+        let path = dir.join(name);
+        if path.is_dir() {
+            return Ok(Some(name.to_owned()));
+        }
+        let data = match self.read_file(dir, name) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).ctx(|| Context::new(Op::List, dir, Some(name))),
+        };
+        let key = header::split(&data).and_then(|(h, _)| h.key);
+        Ok(Some(key.unwrap_or_else(|| name.to_owned())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hashed_name;
+    use crate::Fsdb;
+
+    #[test]
+    fn test_hashed_keys() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("hashed").expect("fail bucket");
+        b.enable_hashed_keys().expect("fail enable_hashed_keys");
+        let long = "x".repeat(1000);
+        let keys = ["a/b", "..", "ключ 🔑", long.as_str(), ".hidden"];
+        for (i, key) in keys.iter().enumerate() {
+            b.put(key, i as u32).expect("failed to save");
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(b.get(key).expect("fail get"), i as u32);
+        }
+        let mut listed = b.list().expect("fail list");
+        listed.sort();
+        let mut expected: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert!(db.path().join("hashed").join(hashed_name("a/b")).is_file());
+
+        b.remove("a/b").expect("fail remove");
+        assert_eq!(b.remove_where(|k| k == "..").expect("fail remove_where"), 1);
+        assert_eq!(b.len().expect("fail len"), 3);
+
+        // saved with the bucket
+        let again = db.bucket::<u32>("hashed").expect("fail bucket");
+        assert_eq!(again.get(&long).expect("fail get"), 3);
+
+        let full = db.bucket::<u32>("full").expect("fail bucket");
+        full.put("k", 1).expect("failed to save");
+        assert!(full.enable_hashed_keys().is_err());
+    }
+}
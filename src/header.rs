@@ -0,0 +1,63 @@
+use crate::Metadata;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+// 0xc1 is never used in msgpack, so no bare encoded value can start with this
+const MAGIC: &[u8] = b"\xc1FSDB";
+const FORMAT: u8 = 1;
+
+/// Optional header stored in front of a value. Values with nothing to put in a
+/// header are stored bare, exactly as encoded
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Header {
+    pub signature: Option<Vec<u8>>,
+    pub type_tag: Option<String>,
+    // when they aren't kept in extended attributes, see `Metadata`
+    pub attrs: Option<Metadata>,
+    // the key, when the file is named after its hash, see `Bucket::enable_hashed_keys`
+    pub key: Option<String>,
+    // after which the value reads as missing, see `Bucket::set_default_ttl`
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Header {
+    fn is_empty(&self) -> bool {
+        self.signature.is_none()
+            && self.type_tag.is_none()
+            && self.attrs.is_none()
+            && self.key.is_none()
+            && self.expires_at.is_none()
+    }
+    /// Whether the value has passed its expiry time
+    pub(crate) fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| t <= SystemTime::now())
+    }
+}
+
+/// Prefix `payload` with `header` (unless the header is empty)
+pub(crate) fn wrap(header: &Header, payload: Vec<u8>) -> Vec<u8> {
+    if header.is_empty() {
+        return payload;
+    }
+    let mut out = MAGIC.to_vec();
+    out.push(FORMAT);
+    // encoding a struct of options and byte vecs into a Vec can't fail
+    rmp_serde::encode::write(&mut out, header).expect("header encodes");
+    out.extend_from_slice(&payload);
+    out
+}
+
+/// Split stored bytes into their header (empty if there was none) and payload.
+/// Returns `None` if the header is corrupt
+pub(crate) fn split(bytes: &[u8]) -> Option<(Header, &[u8])> {
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        return Some((Header::default(), bytes));
+    };
+    let (format, mut rest) = rest.split_first()?;
+    if *format != FORMAT {
+        return None;
+    }
+    let header = rmp_serde::decode::from_read(&mut rest).ok()?;
+    Some((header, rest))
+}
@@ -0,0 +1,428 @@
+use crate::error::WithContext;
+use crate::state::BucketState;
+use crate::{tags, Bucket, Context, Error, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::any::Any;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A secondary index over a bucket's values, kept in memory and shared by every
+/// handle to the bucket. `BucketState` feeds it every write to a top-level key
+pub(crate) trait Index: Send + Sync {
+    /// `name` was written (with its file now at `file`), or removed if `None`
+    fn update(&self, name: &str, file: Option<&Path>);
+    fn clear(&self);
+    /// Keys with the `n` largest sort keys, largest first
+    fn top(&self, n: usize) -> Vec<String>;
+    /// Keys whose sort key (as JSON) satisfies `filter`, for `Query`
+    fn matching(&self, filter: &dyn Fn(&Value) -> bool) -> Option<Vec<String>>;
+    /// Keys whose entry doesn't match their value, given the keys `names`
+    /// stored (as files) in `dir`
+    fn stale(&self, names: &[String], dir: &Path) -> Vec<String>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// What `Bucket::verify_indexes` found
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IndexReport {
+    /// Number of keys checked
+    pub checked: usize,
+    /// Index entries that don't match the stored values, as (index, key). The
+    /// tag index shows up as "tag:<tag>"
+    pub stale: Vec<(String, String)>,
+}
+
+impl IndexReport {
+    /// Check if every index matched the stored values
+    pub fn is_consistent(&self) -> bool {
+        self.stale.is_empty()
+    }
+}
+
+// reads a value's file and extracts its sort key, or None if it can't be read
+type ReadFn<K> = dyn Fn(&Path) -> Option<K> + Send + Sync;
+
+struct Sorted<K> {
+    read: Box<ReadFn<K>>,
+    entries: Mutex<Entries<K>>,
+}
+
+struct Entries<K> {
+    by_name: HashMap<String, K>,
+    sorted: BTreeSet<(K, String)>,
+}
+
+impl<K: Ord + Clone> Entries<K> {
+    fn set(&mut self, name: &str, key: Option<K>) {
+        if let Some(old) = self.by_name.remove(name) {
+            self.sorted.remove(&(old, name.to_owned()));
+        }
+        if let Some(key) = key {
+            self.sorted.insert((key.clone(), name.to_owned()));
+            self.by_name.insert(name.to_owned(), key);
+        }
+    }
+}
+
+impl<K> Sorted<K> {
+    fn entries(&self) -> MutexGuard<'_, Entries<K>> {
+        // every update leaves both maps consistent before anything can panic
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K: Ord + Clone + Serialize + Send + Sync + 'static> Index for Sorted<K> {
+    fn update(&self, name: &str, file: Option<&Path>) {
+        let key = file.and_then(|f| (self.read)(f));
+        self.entries().set(name, key);
+    }
+    fn clear(&self) {
+        let mut entries = self.entries();
+        entries.by_name.clear();
+        entries.sorted.clear();
+    }
+    fn top(&self, n: usize) -> Vec<String> {
+        let entries = self.entries();
+        entries
+            .sorted
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(_, name)| name.clone())
+            .collect()
+    }
+    fn matching(&self, filter: &dyn Fn(&Value) -> bool) -> Option<Vec<String>> {
+        // the caller's filter runs with the entries unlocked
+        let sorted: Vec<(K, String)> = self.entries().sorted.iter().cloned().collect();
+        let mut names = Vec::new();
+        for (key, name) in sorted {
+            if filter(&serde_json::to_value(key).ok()?) {
+                names.push(name);
+            }
+        }
+        Some(names)
+    }
+    fn stale(&self, names: &[String], dir: &Path) -> Vec<String> {
+        // so does the sort key
+        let keys: Vec<Option<K>> = names.iter().map(|n| (self.read)(&dir.join(n))).collect();
+        let entries = self.entries();
+        let mut stale: Vec<String> = names
+            .iter()
+            .zip(keys)
+            .filter(|(name, key)| entries.by_name.get(*name) != key.as_ref())
+            .map(|(name, _)| name.clone())
+            .collect();
+        let listed: BTreeSet<&String> = names.iter().collect();
+        stale.extend(
+            entries
+                .by_name
+                .keys()
+                .filter(|n| !listed.contains(n))
+                .cloned(),
+        );
+        stale.sort();
+        stale
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl BucketState {
+    // feed a write or remove of a top-level key to the bucket's indexes, which
+    // run the caller's sort keys, so not with the index list locked
+    pub(crate) fn update_indexes(&self, name: &str, file: Option<&Path>) {
+        let indexes: Vec<_> = self.indexes().values().cloned().collect();
+        for index in indexes {
+            index.update(name, file);
+        }
+    }
+    pub(crate) fn clear_indexes(&self) {
+        let indexes: Vec<_> = self.indexes().values().cloned().collect();
+        for index in indexes {
+            index.clear();
+        }
+    }
+}
+
+// sorted secondary indexes
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Index this bucket's keys by `sort_key` of their values, for `top_n` and
+    /// `range_by` queries (like a leaderboard) that don't read every value.
+    ///
+    /// The index is built by reading every value once, then lives in memory and
+    /// is kept up to date by writes through every handle to the bucket in this
+    /// process. Call `rebuild_index` to pick up writes by other processes.
+    /// Creating an index with an existing name replaces it. Name it after the
+    /// field it sorts by for `Query` to use it
+    pub fn create_sorted_index<K>(
+        &self,
+        name: &str,
+        sort_key: impl Fn(&V) -> K + Send + Sync + 'static,
+    ) -> Result<()>
+    where
+        V: Send + Sync + 'static,
+        K: Ord + Clone + Serialize + Send + Sync + 'static,
+    {
+        // a handle with this one's keys, but not its state, which would keep itself alive
+        let mut reader = self.clone();
+        reader.state = Arc::new(BucketState::default());
+        let read = move |file: &Path| {
+            let bytes = fs::read(file).ok()?;
+            let value = reader
+                .decode(&bytes, || Context::new(Op::Get, file, None))
+                .ok()?;
+            Some(sort_key(&value))
+        };
+        let index = Arc::new(Sorted {
+            read: Box::new(read),
+            entries: Mutex::new(Entries {
+                by_name: HashMap::new(),
+                sorted: BTreeSet::new(),
+            }),
+        });
+        // hold off writers, so none is missed between the scan and registering the index
+        let _guard = self.state.exclusive_guard();
+        // indexes read values from their files
+        if self.state.pack().is_some() {
+            return Err(Error::Io {
+                ctx: Context::new(Op::Bucket, &self.dir, None),
+                source: io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a packed bucket can't have a sorted index",
+                ),
+            });
+        }
+        fill(index.as_ref(), &self.fs_keys(&self.dir)?, &self.dir);
+        self.state.indexes().insert(name.to_owned(), index);
+        Ok(())
+    }
+    /// Rebuild an index from the values on disk
+    pub fn rebuild_index(&self, name: &str) -> Result<()> {
+        let index = self.index(name)?;
+        let _guard = self.state.exclusive_guard();
+        index.clear();
+        fill(index.as_ref(), &self.fs_keys(&self.dir)?, &self.dir);
+        Ok(())
+    }
+    /// Rebuild every sorted index and the tag index from the values on disk,
+    /// for when a crash or another process's writes left them out of date
+    pub fn rebuild_indexes(&self) -> Result<()> {
+        let _guard = self.state.exclusive_guard();
+        self.rebuild_indexes_locked()
+    }
+    // `rebuild_indexes`, with the bucket locked exclusively
+    pub(crate) fn rebuild_indexes_locked(&self) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.dir, None);
+        let indexes: Vec<_> = self.state.indexes().values().cloned().collect();
+        let names = self.fs_keys(&self.dir)?;
+        for index in indexes {
+            index.clear();
+            fill(index.as_ref(), &names, &self.dir);
+        }
+        let tags_of = |name: &str| self.state.stored_tags(&self.dir, name);
+        tags::rebuild(&self.dir, &names, tags_of).ctx(ctx)
+    }
+    /// Check every sorted index and the tag index against the values on disk,
+    /// without changing them
+    pub fn verify_indexes(&self) -> Result<IndexReport> {
+        let ctx = || Context::new(Op::Get, &self.dir, None);
+        let indexes: Vec<_> = self
+            .state
+            .indexes()
+            .iter()
+            .map(|(name, index)| (name.clone(), index.clone()))
+            .collect();
+        let _guard = self.state.exclusive_guard();
+        let names = self.fs_keys(&self.dir)?;
+        let mut report = IndexReport {
+            checked: names.len(),
+            stale: Vec::new(),
+        };
+        for (name, index) in indexes {
+            for key in index.stale(&names, &self.dir) {
+                report.stale.push((name.clone(), key));
+            }
+        }
+        let tags_of = |name: &str| self.state.stored_tags(&self.dir, name);
+        for (tag, key) in tags::stale(&self.dir, &names, tags_of).ctx(ctx)? {
+            report.stale.push((format!("tag:{}", tag), key));
+        }
+        report.stale.sort();
+        Ok(report)
+    }
+    /// Stop maintaining an index
+    pub fn drop_index(&self, name: &str) {
+        self.state.indexes().remove(name);
+    }
+    /// The `n` keys with the largest sort keys in an index, largest first
+    pub fn top_n(&self, name: &str, n: usize) -> Result<Vec<String>> {
+        Ok(self.index(name)?.top(n))
+    }
+    /// Keys whose sort key in an index is within `range`, smallest first. `K`
+    /// must be the type the index was created with
+    pub fn range_by<K: Ord + Clone + 'static>(
+        &self,
+        name: &str,
+        range: impl RangeBounds<K>,
+    ) -> Result<Vec<String>> {
+        let index = self.index(name)?;
+        let Some(sorted) = index.as_any().downcast_ref::<Sorted<K>>() else {
+            return Err(self.index_error(name, "has a different sort key type"));
+        };
+        let entries = sorted.entries();
+        let start = match range.start_bound() {
+            Bound::Included(k) | Bound::Excluded(k) => Bound::Included((k.clone(), String::new())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Ok(entries
+            .sorted
+            .range((start, Bound::Unbounded))
+            .skip_while(|(k, _)| matches!(range.start_bound(), Bound::Excluded(s) if k == s))
+            .take_while(|(k, _)| range.contains(k))
+            .map(|(_, name)| name.clone())
+            .collect())
+    }
+    fn index(&self, name: &str) -> Result<Arc<dyn Index>> {
+        let index = self.state.indexes().get(name).cloned();
+        index.ok_or_else(|| self.index_error(name, "doesn't exist"))
+    }
+    fn index_error(&self, name: &str, problem: &str) -> Error {
+        Error::Io {
+            ctx: Context::new(Op::Get, &self.dir, None),
+            source: io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("index {:?} {}", name, problem),
+            ),
+        }
+    }
+}
+
+fn fill(index: &dyn Index, names: &[String], dir: &Path) {
+    for name in names {
+        index.update(name, Some(&dir.join(name)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Fsdb, Metadata};
+    use serde::{Deserialize, Serialize};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Player {
+        score: u32,
+    }
+
+    #[test]
+    fn test_sorted_index() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Player>("players").expect("fail bucket");
+        for (name, score) in [("ann", 30), ("bob", 10), ("cat", 50)] {
+            b.put(name, Player { score }).expect("failed to save");
+        }
+        b.create_sorted_index("by_score", |p: &Player| p.score)
+            .expect("fail create_sorted_index");
+        assert_eq!(
+            b.top_n("by_score", 2).expect("fail top_n"),
+            vec!["cat", "ann"]
+        );
+
+        // kept up to date by writes through any handle
+        let other = db.bucket::<Player>("players").expect("fail bucket");
+        other
+            .put("dan", Player { score: 40 })
+            .expect("failed to save");
+        other
+            .put("cat", Player { score: 5 })
+            .expect("failed to save");
+        b.remove("ann").expect("fail remove");
+        assert_eq!(
+            b.top_n("by_score", 10).expect("fail top_n"),
+            vec!["dan", "bob", "cat"]
+        );
+        assert_eq!(
+            b.range_by("by_score", 5u32..=10).expect("fail range"),
+            vec!["cat", "bob"]
+        );
+        assert_eq!(
+            b.range_by("by_score", 10u32..).expect("fail range"),
+            vec!["bob", "dan"]
+        );
+        assert!(b.range_by("by_score", "a"..).is_err());
+        assert!(b.top_n("nope", 1).is_err());
+
+        b.clear().expect("fail clear");
+        assert!(b.top_n("by_score", 10).expect("fail top_n").is_empty());
+        b.put("eve", Player { score: 1 }).expect("failed to save");
+        b.rebuild_index("by_score").expect("fail rebuild_index");
+        assert_eq!(b.top_n("by_score", 10).expect("fail top_n"), vec!["eve"]);
+        b.drop_index("by_score");
+        assert!(b.top_n("by_score", 1).is_err());
+    }
+
+    #[test]
+    fn test_verify_indexes() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Player>("players").expect("fail bucket");
+        let tagged = Metadata {
+            tags: vec!["pro".to_owned()],
+            ..Default::default()
+        };
+        b.put_with_meta("ann", Player { score: 30 }, tagged)
+            .expect("failed to save");
+        b.put("bob", Player { score: 10 }).expect("failed to save");
+        b.create_sorted_index("score", |p: &Player| p.score)
+            .expect("fail create_sorted_index");
+        let report = b.verify_indexes().expect("fail verify_indexes");
+        assert!(report.is_consistent());
+        assert_eq!(report.checked, 2);
+
+        // another process's writes (or a crash) leave the indexes behind
+        let dir = db.path().join("players");
+        std::fs::copy(dir.join("bob"), dir.join("cat")).expect("fail copy");
+        std::fs::remove_file(dir.join("ann")).expect("fail remove");
+        let report = b.verify_indexes().expect("fail verify_indexes");
+        assert_eq!(
+            report.stale,
+            vec![
+                ("score".to_owned(), "ann".to_owned()),
+                ("score".to_owned(), "cat".to_owned()),
+                ("tag:pro".to_owned(), "ann".to_owned()),
+            ]
+        );
+
+        b.rebuild_indexes().expect("fail rebuild_indexes");
+        assert!(b.verify_indexes().expect("fail verify").is_consistent());
+        assert_eq!(
+            b.top_n("score", 10).expect("fail top_n"),
+            vec!["cat", "bob"]
+        );
+        assert!(b.list_by_tag("pro").expect("fail list").is_empty());
+    }
+
+    #[test]
+    fn test_sort_key_callbacks() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Player>("players").expect("fail bucket");
+        let reader = b.clone();
+        // sort keys can use the bucket's indexes, and panic without breaking them
+        b.create_sorted_index("score", move |p: &Player| {
+            assert!(p.score != 13, "unlucky");
+            let _ = reader.top_n("score", 1);
+            p.score
+        })
+        .expect("fail create_sorted_index");
+        b.put("ann", Player { score: 30 }).expect("failed to save");
+        let res = catch_unwind(AssertUnwindSafe(|| b.put("bob", Player { score: 13 })));
+        assert!(res.is_err());
+        b.put("cat", Player { score: 50 }).expect("failed to save");
+        assert_eq!(b.top_n("score", 2).expect("fail top_n"), vec!["cat", "ann"]);
+    }
+}
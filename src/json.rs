@@ -0,0 +1,148 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+// recorded as the bucket's type, so it can't also be opened with `bucket`,
+// which would expect msgpack
+const JSON_TYPE: &str = "fsdb::json";
+
+impl Fsdb {
+    /// Create or open a bucket of JSON values, each stored as a pretty-printed
+    /// JSON file that can be edited by hand, for configuration-like data.
+    /// A file edited into invalid JSON fails to decode. Ciphers and signing
+    /// still apply, but make the files unreadable by hand
+    pub fn json_bucket(&self, p: &str) -> Result<Bucket<Value>> {
+        let (dir, state) = self.open_bucket_dir(p, JSON_TYPE)?;
+        let mut bucket = Bucket::with_state(dir, state);
+        bucket.json = true;
+        Ok(bucket)
+    }
+}
+
+// JSON views of a bucket, for debugging and test fixtures
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Write every key and value in this bucket to `w` as an indented JSON object,
+    /// sorted by key. Sub-buckets are not included
+    pub fn dump_pretty<W: Write>(&self, mut w: W) -> Result<()> {
+        let ctx = || Context::new(Op::List, &self.dir, None);
+        let mut all = BTreeMap::new();
+        for key in self.fs_keys(&self.dir)? {
+            let v = self.fs_get(&self.dir, &key)?;
+            let json = serde_json::to_value(&v)
+                .map_err(std::io::Error::from)
+                .ctx(|| Context::new(Op::Get, &self.dir, Some(&key)))?;
+            all.insert(key, json);
+        }
+        serde_json::to_writer_pretty(&mut w, &all)
+            .map_err(std::io::Error::from)
+            .ctx(ctx)?;
+        writeln!(w).ctx(ctx)
+    }
+    /// Store every `*.json` file in the directory `path` as a value, keyed by the
+    /// file name without its extension. Returns the number of values stored
+    pub fn import_json_dir(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let path = path.as_ref();
+        let entries = fs::read_dir(path).ctx(|| Context::new(Op::List, path, None))?;
+        let mut count = 0;
+        for entry in entries {
+            let file = entry.ctx(|| Context::new(Op::List, path, None))?.path();
+            if file.extension().is_none_or(|e| e != "json") || !file.is_file() {
+                continue;
+            }
+            let Some(key) = file.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let ctx = || Context::new(Op::Put, &self.dir, Some(key));
+            let bytes = fs::read(&file).ctx(ctx)?;
+            let value: V = serde_json::from_slice(&bytes)
+                .map_err(std::io::Error::from)
+                .ctx(ctx)?;
+            self.fs_put(&self.dir, key, &value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+// value -> pretty-printed JSON, ending in a newline like a hand-written file
+pub(crate) fn to_vec<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    let mut bytes = serde_json::to_vec_pretty(value)?;
+    bytes.push(b'\n');
+    Ok(bytes)
+}
+
+pub(crate) fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Fsdb};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::fs;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Thing {
+        n: u8,
+    }
+
+    #[test]
+    fn test_json_bucket() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.json_bucket("settings").expect("fail json_bucket");
+        b.clear().expect("fail clear");
+        b.put("app", json!({"name": "fsdb", "port": 80}))
+            .expect("failed to save");
+        let path = db.path().join("settings").join("app");
+        let text = fs::read_to_string(&path).expect("fail read");
+        assert_eq!(text, "{\n  \"name\": \"fsdb\",\n  \"port\": 80\n}\n");
+
+        // edited by hand
+        fs::write(&path, "{\"name\": \"fsdb\", \"port\": 8080}").expect("fail write");
+        let app = b.get("app").expect("fail get");
+        assert_eq!(app["port"], 8080);
+
+        let err = db
+            .bucket::<Value>("settings")
+            .err()
+            .expect("opened as msgpack");
+        assert!(matches!(err, Error::TypeMismatch { .. }));
+        fs::write(&path, "{\"name\": ").expect("fail write");
+        assert!(b.get("app").is_err());
+    }
+
+    #[test]
+    fn test_dump_pretty() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("things").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.put("b", Thing { n: 2 }).expect("failed to save");
+        b.put("a", Thing { n: 1 }).expect("failed to save");
+        let mut out = Vec::new();
+        b.dump_pretty(&mut out).expect("fail dump");
+        let expected = "{\n  \"a\": {\n    \"n\": 1\n  },\n  \"b\": {\n    \"n\": 2\n  }\n}\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_import_json_dir() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("things").expect("fail bucket");
+        b.clear().expect("fail clear");
+        let fixtures = db.path().join("fixtures");
+        std::fs::create_dir_all(&fixtures).expect("fail mkdir");
+        std::fs::write(fixtures.join("a.json"), r#"{"n": 1}"#).expect("fail write");
+        std::fs::write(fixtures.join("b.json"), r#"{"n": 2}"#).expect("fail write");
+        std::fs::write(fixtures.join("notes.txt"), "skipped").expect("fail write");
+        assert_eq!(b.import_json_dir(&fixtures).expect("fail import"), 2);
+        assert_eq!(b.get("a").expect("fail get"), Thing { n: 1 });
+        assert_eq!(b.get("b").expect("fail get"), Thing { n: 2 });
+        assert_eq!(b.len().expect("fail len"), 2);
+    }
+}
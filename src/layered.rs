@@ -0,0 +1,367 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, ErrorKind, ObjectStore, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// One level of a `Layered` store, such as a `MemoryLayer`, a `Bucket` or an
+/// `ObjectLayer`. Implement it to put anything else behind the same API
+pub trait Layer<V>: Send + Sync {
+    /// Load a value, or `None` if this layer doesn't have it
+    fn get(&self, key: &str) -> Result<Option<V>>;
+    /// Save a value
+    fn put(&self, key: &str, value: V) -> Result<()>;
+    /// Remove a value. Removing a missing one isn't an error
+    fn remove(&self, key: &str) -> Result<()>;
+}
+
+/// How a `Layered` store's writes reach its layers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Write every layer before returning, the last (the source of truth)
+    /// first, so an upper layer never holds a value the last one doesn't
+    Through,
+    /// Write the first layer only, and the others on `flush`, once
+    /// `max_dirty` keys have unflushed changes, or when the store is dropped.
+    /// Reads through the store see the unflushed changes
+    Behind { max_dirty: usize },
+}
+
+/// Layers of storage in front of each other, such as an in-memory cache in
+/// front of a local bucket in front of object storage. Reads try each layer
+/// in turn, and copy a value found in a lower layer into the ones above it.
+/// Writes follow the `WritePolicy`
+pub struct Layered<V> {
+    layers: Vec<Arc<dyn Layer<V>>>,
+    policy: WritePolicy,
+    // changes not yet written below the first layer, for `WritePolicy::Behind`;
+    // None removes the key
+    dirty: Mutex<BTreeMap<String, Option<V>>>,
+    // Layered::flush, which Drop can't name without V's bounds
+    flush_fn: fn(&Layered<V>) -> Result<()>,
+}
+
+/// A bounded in-memory layer, dropping the least recently used value once it's
+/// full. It only sees writes through the `Layered` store it's part of
+pub struct MemoryLayer<V> {
+    max_entries: usize,
+    entries: Mutex<Lru<V>>,
+}
+
+struct Lru<V> {
+    values: HashMap<String, (V, u64)>,
+    // keys by last use, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// A layer storing each value as an object in an `ObjectStore`, named
+/// `<prefix>/<key>` and encoded as MessagePack
+pub struct ObjectLayer {
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+}
+
+impl<V: Clone> Layered<V> {
+    /// Layers, first (fastest) to last (the source of truth)
+    pub fn new(layers: Vec<Arc<dyn Layer<V>>>, policy: WritePolicy) -> Self {
+        Self {
+            layers,
+            policy,
+            dirty: Mutex::new(BTreeMap::new()),
+            flush_fn: Self::flush,
+        }
+    }
+    /// Load a value from the first layer that has it, or `None`
+    pub fn get(&self, key: &str) -> Result<Option<V>> {
+        if let Some(change) = self.dirty().get(key) {
+            return Ok(change.clone());
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(value) = layer.get(key)? {
+                for above in &self.layers[..i] {
+                    above.put(key, value.clone())?;
+                }
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+    /// Save a value
+    pub fn put(&self, key: &str, value: V) -> Result<()> {
+        self.write(key, Some(value))
+    }
+    /// Remove a value from every layer
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.write(key, None)
+    }
+    /// Write changes buffered by `WritePolicy::Behind` to the layers below
+    /// the first. A change that fails stays buffered, for the next flush
+    pub fn flush(&self) -> Result<()> {
+        let changes = std::mem::take(&mut *self.dirty());
+        let mut pending = changes.into_iter();
+        while let Some((key, change)) = pending.next() {
+            if let Err(e) = self.write_below(1, &key, change.clone()) {
+                // keep it, and the rest, unless they've been changed since
+                let mut dirty = self.dirty();
+                for (key, change) in std::iter::once((key, change)).chain(pending) {
+                    dirty.entry(key).or_insert(change);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    fn write(&self, key: &str, change: Option<V>) -> Result<()> {
+        match self.policy {
+            WritePolicy::Through => self.write_below(0, key, change),
+            WritePolicy::Behind { max_dirty } => {
+                if let Some(first) = self.layers.first() {
+                    match change.clone() {
+                        Some(value) => first.put(key, value)?,
+                        None => first.remove(key)?,
+                    }
+                }
+                let full = {
+                    let mut dirty = self.dirty();
+                    dirty.insert(key.to_owned(), change);
+                    dirty.len() >= max_dirty
+                };
+                match full {
+                    true => self.flush(),
+                    false => Ok(()),
+                }
+            }
+        }
+    }
+    // write layers from `from` on, last first
+    fn write_below(&self, from: usize, key: &str, change: Option<V>) -> Result<()> {
+        for layer in self.layers.iter().skip(from).rev() {
+            match change.clone() {
+                Some(value) => layer.put(key, value)?,
+                None => layer.remove(key)?,
+            }
+        }
+        Ok(())
+    }
+    fn dirty(&self) -> MutexGuard<'_, BTreeMap<String, Option<V>>> {
+        // a change is inserted or taken in one step, so a panic can't leave it half done
+        self.dirty.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<V> Drop for Layered<V> {
+    fn drop(&mut self) {
+        // nowhere to report an error; call `flush` first to find out
+        let _ = (self.flush_fn)(self);
+    }
+}
+
+impl<V> MemoryLayer<V> {
+    /// A layer holding at most `max_entries` values
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: Mutex::new(Lru {
+                values: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+    /// Number of values held
+    pub fn len(&self) -> usize {
+        self.entries().values.len()
+    }
+    /// Check if it holds no values
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn entries(&self) -> MutexGuard<'_, Lru<V>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<V: Clone + Send> Layer<V> for MemoryLayer<V> {
+    fn get(&self, key: &str) -> Result<Option<V>> {
+        let mut lru = self.entries();
+        lru.tick += 1;
+        let tick = lru.tick;
+        let Some((value, used)) = lru.values.get_mut(key) else {
+            return Ok(None);
+        };
+        let (value, old) = (value.clone(), std::mem::replace(used, tick));
+        lru.order.remove(&old);
+        lru.order.insert(tick, key.to_owned());
+        Ok(Some(value))
+    }
+    fn put(&self, key: &str, value: V) -> Result<()> {
+        let mut lru = self.entries();
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some((_, old)) = lru.values.insert(key.to_owned(), (value, tick)) {
+            lru.order.remove(&old);
+        }
+        lru.order.insert(tick, key.to_owned());
+        while lru.values.len() > self.max_entries {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            lru.values.remove(&oldest);
+        }
+        Ok(())
+    }
+    fn remove(&self, key: &str) -> Result<()> {
+        let mut lru = self.entries();
+        if let Some((_, used)) = lru.values.remove(key) {
+            lru.order.remove(&used);
+        }
+        Ok(())
+    }
+}
+
+impl<V: Serialize + DeserializeOwned + Send + Sync> Layer<V> for Bucket<V> {
+    fn get(&self, key: &str) -> Result<Option<V>> {
+        match Bucket::get(self, key) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            res => res.map(Some),
+        }
+    }
+    fn put(&self, key: &str, value: V) -> Result<()> {
+        Bucket::put(self, key, value)
+    }
+    fn remove(&self, key: &str) -> Result<()> {
+        match Bucket::remove(self, key) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+impl ObjectLayer {
+    /// Store values in `store`, under `prefix`
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: &str) -> Self {
+        Self {
+            store,
+            prefix: prefix.to_owned(),
+        }
+    }
+    fn ctx(&self, op: Op, key: &str) -> Context {
+        Context::new(op, &self.prefix, Some(key))
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Layer<V> for ObjectLayer {
+    fn get(&self, key: &str) -> Result<Option<V>> {
+        let ctx = || self.ctx(Op::Get, key);
+        let bytes = match self.store.get(&format!("{}/{}", self.prefix, key)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        rmp_serde::from_slice(&bytes).ctx(ctx).map(Some)
+    }
+    fn put(&self, key: &str, value: V) -> Result<()> {
+        let ctx = || self.ctx(Op::Put, key);
+        let bytes = rmp_serde::to_vec(&value).ctx(ctx)?;
+        self.store
+            .put(&format!("{}/{}", self.prefix, key), &bytes)
+            .ctx(ctx)
+    }
+    fn remove(&self, key: &str) -> Result<()> {
+        let ctx = || self.ctx(Op::Remove, key);
+        self.store
+            .delete(&format!("{}/{}", self.prefix, key))
+            .ctx(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Layer, Layered, MemoryLayer, ObjectLayer, WritePolicy};
+    use crate::{Fsdb, ObjectStore};
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Memory(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl ObjectStore for Memory {
+        fn put(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(name.to_owned(), bytes.to_vec());
+            Ok(())
+        }
+        fn get(&self, name: &str) -> io::Result<Vec<u8>> {
+            let objects = self.0.lock().unwrap();
+            objects
+                .get(name)
+                .cloned()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+        fn delete(&self, name: &str) -> io::Result<()> {
+            self.0.lock().unwrap().remove(name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_through() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let memory = Arc::new(MemoryLayer::new(1));
+        let bucket = Arc::new(db.bucket::<u32>("local").expect("fail bucket"));
+        let remote = Arc::new(ObjectLayer::new(Arc::new(Memory::default()), "remote"));
+        let layers: Vec<Arc<dyn Layer<u32>>> = vec![memory.clone(), bucket.clone(), remote.clone()];
+        let store = Layered::new(layers, WritePolicy::Through);
+
+        store.put("a", 1).expect("failed to save");
+        assert_eq!(bucket.get("a").expect("fail get"), 1);
+        assert_eq!(
+            Layer::<u32>::get(remote.as_ref(), "a").expect("fail get"),
+            Some(1)
+        );
+
+        // only in the last layer: read through, and copied up
+        Layer::put(remote.as_ref(), "b", 2u32).expect("failed to save");
+        assert_eq!(store.get("b").expect("fail get"), Some(2));
+        assert_eq!(bucket.get("b").expect("fail get"), 2);
+        // the memory layer only holds one, the most recently used
+        assert_eq!(memory.len(), 1);
+        assert_eq!(memory.get("b").expect("fail get"), Some(2));
+
+        store.remove("b").expect("fail remove");
+        assert_eq!(store.get("b").expect("fail get"), None);
+        assert!(!bucket.exists("b"));
+    }
+
+    #[test]
+    fn test_write_behind() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let memory = Arc::new(MemoryLayer::new(10));
+        let bucket = Arc::new(db.bucket::<u32>("local").expect("fail bucket"));
+        let layers: Vec<Arc<dyn Layer<u32>>> = vec![memory.clone(), bucket.clone()];
+        let store = Layered::new(layers, WritePolicy::Behind { max_dirty: 3 });
+
+        store.put("a", 1).expect("failed to save");
+        store.put("b", 2).expect("failed to save");
+        assert!(!bucket.exists("a"));
+        assert_eq!(store.get("a").expect("fail get"), Some(1));
+        store.flush().expect("fail flush");
+        assert_eq!(bucket.get("a").expect("fail get"), 1);
+
+        // flushed once enough keys have changed
+        for (i, key) in ["c", "d", "e"].into_iter().enumerate() {
+            store.put(key, i as u32).expect("failed to save");
+        }
+        assert!(bucket.exists("e"));
+
+        // and when dropped
+        store.remove("a").expect("fail remove");
+        drop(store);
+        assert!(!bucket.exists("a"));
+    }
+}
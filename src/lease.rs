@@ -0,0 +1,218 @@
+use crate::error::WithContext;
+use crate::lockfile::with_lock_file;
+use crate::{Bucket, Context, Error, Op, Result};
+use rmp_serde::{decode, encode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Directory (inside a bucket) holding lease files
+pub(crate) const LEASE_DIR: &str = ".leases";
+
+/// What's stored in a lease file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LeaseRecord {
+    owner: String,
+    // milliseconds since the unix epoch
+    expires: u64,
+}
+
+/// An exclusive claim on a name within a bucket, held until it is released or
+/// its time to live runs out, from `Bucket::acquire_lease`.
+///
+/// Expiry uses the system clock, so processes on different machines sharing the
+/// directory need roughly synchronized clocks. Dropping a `Lease` releases it
+pub struct Lease {
+    path: PathBuf,
+    owner: String,
+    expires: u64,
+    released: bool,
+}
+
+// leases, for coordinating processes
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Take the lease `name` for `ttl`, unless another owner holds it and it hasn't
+    /// expired, in which case `None` is returned. See `Lease`
+    pub fn acquire_lease(&self, name: &str, ttl: Duration) -> Result<Option<Lease>> {
+        self.acquire_lease_as(name, &new_owner_id(), ttl)
+    }
+    // acquire_lease with a chosen owner id. An owner already holding the lease takes it again
+    pub(crate) fn acquire_lease_as(
+        &self,
+        name: &str,
+        owner: &str,
+        ttl: Duration,
+    ) -> Result<Option<Lease>> {
+        let ctx = || Context::new(Op::Lease, &self.dir, Some(name));
+        let dir = self.dir.join(LEASE_DIR);
+        fs::create_dir_all(&dir).ctx(ctx)?;
+        let path = dir.join(self.maxify(name));
+        let expires = with_lock_file(&path, || {
+            if let Some(held) = read(&path)? {
+                if held.owner != owner && held.expires > now_millis() {
+                    return Ok(None);
+                }
+            }
+            let expires = now_millis() + ttl.as_millis() as u64;
+            write(&path, owner, expires)?;
+            Ok(Some(expires))
+        })
+        .ctx(ctx)?;
+        Ok(expires.map(|expires| Lease {
+            path,
+            owner: owner.to_owned(),
+            expires,
+            released: false,
+        }))
+    }
+}
+
+impl Lease {
+    /// Unique id of this lease's holder
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+    /// When the lease runs out, unless renewed
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.expires)
+    }
+    /// Extend the lease to `ttl` from now. Fails with `Error::LeaseLost` if it
+    /// expired and another owner has taken it since
+    pub fn renew(&mut self, ttl: Duration) -> Result<()> {
+        let ctx = || Context::new(Op::Lease, self.path.parent().unwrap_or(&self.path), None);
+        let owner = &self.owner;
+        let renewed = with_lock_file(&self.path, || {
+            match read(&self.path)? {
+                Some(held) if held.owner != *owner && held.expires > now_millis() => {
+                    return Ok(None)
+                }
+                _ => (),
+            }
+            let expires = now_millis() + ttl.as_millis() as u64;
+            write(&self.path, owner, expires)?;
+            Ok(Some(expires))
+        })
+        .ctx(ctx)?;
+        match renewed {
+            Some(expires) => {
+                self.expires = expires;
+                Ok(())
+            }
+            None => Err(Error::LeaseLost { ctx: ctx() }),
+        }
+    }
+    /// Give up the lease, so another owner can take it straight away
+    pub fn release(mut self) -> Result<()> {
+        self.released = true;
+        let ctx = || Context::new(Op::Lease, self.path.parent().unwrap_or(&self.path), None);
+        release(&self.path, &self.owner).ctx(ctx)
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = release(&self.path, &self.owner);
+        }
+    }
+}
+
+// remove the lease file, if `owner` still holds it
+fn release(path: &Path, owner: &str) -> io::Result<()> {
+    with_lock_file(path, || match read(path)? {
+        Some(held) if held.owner == owner => fs::remove_file(path),
+        _ => Ok(()),
+    })
+}
+
+fn read(path: &Path) -> io::Result<Option<LeaseRecord>> {
+    match fs::read(path) {
+        Ok(buf) => decode::from_slice(&buf)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write(path: &Path, owner: &str, expires: u64) -> io::Result<()> {
+    let record = LeaseRecord {
+        owner: owner.to_owned(),
+        expires,
+    };
+    let buf = encode::to_vec(&record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, buf)?;
+    fs::rename(tmp, path)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// unique across threads and processes on this machine
+fn new_owner_id() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!(
+        "{}-{}-{}",
+        nanos,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Fsdb};
+    use std::time::Duration;
+
+    #[test]
+    fn test_lease() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("jobs").expect("fail bucket");
+        let ttl = Duration::from_secs(60);
+        let mut lease = b
+            .acquire_lease("job-x", ttl)
+            .expect("fail acquire")
+            .expect("lease taken");
+        assert!(b
+            .acquire_lease("job-x", ttl)
+            .expect("fail acquire")
+            .is_none());
+        lease.renew(ttl).expect("fail renew");
+        lease.release().expect("fail release");
+
+        // an expired lease can be taken over, and the old holder loses it
+        let mut old = b
+            .acquire_lease("job-x", Duration::ZERO)
+            .expect("fail acquire")
+            .expect("lease taken");
+        let new = b
+            .acquire_lease("job-x", ttl)
+            .expect("fail acquire")
+            .expect("expired lease not taken");
+        assert!(matches!(old.renew(ttl), Err(Error::LeaseLost { .. })));
+        drop(old);
+        assert!(b
+            .acquire_lease("job-x", ttl)
+            .expect("fail acquire")
+            .is_none());
+        drop(new);
+        assert!(b
+            .acquire_lease("job-x", ttl)
+            .expect("fail acquire")
+            .is_some());
+        assert!(b.list().expect("fail list").is_empty());
+    }
+}
@@ -1,31 +1,253 @@
 use rmp_serde::{decode, encode};
-use std::fmt::Debug;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
 extern crate serde;
 
 use serde::{de::DeserializeOwned, Serialize};
 
+mod access;
+mod alias;
+#[cfg(feature = "async")]
+mod async_bucket;
+mod attrs;
+mod backup;
+pub mod bench;
+mod buffered;
+mod bus;
+mod cache;
+mod changelog;
+mod cipher;
+mod compact;
+mod compat;
+mod config;
+mod copy;
+mod crdt;
+mod diff;
+mod durable;
+mod elect;
+mod error;
+mod foreign;
+mod gc;
+mod generation;
+mod hashed;
+mod header;
+mod index;
+mod json;
+mod layered;
+mod lease;
+mod lockfile;
+mod maintenance;
+mod manifest;
+mod merge;
+mod meta;
+pub mod migrate;
+mod multi;
+mod offload;
+mod options;
+mod pack;
+mod page;
+mod poly;
+#[cfg(feature = "async")]
+mod pool;
+mod prefetch;
+mod project;
+mod query;
+mod read_only;
+mod recover;
+mod restore;
+mod retry;
+mod roots;
+mod seal;
+mod seq;
+mod set;
+mod shutdown;
+mod sign;
+mod slow;
+mod snapshot;
+mod space;
+mod state;
+#[cfg(feature = "csv")]
+mod tabular;
+mod tags;
+mod temp;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tier;
+mod ttl;
+mod txn;
+mod upload;
+mod view;
+#[cfg(feature = "async")]
+mod watch;
+use access::AccessLog;
+#[cfg(feature = "async")]
+pub use async_bucket::{AsyncBucket, Export};
+
+pub use attrs::Metadata;
+pub use backup::BackupMarker;
+pub use buffered::{BufferOptions, BufferedBucket};
+use bus::Notice;
+pub use bus::{BucketEvent, Subscription};
+pub use cache::CacheStats;
+use changelog::ChangeLog;
+pub use changelog::{ChangeIter, ChangeLogOptions, ChangeRecord};
+pub use cipher::Cipher;
+pub use compact::Reconfigured;
+pub use compat::CompatReport;
+use config::BucketConfig;
+pub use crdt::{Crdt, CrdtBucket};
+pub use diff::{diff, diff_databases, diff_structural, DiffReport, FieldChange, ValueDiff};
+pub use durable::Durability;
+pub use elect::Leadership;
+use error::WithContext;
+pub use error::{Context, Error, ErrorKind, Op};
+pub use foreign::OnDelete;
+use header::Header;
+pub use index::IndexReport;
+pub use layered::{Layer, Layered, MemoryLayer, ObjectLayer, WritePolicy};
+pub use lease::Lease;
+use maintenance::Maintenance;
+pub use maintenance::MaintenanceHandle;
+use manifest::Manifest;
+pub use meta::Version;
+pub use multi::MultiBucket;
+pub use offload::ObjectStore;
+pub use options::{BucketOptions, FsdbOptions};
+use pack::Pack;
+pub use pack::PackMerger;
+pub use page::{Cursor, Page};
+pub use poly::PolyBucket;
+#[cfg(feature = "async")]
+pub use pool::{Blocking, IoPool, PoolStats, Priority};
+pub use query::Query;
+pub use read_only::ReadBucket;
+pub use retry::RetryPolicy;
+pub use roots::{MultiRoot, SpreadBucket};
+pub use set::PersistentSet;
+pub use sign::{Signer, Verifier};
+pub use snapshot::SnapshotIter;
+pub use space::{SpaceMonitor, WriteThrottle};
+use state::BucketState;
+pub use temp::TempFsdb;
+pub use tier::{TierMigrator, TieredBucket};
+pub use txn::{Savepoint, Txn};
+pub use upload::Upload;
+#[cfg(feature = "async")]
+pub use watch::{ChangeEvent, ChangeKind, ChangeOptions, Changes};
+
 pub struct Fsdb {
     dir: PathBuf,
+    options: FsdbOptions,
+    // from `FsdbOptions::change_log`, for the buckets it opens
+    change_log: Option<Arc<ChangeLog>>,
+    // background threads started from this Fsdb, for `close` to stop: see `add_task`
+    maintenance: Arc<Maintenance>,
+    // from `FsdbOptions::io_threads`, for the buckets it opens
+    #[cfg(feature = "async")]
+    io_pool: Option<Arc<IoPool>>,
 }
 
 pub struct Bucket<V> {
     dir: PathBuf,
     max_file_name: Option<usize>,
+    // values are stored as pretty-printed JSON rather than msgpack, see `Fsdb::json_bucket`
+    json: bool,
+    state: Arc<BucketState>,
+    cipher: Option<Arc<dyn Cipher>>,
+    previous_cipher: Option<Arc<dyn Cipher>>,
+    signer: Option<Arc<dyn Signer>>,
+    verifier: Option<Arc<dyn Verifier>>,
+    retry: Option<RetryPolicy>,
+    scan_rate: Option<u32>,
+    timeout: Option<std::time::Duration>,
+    xattrs: bool,
+    named_fields: bool,
+    prune_empty: bool,
+    actor: Option<Arc<str>>,
+    slow_op: Option<(std::time::Duration, Arc<slow::SlowOpHook>)>,
+    merge_op: Option<merge::AnyMergeFn>,
+    preallocate: bool,
+    disk_full_hook: Option<Arc<space::DiskFullHook>>,
+    #[cfg(feature = "testing")]
+    flaky: Option<testing::FlakyBackend>,
+    #[cfg(feature = "async")]
+    io_pool: Option<Arc<IoPool>>,
     _v: PhantomData<V>,
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum Error {
-    #[error("io error: {0}")]
-    Io(#[from] std::io::Error),
-    #[error("encode error: {0}")]
-    Encode(#[from] rmp_serde::encode::Error),
-    #[error("dncode error: {0}")]
-    Decode(#[from] rmp_serde::decode::Error),
+impl<V> Clone for Bucket<V> {
+    fn clone(&self) -> Self {
+        Self {
+            dir: self.dir.clone(),
+            max_file_name: self.max_file_name,
+            json: self.json,
+            state: self.state.clone(),
+            cipher: self.cipher.clone(),
+            previous_cipher: self.previous_cipher.clone(),
+            signer: self.signer.clone(),
+            verifier: self.verifier.clone(),
+            retry: self.retry,
+            scan_rate: self.scan_rate,
+            timeout: self.timeout,
+            xattrs: self.xattrs,
+            named_fields: self.named_fields,
+            prune_empty: self.prune_empty,
+            actor: self.actor.clone(),
+            slow_op: self.slow_op.clone(),
+            merge_op: self.merge_op.clone(),
+            preallocate: self.preallocate,
+            disk_full_hook: self.disk_full_hook.clone(),
+            #[cfg(feature = "testing")]
+            flaky: self.flaky.clone(),
+            #[cfg(feature = "async")]
+            io_pool: self.io_pool.clone(),
+            _v: PhantomData,
+        }
+    }
+}
+
+impl<V> Bucket<V> {
+    fn with_state(dir: PathBuf, state: Arc<BucketState>) -> Self {
+        Self {
+            dir,
+            max_file_name: None,
+            json: false,
+            state,
+            cipher: None,
+            previous_cipher: None,
+            signer: None,
+            verifier: None,
+            retry: None,
+            scan_rate: None,
+            timeout: None,
+            xattrs: false,
+            named_fields: false,
+            prune_empty: false,
+            actor: None,
+            slow_op: None,
+            merge_op: None,
+            preallocate: false,
+            disk_full_hook: None,
+            #[cfg(feature = "testing")]
+            flaky: None,
+            #[cfg(feature = "async")]
+            io_pool: None,
+            _v: PhantomData,
+        }
+    }
+    // faults are only injected in testing builds, see `testing::FlakyBackend`
+    #[cfg(not(feature = "testing"))]
+    fn inject_fault(&self, _dir: &Path, _name: &str, _write: Option<&[u8]>) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -33,66 +255,447 @@ type Result<T> = std::result::Result<T, Error>;
 impl Fsdb {
     /// Create a new Fsdb
     pub fn new(dir: &str) -> Result<Self> {
+        Self::with_options(dir, FsdbOptions::default())
+    }
+
+    /// Create a new Fsdb with non-default options
+    pub fn with_options(dir: &str, options: FsdbOptions) -> Result<Self> {
+        let ctx = || Context::new(Op::Open, dir, None);
+        if options.redact_paths {
+            error::redact_under(Path::new(dir));
+        }
         if !Path::new(dir).exists() {
-            fs::create_dir_all(dir)?;
+            fs::create_dir_all(dir).ctx(ctx)?;
         }
-        Ok(Self { dir: dir.into() })
+        txn::recover(Path::new(dir)).ctx(ctx)?;
+        let change_log = match &options.change_log {
+            Some(o) => Some(Arc::new(
+                ChangeLog::open(Path::new(dir), o.clone()).ctx(ctx)?,
+            )),
+            None => None,
+        };
+        #[cfg(feature = "async")]
+        let io_pool = options.io_threads.map(IoPool::new);
+        Ok(Self {
+            dir: dir.into(),
+            options,
+            change_log,
+            maintenance: Arc::default(),
+            #[cfg(feature = "async")]
+            io_pool,
+        })
+    }
+
+    /// Use network filesystem (NFS, SMB) mode for buckets opened from now on, because
+    /// fsdb's usual assumptions about a local disk don't hold there. In this mode:
+    ///
+    /// - manifest updates take an `O_EXCL` lock file, rather than relying on appends
+    ///   from separate processes not overwriting each other
+    /// - values are flushed to the file server before being renamed into place, and
+    ///   reads, listings and writes are retried on transient errors (with the default
+    ///   `RetryPolicy`, unless a bucket sets its own)
+    /// - the cached manifest is reread only when the file's length or modification
+    ///   time changes
+    ///
+    /// Every handle to a bucket, including ones opened earlier, switches over once
+    /// it's opened in this mode. Processes sharing the directory should all use it.
+    pub fn set_network_fs(&mut self, on: bool) {
+        self.options.network_fs = on;
+    }
+
+    /// Leave bucket paths and keys out of the `Display` output of errors from
+    /// this database (and so out of logs), for when they hold something
+    /// sensitive, like a user name, tenant id or email address. `Context`
+    /// still has them, for code handling the error. Errors from other
+    /// databases in the process are left alone, though this one's stay
+    /// redacted for as long as the process runs
+    pub fn enable_redaction(&mut self) {
+        error::redact_under(&self.dir);
+        self.options.redact_paths = true;
+    }
+
+    /// Start a transaction, which can write to any buckets in this database
+    pub fn transaction(&self) -> Result<Txn> {
+        Txn::begin(&self.dir)
     }
 
-    // Create new bucket
+    /// Create new bucket, or open an existing one.
+    ///
+    /// The value type is recorded the first time a bucket is opened, and opening
+    /// it again as a different type returns `Error::TypeMismatch`. Settings
+    /// recorded by `bucket_with` apply to the handle
     pub fn bucket<V: Serialize + DeserializeOwned>(&self, p: &str) -> Result<Bucket<V>> {
+        self.open_bucket(p, None)
+    }
+
+    /// Create a bucket with `options`, which are recorded with it and apply to
+    /// every handle later opened with `bucket`. Opening an existing bucket this
+    /// way checks its recorded settings match, returning `Error::ConfigMismatch`
+    /// if not, so processes can't use one bucket with incompatible settings
+    pub fn bucket_with<V: Serialize + DeserializeOwned>(
+        &self,
+        p: &str,
+        options: BucketOptions,
+    ) -> Result<Bucket<V>> {
+        self.open_bucket(p, Some(&options))
+    }
+
+    fn open_bucket<V: Serialize + DeserializeOwned>(
+        &self,
+        p: &str,
+        options: Option<&BucketOptions>,
+    ) -> Result<Bucket<V>> {
+        let type_name = std::any::type_name::<V>();
+        let (dir, state, config) = self.open_bucket_dir_with(p, type_name, options)?;
+        let mut bucket = Bucket::with_state(dir, state);
+        bucket.max_file_name = config.max_file_name;
+        bucket.named_fields = config.named_fields;
+        #[cfg(feature = "async")]
+        {
+            bucket.io_pool = self.io_pool.clone();
+        }
+        Ok(bucket)
+    }
+
+    // create (if needed) a bucket directory and check it holds `type_name` values
+    fn open_bucket_dir(&self, p: &str, type_name: &str) -> Result<(PathBuf, Arc<BucketState>)> {
+        let (dir, state, _) = self.open_bucket_dir_with(p, type_name, None)?;
+        Ok((dir, state))
+    }
+
+    // `open_bucket_dir`, recording `options` on the first open and checking
+    // them on later ones. Returns the bucket's config
+    fn open_bucket_dir_with(
+        &self,
+        p: &str,
+        type_name: &str,
+        options: Option<&BucketOptions>,
+    ) -> Result<(PathBuf, Arc<BucketState>, BucketConfig)> {
+        let target =
+            alias::target(&self.dir, p).ctx(|| Context::new(Op::Bucket, &self.dir, Some(p)))?;
         let mut dir = self.dir.clone();
-        dir.push::<PathBuf>(p.into());
+        dir.push::<PathBuf>(target.as_deref().unwrap_or(p).into());
+        let ctx = || Context::new(Op::Bucket, &dir, None);
         if !Path::new(&dir).exists() {
-            fs::create_dir(dir.clone())?;
+            fs::create_dir(dir.clone()).ctx(ctx)?;
         }
-        Ok(Bucket {
-            dir: dir.into(),
-            max_file_name: None,
-            _v: PhantomData,
-        })
+        let mut config = BucketConfig::load(&dir).ctx(ctx)?.unwrap_or_default();
+        if config.type_name.is_none() {
+            // another process may be opening it for the first time too
+            config = BucketConfig::update(&dir, |config| {
+                if config.type_name.is_none() {
+                    config.type_name = Some(type_name.to_owned());
+                    if let Some(options) = options {
+                        config.apply(options);
+                    }
+                }
+                Ok(config.clone())
+            })
+            .ctx(ctx)?;
+        }
+        match &config.type_name {
+            Some(expected) if expected != type_name => {
+                return Err(Error::TypeMismatch {
+                    ctx: ctx(),
+                    expected: expected.clone(),
+                    found: type_name.to_owned(),
+                });
+            }
+            _ => {
+                if let Some(options) = options {
+                    config
+                        .check(options)
+                        .map_err(|reason| Error::ConfigMismatch { ctx: ctx(), reason })?;
+                }
+            }
+        }
+        let state = self.bucket_state(&dir)?;
+        if config.hashed_keys {
+            state.set_hashed_keys();
+        }
+        state.set_default_ttl(config.default_ttl);
+        state.set_sealed(config.sealed);
+        Ok((dir, state, config))
+    }
+
+    // the shared state of a bucket directory, set up for this Fsdb's options
+    fn bucket_state(&self, dir: &Path) -> Result<Arc<BucketState>> {
+        let ctx = || Context::new(Op::Bucket, dir, None);
+        let state = BucketState::get(dir);
+        if self.options.network_fs {
+            state.set_network_fs();
+        }
+        if let Some(throttle) = self.options.write_throttle {
+            state.set_write_throttle(throttle);
+        }
+        if self.options.durability != Durability::None {
+            state.set_durability(self.options.durability);
+        }
+        if let Some(log) = &self.change_log {
+            state.set_change_log(log.clone());
+        }
+        {
+            let mut manifest = state.manifest();
+            if manifest.is_none() && Manifest::exists(dir) {
+                let m = Manifest::load(dir, state.network_fs()).ctx(ctx)?;
+                *manifest = Some(m);
+            }
+        }
+        {
+            let mut pack = state.pack();
+            if pack.is_none() && Pack::exists(dir) {
+                *pack = Some(Pack::open(dir).ctx(ctx)?);
+            }
+        }
+        {
+            let mut access = state.access();
+            if access.is_none() && AccessLog::exists(dir) {
+                *access = Some(AccessLog::open(dir).ctx(ctx)?);
+            }
+        }
+        Ok(state)
     }
 }
 
 // store things at top level of a bucket
 impl<V: Serialize + DeserializeOwned> Bucket<V> {
-    /// Set a max file name length for this bucket
-    pub fn set_max_file_name(&mut self, x: usize) {
-        self.max_file_name = Some(x);
+    /// Set a max file name length. It's recorded with the bucket, for every
+    /// handle opened from then on (see `reconfigure`), so it can only be
+    /// changed while the bucket is empty
+    pub fn set_max_file_name(&mut self, x: usize) -> Result<()> {
+        let options = BucketOptions {
+            max_file_name: Some(x),
+            named_fields: self.named_fields,
+        };
+        self.reconfigure(options).map(|_| ())
+    }
+    /// Keep a manifest of this bucket's keys (in a `.manifest` file), so that `exists`,
+    /// `list` and `len` don't need to read the directory. Once enabled, the manifest
+    /// is loaded whenever the bucket is opened and kept up to date by every write.
+    pub fn enable_manifest(&self) -> Result<()> {
+        let mut manifest = self.state.manifest();
+        if manifest.is_some() {
+            return Ok(());
+        }
+        if self.state.pack().is_some() {
+            return Err(Error::Io {
+                ctx: Context::new(Op::Open, &self.dir, None),
+                source: std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "a packed bucket can't have a manifest",
+                ),
+            });
+        }
+        let keys = self.fs_list(&self.dir)?.into_iter().collect();
+        let m = Manifest::create(&self.dir, keys, self.state.network_fs())
+            .ctx(|| Context::new(Op::Open, &self.dir, None))?;
+        *manifest = Some(m);
+        Ok(())
     }
     /// Check if a key exists
     pub fn exists(&self, key: &str) -> bool {
+        if let Some(p) = self.state.pack().as_ref() {
+            if p.contains(&self.maxify(key)) {
+                return true;
+            }
+        }
+        if let Some(m) = self.state.manifest().as_mut() {
+            if let Ok(keys) = m.keys() {
+                return keys.contains(&self.maxify(key));
+            }
+        }
         let mut path = self.dir.clone();
         path.push(self.maxify(key));
         path.exists()
     }
     /// Create a key
     pub fn put(&self, key: &str, value: V) -> Result<()> {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(key));
-        self.fs_put(path, value)
+        self.put_ref(key, &value)
+    }
+    /// Create a key from a borrowed value, for one the caller still needs.
+    /// Serializing never needs ownership, so this saves a clone over `put`
+    pub fn put_ref(&self, key: &str, value: &V) -> Result<()> {
+        self.fs_put(&self.dir, key, value)
+    }
+    /// Store a value that's already encoded, as `rmp_serde::to_vec` would (or
+    /// `to_vec_named`, with `set_named_fields`, or as JSON, in a json bucket),
+    /// without serializing it again. It's checked to be a msgpack (or JSON)
+    /// value, but not that it decodes as `V`
+    pub fn put_serialized(&self, key: &str, bytes: &[u8]) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.dir, Some(key));
+        self.decode_plain::<serde::de::IgnoredAny>(bytes, ctx)?;
+        match self.stored_bare() {
+            true => self.fs_put_bytes(&self.dir, key, bytes)?,
+            false => {
+                let payload = match &self.cipher {
+                    Some(c) => c.encrypt(bytes),
+                    None => bytes.to_vec(),
+                };
+                self.fs_put_bytes(&self.dir, key, &self.seal_value(Some(key), payload))?
+            }
+        }
+        if self.state.subscribers.active() {
+            let notice = Notice::Put(self.maxify(key), Arc::new(bytes.to_vec()));
+            self.publish(notice);
+        }
+        Ok(())
     }
     /// Get a key
     pub fn get(&self, key: &str) -> Result<V> {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(key));
-        self.fs_get(path)
+        self.fs_get(&self.dir, key)
+    }
+    /// Start (or resume) writing a large value in chunks. See `Upload`
+    pub fn put_resumable(&self, key: &str) -> Result<Upload> {
+        Upload::open(self, key)
+    }
+    /// Read up to `len` raw bytes of a key's stored file, starting at `offset`,
+    /// without decoding, decrypting or verifying it. Returns fewer bytes if the file ends first.
+    /// A packed value is read whole from its segment and sliced
+    pub fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let ctx = || Context::new(Op::Get, &self.dir, Some(key));
+        let name = self.maxify(key);
+        if let Some(bytes) = self.state.packed(&name).ctx(ctx)? {
+            let start = usize::try_from(offset)
+                .unwrap_or(usize::MAX)
+                .min(bytes.len());
+            let end = start.saturating_add(usize::try_from(len).unwrap_or(usize::MAX));
+            return Ok(bytes[start..end.min(bytes.len())].to_vec());
+        }
+        let mut f = fs::File::open(self.dir.join(name)).ctx(ctx)?;
+        f.seek(SeekFrom::Start(offset)).ctx(ctx)?;
+        let mut buf = Vec::new();
+        f.take(len).read_to_end(&mut buf).ctx(ctx)?;
+        Ok(buf)
+    }
+    /// Get a key, or `V::default()` if it doesn't exist
+    pub fn get_or_default(&self, key: &str) -> Result<V>
+    where
+        V: Default,
+    {
+        Ok(self.fs_get_opt(&self.dir, key)?.unwrap_or_default())
     }
     /// Delete a file
     pub fn remove(&self, key: &str) -> Result<()> {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(key));
-        self.fs_remove(path)
+        self.fs_remove(&self.dir, key)
     }
-    /// List keys in this bucket (or sub-buckets in this bucket)
+    /// List keys in this bucket (or sub-buckets in this bucket).
+    /// Names starting with `.` are reserved for fsdb's own files and never listed
     pub fn list(&self) -> Result<Vec<String>> {
-        let path = self.dir.clone();
-        self.fs_list(path)
+        let listed = match self.state.manifest().as_mut() {
+            Some(m) => {
+                let keys = m.keys().ctx(|| Context::new(Op::List, &self.dir, None))?;
+                keys.iter().cloned().collect()
+            }
+            None => self.fs_list(&self.dir)?,
+        };
+        self.keys_of(&self.dir, listed)
+    }
+    /// List keys like `list`, but as a consistent snapshot: writes to this bucket
+    /// from other threads in this process wait until the listing is done, so a
+    /// key is never skipped or returned twice. Writes from other processes are not blocked.
+    pub fn list_stable(&self) -> Result<Vec<String>> {
+        let _guard = self.state.exclusive_guard();
+        self.list()
+    }
+    /// Iterate over every key and value as they were when this was called.
+    /// The bucket's files are hard-linked into a hidden snapshot directory (or copied,
+    /// where hard links aren't supported), so writes made while iterating aren't seen.
+    /// The snapshot is deleted when the iterator is dropped.
+    pub fn snapshot_iter(&self) -> Result<SnapshotIter<V>> {
+        SnapshotIter::new(self)
+    }
+    /// Number of keys (and sub-buckets) in this bucket
+    pub fn len(&self) -> Result<usize> {
+        if let Some(m) = self.state.manifest().as_mut() {
+            let keys = m.keys().ctx(|| Context::new(Op::List, &self.dir, None))?;
+            return Ok(keys.len());
+        }
+        Ok(self.fs_list(&self.dir)?.len())
+    }
+    /// Check if this bucket has no keys
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
     }
     /// Clear all keys in this bucket
     pub fn clear(&self) -> Result<()> {
-        let path = self.dir.clone();
-        self.fs_clear(path)
+        self.fs_clear(&self.dir)
+    }
+    /// Keep only the keys for which `f` returns true, deleting the rest. Returns the number deleted
+    pub fn retain<F: FnMut(&str, &V) -> bool>(&self, mut f: F) -> Result<usize> {
+        let mut removed = 0;
+        let mut pacer = self.pacer();
+        for name in self.fs_keys(&self.dir)? {
+            pacer.tick();
+            let Some(key) = self.key_of(&self.dir, &name)? else {
+                continue;
+            };
+            let v = self.fs_get(&self.dir, &name)?;
+            if !f(&key, &v) {
+                self.fs_remove(&self.dir, &name)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+    /// Delete every key for which `f` returns true, without decoding values. Returns the number deleted
+    pub fn remove_where<F: FnMut(&str) -> bool>(&self, mut f: F) -> Result<usize> {
+        let mut removed = 0;
+        let mut pacer = self.pacer();
+        for name in self.fs_keys(&self.dir)? {
+            pacer.tick();
+            let Some(key) = self.key_of(&self.dir, &name)? else {
+                continue;
+            };
+            if f(&key) {
+                self.fs_remove(&self.dir, &name)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+// optimistic concurrency via version stamps
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Get a key along with its current version.
+    ///
+    /// The first call turns on version tracking for this bucket: from then on
+    /// every write to a key changes its version.
+    pub fn get_versioned(&self, key: &str) -> Result<(V, Version)> {
+        let ctx = || Context::new(Op::Get, &self.dir, Some(key));
+        let path = self.dir.join(self.maxify(key));
+        let _lock = self.state.key_lock(&path);
+        let mdir = meta::meta_dir(&self.dir);
+        if !mdir.is_dir() {
+            fs::create_dir_all(&mdir).ctx(ctx)?;
+        }
+        let value = self.fs_get(&self.dir, key)?;
+        Ok((value, self.current_version(&self.dir, key)?))
+    }
+    /// Write a key only if its version is still `expected` (use `Version::default()`
+    /// to require that the key doesn't exist yet). Returns the new version, or
+    /// `Error::VersionMismatch` if someone else wrote the key in the meantime.
+    ///
+    /// Conflicts are detected between handles in this process; writers in other
+    /// processes can still race between the check and the write.
+    pub fn put_versioned(&self, key: &str, value: V, expected: Version) -> Result<Version> {
+        self.put_versioned_ref(key, &value, expected)
+    }
+    /// `put_versioned`, from a borrowed value
+    pub fn put_versioned_ref(&self, key: &str, value: &V, expected: Version) -> Result<Version> {
+        let path = self.dir.join(self.maxify(key));
+        let _lock = self.state.key_lock(&path);
+        let actual = self.current_version(&self.dir, key)?;
+        if actual != expected {
+            return Err(Error::VersionMismatch {
+                ctx: Context::new(Op::Put, &self.dir, Some(key)),
+                expected,
+                actual,
+            });
+        }
+        let version = actual.next();
+        self.fs_put_locked(&self.dir, key, value, Some(version))?;
+        Ok(version)
     }
 }
 
@@ -100,80 +703,495 @@ impl<V: Serialize + DeserializeOwned> Bucket<V> {
 impl<V: Serialize + DeserializeOwned> Bucket<V> {
     /// Check if a key exists within sub-bucket
     pub fn exists_within(&self, key: &str, sub: &str) -> bool {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(sub));
+        let mut path = self.sub_dir(sub);
         path.push(self.maxify(key));
         path.exists()
     }
     /// Create a key in a sub-bucket
     pub fn put_within(&self, key: &str, value: V, sub: &str) -> Result<()> {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(sub));
-        if !Path::new(&path).exists() {
-            fs::create_dir(path.clone())?;
+        self.put_within_ref(key, &value, sub)
+    }
+    /// Create a key in a sub-bucket, from a borrowed value
+    pub fn put_within_ref(&self, key: &str, value: &V, sub: &str) -> Result<()> {
+        with_buffer(|buf| {
+            self.encode_into(key, value, buf, || {
+                Context::new(Op::Put, self.sub_dir(sub), Some(key))
+            })?;
+            loop {
+                let dir = self.create_sub_dir(sub, key)?;
+                match self.fs_put_bytes(&dir, key, buf) {
+                    // the sub-bucket was pruned (see `set_prune_empty_sub_buckets`) just now
+                    Err(Error::Io { source, .. })
+                        if source.kind() == std::io::ErrorKind::NotFound => {}
+                    res => return res,
+                }
+            }
+        })
+    }
+    // the sub-bucket's directory, created (and added to the manifest) if it's missing
+    fn create_sub_dir(&self, sub: &str, key: &str) -> Result<PathBuf> {
+        let dir = self.sub_dir(sub);
+        if !Path::new(&dir).exists() {
+            let ctx = || Context::new(Op::Put, &dir, Some(key));
+            let _guard = self.state.write_guard();
+            match fs::create_dir(dir.clone()) {
+                // created by another writer in the meantime
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+                res => res.ctx(ctx)?,
+            }
+            if let Some(m) = self.state.manifest().as_mut() {
+                m.insert(&self.maxify(sub)).ctx(ctx)?;
+            }
         }
-        path.push(self.maxify(key));
-        self.fs_put(path, value)
+        Ok(dir)
     }
-    /// Get a key in a sub-bucket
+    /// Get a key in a sub-bucket. Returns `Error::NoSuchBucket` if the sub-bucket doesn't exist
     pub fn get_within(&self, key: &str, sub: &str) -> Result<V> {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(sub));
-        path.push(self.maxify(key));
-        self.fs_get(path)
+        let dir = self.existing_sub_dir(Op::Get, sub, Some(key))?;
+        self.fs_get(&dir, key)
     }
-    /// Delete a file in a sub-bucket
+    /// Delete a file in a sub-bucket. Returns `Error::NoSuchBucket` if the sub-bucket doesn't exist
     pub fn remove_within(&self, key: &str, sub: &str) -> Result<()> {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(sub));
-        path.push(self.maxify(key));
-        self.fs_remove(path)
+        let dir = self.existing_sub_dir(Op::Remove, sub, Some(key))?;
+        self.fs_remove(&dir, key)?;
+        if self.prune_empty {
+            self.prune_sub_dir(sub)?;
+        }
+        Ok(())
     }
-    /// List keys in this bucket's sub-bucket
+    /// List keys in this bucket's sub-bucket (empty if the sub-bucket doesn't exist)
     pub fn list_within(&self, sub: &str) -> Result<Vec<String>> {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(sub));
-        self.fs_list(path)
+        let dir = self.sub_dir(sub);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        self.keys_of(&dir, self.fs_list(&dir)?)
     }
-    /// Clear all keys in this sub-bucket
+    /// Clear all keys in this sub-bucket (a no-op if the sub-bucket doesn't exist)
     pub fn clear_within(&self, sub: &str) -> Result<()> {
-        let mut path = self.dir.clone();
-        path.push(self.maxify(sub));
-        self.fs_clear(path)
+        let dir = self.sub_dir(sub);
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        let ctx = || Context::new(Op::Clear, &dir, None);
+        let _guard = self.state.write_guard();
+        fs::remove_dir_all(&dir).ctx(ctx)?;
+        if let Some(m) = self.state.manifest().as_mut() {
+            m.remove(&self.maxify(sub)).ctx(ctx)?;
+        }
+        Ok(())
     }
 }
 
 // internal implementations
 impl<V: Serialize + DeserializeOwned> Bucket<V> {
-    fn fs_put(&self, path: PathBuf, value: V) -> Result<()> {
-        let mut f = fs::File::create(path.clone())?;
-        encode::write(&mut f, &value)?;
+    fn fs_put(&self, dir: &Path, key: &str, value: &V) -> Result<()> {
+        with_buffer(|buf| {
+            self.encode_into(key, value, buf, || Context::new(Op::Put, dir, Some(key)))?;
+            self.fs_put_bytes(dir, key, buf)
+        })?;
+        if dir == self.dir {
+            self.publish_put(key, value);
+        }
         Ok(())
     }
-    fn fs_get(&self, path: PathBuf) -> Result<V> {
-        let f = fs::File::open(path)?;
-        Ok(decode::from_read(f)?)
+    // store already encoded bytes under a key
+    fn fs_put_bytes(&self, dir: &Path, key: &str, bytes: &[u8]) -> Result<()> {
+        self.fs_put_bytes_with(dir, key, bytes, None)
+    }
+    // fs_put_bytes, also setting the file's extended attributes to `xattr` (encoded
+    // `Metadata`), if given. If the disk is full, the disk full hook gets a chance to
+    // free space (outside the key lock) before one more try
+    fn fs_put_bytes_with(
+        &self,
+        dir: &Path,
+        key: &str,
+        bytes: &[u8],
+        xattr: Option<&[u8]>,
+    ) -> Result<()> {
+        let _slow = self.slow_guard(Op::Put, dir, Some(key));
+        self.throttle(dir, key)?;
+        match self.fs_put_bytes_once(dir, key, bytes, xattr) {
+            Err(Error::DiskFull { .. }) if self.reclaim_space(bytes.len() as u64) => {
+                self.fs_put_bytes_once(dir, key, bytes, xattr)?
+            }
+            res => res?,
+        }
+        self.make_durable(dir)
+            .ctx(|| Context::new(Op::Put, dir, Some(key)))
     }
-    fn fs_remove(&self, path: PathBuf) -> Result<()> {
-        Ok(std::fs::remove_file(path)?)
+    fn fs_put_bytes_once(
+        &self,
+        dir: &Path,
+        key: &str,
+        bytes: &[u8],
+        xattr: Option<&[u8]>,
+    ) -> Result<()> {
+        let name = self.maxify(key);
+        let _lock = self.state.key_lock(&dir.join(&name));
+        let version = if meta::tracking(dir) {
+            Some(self.current_version(dir, key)?.next())
+        } else {
+            None
+        };
+        self.put_bytes_locked(dir, key, bytes, xattr, version)
     }
-    fn fs_list(&self, path: PathBuf) -> Result<Vec<String>> {
-        let paths = fs::read_dir(path)?;
+    // write a value (and stamp its version, if given) while holding its key lock
+    fn fs_put_locked(
+        &self,
+        dir: &Path,
+        key: &str,
+        value: &V,
+        version: Option<Version>,
+    ) -> Result<()> {
+        let _slow = self.slow_guard(Op::Put, dir, Some(key));
+        with_buffer(|buf| {
+            self.encode_into(key, value, buf, || Context::new(Op::Put, dir, Some(key)))?;
+            self.put_bytes_locked(dir, key, buf, None, version)
+        })?;
+        self.make_durable(dir)
+            .ctx(|| Context::new(Op::Put, dir, Some(key)))?;
+        if dir == self.dir {
+            self.publish_put(key, value);
+        }
+        Ok(())
+    }
+    // the value (and its extended attributes) goes to a temp file first, so readers
+    // never see a partial write
+    fn put_bytes_locked(
+        &self,
+        dir: &Path,
+        key: &str,
+        bytes: &[u8],
+        xattr: Option<&[u8]>,
+        version: Option<Version>,
+    ) -> Result<()> {
+        let ctx = || Context::new(Op::Put, dir, Some(key));
+        let name = self.maxify(key);
+        self.state.writable().ctx(ctx)?;
+        self.inject_fault(dir, &name, Some(bytes)).ctx(ctx)?;
+        let sync = self.state.network_fs() || self.state.durability() != Durability::None;
+        // values with metadata in extended attributes need a file to hold them
+        if dir == self.dir && xattr.is_none() {
+            let packed = self
+                .state
+                .install_packed(dir, &name, bytes, version, self.actor())
+                .ctx(ctx)?;
+            if packed {
+                return Ok(());
+            }
+        }
+        let prealloc = self.preallocate;
+        let tmp = match self.timeout {
+            None => self.retrying(|| write_temp(dir, &name, bytes, sync, prealloc)),
+            // the watchdog thread needs its own copy
+            Some(_) => {
+                let (dir, name, bytes) = (dir.to_owned(), name.clone(), bytes.to_vec());
+                self.timed(move || write_temp(&dir, &name, &bytes, sync, prealloc))
+            }
+        }
+        .ctx(ctx)?;
+        let tmp = TempFile(Some(tmp));
+        if let Some(xattr) = xattr {
+            attrs::set_xattr(tmp.path(), xattr).ctx(ctx)?;
+        }
+        self.state
+            .install(
+                dir,
+                &name,
+                tmp.path(),
+                version,
+                dir == self.dir,
+                self.actor(),
+            )
+            .ctx(ctx)?;
+        tmp.keep();
+        Ok(())
+    }
+    fn fs_get(&self, dir: &Path, key: &str) -> Result<V> {
+        let _slow = self.slow_guard(Op::Get, dir, Some(key));
+        let ctx = || Context::new(Op::Get, dir, Some(key));
+        let name = self.maxify(key);
+        self.inject_fault(dir, &name, None).ctx(ctx)?;
+        let bytes = self.read_file(dir, &name).ctx(ctx)?;
+        let value = self.decode(&bytes, ctx)?;
+        self.record_read(dir, &name);
+        Ok(value)
+    }
+    // like fs_get, but a missing key is None instead of an error
+    fn fs_get_opt(&self, dir: &Path, key: &str) -> Result<Option<V>> {
+        let _slow = self.slow_guard(Op::Get, dir, Some(key));
+        let ctx = || Context::new(Op::Get, dir, Some(key));
+        let name = self.maxify(key);
+        self.inject_fault(dir, &name, None).ctx(ctx)?;
+        let bytes = match self.read_file(dir, &name) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        let value = self.decode(&bytes, ctx)?;
+        self.record_read(dir, &name);
+        Ok(Some(value))
+    }
+    fn fs_remove(&self, dir: &Path, key: &str) -> Result<()> {
+        let _slow = self.slow_guard(Op::Remove, dir, Some(key));
+        let name = self.maxify(key);
+        let ctx = || Context::new(Op::Remove, dir, Some(key));
+        self.state.writable().ctx(ctx)?;
+        self.inject_fault(dir, &name, None).ctx(ctx)?;
+        let _lock = self.state.key_lock(&dir.join(&name));
+        self.state
+            .uninstall(dir, &name, dir == self.dir, self.actor())
+            .ctx(ctx)?;
+        self.make_durable(dir).ctx(ctx)?;
+        if dir == self.dir {
+            self.publish(Notice::Remove(name));
+        }
+        Ok(())
+    }
+    fn fs_list(&self, dir: &Path) -> Result<Vec<String>> {
+        let _slow = self.slow_guard(Op::List, dir, None);
+        let paths = self
+            .read_dir(dir)
+            .ctx(|| Context::new(Op::List, dir, None))?;
         let mut r = Vec::new();
-        paths.for_each(|name| {
+        paths.into_iter().for_each(|name| {
             if let Ok(na) = name {
                 if let Ok(n) = na.file_name().into_string() {
-                    r.push(n);
+                    if !n.starts_with('.') {
+                        r.push(n);
+                    }
                 }
             }
         });
+        self.list_packed(dir, &mut r);
+        Ok(r)
+    }
+    // like fs_list, but only keys (files), not sub-buckets
+    fn fs_keys(&self, dir: &Path) -> Result<Vec<String>> {
+        let _slow = self.slow_guard(Op::List, dir, None);
+        let paths = self
+            .read_dir(dir)
+            .ctx(|| Context::new(Op::List, dir, None))?;
+        let mut r = Vec::new();
+        for entry in paths.into_iter().flatten() {
+            if !entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                continue;
+            }
+            if let Ok(n) = entry.file_name().into_string() {
+                if !n.starts_with('.') {
+                    r.push(n);
+                }
+            }
+        }
+        self.list_packed(dir, &mut r);
         Ok(r)
     }
-    fn fs_clear(&self, path: PathBuf) -> Result<()> {
-        Ok(fs::remove_dir_all(path)?)
+    // every entry in a directory, read in one go so it can be timed out
+    fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<std::io::Result<fs::DirEntry>>> {
+        let dir = dir.to_owned();
+        self.timed(move || Ok(fs::read_dir(&dir)?.collect()))
+    }
+    // remove every key and sub-bucket, keeping the bucket directory and fsdb's own files
+    fn fs_clear(&self, dir: &Path) -> Result<()> {
+        let _slow = self.slow_guard(Op::Clear, dir, None);
+        let _guard = self.state.write_guard();
+        self.clear_locked(dir)?;
+        if dir == self.dir {
+            self.publish(Notice::Clear);
+        }
+        Ok(())
+    }
+    // fs_clear, for callers already holding the bucket lock
+    fn clear_locked(&self, dir: &Path) -> Result<()> {
+        let ctx = || Context::new(Op::Clear, dir, None);
+        self.state.writable().ctx(ctx)?;
+        if dir == self.dir {
+            if let Some(p) = self.state.pack().as_mut() {
+                p.clear().ctx(ctx)?;
+            }
+        }
+        for name in self.fs_list(dir)? {
+            let path = dir.join(name);
+            if path.is_dir() {
+                fs::remove_dir_all(path).ctx(ctx)?;
+            } else {
+                fs::remove_file(path).ctx(ctx)?;
+            }
+        }
+        let mdir = meta::meta_dir(dir);
+        if mdir.is_dir() {
+            // keep the directory itself, so version tracking stays on
+            for entry in fs::read_dir(&mdir).ctx(ctx)?.flatten() {
+                fs::remove_file(entry.path()).ctx(ctx)?;
+            }
+        }
+        if let Some(m) = self.state.manifest().as_mut() {
+            m.clear().ctx(ctx)?;
+        }
+        if let Some(log) = self.state.change_log() {
+            log.record(dir, None, Op::Clear, None, None, self.actor())
+                .ctx(ctx)?;
+        }
+        if dir == self.dir {
+            tags::clear(dir).ctx(ctx)?;
+            if let Some(access) = self.state.access().as_mut() {
+                access.clear().ctx(ctx)?;
+            }
+            if let Some(cache) = self.state.cache().as_mut() {
+                cache.clear();
+            }
+            self.state.clear_indexes();
+            self.state.clear_views().ctx(ctx)?;
+            self.state.bump_seq(dir).ctx(ctx)?;
+            generation::bump(dir).ctx(ctx)?;
+        }
+        Ok(())
+    }
+    // a missing key is Version::default()
+    fn current_version(&self, dir: &Path, key: &str) -> Result<Version> {
+        self.state
+            .current_version(dir, &self.maxify(key), dir == self.dir)
+            .ctx(|| Context::new(Op::Get, dir, Some(key)))
+    }
+    // encode into `buf`, straight from the msgpack serializer when there's no
+    // cipher or header
+    fn encode_into(
+        &self,
+        key: &str,
+        value: &V,
+        buf: &mut Vec<u8>,
+        ctx: impl Fn() -> Context,
+    ) -> Result<()> {
+        if !self.stored_bare() || self.json {
+            buf.extend_from_slice(&self.encode(key, value, ctx)?);
+            return Ok(());
+        }
+        match self.named_fields {
+            true => encode::write_named(buf, value),
+            false => encode::write(buf, value),
+        }
+        .ctx(ctx)
+    }
+    // whether values are stored exactly as encoded: not encrypted, and with no header
+    fn stored_bare(&self) -> bool {
+        self.cipher.is_none()
+            && self.signer.is_none()
+            && !self.state.hashed_keys()
+            && self.state.default_ttl().is_none()
+    }
+    // value -> msgpack -> encrypted (if there's a cipher) -> signed (if there's a signer)
+    fn encode(&self, key: &str, value: &V, ctx: impl Fn() -> Context) -> Result<Vec<u8>> {
+        Ok(self.seal_value(Some(key), self.payload(value, ctx)?))
+    }
+    // value -> msgpack -> encrypted (if there's a cipher), ready to be sealed
+    fn payload(&self, value: &V, ctx: impl Fn() -> Context) -> Result<Vec<u8>> {
+        let bytes = match (self.json, self.named_fields) {
+            (true, _) => json::to_vec(value).ctx(&ctx)?,
+            (false, true) => encode::to_vec_named(value).ctx(&ctx)?,
+            (false, false) => encode::to_vec(value).ctx(&ctx)?,
+        };
+        Ok(match &self.cipher {
+            Some(c) => c.encrypt(&bytes),
+            None => bytes,
+        })
+    }
+    fn decode(&self, bytes: &[u8], ctx: impl Fn() -> Context) -> Result<V> {
+        self.decode_as(bytes, ctx)
+    }
+    // decode stored bytes as some type other than V
+    fn decode_as<W: DeserializeOwned>(&self, bytes: &[u8], ctx: impl Fn() -> Context) -> Result<W> {
+        let plain = self.plain(bytes, &ctx)?;
+        self.decode_plain(&plain, ctx)
+    }
+    // stored bytes -> msgpack, checking the signature and decrypting
+    fn plain<'a>(&self, bytes: &'a [u8], ctx: impl Fn() -> Context) -> Result<Cow<'a, [u8]>> {
+        let payload = self.unseal_value(bytes, &ctx)?;
+        let Some(cipher) = &self.cipher else {
+            return Ok(Cow::Borrowed(payload));
+        };
+        let plain = cipher
+            .decrypt(payload)
+            .or_else(|| self.previous_cipher.as_ref()?.decrypt(payload))
+            .ok_or_else(|| Error::Decrypt { ctx: ctx() })?;
+        Ok(Cow::Owned(plain))
+    }
+    // msgpack (or JSON, in a json bucket) -> value
+    fn decode_plain<W: DeserializeOwned>(
+        &self,
+        plain: &[u8],
+        ctx: impl Fn() -> Context,
+    ) -> Result<W> {
+        match self.json {
+            true => json::from_slice(plain).ctx(ctx),
+            false => decode::from_slice(plain).ctx(ctx),
+        }
+    }
+    // add the header (with a signature, if signing, and the key, if it's hashed)
+    // in front of a payload
+    fn seal_value(&self, key: Option<&str>, payload: Vec<u8>) -> Vec<u8> {
+        self.seal_with(key, payload, None)
+    }
+    // seal_value, with metadata stored in the header
+    fn seal_with(&self, key: Option<&str>, payload: Vec<u8>, attrs: Option<Metadata>) -> Vec<u8> {
+        let expires_at = self.state.default_ttl().map(|ttl| SystemTime::now() + ttl);
+        self.seal_expiring(key, payload, attrs, expires_at)
+    }
+    // seal_with, expiring at `expires_at` rather than after the default ttl
+    fn seal_expiring(
+        &self,
+        key: Option<&str>,
+        payload: Vec<u8>,
+        attrs: Option<Metadata>,
+        expires_at: Option<SystemTime>,
+    ) -> Vec<u8> {
+        let header = Header {
+            signature: self.signer.as_ref().map(|s| s.sign(&payload)),
+            attrs,
+            key: key.filter(|_| self.state.hashed_keys()).map(str::to_owned),
+            expires_at,
+            ..Default::default()
+        };
+        header::wrap(&header, payload)
+    }
+    // strip the header from stored bytes, checking the signature if verifying
+    fn unseal_value<'a>(&self, bytes: &'a [u8], ctx: impl Fn() -> Context) -> Result<&'a [u8]> {
+        let (header, payload) = header::split(bytes)
+            .ok_or_else(|| decode::Error::Syntax("corrupt value header".into()))
+            .ctx(&ctx)?;
+        if header.expired() {
+            let expired = std::io::Error::new(std::io::ErrorKind::NotFound, "expired");
+            return Err(expired).ctx(ctx);
+        }
+        if let Some(verifier) = self.verifying() {
+            let valid = match &header.signature {
+                Some(sig) => verifier.verify(payload, sig),
+                None => false,
+            };
+            if !valid {
+                return Err(Error::SignatureInvalid { ctx: ctx() });
+            }
+        }
+        Ok(payload)
+    }
+    fn sub_dir(&self, sub: &str) -> PathBuf {
+        let mut dir = self.dir.clone();
+        dir.push(self.maxify(sub));
+        dir
+    }
+    fn existing_sub_dir(&self, op: Op, sub: &str, key: Option<&str>) -> Result<PathBuf> {
+        let dir = self.sub_dir(sub);
+        if !dir.is_dir() {
+            return Err(Error::NoSuchBucket {
+                ctx: Context::new(op, dir, key),
+            });
+        }
+        Ok(dir)
     }
     fn maxify(&self, name: &str) -> String {
-        if let Some(max) = self.max_file_name {
+        if self.state.hashed_keys() {
+            hashed::hashed_name(name)
+        } else if let Some(max) = self.max_file_name {
             let mut s = name.to_string();
             s.truncate(max);
             s
@@ -183,9 +1201,81 @@ impl<V: Serialize + DeserializeOwned> Bucket<V> {
     }
 }
 
+// write bytes to a hidden temp file next to `dir/name`, ready to be renamed into place.
+// With `sync`, the data is flushed to disk (or the file server) before the rename.
+// With `prealloc`, space is reserved up front, so a full disk fails before writing anything
+fn write_temp(
+    dir: &Path,
+    name: &str,
+    bytes: &[u8],
+    sync: bool,
+    prealloc: bool,
+) -> std::io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let tmp = dir.join(format!(".{}.{}-{}.tmp", name, std::process::id(), n));
+    let mut f = fs::File::create(&tmp)?;
+    let reserved = match prealloc {
+        true => space::preallocate(&f, bytes.len() as u64),
+        false => Ok(()),
+    };
+    let written = reserved
+        .and_then(|_| f.write_all(bytes))
+        .and_then(|_| match sync {
+            true => f.sync_all(),
+            false => Ok(()),
+        });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(tmp)
+}
+
+thread_local! {
+    // reused by each put on this thread, see `with_buffer`
+    static BUFFER: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+// larger buffers aren't kept, so one big value doesn't hold on to its memory
+const MAX_BUFFER: usize = 1 << 20;
+
+// run `f` with this thread's encoding buffer, emptied. A nested call (from a
+// hook that puts, say) gets a new one
+fn with_buffer<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    let mut buf = BUFFER.take();
+    buf.clear();
+    let res = f(&mut buf);
+    if buf.capacity() <= MAX_BUFFER {
+        BUFFER.set(buf);
+    }
+    res
+}
+
+// a temp file that's removed when dropped (including by a panic), unless kept
+struct TempFile(Option<PathBuf>);
+
+impl TempFile {
+    fn path(&self) -> &Path {
+        self.0.as_deref().expect("temp file already kept")
+    }
+    // it's been renamed into place
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::Fsdb;
+    use crate::{BucketOptions, Error, Fsdb, Op, Version};
     use serde::{Deserialize, Serialize};
 
     #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -195,9 +1285,9 @@ mod tests {
 
     #[test]
     fn test_db() {
-        let db = Fsdb::new("testdb").expect("fail Fsdb::new");
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
         let mut b = db.bucket("hi").expect("fail bucket");
-        b.set_max_file_name(8);
+        b.set_max_file_name(8).expect("fail set_max_file_name");
         let t1 = Thing { n: 1 };
         b.put("keythatisverylong", t1.clone())
             .expect("failed to save");
@@ -206,11 +1296,15 @@ mod tests {
         assert_eq!(t1, t2);
         let list = b.list().expect("fail list");
         assert_eq!(list, vec!["keythati".to_string()]);
+        // recorded for other handles
+        let other = db.bucket::<Thing>("hi").expect("fail bucket");
+        assert_eq!(other.get("keythatisverylong").expect("fail get"), t1);
+        assert!(b.set_max_file_name(4).is_err());
     }
 
     #[test]
     fn test_within() {
-        let db = Fsdb::new("testdb2").expect("fail Fsdb::new");
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
         let b = db.bucket("hi").expect("fail bucket");
         let t1 = Thing { n: 1 };
         b.put_within("key", t1.clone(), "sub1")
@@ -221,4 +1315,207 @@ mod tests {
         let list = b.list_within("sub1").expect("fail list");
         assert_eq!(list, vec!["key".to_string()]);
     }
+
+    #[test]
+    fn test_put_ref() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket("hi").expect("fail bucket");
+        let t1 = Thing { n: 1 };
+        b.put_ref("key", &t1).expect("failed to save");
+        b.put_within_ref("key", &t1, "sub1")
+            .expect("failed to save");
+        assert_eq!(b.get("key").expect("fail to load"), t1);
+        assert_eq!(b.get_within("key", "sub1").expect("fail to load"), t1);
+
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put_ref(&b, "other", &t1).expect("fail stage");
+        tx.commit().expect("fail commit");
+        assert_eq!(b.get("other").expect("fail to load"), t1);
+    }
+
+    #[test]
+    fn test_error_context() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("hi").expect("fail bucket");
+        let err = b.get("missing").expect_err("should not exist");
+        assert!(matches!(err, Error::Io { .. }));
+        let ctx = err.context();
+        assert_eq!(ctx.op, Op::Get);
+        assert_eq!(ctx.key.as_deref(), Some("missing"));
+        assert!(err.to_string().contains("\"missing\""));
+    }
+
+    #[test]
+    fn test_missing_sub_bucket() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("hi").expect("fail bucket");
+        let list = b.list_within("nope").expect("fail list");
+        assert!(list.is_empty());
+        b.clear_within("nope").expect("fail clear");
+        let err = b.get_within("key", "nope").expect_err("should not exist");
+        assert!(matches!(err, Error::NoSuchBucket { .. }));
+    }
+
+    #[test]
+    fn test_get_or_default() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u64>("counters").expect("fail bucket");
+        b.remove("hits").ok();
+        assert_eq!(b.get_or_default("hits").expect("fail get"), 0);
+        b.put("hits", 3).expect("failed to save");
+        assert_eq!(b.get_or_default("hits").expect("fail get"), 3);
+    }
+
+    #[test]
+    fn test_retain() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("hi").expect("fail bucket");
+        for n in 0..6 {
+            b.put(&format!("key{}", n), Thing { n })
+                .expect("failed to save");
+        }
+        b.put_within("key", Thing { n: 9 }, "sub")
+            .expect("failed to save");
+        let removed = b.retain(|_, t| t.n % 2 == 0).expect("fail retain");
+        assert_eq!(removed, 3);
+        let removed = b.remove_where(|k| k == "key0").expect("fail remove_where");
+        assert_eq!(removed, 1);
+        let mut list = b.list().expect("fail list");
+        list.sort();
+        assert_eq!(list, vec!["key2", "key4", "sub"]);
+    }
+
+    #[test]
+    fn test_list_stable() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("hi").expect("fail bucket");
+        let writer = {
+            let b = db.bucket::<Thing>("hi").expect("fail bucket");
+            std::thread::spawn(move || {
+                for n in 0..50 {
+                    b.put(&format!("key{}", n), Thing { n })
+                        .expect("failed to save");
+                }
+            })
+        };
+        for _ in 0..10 {
+            let mut list = b.list_stable().expect("fail list");
+            let len = list.len();
+            list.sort();
+            list.dedup();
+            assert_eq!(list.len(), len);
+        }
+        writer.join().expect("writer panicked");
+        assert_eq!(b.list_stable().expect("fail list").len(), 50);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("hi").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.put("one", Thing { n: 1 }).expect("failed to save");
+        b.enable_manifest().expect("fail manifest");
+        b.put("two", Thing { n: 2 }).expect("failed to save");
+        b.put_within("three", Thing { n: 3 }, "sub")
+            .expect("failed to save");
+        b.remove("one").expect("fail remove");
+        // a file written behind fsdb's back is invisible to the manifest
+        std::fs::write(db.path().join("hi/stray"), b"").expect("fail write");
+        assert!(!b.exists("stray"));
+        assert!(b.exists("two"));
+        assert_eq!(b.len().expect("fail len"), 2);
+        let reopened = Fsdb::new(db.path().to_str().expect("non-utf8 temp dir"))
+            .and_then(|db| db.bucket::<Thing>("hi"))
+            .expect("fail bucket");
+        assert_eq!(reopened.list().expect("fail list"), vec!["sub", "two"]);
+    }
+
+    #[test]
+    fn test_network_fs() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        db.set_network_fs(true);
+        let b = db.bucket::<Thing>("hi").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.enable_manifest().expect("fail manifest");
+        b.put("one", Thing { n: 1 }).expect("failed to save");
+        b.put("two", Thing { n: 2 }).expect("failed to save");
+        b.remove("one").expect("fail remove");
+        assert_eq!(b.list().expect("fail list"), vec!["two"]);
+        assert_eq!(b.get("two").expect("fail get"), Thing { n: 2 });
+        // lock files are gone once each update is done
+        let leftover = std::fs::read_dir(db.path().join("hi"))
+            .expect("fail read_dir")
+            .flatten()
+            .any(|e| e.file_name().to_string_lossy().ends_with(".guard"));
+        assert!(!leftover);
+    }
+
+    #[test]
+    fn test_versioned() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("hi").expect("fail bucket");
+        b.clear().expect("fail clear");
+        let v1 = b
+            .put_versioned("key", Thing { n: 1 }, Version::default())
+            .expect("failed to create");
+        let (t, v) = b.get_versioned("key").expect("fail to load");
+        assert_eq!((t.n, v), (1, v1));
+        b.put("key", Thing { n: 2 }).expect("failed to save");
+        let err = b
+            .put_versioned("key", Thing { n: 3 }, v1)
+            .expect_err("stale write");
+        assert!(matches!(err, Error::VersionMismatch { .. }));
+        let (_, v2) = b.get_versioned("key").expect("fail to load");
+        assert!(v2 > v1);
+        b.put_versioned("key", Thing { n: 3 }, v2)
+            .expect("failed to save");
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        db.bucket::<Thing>("things").expect("fail bucket");
+        db.bucket::<Thing>("things").expect("fail bucket");
+        let err = db.bucket::<String>("things").err().expect("wrong type");
+        assert!(matches!(err, Error::TypeMismatch { .. }));
+    }
+
+    #[test]
+    fn test_bucket_with() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let options = BucketOptions {
+            max_file_name: Some(4),
+            ..Default::default()
+        };
+        let b = db
+            .bucket_with::<Thing>("things", options.clone())
+            .expect("fail bucket_with");
+        b.put("truncated", Thing { n: 1 }).expect("failed to save");
+        assert!(db.path().join("things/trun").is_file());
+        // recorded for later handles
+        let again = db.bucket::<Thing>("things").expect("fail bucket");
+        assert_eq!(again.get("truncated").expect("fail get"), Thing { n: 1 });
+        db.bucket_with::<Thing>("things", options)
+            .expect("fail bucket_with");
+        let err = db
+            .bucket_with::<Thing>("things", BucketOptions::default())
+            .err()
+            .expect("opened with other settings");
+        assert!(matches!(err, Error::ConfigMismatch { .. }));
+    }
+
+    #[test]
+    fn test_put_serialized() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Thing>("things").expect("fail bucket");
+        let bytes = rmp_serde::to_vec(&Thing { n: 7 }).expect("fail encode");
+        b.put_serialized("key", &bytes).expect("failed to save");
+        assert_eq!(b.get("key").expect("fail get"), Thing { n: 7 });
+        // stored as is
+        let stored = std::fs::read(db.path().join("things/key")).expect("fail read");
+        assert_eq!(stored, bytes);
+        assert!(b.put_serialized("bad", &[0xc1]).is_err());
+        assert!(!b.exists("bad"));
+    }
 }
@@ -0,0 +1,41 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// holders only keep the lock for a few file operations
+const STALE: Duration = Duration::from_secs(10);
+
+/// Run `f` while holding the lock file `<path>.guard`. It's created with `O_EXCL`,
+/// so only one process can hold it at a time, which (unlike `flock`) also holds on
+/// network filesystems. A lock file left behind by a crashed process is taken over
+/// once it's older than `STALE`
+pub(crate) fn with_lock_file<T>(path: &Path, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    let mut guard = path.as_os_str().to_owned();
+    guard.push(".guard");
+    let guard = PathBuf::from(guard);
+    loop {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&guard)
+        {
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let stale = fs::metadata(&guard)
+                    .and_then(|m| m.modified())
+                    .map(|t| t.elapsed().unwrap_or_default() > STALE)
+                    .unwrap_or(false);
+                if stale {
+                    let _ = fs::remove_file(&guard);
+                } else {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let res = f();
+    fs::remove_file(&guard)?;
+    res
+}
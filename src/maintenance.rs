@@ -0,0 +1,213 @@
+use crate::Fsdb;
+use std::cell::RefCell;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Control over the background threads started from an `Fsdb` (space
+/// monitors, pack mergers and elections), from `Fsdb::maintenance`, so the
+/// application hosting it decides when they run and when they stop
+#[derive(Clone)]
+pub struct MaintenanceHandle {
+    inner: Arc<Maintenance>,
+}
+
+/// Background threads of an `Fsdb`, and whether they're paused
+#[derive(Default)]
+pub(crate) struct Maintenance {
+    // stop channels, and channels whose sender the task drops when it's done
+    tasks: Mutex<Vec<(Sender<()>, Receiver<()>)>>,
+    passes: Mutex<Passes>,
+    idle: Condvar,
+}
+
+#[derive(Default)]
+struct Passes {
+    paused: bool,
+    running: usize,
+}
+
+thread_local! {
+    // the `Maintenance` of each pass running on this thread, so `pause` called
+    // from inside one doesn't wait for itself
+    static OWN_PASSES: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A periodic task's pass in progress, from `Maintenance::pass`
+pub(crate) struct Pass<'a> {
+    maintenance: &'a Maintenance,
+}
+
+impl Fsdb {
+    /// A handle to pause, resume, count and stop this database's background
+    /// threads. They also stop when it's closed
+    pub fn maintenance(&self) -> MaintenanceHandle {
+        MaintenanceHandle {
+            inner: self.maintenance.clone(),
+        }
+    }
+    // have `close` stop a background thread, by sending on its stop channel, and
+    // wait for it to drop the sending end of `finished`
+    pub(crate) fn add_task(&self, stop: Sender<()>, finished: Receiver<()>) {
+        let mut tasks = self.maintenance.tasks.lock().expect("task list poisoned");
+        tasks.push((stop, finished));
+    }
+}
+
+impl Maintenance {
+    // start a pass of a periodic task, or None if paused, to skip it
+    pub(crate) fn pass(&self) -> Option<Pass<'_>> {
+        let mut passes = self.passes.lock().expect("maintenance poisoned");
+        if passes.paused {
+            return None;
+        }
+        passes.running += 1;
+        OWN_PASSES.with(|own| own.borrow_mut().push(self.id()));
+        Some(Pass { maintenance: self })
+    }
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+    // how many passes running on this thread are this one's
+    fn own_passes(&self) -> usize {
+        OWN_PASSES.with(|own| own.borrow().iter().filter(|id| **id == self.id()).count())
+    }
+    // stop every background thread, waiting for each to finish
+    pub(crate) fn stop(&self) {
+        let tasks: Vec<_> = self
+            .tasks
+            .lock()
+            .expect("task list poisoned")
+            .drain(..)
+            .collect();
+        for (stop, finished) in tasks {
+            // a task that already stopped has dropped its receiver
+            let _ = stop.send(());
+            // returns once the task's thread drops its end
+            let _ = finished.recv();
+        }
+    }
+}
+
+impl Drop for Pass<'_> {
+    fn drop(&mut self) {
+        let mut passes = self
+            .maintenance
+            .passes
+            .lock()
+            .expect("maintenance poisoned");
+        passes.running -= 1;
+        OWN_PASSES.with(|own| {
+            let mut own = own.borrow_mut();
+            if let Some(i) = own.iter().rposition(|id| *id == self.maintenance.id()) {
+                own.remove(i);
+            }
+        });
+        // a `pause` from inside a pass waits for the others only
+        self.maintenance.idle.notify_all();
+    }
+}
+
+impl MaintenanceHandle {
+    /// Skip the periodic work of space monitors and pack mergers until
+    /// `resume`, returning once any pass already running has finished.
+    /// Elections keep renewing their leases. Called from inside a pass (from
+    /// a callback it runs), it doesn't wait for that pass, which can't finish
+    /// until it returns
+    pub fn pause(&self) {
+        let own = self.inner.own_passes();
+        let mut passes = self.inner.passes.lock().expect("maintenance poisoned");
+        passes.paused = true;
+        while passes.running > own {
+            passes = self.inner.idle.wait(passes).expect("maintenance poisoned");
+        }
+    }
+    /// Let paused tasks run again, from their next pass
+    pub fn resume(&self) {
+        self.inner
+            .passes
+            .lock()
+            .expect("maintenance poisoned")
+            .paused = false;
+    }
+    /// Whether background tasks are paused
+    pub fn is_paused(&self) -> bool {
+        self.inner
+            .passes
+            .lock()
+            .expect("maintenance poisoned")
+            .paused
+    }
+    /// Number of background threads still running
+    pub fn running(&self) -> usize {
+        let mut tasks = self.inner.tasks.lock().expect("task list poisoned");
+        // one that's finished (its handle dropped, say) has dropped its sender
+        tasks.retain(|(_, finished)| finished.try_recv() != Err(TryRecvError::Disconnected));
+        tasks.len()
+    }
+    /// Stop every background thread, and wait for them to finish. Their
+    /// handles (`SpaceMonitor` and so on) stay valid, but do nothing more;
+    /// a `Leadership` steps down
+    pub fn stop(&self) {
+        self.inner.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_maintenance() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let handle = db.maintenance();
+        handle.pause();
+        assert!(handle.is_paused());
+        let lows = Arc::new(AtomicUsize::new(0));
+        let lows2 = lows.clone();
+        let _monitor = db
+            .monitor_space(u64::MAX, Duration::from_millis(5), move |_| {
+                lows2.fetch_add(1, Ordering::Relaxed);
+            })
+            .expect("fail monitor_space");
+        let _merger = db.merge_packed_every(Duration::from_millis(5), 0.5);
+        assert_eq!(handle.running(), 2);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(lows.load(Ordering::Relaxed), 0);
+
+        handle.resume();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lows.load(Ordering::Relaxed) == 0 {
+            assert!(Instant::now() < deadline, "monitor didn't resume");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        handle.stop();
+        assert_eq!(handle.running(), 0);
+    }
+
+    #[test]
+    fn test_pause_from_pass() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let handle = db.maintenance();
+        let pass = db.maintenance.pass().expect("fail pass");
+        // would wait for itself
+        handle.pause();
+        assert!(handle.is_paused());
+        assert!(db.maintenance.pass().is_none());
+        drop(pass);
+        handle.resume();
+
+        // but not for another thread's
+        let pass = db.maintenance.pass().expect("fail pass");
+        let paused = std::thread::spawn(move || {
+            handle.pause();
+            Instant::now()
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        let released = Instant::now();
+        drop(pass);
+        assert!(paused.join().expect("fail join") >= released);
+    }
+}
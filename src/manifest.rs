@@ -0,0 +1,192 @@
+use crate::lockfile::with_lock_file;
+use rmp_serde::{decode, encode};
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File name of a bucket's manifest
+pub(crate) const MANIFEST: &str = ".manifest";
+
+/// An append-only index of the keys in a bucket, so exists/list/len don't need readdir.
+///
+/// The file starts with a generation stamp, followed by one `(inserted, key)` record per
+/// change. It is rewritten (with a new generation) when removals make it much larger than
+/// the key set. Other processes appending to the same file are picked up by `sync`.
+///
+/// In network filesystem mode, appends and rewrites happen under a lock file (since
+/// appends from separate NFS clients can overwrite each other), and `sync` only
+/// rereads the file when its length or modification time has changed.
+pub(crate) struct Manifest {
+    path: PathBuf,
+    keys: BTreeSet<String>,
+    generation: u64,
+    // how far into the file we've replayed
+    offset: u64,
+    records: usize,
+    pub network: bool,
+    // length and modification time of the file when last synced, in network mode
+    seen: Option<(u64, SystemTime)>,
+}
+
+impl Manifest {
+    pub(crate) fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST).is_file()
+    }
+
+    /// Write a fresh manifest containing `keys`
+    pub(crate) fn create(dir: &Path, keys: BTreeSet<String>, network: bool) -> io::Result<Self> {
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        let path = dir.join(MANIFEST);
+        let tmp = dir.join(format!("{}.tmp", MANIFEST));
+        let mut buf = encode::to_vec(&generation).map_err(invalid)?;
+        for k in keys.iter() {
+            encode::write(&mut buf, &(true, k)).map_err(invalid)?;
+        }
+        let write = || {
+            fs::write(&tmp, &buf)?;
+            fs::rename(&tmp, &path)
+        };
+        if network {
+            with_lock_file(&path, write)?;
+        } else {
+            write()?;
+        }
+        Ok(Self {
+            path,
+            records: keys.len(),
+            keys,
+            generation,
+            offset: buf.len() as u64,
+            network,
+            seen: None,
+        })
+    }
+
+    /// Rewrite the manifest from the keys and sub-buckets actually in its directory,
+    /// for when it may have missed an update
+    pub(crate) fn rebuild(&mut self) -> io::Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let mut keys = BTreeSet::new();
+        for entry in fs::read_dir(dir)?.flatten() {
+            if let Ok(name) = entry.file_name().into_string() {
+                if !name.starts_with('.') {
+                    keys.insert(name);
+                }
+            }
+        }
+        *self = Self::create(dir, keys, self.network)?;
+        Ok(())
+    }
+
+    pub(crate) fn load(dir: &Path, network: bool) -> io::Result<Self> {
+        let mut m = Self {
+            path: dir.join(MANIFEST),
+            keys: BTreeSet::new(),
+            generation: 0,
+            offset: 0,
+            records: 0,
+            network,
+            seen: None,
+        };
+        m.sync()?;
+        if m.records > 2 * m.keys.len() + 1024 {
+            return Self::create(dir, m.keys, network);
+        }
+        Ok(m)
+    }
+
+    /// Replay any records appended since the last sync (by this or another process)
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        if self.network {
+            let meta = fs::metadata(&self.path)?;
+            let stamp = (meta.len(), meta.modified()?);
+            if self.seen == Some(stamp) {
+                return Ok(());
+            }
+            self.seen = Some(stamp);
+        }
+        let mut f = BufReader::new(File::open(&self.path)?);
+        let generation: u64 = decode::from_read(&mut f).map_err(invalid)?;
+        if generation != self.generation {
+            self.generation = generation;
+            self.keys.clear();
+            self.records = 0;
+            self.offset = f.stream_position()?;
+        }
+        f.seek(SeekFrom::Start(self.offset))?;
+        // stops at the end of the file, or at a partial record that is still being written
+        while let Ok((inserted, key)) = decode::from_read::<_, (bool, String)>(&mut f) {
+            self.offset = f.stream_position()?;
+            self.records += 1;
+            if inserted {
+                self.keys.insert(key);
+            } else {
+                self.keys.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) -> io::Result<()> {
+        self.locked(|m| m.insert_locked(key))
+    }
+    fn insert_locked(&mut self, key: &str) -> io::Result<()> {
+        self.sync()?;
+        if self.keys.contains(key) {
+            return Ok(());
+        }
+        self.append(true, key)?;
+        self.keys.insert(key.to_owned());
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> io::Result<()> {
+        self.locked(|m| m.remove_locked(key))
+    }
+    fn remove_locked(&mut self, key: &str) -> io::Result<()> {
+        self.sync()?;
+        if !self.keys.contains(key) {
+            return Ok(());
+        }
+        self.append(false, key)?;
+        self.keys.remove(key);
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        *self = Self::create(dir, BTreeSet::new(), self.network)?;
+        Ok(())
+    }
+
+    pub(crate) fn keys(&mut self) -> io::Result<&BTreeSet<String>> {
+        self.sync()?;
+        Ok(&self.keys)
+    }
+
+    // run `f` under the manifest's lock file, in network mode
+    fn locked<T>(&mut self, f: impl FnOnce(&mut Self) -> io::Result<T>) -> io::Result<T> {
+        if !self.network {
+            return f(self);
+        }
+        let path = self.path.clone();
+        with_lock_file(&path, || f(self))
+    }
+
+    fn append(&self, inserted: bool, key: &str) -> io::Result<()> {
+        let buf = encode::to_vec(&(inserted, key)).map_err(invalid)?;
+        let mut f = OpenOptions::new().append(true).open(&self.path)?;
+        // the record is replayed (as a no-op) on the next sync, which keeps
+        // ordering with other processes' appends intact
+        f.write_all(&buf)
+    }
+}
+
+fn invalid<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
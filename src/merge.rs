@@ -0,0 +1,97 @@
+use crate::{meta, Bucket, Context, Error, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::any::Any;
+use std::io;
+use std::sync::Arc;
+
+/// Combines a key's current value (if it has one) with a delta, see `Bucket::set_merge_operator`
+pub(crate) type MergeFn<V, D> = dyn Fn(Option<V>, D) -> V + Send + Sync;
+
+// a merge operator, as Any so Bucket doesn't need a type parameter for its deltas
+pub(crate) type AnyMergeFn = Arc<dyn Any + Send + Sync>;
+
+// accumulator-style updates
+impl<V: Serialize + DeserializeOwned + 'static> Bucket<V> {
+    /// Set how `merge` applies deltas of type `D` to a key's value, such as
+    /// adding to a counter or appending to a list. It's called with the current
+    /// value (`None` if the key doesn't exist) and the delta, and returns the new
+    /// value. Replaces any operator set before, for any delta type
+    pub fn set_merge_operator<D: 'static>(
+        &mut self,
+        op: impl Fn(Option<V>, D) -> V + Send + Sync + 'static,
+    ) {
+        let op: Box<MergeFn<V, D>> = Box::new(op);
+        self.merge_op = Some(Arc::new(op));
+    }
+    /// Apply `delta` to a key with the merge operator, under the key's lock, so
+    /// concurrent merges through handles in this process all take effect. Writers
+    /// in other processes can still race with it, as with `put_versioned`.
+    ///
+    /// Fails if the operator wasn't set, or was set for another delta type
+    pub fn merge<D: 'static>(&self, key: &str, delta: D) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.dir, Some(key));
+        let Some(op) = self
+            .merge_op
+            .as_ref()
+            .and_then(|op| op.downcast_ref::<Box<MergeFn<V, D>>>())
+        else {
+            let source = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no merge operator for {}", std::any::type_name::<D>()),
+            );
+            return Err(Error::Io { ctx: ctx(), source });
+        };
+        let _lock = self.state.key_lock(&self.dir.join(self.maxify(key)));
+        let old = self.fs_get_opt(&self.dir, key)?;
+        let value = op(old, delta);
+        let version = if meta::tracking(&self.dir) {
+            Some(self.current_version(&self.dir, key)?.next())
+        } else {
+            None
+        };
+        self.fs_put_locked(&self.dir, key, &value, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_merge_counter() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<u64>("counters").expect("fail bucket");
+        assert!(b.merge("hits", 1u64).is_err());
+        b.set_merge_operator(|old: Option<u64>, n: u64| old.unwrap_or(0) + n);
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let b = b.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        b.merge("hits", 1u64).expect("fail merge");
+                    }
+                })
+            })
+            .collect();
+        writers.into_iter().for_each(|w| w.join().unwrap());
+        assert_eq!(b.get("hits").expect("fail get"), 80);
+        // wrong delta type
+        assert!(b.merge("hits", "one").is_err());
+    }
+
+    #[test]
+    fn test_merge_set_union() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<BTreeSet<String>>("sets").expect("fail bucket");
+        b.set_merge_operator(|old: Option<BTreeSet<String>>, more: Vec<&str>| {
+            let mut set = old.unwrap_or_default();
+            set.extend(more.into_iter().map(String::from));
+            set
+        });
+        b.merge("tags", vec!["a", "b"]).expect("fail merge");
+        b.merge("tags", vec!["b", "c"]).expect("fail merge");
+        let set = b.get("tags").expect("fail get");
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+    }
+}
@@ -0,0 +1,89 @@
+use rmp_serde::{decode, encode};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory (inside a bucket or sub-bucket) holding per-key metadata files
+pub(crate) const META_DIR: &str = ".meta";
+
+/// A stamp that changes every time a key is written. `Version::default()` means "no value"
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Version(pub u64);
+
+impl Version {
+    /// Stamp for a key that exists but was written before versioning was turned on
+    pub(crate) const UNSTAMPED: Version = Version(1);
+
+    // strictly greater than self, and than any stamp handed out before now,
+    // so a key that is removed and re-created never reuses an old version
+    pub(crate) fn next(self) -> Version {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Version(now.max(self.0 + 1))
+    }
+}
+
+/// Per-key metadata, stored next to the value
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct KeyMeta {
+    pub version: Version,
+}
+
+pub(crate) fn meta_dir(dir: &Path) -> PathBuf {
+    dir.join(META_DIR)
+}
+
+/// Version tracking is on for a directory once its metadata directory exists
+pub(crate) fn tracking(dir: &Path) -> bool {
+    meta_dir(dir).is_dir()
+}
+
+/// The version of a key (by file name); a missing key is `Version::default()`
+pub(crate) fn current_version(dir: &Path, name: &str) -> io::Result<Version> {
+    Ok(match read(dir, name)? {
+        Some(m) => m.version,
+        None if dir.join(name).exists() => Version::UNSTAMPED,
+        None => Version::default(),
+    })
+}
+
+pub(crate) fn stamp(dir: &Path, name: &str, version: Version) -> io::Result<()> {
+    let mut m = read(dir, name)?.unwrap_or_default();
+    m.version = version;
+    write(dir, name, &m)
+}
+
+pub(crate) fn read(dir: &Path, name: &str) -> io::Result<Option<KeyMeta>> {
+    match fs::read(meta_dir(dir).join(name)) {
+        Ok(buf) => decode::from_slice(&buf)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn write(dir: &Path, name: &str, meta: &KeyMeta) -> io::Result<()> {
+    let mdir = meta_dir(dir);
+    if !mdir.is_dir() {
+        fs::create_dir_all(&mdir)?;
+    }
+    let buf = encode::to_vec(meta).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let tmp = mdir.join(format!(".{}.tmp", name));
+    fs::write(&tmp, buf)?;
+    fs::rename(tmp, mdir.join(name))
+}
+
+pub(crate) fn remove(dir: &Path, name: &str) -> io::Result<()> {
+    match fs::remove_file(meta_dir(dir).join(name)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,214 @@
+//! Copying data between fsdb and other key-value stores
+
+use crate::{Bucket, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Store every key and value from `iter` in `bucket`, returning the number stored.
+/// Works with any store that can be iterated, such as a rocksdb or redb table
+/// (decode its values into `V` first), or another fsdb bucket's `snapshot_iter`
+pub fn from_kv_iter<V, K, I>(iter: I, bucket: &Bucket<V>) -> Result<usize>
+where
+    V: Serialize + DeserializeOwned,
+    K: AsRef<str>,
+    I: IntoIterator<Item = (K, V)>,
+{
+    let mut count = 0;
+    for (key, value) in iter {
+        bucket.put(key.as_ref(), value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Copy a sled database into `fsdb`. `mapping` is called with the tree name, key
+/// and value of every entry in every tree, and returns the bucket, key and value
+/// to store it as, or `None` to skip it. Returns the number of values stored
+#[cfg(feature = "sled")]
+pub fn from_sled<V, F>(sled_db: &sled::Db, fsdb: &crate::Fsdb, mut mapping: F) -> Result<usize>
+where
+    V: Serialize + DeserializeOwned,
+    F: FnMut(&[u8], &[u8], &[u8]) -> Option<(String, String, V)>,
+{
+    use crate::error::WithContext;
+    use crate::{Context, Op};
+    use std::collections::HashMap;
+
+    let ctx = || Context::new(Op::Put, &fsdb.dir, None);
+    let mut buckets: HashMap<String, Bucket<V>> = HashMap::new();
+    let mut count = 0;
+    for name in sled_db.tree_names() {
+        let tree = sled_db
+            .open_tree(&name)
+            .map_err(std::io::Error::from)
+            .ctx(ctx)?;
+        for entry in tree.iter() {
+            let (k, v) = entry.map_err(std::io::Error::from).ctx(ctx)?;
+            let Some((bucket, key, value)) = mapping(&name, &k, &v) else {
+                continue;
+            };
+            if !buckets.contains_key(&bucket) {
+                buckets.insert(bucket.clone(), fsdb.bucket(&bucket)?);
+            }
+            buckets[&bucket].put(&key, value)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Write every key and value in `bucket` to the SQLite table `table` (created
+/// if needed) as `key TEXT PRIMARY KEY, value BLOB`, replacing rows with the same key.
+/// Values are stored msgpack-encoded, without fsdb's encryption or signature.
+/// Sub-buckets are not included. Returns the number of rows written
+#[cfg(feature = "rusqlite")]
+pub fn to_sqlite<V>(
+    bucket: &Bucket<V>,
+    conn: &mut rusqlite::Connection,
+    table: &str,
+) -> Result<usize>
+where
+    V: Serialize + DeserializeOwned,
+{
+    use crate::error::WithContext;
+    use crate::{Context, Op};
+
+    let ctx = || Context::new(Op::List, &bucket.dir, None);
+    let tx = conn.transaction().map_err(std::io::Error::other).ctx(ctx)?;
+    let table = quote_ident(table);
+    tx.execute(
+        &format!("CREATE TABLE IF NOT EXISTS {table} (key TEXT PRIMARY KEY, value BLOB)"),
+        [],
+    )
+    .map_err(std::io::Error::other)
+    .ctx(ctx)?;
+    let mut count = 0;
+    {
+        let mut insert = tx
+            .prepare(&format!(
+                "INSERT OR REPLACE INTO {table} (key, value) VALUES (?1, ?2)"
+            ))
+            .map_err(std::io::Error::other)
+            .ctx(ctx)?;
+        for key in bucket.fs_keys(&bucket.dir)? {
+            let ctx = || Context::new(Op::Get, &bucket.dir, Some(&key));
+            let value = bucket.get(&key)?;
+            let bytes = rmp_serde::encode::to_vec(&value).ctx(ctx)?;
+            insert
+                .execute(rusqlite::params![key, bytes])
+                .map_err(std::io::Error::other)
+                .ctx(ctx)?;
+            count += 1;
+        }
+    }
+    tx.commit().map_err(std::io::Error::other).ctx(ctx)?;
+    Ok(count)
+}
+
+/// Store every row of the SQLite table `table` (as written by `to_sqlite`, with
+/// msgpack-encoded values) in `bucket`. Returns the number of values stored
+#[cfg(feature = "rusqlite")]
+pub fn from_sqlite<V>(conn: &rusqlite::Connection, table: &str, bucket: &Bucket<V>) -> Result<usize>
+where
+    V: Serialize + DeserializeOwned,
+{
+    use crate::error::WithContext;
+    use crate::{Context, Op};
+
+    let ctx = || Context::new(Op::Put, &bucket.dir, None);
+    let mut select = conn
+        .prepare(&format!("SELECT key, value FROM {}", quote_ident(table)))
+        .map_err(std::io::Error::other)
+        .ctx(ctx)?;
+    let rows = select
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })
+        .map_err(std::io::Error::other)
+        .ctx(ctx)?;
+    let mut count = 0;
+    for row in rows {
+        let (key, bytes) = row.map_err(std::io::Error::other).ctx(ctx)?;
+        let value: V = rmp_serde::decode::from_slice(&bytes)
+            .ctx(|| Context::new(Op::Put, &bucket.dir, Some(&key)))?;
+        bucket.put(&key, value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// quote a table name for use in SQL
+#[cfg(feature = "rusqlite")]
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Fsdb;
+
+    #[test]
+    fn test_from_kv_iter() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("nums").expect("fail bucket");
+        b.clear().expect("fail clear");
+        let n = from_kv_iter(vec![("a", 1), ("b", 2)], &b).expect("fail import");
+        assert_eq!(n, 2);
+        assert_eq!(b.get("b").expect("fail get"), 2);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_from_sled() {
+        let sled_db = sled::Config::new().temporary(true).open().unwrap();
+        sled_db.insert("a", &1u32.to_be_bytes()).unwrap();
+        let users = sled_db.open_tree("users").unwrap();
+        users.insert("x", &2u32.to_be_bytes()).unwrap();
+        users.insert("skip", &3u32.to_be_bytes()).unwrap();
+
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        for name in ["__sled__default", "users"] {
+            db.bucket::<u32>(name)
+                .expect("fail bucket")
+                .clear()
+                .unwrap();
+        }
+        let n = from_sled(&sled_db, &db, |tree, k, v| {
+            let key = String::from_utf8(k.to_vec()).ok()?;
+            if key == "skip" {
+                return None;
+            }
+            let bucket = String::from_utf8(tree.to_vec()).ok()?;
+            Some((bucket, key, u32::from_be_bytes(v.try_into().ok()?)))
+        })
+        .expect("fail import");
+        assert_eq!(n, 2);
+        let users = db.bucket::<u32>("users").expect("fail bucket");
+        assert_eq!(users.get("x").expect("fail get"), 2);
+        assert!(!users.exists("skip"));
+        let default = db.bucket::<u32>("__sled__default").expect("fail bucket");
+        assert_eq!(default.get("a").expect("fail get"), 1);
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn test_sqlite_round_trip() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<String>("words").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.put("a", "one".into()).expect("failed to save");
+        b.put("b", "two".into()).expect("failed to save");
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert_eq!(
+            to_sqlite(&b, &mut conn, "my \"words\"").expect("fail export"),
+            2
+        );
+        b.clear().expect("fail clear");
+        assert_eq!(
+            from_sqlite(&conn, "my \"words\"", &b).expect("fail import"),
+            2
+        );
+        assert_eq!(b.get("b").expect("fail get"), "two");
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{Bucket, Context, Error, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A bucket holding any number of values per key, from `Fsdb::multi_bucket`,
+/// for one-to-many relationships like a user's sessions. Each key is a
+/// sub-directory with one file per value, named so that values come back in
+/// the order they were pushed. A key exists while it has at least one value
+pub struct MultiBucket<V> {
+    inner: Bucket<V>,
+}
+
+impl<V> Clone for MultiBucket<V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Fsdb {
+    /// Create or open a bucket with several values per key. See `MultiBucket`
+    pub fn multi_bucket<V: Serialize + DeserializeOwned>(&self, p: &str) -> Result<MultiBucket<V>> {
+        let (dir, state) = self.open_bucket_dir(p, std::any::type_name::<MultiBucket<V>>())?;
+        Ok(MultiBucket {
+            inner: Bucket::with_state(dir, state),
+        })
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> MultiBucket<V> {
+    /// Add a value to a key
+    pub fn push(&self, key: &str, value: V) -> Result<()> {
+        self.push_ref(key, &value)
+    }
+    /// Add a borrowed value to a key
+    pub fn push_ref(&self, key: &str, value: &V) -> Result<()> {
+        let id = next_id();
+        let encoded = self.inner.encode(&id, value, || {
+            Context::new(Op::Put, &self.inner.dir, Some(key))
+        })?;
+        loop {
+            let dir = self.inner.create_sub_dir(key, &id)?;
+            match self.inner.fs_put_bytes(&dir, &id, &encoded) {
+                // the key's last value was removed (and its directory with it) just now
+                Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => {}
+                res => return res,
+            }
+        }
+    }
+    /// Every value of a key, in the order they were pushed (empty if it has none)
+    pub fn get_all(&self, key: &str) -> Result<Vec<V>> {
+        Ok(self.entries(key)?.into_iter().map(|(_, v)| v).collect())
+    }
+    /// Number of values a key has
+    pub fn count(&self, key: &str) -> Result<usize> {
+        Ok(self.inner.list_within(key)?.len())
+    }
+    /// Keys with at least one value
+    pub fn keys(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+    /// Remove every value of a key equal to `value`. Returns the number removed
+    pub fn remove_value(&self, key: &str, value: &V) -> Result<usize>
+    where
+        V: PartialEq,
+    {
+        let mut removed = 0;
+        for (id, v) in self.entries(key)? {
+            if v != *value {
+                continue;
+            }
+            match self.inner.remove_within(&id, key) {
+                Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => (),
+                res => {
+                    res?;
+                    removed += 1;
+                }
+            }
+        }
+        if removed > 0 {
+            self.inner.prune_sub_dir(key)?;
+        }
+        Ok(removed)
+    }
+    /// Remove a key and all its values
+    pub fn remove_all(&self, key: &str) -> Result<()> {
+        self.inner.clear_within(key)
+    }
+    // (file name, value) of each of a key's values, oldest first
+    fn entries(&self, key: &str) -> Result<Vec<(String, V)>> {
+        let dir = self.inner.sub_dir(key);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut ids = self.inner.fs_keys(&dir)?;
+        ids.sort();
+        let mut entries = Vec::with_capacity(ids.len());
+        for id in ids {
+            // removed since listing
+            if let Some(v) = self.inner.fs_get_opt(&dir, &id)? {
+                entries.push((id, v));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// value file names sort in the order they were created: the time, then a
+// per-process counter for values pushed in the same nanosecond
+fn next_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    format!(
+        "{:020}-{:010}-{}",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        std::process::id()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+
+    #[test]
+    fn test_multi_bucket() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.multi_bucket::<String>("sessions").expect("fail bucket");
+        assert!(b.get_all("ann").expect("fail get_all").is_empty());
+        for s in ["s1", "s2", "s1", "s3"] {
+            b.push("ann", s.to_owned()).expect("fail push");
+        }
+        b.push("bob", "s9".to_owned()).expect("fail push");
+        assert_eq!(
+            b.get_all("ann").expect("fail get_all"),
+            vec!["s1", "s2", "s1", "s3"]
+        );
+        let mut keys = b.keys().expect("fail keys");
+        keys.sort();
+        assert_eq!(keys, vec!["ann", "bob"]);
+
+        assert_eq!(
+            b.remove_value("ann", &"s1".to_owned())
+                .expect("fail remove"),
+            2
+        );
+        assert_eq!(b.get_all("ann").expect("fail get_all"), vec!["s2", "s3"]);
+        assert_eq!(b.count("ann").expect("fail count"), 2);
+
+        // removing the last value removes the key
+        b.remove_value("bob", &"s9".to_owned())
+            .expect("fail remove");
+        assert_eq!(b.keys().expect("fail keys"), vec!["ann"]);
+        b.remove_all("ann").expect("fail remove_all");
+        assert!(b.keys().expect("fail keys").is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_push() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.multi_bucket::<u32>("multi").expect("fail bucket");
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let b = b.clone();
+                std::thread::spawn(move || b.push("k", i).expect("fail push"))
+            })
+            .collect();
+        writers.into_iter().for_each(|w| w.join().unwrap());
+        let mut all = b.get_all("k").expect("fail get_all");
+        all.sort();
+        assert_eq!(all, (0..8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::changelog::hash_file;
+use crate::error::WithContext;
+use crate::tier::ignore_missing;
+use crate::{Context, Op, Result, TieredBucket};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Directory (inside a tiered bucket's cold tier) holding a stub per offloaded
+/// value, with the name of the object it was stored as
+const OFFLOADED: &str = ".offloaded";
+
+/// Object storage (S3 or the like) that a `TieredBucket` can offload its
+/// coldest values to, see `TieredBucket::set_offload`. fsdb doesn't talk to
+/// any service itself: implement this over the client the application uses
+pub trait ObjectStore: Send + Sync {
+    /// Store an object, replacing any with the same name
+    fn put(&self, name: &str, bytes: &[u8]) -> io::Result<()>;
+    /// Fetch an object. A missing one is an `io::ErrorKind::NotFound` error
+    fn get(&self, name: &str) -> io::Result<Vec<u8>>;
+    /// Delete an object. Deleting a missing one isn't an error
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+#[derive(Clone)]
+pub(crate) struct Offload {
+    store: Arc<dyn ObjectStore>,
+    after: Duration,
+    rehydrate: bool,
+}
+
+impl<V: Serialize + DeserializeOwned> TieredBucket<V> {
+    /// Offload values that have sat in the cold tier for `after` to `store`,
+    /// leaving a stub behind, so `get` still finds them. With `rehydrate`, a
+    /// value fetched back is stored in the cold tier again. Offloading happens
+    /// on `offload`, or in the background with `Fsdb::migrate_cold_every`
+    pub fn set_offload(&mut self, store: Arc<dyn ObjectStore>, after: Duration, rehydrate: bool) {
+        self.offload = Some(Offload {
+            store,
+            after,
+            rehydrate,
+        });
+    }
+    /// Move every value of the cold tier that hasn't been written for the
+    /// `after` given to `set_offload` to the object store. Returns the number
+    /// moved, which is 0 without an object store
+    pub fn offload(&self) -> Result<usize> {
+        let Some(offload) = &self.offload else {
+            return Ok(0);
+        };
+        let dir = &self.cold.dir;
+        let ctx = || Context::new(Op::Copy, dir, None);
+        let cutoff = SystemTime::now() - offload.after;
+        let mut moved = 0;
+        let mut pacer = self.cold.pacer();
+        for name in self.cold.fs_keys(dir)? {
+            pacer.tick();
+            let path = dir.join(&name);
+            let written = match fs::metadata(&path) {
+                Ok(meta) => meta.modified().ctx(ctx)?,
+                // removed since listing
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).ctx(ctx),
+            };
+            if written > cutoff {
+                continue;
+            }
+            let Some(before) = hash_file(&path).ctx(ctx)? else {
+                continue;
+            };
+            let bytes = self.cold.read_file(dir, &name).ctx(ctx)?;
+            let object = format!("{}/{}", self.name, name);
+            offload.store.put(&object, &bytes).ctx(ctx)?;
+            let stub = self.stub(&name);
+            fs::create_dir_all(dir.join(OFFLOADED)).ctx(ctx)?;
+            crate::write_temp(&dir.join(OFFLOADED), &name, object.as_bytes(), false, false)
+                .and_then(|tmp| fs::rename(tmp, &stub))
+                .ctx(ctx)?;
+            // rewritten in the meantime, so the stub would hide the new value
+            if hash_file(&path).ctx(ctx)? != Some(before) {
+                fs::remove_file(&stub).ctx(ctx)?;
+                continue;
+            }
+            ignore_missing(self.cold.remove(&name))?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+    // fetch an offloaded value, or None if it isn't offloaded
+    pub(crate) fn get_offloaded(&self, key: &str) -> Result<Option<V>> {
+        let ctx = || Context::new(Op::Get, &self.cold.dir, Some(key));
+        let Some(offload) = &self.offload else {
+            return Ok(None);
+        };
+        let object = match fs::read_to_string(self.stub(key)) {
+            Ok(object) => object,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        let bytes = offload.store.get(&object).ctx(ctx)?;
+        let value = self.cold.decode(&bytes, ctx)?;
+        if offload.rehydrate {
+            self.cold.put(key, self.cold.decode(&bytes, ctx)?)?;
+            self.discard_offloaded(key)?;
+        }
+        Ok(Some(value))
+    }
+    // remove a key's stub and object, if it has been offloaded
+    pub(crate) fn discard_offloaded(&self, key: &str) -> Result<()> {
+        let ctx = || Context::new(Op::Remove, &self.cold.dir, Some(key));
+        let stub = self.stub(key);
+        let object = match fs::read_to_string(&stub) {
+            Ok(object) => object,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        if let Some(offload) = &self.offload {
+            offload.store.delete(&object).ctx(ctx)?;
+        }
+        match fs::remove_file(stub) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).ctx(ctx),
+            _ => Ok(()),
+        }
+    }
+    pub(crate) fn is_offloaded(&self, key: &str) -> bool {
+        self.stub(key).is_file()
+    }
+    pub(crate) fn list_offloaded(&self) -> Result<Vec<String>> {
+        let dir = self.cold.dir.join(OFFLOADED);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).ctx(|| Context::new(Op::List, &dir, None)),
+        };
+        Ok(entries
+            .flatten()
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|n| !n.starts_with('.'))
+            .collect())
+    }
+    fn stub(&self, key: &str) -> PathBuf {
+        self.cold.dir.join(OFFLOADED).join(self.cold.maxify(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ObjectStore;
+    use crate::Fsdb;
+    use std::collections::HashMap;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Memory(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl ObjectStore for Memory {
+        fn put(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(name.to_owned(), bytes.to_vec());
+            Ok(())
+        }
+        fn get(&self, name: &str) -> io::Result<Vec<u8>> {
+            let objects = self.0.lock().unwrap();
+            objects
+                .get(name)
+                .cloned()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+        fn delete(&self, name: &str) -> io::Result<()> {
+            self.0.lock().unwrap().remove(name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_offload() {
+        let hot = Fsdb::temp().expect("fail Fsdb::temp");
+        let cold = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = hot
+            .tiered_bucket::<u32>("logs", &cold, Duration::ZERO)
+            .expect("fail tiered_bucket");
+        let store = Arc::new(Memory::default());
+        b.set_offload(store.clone(), Duration::ZERO, false);
+        b.put("a", 1).expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        assert_eq!(b.migrate().expect("fail migrate"), 2);
+        assert_eq!(b.offload().expect("fail offload"), 2);
+        assert!(b.cold().list().expect("fail list").is_empty());
+        assert_eq!(store.0.lock().unwrap().len(), 2);
+
+        // still there through the same API
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        assert!(b.exists("b"));
+        assert_eq!(b.list().expect("fail list"), vec!["a", "b"]);
+
+        // a write replaces the offloaded copy
+        b.put("a", 3).expect("failed to save");
+        assert_eq!(b.get("a").expect("fail get"), 3);
+        assert!(!store.0.lock().unwrap().contains_key("logs/a"));
+
+        // fetching with rehydration brings it back to the cold tier
+        b.set_offload(store.clone(), Duration::ZERO, true);
+        assert_eq!(b.get("b").expect("fail get"), 2);
+        assert_eq!(b.cold().get("b").expect("fail get"), 2);
+        assert!(store.0.lock().unwrap().is_empty());
+
+        b.remove("b").expect("fail remove");
+        assert!(!b.exists("b"));
+    }
+}
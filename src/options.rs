@@ -0,0 +1,30 @@
+use crate::{ChangeLogOptions, Durability, WriteThrottle};
+
+/// Options for `Fsdb::with_options`. `Fsdb::new` uses the defaults
+#[derive(Debug, Clone, Default)]
+pub struct FsdbOptions {
+    /// Network filesystem mode, see `Fsdb::set_network_fs`
+    pub network_fs: bool,
+    /// Hold back writes when the filesystem is nearly full, see `WriteThrottle`
+    pub write_throttle: Option<WriteThrottle>,
+    /// Flush writes to disk before they return, see `Durability`
+    pub durability: Durability,
+    /// Log every write, see `Fsdb::enable_change_log`
+    pub change_log: Option<ChangeLogOptions>,
+    /// Leave paths and keys out of error messages, see `Fsdb::enable_redaction`
+    pub redact_paths: bool,
+    /// Run async operations on a pool of this many threads for this Fsdb,
+    /// rather than the pool shared by the process, see `IoPool`
+    #[cfg(feature = "async")]
+    pub io_threads: Option<usize>,
+}
+
+/// Settings recorded with a bucket when it's created with `Fsdb::bucket_with`,
+/// which every handle to it then uses
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BucketOptions {
+    /// Truncate file names to this many bytes, see `Bucket::set_max_file_name`
+    pub max_file_name: Option<usize>,
+    /// Store struct fields by name, see `Bucket::set_named_fields`
+    pub named_fields: bool,
+}
@@ -0,0 +1,989 @@
+use crate::attrs;
+use crate::changelog::{self, numbered};
+use crate::durable::Durability;
+use crate::error::WithContext;
+use crate::meta::{self, Version};
+use crate::state::BucketState;
+use crate::tags;
+use crate::{Bucket, Context, Error, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Directory (inside a bucket) holding its packed values
+pub(crate) const PACK: &str = ".pack";
+const SEGMENT: &str = ".seg";
+
+// a record's value length, for a removal
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Start a new segment once the newest reaches this size, unless set otherwise
+/// with `Bucket::set_pack_segment_size`
+const SEGMENT_SIZE: u64 = 4 << 20;
+
+/// Values larger than this (stored) are kept as files, unless set otherwise
+/// with `Bucket::set_pack_threshold`
+const THRESHOLD: usize = 64 << 10;
+
+/// Values stored many to a file, for buckets of lots of small values.
+///
+/// Each segment file is a sequence of records: the length of the rest of the
+/// record, the key's length and name, the value's length (or `TOMBSTONE`) and
+/// bytes, then an FNV-1a hash of all that. Writes and removals append to the
+/// newest segment, and a full one is sealed and a new one started. The index
+/// of where each key's latest value is comes from reading every segment when
+/// the bucket is opened; a torn record at the end (from a crash) is cut off.
+///
+/// Segments are numbered two apart, leaving a gap below the newest for `merge`
+/// to write the live values of the sealed ones into. The index is per
+/// process, so only one process should write a packed bucket
+pub(crate) struct Pack {
+    dir: PathBuf,
+    index: BTreeMap<String, Location>,
+    active: u64,
+    file: File,
+    len: u64,
+    // total length of the sealed segments
+    sealed: u64,
+    segment_size: u64,
+    threshold: usize,
+    // bumped by `clear`, so a merge running meanwhile is thrown away
+    generation: u64,
+    merging: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u32,
+}
+
+/// A merge of the sealed segments, from `Pack::prepare_merge`
+pub(crate) struct Merge {
+    dir: PathBuf,
+    generation: u64,
+    out: u64,
+    segments: Vec<(u64, PathBuf)>,
+    live: Vec<(String, Location)>,
+}
+
+impl Pack {
+    pub(crate) fn exists(bucket: &Path) -> bool {
+        bucket.join(PACK).is_dir()
+    }
+
+    /// Open (creating if need be) the packed values of a bucket
+    pub(crate) fn open(bucket: &Path) -> io::Result<Self> {
+        let dir = bucket.join(PACK);
+        fs::create_dir_all(&dir)?;
+        // left by a merge that didn't finish
+        for entry in fs::read_dir(&dir)?.flatten() {
+            if entry.file_name().to_string_lossy().ends_with(".tmp") {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        let mut index = BTreeMap::new();
+        let mut active = (0, 0);
+        let mut sealed = 0;
+        for (segment, path) in numbered(&dir, SEGMENT)? {
+            sealed += active.1;
+            let len = scan(&path, segment, &mut index)?;
+            active = (segment, len);
+        }
+        let (active, len) = active;
+        let path = segment_path(&dir, active);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // drop a torn record at the end
+        file.set_len(len)?;
+        Ok(Self {
+            dir,
+            index,
+            active,
+            file,
+            len,
+            sealed,
+            segment_size: SEGMENT_SIZE,
+            threshold: THRESHOLD,
+            generation: 0,
+            merging: false,
+        })
+    }
+
+    pub(crate) fn set_segment_size(&mut self, bytes: u64) {
+        self.segment_size = bytes;
+    }
+
+    pub(crate) fn set_threshold(&mut self, bytes: usize) {
+        self.threshold = bytes;
+    }
+
+    /// Whether a value of this (stored) size is packed, rather than kept as a file
+    pub(crate) fn fits(&self, len: usize) -> bool {
+        len <= self.threshold
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &String> {
+        self.index.keys()
+    }
+
+    /// The stored bytes of a key, or None if it isn't packed
+    pub(crate) fn get(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let Some(loc) = self.index.get(name) else {
+            return Ok(None);
+        };
+        let mut f = File::open(segment_path(&self.dir, loc.segment))?;
+        read_at(&mut f, loc).map(Some)
+    }
+
+    /// The segment holding a key's value, and where in it the value is
+    pub(crate) fn locate(&self, name: &str) -> Option<(PathBuf, u64, u32)> {
+        let loc = self.index.get(name)?;
+        Some((segment_path(&self.dir, loc.segment), loc.offset, loc.len))
+    }
+
+    /// Append a key's new value, flushing it to disk first with `sync`
+    pub(crate) fn put(&mut self, name: &str, bytes: &[u8], sync: bool) -> io::Result<()> {
+        if bytes.len() >= TOMBSTONE as usize {
+            let msg = "value too large to pack";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let offset = self.append(name, Some(bytes), sync)?;
+        let loc = Location {
+            segment: self.active,
+            offset,
+            len: bytes.len() as u32,
+        };
+        self.index.insert(name.to_owned(), loc);
+        self.rotate_if_full()
+    }
+
+    /// Record that a key was removed. Returns whether it was there
+    pub(crate) fn remove(&mut self, name: &str, sync: bool) -> io::Result<bool> {
+        if !self.index.contains_key(name) {
+            return Ok(false);
+        }
+        self.append(name, None, sync)?;
+        self.index.remove(name);
+        self.rotate_if_full()?;
+        Ok(true)
+    }
+
+    /// Remove every value, starting over with an empty segment
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        let next = self.active + 2;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, next))?;
+        for (segment, path) in numbered(&self.dir, SEGMENT)? {
+            if segment != next {
+                fs::remove_file(path)?;
+            }
+        }
+        self.index.clear();
+        self.active = next;
+        self.file = file;
+        self.len = 0;
+        self.sealed = 0;
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Bytes taken up by overwritten values and removals, and by every segment
+    pub(crate) fn garbage(&self) -> (u64, u64) {
+        let total = self.sealed + self.len;
+        let live: u64 = self
+            .index
+            .iter()
+            .map(|(name, loc)| record_len(name, loc.len))
+            .sum();
+        (total.saturating_sub(live), total)
+    }
+
+    /// Seal the newest segment and pick out the live values of every sealed
+    /// one, to be copied (without holding the pack) by `Merge::write`. None if
+    /// there's nothing to drop, or a merge is already running
+    pub(crate) fn prepare_merge(&mut self) -> io::Result<Option<Merge>> {
+        if self.merging || self.garbage().0 == 0 {
+            return Ok(None);
+        }
+        if self.len > 0 {
+            self.rotate()?;
+        }
+        let out = self.active - 1;
+        if segment_path(&self.dir, out).exists() {
+            // merged already, with nothing written since
+            return Ok(None);
+        }
+        let segments: Vec<_> = numbered(&self.dir, SEGMENT)?
+            .into_iter()
+            .filter(|(segment, _)| *segment < self.active)
+            .collect();
+        let live = self
+            .index
+            .iter()
+            .filter(|(_, loc)| loc.segment < self.active)
+            .map(|(name, loc)| (name.clone(), *loc))
+            .collect();
+        self.merging = true;
+        Ok(Some(Merge {
+            dir: self.dir.clone(),
+            generation: self.generation,
+            out,
+            segments,
+            live,
+        }))
+    }
+
+    /// Put a written merge in place of the segments it was made from, oldest
+    /// first, so that a crash partway leaves the newer ones (and their
+    /// removals) behind. Returns the bytes freed
+    pub(crate) fn finish_merge(
+        &mut self,
+        merge: Merge,
+        written: io::Result<Written>,
+    ) -> io::Result<u64> {
+        self.merging = false;
+        if merge.generation != self.generation {
+            // cleared meanwhile
+            if let Ok(written) = written {
+                fs::remove_file(written.tmp)?;
+            }
+            return Ok(0);
+        }
+        let written = written?;
+        let (_, before) = self.garbage();
+        fs::rename(&written.tmp, segment_path(&self.dir, merge.out))?;
+        for (name, old, new) in written.moved {
+            // unless it's been written or removed since
+            if self.index.get(&name) == Some(&old) {
+                self.index.insert(name, new);
+            }
+        }
+        self.sealed += written.len;
+        for (_, path) in &merge.segments {
+            let len = fs::metadata(path)?.len();
+            fs::remove_file(path)?;
+            self.sealed -= len;
+        }
+        Ok(before.saturating_sub(self.garbage().1))
+    }
+
+    // start a new segment once this one is full
+    fn rotate_if_full(&mut self) -> io::Result<()> {
+        match self.len >= self.segment_size {
+            true => self.rotate(),
+            false => Ok(()),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let next = self.active + 2;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, next))?;
+        self.active = next;
+        self.sealed += self.len;
+        self.len = 0;
+        Ok(())
+    }
+
+    // append a record, returning the offset of its value
+    fn append(&mut self, name: &str, bytes: Option<&[u8]>, sync: bool) -> io::Result<u64> {
+        let record = encode_record(name, bytes);
+        self.file.write_all(&record)?;
+        if sync {
+            self.file.sync_data()?;
+        }
+        let offset = self.len + value_offset(name);
+        self.len += record.len() as u64;
+        Ok(offset)
+    }
+}
+
+/// The live values of a merge, copied into a temporary segment by `Merge::write`
+pub(crate) struct Written {
+    tmp: PathBuf,
+    // each value's name, and where it was and is now
+    moved: Vec<(String, Location, Location)>,
+    len: u64,
+}
+
+impl Merge {
+    /// Copy the live values into a new segment, flushed to disk
+    pub(crate) fn write(&self) -> io::Result<Written> {
+        let tmp = segment_path(&self.dir, self.out).with_extension("seg.tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        let mut files = HashMap::new();
+        let mut moved = Vec::with_capacity(self.live.len());
+        let mut len = 0;
+        for (name, old) in &self.live {
+            let f = match files.entry(old.segment) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(File::open(segment_path(&self.dir, old.segment))?),
+            };
+            let record = encode_record(name, Some(&read_at(f, old)?));
+            out.write_all(&record)?;
+            let new = Location {
+                segment: self.out,
+                offset: len + value_offset(name),
+                len: old.len,
+            };
+            len += record.len() as u64;
+            moved.push((name.clone(), *old, new));
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(Written { tmp, moved, len })
+    }
+}
+
+/// Merge a bucket's packed segments if at least `min_garbage` (a fraction) of
+/// their bytes are dead. Returns the bytes freed
+pub(crate) fn merge(state: &BucketState, min_garbage: f64) -> io::Result<u64> {
+    let merge = match state.pack().as_mut() {
+        Some(p) => {
+            let (dead, total) = p.garbage();
+            match dead > 0 && dead as f64 >= total as f64 * min_garbage {
+                true => p.prepare_merge()?,
+                false => None,
+            }
+        }
+        None => None,
+    };
+    let Some(merge) = merge else {
+        return Ok(0);
+    };
+    // copied while writes carry on
+    let written = merge.write();
+    match state.pack().as_mut() {
+        Some(p) => p.finish_merge(merge, written),
+        None => Ok(0),
+    }
+}
+
+/// Write the packed values of every bucket under `src` as files into the
+/// matching directories under `dst`, which `link_tree` leaves out
+pub(crate) fn unpack_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    if Pack::exists(src) {
+        let state = BucketState::get(src);
+        let mut pack = state.pack();
+        if pack.is_none() {
+            *pack = Some(Pack::open(src)?);
+        }
+        if let Some(p) = pack.as_ref() {
+            for key in p.keys() {
+                if let Some(bytes) = p.get(key)? {
+                    fs::write(dst.join(key), bytes)?;
+                }
+            }
+        }
+    }
+    for entry in fs::read_dir(src)?.flatten() {
+        let name = entry.file_name();
+        if !name.to_string_lossy().starts_with('.') && entry.file_type()?.is_dir() {
+            unpack_tree(&entry.path(), &dst.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{:020}{}", segment, SEGMENT))
+}
+
+fn encode_record(name: &str, bytes: Option<&[u8]>) -> Vec<u8> {
+    let mut body = Vec::with_capacity(name.len() + bytes.map_or(0, |b| b.len()) + 8);
+    body.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    body.extend_from_slice(name.as_bytes());
+    match bytes {
+        Some(b) => {
+            body.extend_from_slice(&(b.len() as u32).to_le_bytes());
+            body.extend_from_slice(b);
+        }
+        None => body.extend_from_slice(&TOMBSTONE.to_le_bytes()),
+    }
+    let mut record = Vec::with_capacity(body.len() + 12);
+    record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    record.extend_from_slice(&body);
+    record.extend_from_slice(&fnv(&body).to_le_bytes());
+    record
+}
+
+// where a record's value starts: after its length, the name and the value's length
+fn value_offset(name: &str) -> u64 {
+    12 + name.len() as u64
+}
+
+fn record_len(name: &str, len: u32) -> u64 {
+    value_offset(name) + len as u64 + 8
+}
+
+fn read_at(f: &mut File, loc: &Location) -> io::Result<Vec<u8>> {
+    f.seek(SeekFrom::Start(loc.offset))?;
+    let mut buf = vec![0; loc.len as usize];
+    f.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn fnv(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// index a segment's records, returning the length of its intact part
+fn scan(path: &Path, segment: u64, index: &mut BTreeMap<String, Location>) -> io::Result<u64> {
+    let bytes = fs::read(path)?;
+    let mut at = 0;
+    while let Some((len, rest)) = bytes[at..].split_first_chunk::<4>() {
+        let len = u32::from_le_bytes(*len) as usize;
+        let Some((body, rest)) = rest.split_at_checked(len) else {
+            break;
+        };
+        let Some((hash, _)) = rest.split_first_chunk::<8>() else {
+            break;
+        };
+        let Some(record) = parse(body).filter(|_| u64::from_le_bytes(*hash) == fnv(body)) else {
+            break;
+        };
+        match record {
+            (name, Some((offset, len))) => {
+                let offset = (at + 4 + offset) as u64;
+                index.insert(
+                    name,
+                    Location {
+                        segment,
+                        offset,
+                        len,
+                    },
+                );
+            }
+            (name, None) => {
+                index.remove(&name);
+            }
+        }
+        at += 4 + len + 8;
+    }
+    Ok(at as u64)
+}
+
+// a record's key, and the offset (within the body) and length of its value, if it has one
+fn parse(body: &[u8]) -> Option<(String, Option<(usize, u32)>)> {
+    let (klen, rest) = body.split_first_chunk::<4>()?;
+    let (name, rest) = rest.split_at_checked(u32::from_le_bytes(*klen) as usize)?;
+    let name = String::from_utf8(name.to_vec()).ok()?;
+    let (vlen, rest) = rest.split_first_chunk::<4>()?;
+    match u32::from_le_bytes(*vlen) {
+        TOMBSTONE => Some((name, None)),
+        len if len as usize == rest.len() => Some((name, Some((body.len() - rest.len(), len)))),
+        _ => None,
+    }
+}
+
+/// Merges packed buckets in the background, from `Fsdb::merge_packed_every`.
+/// Dropping it (or closing the `Fsdb`) stops the background thread
+pub struct PackMerger {
+    stop: Option<Sender<()>>,
+    merger: Option<JoinHandle<()>>,
+}
+
+impl Fsdb {
+    /// Every `every`, merge the segments of each open packed bucket in this
+    /// database with at least `min_garbage` (a fraction, such as 0.5) of its
+    /// bytes taken up by overwritten values and removals. See `Bucket::merge_packed`
+    pub fn merge_packed_every(&self, every: Duration, min_garbage: f64) -> PackMerger {
+        let dir = self.dir.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (done, finished) = mpsc::channel::<()>();
+        self.add_task(stop.clone(), finished);
+        let maintenance = self.maintenance.clone();
+        let merger = std::thread::spawn(move || {
+            let _done = done;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                let Some(_pass) = maintenance.pass() else {
+                    continue;
+                };
+                for (_, state) in BucketState::under(&dir) {
+                    // tried again next time
+                    let _ = merge(&state, min_garbage);
+                }
+            }
+        });
+        PackMerger {
+            stop: Some(stop),
+            merger: Some(merger),
+        }
+    }
+}
+
+impl Drop for PackMerger {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(merger) = self.merger.take() {
+            let _ = merger.join();
+        }
+    }
+}
+
+// keeping packed values in step with the rest of the bucket
+impl BucketState {
+    /// The packed value of top-level key `name`, if it has one
+    pub(crate) fn packed(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.pack().as_ref() {
+            Some(p) => p.get(name),
+            None => Ok(None),
+        }
+    }
+    pub(crate) fn is_packed(&self, name: &str) -> bool {
+        self.pack().as_ref().is_some_and(|p| p.contains(name))
+    }
+    pub(crate) fn packed_keys(&self) -> Vec<String> {
+        match self.pack().as_ref() {
+            Some(p) => p.keys().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+    /// The version of key `name` in `dir`, counting a packed value as stored
+    pub(crate) fn current_version(
+        &self,
+        dir: &Path,
+        name: &str,
+        top_level: bool,
+    ) -> io::Result<Version> {
+        let version = meta::current_version(dir, name)?;
+        match version == Version::default() && top_level && self.is_packed(name) {
+            true => Ok(Version::UNSTAMPED),
+            false => Ok(version),
+        }
+    }
+    /// Drop the packed value of top-level key `name` (once a file replaces
+    /// it), returning whether there was one
+    pub(crate) fn unpack(&self, name: &str) -> io::Result<bool> {
+        let sync = self.pack_sync();
+        match self.pack().as_mut() {
+            Some(p) => p.remove(name, sync),
+            None => Ok(false),
+        }
+    }
+    /// The tags of top-level key `name`, packed or not
+    pub(crate) fn stored_tags(&self, dir: &Path, name: &str) -> io::Result<Vec<String>> {
+        match self.packed(name)? {
+            Some(bytes) => tags::value_tags(&bytes),
+            None => tags::file_tags(&dir.join(name)),
+        }
+    }
+    /// The change log hash of top-level key `name`, packed or not
+    pub(crate) fn stored_hash(&self, dir: &Path, name: &str) -> io::Result<Option<u64>> {
+        match self.packed(name)? {
+            Some(bytes) => Ok(Some(changelog::hash_bytes(&bytes))),
+            None => changelog::hash_file(&dir.join(name)),
+        }
+    }
+    /// Pack `bytes` as top-level key `name`, recording it the way `install`
+    /// does a file. `false` if the bucket isn't packed or they don't fit
+    pub(crate) fn install_packed(
+        &self,
+        dir: &Path,
+        name: &str,
+        bytes: &[u8],
+        version: Option<Version>,
+        actor: Option<&str>,
+    ) -> io::Result<bool> {
+        let _guard = self.write_guard();
+        self.writable()?;
+        if !self.pack().as_ref().is_some_and(|p| p.fits(bytes.len())) {
+            return Ok(false);
+        }
+        let retag = match tags::indexing(dir) {
+            true => Some((self.stored_tags(dir, name)?, tags::value_tags(bytes)?)),
+            false => None,
+        };
+        let log = self.change_log();
+        let before = match &log {
+            Some(_) => self.stored_hash(dir, name)?,
+            None => None,
+        };
+        let sync = self.pack_sync();
+        if let Some(p) = self.pack().as_mut() {
+            p.put(name, bytes, sync)?;
+        }
+        // a value too large to pack that it replaces
+        match fs::remove_file(dir.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        if let Some(log) = log {
+            log.record_packed(dir, name, before, bytes, actor)?;
+        }
+        if let Some(version) = version {
+            meta::stamp(dir, name, version)?;
+        }
+        if let Some((old, new)) = retag {
+            tags::retag(dir, name, &old, &new)?;
+        }
+        self.bump_seq(dir)?;
+        Ok(true)
+    }
+    /// Remove top-level key `name` from the pack along with its metadata, the
+    /// way `uninstall` does a file. `false` if it isn't packed
+    pub(crate) fn uninstall_packed(
+        &self,
+        dir: &Path,
+        name: &str,
+        actor: Option<&str>,
+    ) -> io::Result<bool> {
+        let _guard = self.write_guard();
+        self.writable()?;
+        let old = match self.packed(name)? {
+            Some(bytes) => bytes,
+            None => return Ok(false),
+        };
+        let old_tags = match tags::indexing(dir) {
+            true => tags::value_tags(&old)?,
+            false => Vec::new(),
+        };
+        self.unpack(name)?;
+        if let Some(log) = self.change_log() {
+            let before = Some(changelog::hash_bytes(&old));
+            log.record(dir, Some(name), Op::Remove, before, None, actor)?;
+        }
+        meta::remove(dir, name)?;
+        tags::retag(dir, name, &old_tags, &[])?;
+        if let Some(access) = self.access().as_mut() {
+            access.forget(name);
+        }
+        if let Some(cache) = self.cache().as_mut() {
+            cache.remove(name);
+        }
+        self.bump_seq(dir)?;
+        Ok(true)
+    }
+    fn pack_sync(&self) -> bool {
+        self.network_fs() || self.durability() != Durability::None
+    }
+}
+
+// packing small values
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    // add the packed keys to a listing of `dir`
+    pub(crate) fn list_packed(&self, dir: &Path, names: &mut Vec<String>) {
+        if dir != self.dir {
+            return;
+        }
+        if let Some(p) = self.state.pack().as_ref() {
+            names.extend(p.keys().cloned());
+        }
+    }
+    /// Store this bucket's values many to a file (in a `.pack` directory)
+    /// instead of one file per key, to save inodes and space when there are
+    /// lots of small values. Values larger than a threshold (see
+    /// `set_pack_threshold`), and ones with metadata in extended attributes,
+    /// are still stored as files, chosen on each write. Values already in the
+    /// bucket are moved in. Once enabled, it's used whenever the bucket is opened.
+    ///
+    /// Versions, metadata, tags, the change log, transactions, snapshots and so
+    /// on work as before, whichever way a value is stored. Sorted indexes,
+    /// views and manifests read value files, so a bucket with any of them
+    /// can't be packed, and a packed bucket can't have them. Packed values
+    /// have no modification time, so tiering, offloading and
+    /// `list_least_recently_used` (without access tracking) pass them over.
+    /// Sub-buckets are still directories, and only one process should write
+    /// to a packed bucket
+    pub fn enable_packed(&self) -> Result<()> {
+        let ctx = || Context::new(Op::Open, &self.dir, None);
+        let _guard = self.state.exclusive_guard();
+        if self.state.pack().is_some() {
+            return Ok(());
+        }
+        if self.state.manifest().is_some() {
+            return Err(Error::Io {
+                ctx: ctx(),
+                source: io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a bucket with a manifest can't be packed",
+                ),
+            });
+        }
+        // indexes and views read values from their files
+        let problem = match (
+            self.state.indexes().is_empty(),
+            self.state.views().is_empty(),
+        ) {
+            (false, _) => Some("a bucket with a sorted index can't be packed"),
+            (_, false) => Some("a bucket with views can't be packed"),
+            _ => None,
+        };
+        if let Some(problem) = problem {
+            return Err(Error::Io {
+                ctx: ctx(),
+                source: io::Error::new(io::ErrorKind::InvalidInput, problem),
+            });
+        }
+        let names = self.fs_keys(&self.dir)?;
+        let mut p = Pack::open(&self.dir).ctx(ctx)?;
+        let sync = self.state.network_fs();
+        for name in names {
+            let path = self.dir.join(&name);
+            let bytes = fs::read(&path).ctx(ctx)?;
+            if !p.fits(bytes.len()) || attrs::get_xattr(&path).ctx(ctx)?.is_some() {
+                continue;
+            }
+            p.put(&name, &bytes, sync).ctx(ctx)?;
+            fs::remove_file(&path).ctx(ctx)?;
+        }
+        *self.state.pack() = Some(p);
+        Ok(())
+    }
+    /// Write this bucket's packed values into `dir` as files, for copies of it
+    pub(crate) fn copy_packed(&self, dir: &Path) -> io::Result<()> {
+        if let Some(p) = self.state.pack().as_ref() {
+            for key in p.keys() {
+                if let Some(bytes) = p.get(key)? {
+                    fs::write(dir.join(key), bytes)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Start a new packed segment once the newest reaches `bytes` (4 MiB by
+    /// default), for every handle to this bucket in this process
+    pub fn set_pack_segment_size(&self, bytes: u64) {
+        if let Some(p) = self.state.pack().as_mut() {
+            p.set_segment_size(bytes);
+        }
+    }
+    /// Store values up to `bytes` long (as stored, with any header) packed, and
+    /// larger ones as files (64 KiB by default), for every handle to this bucket
+    /// in this process. Values already stored move when they're next written
+    pub fn set_pack_threshold(&self, bytes: usize) {
+        if let Some(p) = self.state.pack().as_mut() {
+            p.set_threshold(bytes);
+        }
+    }
+    /// Copy the live values out of this bucket's sealed packed segments into
+    /// one, dropping overwritten values and removals, and delete the old ones.
+    /// Writes carry on meanwhile. Returns the number of bytes freed. See also
+    /// `Fsdb::merge_packed_every`
+    pub fn merge_packed(&self) -> Result<u64> {
+        merge(&self.state, 0.0).ctx(|| Context::new(Op::Space, &self.dir, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{segment_path, PACK};
+    use crate::{Fsdb, Metadata, Version};
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_packed() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("packed").expect("fail bucket");
+        b.put("old", 7).expect("failed to save");
+        b.enable_packed().expect("fail enable_packed");
+        for i in 0..100 {
+            b.put(&format!("k{}", i), i).expect("failed to save");
+        }
+        b.put_within("x", 1, "sub").expect("failed to save");
+        assert_eq!(b.get("old").expect("fail get"), 7);
+        assert_eq!(b.get("k42").expect("fail get"), 42);
+        assert_eq!(b.len().expect("fail len"), 102);
+        b.put("k42", 0).expect("failed to save");
+        b.remove("k1").expect("fail remove");
+        assert!(b.remove("k1").is_err());
+        assert!(!b.exists("k1"));
+        assert!(b.exists("k2"));
+        assert_eq!(b.retain(|_, v| *v < 50).expect("fail retain"), 50);
+        let dir = db.path().join("packed");
+        // one file for all the values, and the sub-bucket
+        assert_eq!(fs::read_dir(&dir).expect("fail read_dir").count(), 3);
+        let iter = b.snapshot_iter().expect("fail snapshot_iter");
+        let items: Vec<_> = iter.map(|r| r.expect("fail item")).collect();
+        assert_eq!(items.len(), 50);
+
+        // reopened, with a torn write at the end
+        drop(b);
+        let segment = segment_path(&dir.join(PACK), 0);
+        let mut bytes = fs::read(&segment).expect("fail read");
+        bytes.extend_from_slice(&[9, 0, 0, 0, 1]);
+        fs::write(&segment, bytes).expect("fail write");
+        let b = db.bucket::<u32>("packed").expect("fail bucket");
+        assert_eq!(b.get("k42").expect("fail get"), 0);
+        assert!(b.get("k1").is_err());
+        let mut keys = b.list().expect("fail list");
+        keys.sort();
+        assert_eq!(keys.len(), 51);
+        assert_eq!(keys[0], "k0");
+        b.put("k99", 99).expect("failed to save");
+        assert_eq!(b.get("k99").expect("fail get"), 99);
+
+        b.clear().expect("fail clear");
+        assert!(b.list().expect("fail list").is_empty());
+        b.put("k0", 3).expect("failed to save");
+        assert_eq!(b.get("k0").expect("fail get"), 3);
+    }
+
+    #[test]
+    fn test_read_range_packed() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("packed").expect("fail bucket");
+        b.enable_packed().expect("fail enable_packed");
+        b.put("k", 300).expect("failed to save");
+        assert!(!db.path().join("packed/k").exists());
+        // a uint16 marker and two bytes
+        assert_eq!(b.read_range("k", 0, 10).expect("fail read"), [0xcd, 1, 44]);
+        assert_eq!(b.read_range("k", 1, 1).expect("fail read"), [1]);
+        assert!(b.read_range("k", 5, 1).expect("fail read").is_empty());
+        assert!(b.read_range("missing", 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_packed_features() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("packed").expect("fail bucket");
+        b.enable_packed().expect("fail enable_packed");
+        b.put("a", 1).expect("failed to save");
+
+        // a transaction's file replaces the packed value
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put(&b, "a", 2).expect("fail stage");
+        tx.commit().expect("fail commit");
+        assert_eq!(b.get("a").expect("fail get"), 2);
+        let mut tx = db.transaction().expect("fail transaction");
+        b.put("gone", 3).expect("failed to save");
+        tx.remove(&b, "gone");
+        tx.commit().expect("fail commit");
+        assert!(!b.exists("gone"));
+
+        // versions
+        b.put("v", 1).expect("failed to save");
+        let (_, version) = b.get_versioned("v").expect("fail get_versioned");
+        assert_ne!(version, Version::default());
+        assert!(b.put_versioned("v", 2, Version::default()).is_err());
+        let next = b
+            .put_versioned("v", 2, version)
+            .expect("fail put_versioned");
+        assert_eq!(b.get_versioned("v").expect("fail get_versioned").1, next);
+
+        // metadata and tags
+        let attrs = Metadata {
+            tags: vec!["red".into()],
+            ..Metadata::default()
+        };
+        b.put_with_meta("t", 4, attrs).expect("failed to save");
+        assert_eq!(b.get_meta("t").expect("fail get_meta").tags, vec!["red"]);
+        assert_eq!(b.list_by_tag("red").expect("fail list_by_tag"), vec!["t"]);
+        b.remove("t").expect("fail remove");
+        assert!(b.list_by_tag("red").expect("fail list_by_tag").is_empty());
+
+        // snapshots
+        b.snapshot("s").expect("fail snapshot");
+        b.put("a", 9).expect("failed to save");
+        b.restore_snapshot("s").expect("fail restore_snapshot");
+        assert_eq!(b.get("a").expect("fail get"), 2);
+        assert_eq!(b.get("v").expect("fail get"), 2);
+
+        // features that read value files
+        assert!(b.create_sorted_index("by", |v| *v).is_err());
+        let other = db.bucket::<u32>("indexed").expect("fail bucket");
+        other.create_sorted_index("by", |v| *v).expect("fail index");
+        assert!(other.enable_packed().is_err());
+    }
+
+    #[test]
+    fn test_pack_threshold() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<String>("mixed").expect("fail bucket");
+        b.enable_packed().expect("fail enable_packed");
+        b.set_pack_threshold(32);
+        b.put("big", "x".repeat(100)).expect("failed to save");
+        b.put("small", "y".into()).expect("failed to save");
+        let dir = db.path().join("mixed");
+        assert!(dir.join("big").is_file());
+        assert!(!dir.join("small").exists());
+
+        // moves between the pack and a file as it changes size
+        b.put("big", "z".into()).expect("failed to save");
+        b.put("small", "w".repeat(100)).expect("failed to save");
+        assert!(!dir.join("big").exists());
+        assert!(dir.join("small").is_file());
+        assert_eq!(b.get("big").expect("fail get"), "z");
+        assert_eq!(b.get("small").expect("fail get"), "w".repeat(100));
+        let mut keys = b.list().expect("fail list");
+        keys.sort();
+        assert_eq!(keys, vec!["big", "small"]);
+
+        b.remove("small").expect("fail remove");
+        b.remove("big").expect("fail remove");
+        assert!(b.is_empty().expect("fail is_empty"));
+        assert!(b.remove("big").is_err());
+    }
+
+    #[test]
+    fn test_merge_packed() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("log").expect("fail bucket");
+        b.enable_packed().expect("fail enable_packed");
+        b.set_pack_segment_size(256);
+        for round in 0..10 {
+            for i in 0..20 {
+                b.put(&format!("k{}", i), round * i)
+                    .expect("failed to save");
+            }
+        }
+        for i in 10..20 {
+            b.remove(&format!("k{}", i)).expect("fail remove");
+        }
+        let pack = db.path().join("log").join(PACK);
+        let segments = || {
+            let mut names: Vec<_> = fs::read_dir(&pack)
+                .expect("fail read_dir")
+                .map(|e| e.expect("fail read_dir").file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let count = || segments().len();
+        assert!(count() > 10);
+        assert!(b.merge_packed().expect("fail merge_packed") > 0);
+        // the merged segment, and a new one to write to
+        assert_eq!(count(), 2);
+        assert_eq!(b.merge_packed().expect("fail merge_packed"), 0);
+        assert_eq!(b.len().expect("fail len"), 10);
+        assert_eq!(b.get("k9").expect("fail get"), 81);
+        assert!(b.get("k10").is_err());
+
+        b.put("k9", 1).expect("failed to save");
+        let before = segments();
+        let merger = db.merge_packed_every(Duration::from_millis(10), 0.01);
+        let start = Instant::now();
+        while segments() == before && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(merger);
+        assert_ne!(segments(), before);
+        assert_eq!(count(), 2);
+
+        // reopened from the merged segments
+        drop(b);
+        let b = db.bucket::<u32>("log").expect("fail bucket");
+        assert_eq!(b.get("k9").expect("fail get"), 1);
+        assert_eq!(b.get("k8").expect("fail get"), 72);
+        assert_eq!(b.len().expect("fail len"), 10);
+    }
+}
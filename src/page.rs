@@ -0,0 +1,123 @@
+use crate::{Bucket, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+
+/// Where a page of results left off, to pass back for the next page. It's an
+/// opaque token (from `Display`, read back with `Cursor::parse`) suitable for
+/// handing to API clients.
+///
+/// Results are ordered by key, and a cursor remembers the last key returned,
+/// so pages stay consistent while keys are added and removed: nothing is
+/// skipped or returned twice, and keys added behind the cursor are picked up
+/// by later pages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    after: String,
+}
+
+// the token format, so it can change without breaking old tokens unnoticed
+const TOKEN_VERSION: char = 'k';
+
+impl Cursor {
+    /// Read a cursor from its token, or `None` if it isn't one
+    pub fn parse(token: &str) -> Option<Self> {
+        let hex = token.strip_prefix(TOKEN_VERSION)?;
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Self {
+            after: String::from_utf8(bytes).ok()?,
+        })
+    }
+    pub(crate) fn after(&self) -> &str {
+        &self.after
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", TOKEN_VERSION)?;
+        self.after.bytes().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+/// One page of results, and the cursor for the next (`None` on the last page)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<Cursor>,
+}
+
+impl<T> Page<T> {
+    // a page of up to `limit` items from `items`, which has one more if there's another page
+    pub(crate) fn new(mut items: Vec<T>, limit: usize, key: impl Fn(&T) -> &str) -> Self {
+        let next = match items.len() > limit {
+            true => {
+                items.truncate(limit);
+                items.last().map(|last| Cursor {
+                    after: key(last).to_owned(),
+                })
+            }
+            false => None,
+        };
+        Self { items, next }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// List up to `limit` keys (and sub-buckets), in order, starting after
+    /// `cursor` (or from the start). See `Cursor`
+    pub fn list_page(&self, limit: usize, cursor: Option<&Cursor>) -> Result<Page<String>> {
+        let mut keys = self.list()?;
+        keys.sort();
+        let start = cursor.map_or(0, |c| keys.partition_point(|k| k.as_str() <= c.after()));
+        let items = keys
+            .into_iter()
+            .skip(start)
+            .take(limit.saturating_add(1))
+            .collect();
+        Ok(Page::new(items, limit, String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cursor;
+    use crate::Fsdb;
+
+    #[test]
+    fn test_list_page() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("paged").expect("fail bucket");
+        for k in ["a", "b", "c", "d", "e"] {
+            b.put(k, 0).expect("failed to save");
+        }
+        let page = b.list_page(2, None).expect("fail list_page");
+        assert_eq!(page.items, vec!["a", "b"]);
+        let token = page.next.expect("no next page").to_string();
+
+        // changes between requests don't shift the pages
+        b.remove("a").expect("fail remove");
+        b.put("bb", 0).expect("failed to save");
+        let cursor = Cursor::parse(&token).expect("bad token");
+        let page = b.list_page(2, Some(&cursor)).expect("fail list_page");
+        assert_eq!(page.items, vec!["bb", "c"]);
+        let page = b.list_page(2, page.next.as_ref()).expect("fail list_page");
+        assert_eq!(page.items, vec!["d", "e"]);
+        assert!(page.next.is_none());
+    }
+
+    #[test]
+    fn test_cursor_token() {
+        let c = Cursor {
+            after: "ключ/1".into(),
+        };
+        assert_eq!(Cursor::parse(&c.to_string()), Some(c));
+        assert!(Cursor::parse("nope").is_none());
+        assert!(Cursor::parse("kzz").is_none());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::error::WithContext;
+use crate::header::{self, Header};
+use crate::{Bucket, Context, Error, Fsdb, Op, Result};
+use rmp_serde::{decode, encode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fs;
+
+type DecodeFn = fn(&[u8]) -> std::result::Result<Box<dyn Any + Send>, decode::Error>;
+
+fn decode_boxed<T: DeserializeOwned + Send + 'static>(
+    bytes: &[u8],
+) -> std::result::Result<Box<dyn Any + Send>, decode::Error> {
+    Ok(Box::new(decode::from_slice::<T>(bytes)?))
+}
+
+/// A bucket holding values of several registered types, such as the event types
+/// of an event store. Each value's type tag is stored in its header, and values
+/// are read back as `Box<dyn Any>` (or a specific type with `get_as`).
+///
+/// Values in a `PolyBucket` are not encrypted or signed.
+pub struct PolyBucket {
+    inner: Bucket<()>,
+    decoders: HashMap<String, DecodeFn>,
+    tags: HashMap<TypeId, String>,
+}
+
+impl Fsdb {
+    /// Create or open a bucket holding values of several types. See `PolyBucket`
+    pub fn poly_bucket(&self, p: &str) -> Result<PolyBucket> {
+        let (dir, state) = self.open_bucket_dir(p, std::any::type_name::<PolyBucket>())?;
+        Ok(PolyBucket {
+            inner: Bucket::with_state(dir, state),
+            decoders: HashMap::new(),
+            tags: HashMap::new(),
+        })
+    }
+}
+
+impl PolyBucket {
+    /// Allow values of type `T` in this bucket, stored with the given tag.
+    /// Tags must stay the same across releases for stored values to be readable.
+    pub fn register<T: Serialize + DeserializeOwned + Send + 'static>(&mut self, tag: &str) {
+        self.decoders.insert(tag.to_owned(), decode_boxed::<T>);
+        self.tags.insert(TypeId::of::<T>(), tag.to_owned());
+    }
+    /// Store a value of a registered type
+    pub fn put<T: Serialize + 'static>(&self, key: &str, value: &T) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.inner.dir, Some(key));
+        let tag = self
+            .tags
+            .get(&TypeId::of::<T>())
+            .ok_or_else(|| Error::UnknownType {
+                ctx: ctx(),
+                tag: std::any::type_name::<T>().to_owned(),
+            })?;
+        let header = Header {
+            type_tag: Some(tag.clone()),
+            ..Default::default()
+        };
+        let bytes = header::wrap(&header, encode::to_vec(value).ctx(ctx)?);
+        self.inner.fs_put_bytes(&self.inner.dir, key, &bytes)
+    }
+    /// Get a value, as whatever type it was stored as
+    pub fn get(&self, key: &str) -> Result<Box<dyn Any + Send>> {
+        let ctx = || Context::new(Op::Get, &self.inner.dir, Some(key));
+        let bytes = fs::read(self.inner.dir.join(self.inner.maxify(key))).ctx(ctx)?;
+        let (tag, payload) = split(&bytes).ctx(ctx)?;
+        let decoder = self.decoders.get(&tag).ok_or_else(|| Error::UnknownType {
+            ctx: ctx(),
+            tag: tag.clone(),
+        })?;
+        decoder(payload).ctx(ctx)
+    }
+    /// Get a value if it was stored as a `T`, or `None` if it's some other type
+    pub fn get_as<T: 'static>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self.get(key)?.downcast::<T>().ok().map(|b| *b))
+    }
+    /// The type tag a value was stored with
+    pub fn type_tag(&self, key: &str) -> Result<String> {
+        let ctx = || Context::new(Op::Get, &self.inner.dir, Some(key));
+        let bytes = fs::read(self.inner.dir.join(self.inner.maxify(key))).ctx(ctx)?;
+        Ok(split(&bytes).ctx(ctx)?.0)
+    }
+    /// Check if a key exists
+    pub fn exists(&self, key: &str) -> bool {
+        self.inner.exists(key)
+    }
+    /// Delete a key
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.inner.remove(key)
+    }
+    /// List keys in this bucket
+    pub fn list(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+    /// Clear all keys in this bucket
+    pub fn clear(&self) -> Result<()> {
+        self.inner.clear()
+    }
+}
+
+fn split(bytes: &[u8]) -> std::result::Result<(String, &[u8]), decode::Error> {
+    match header::split(bytes) {
+        Some((
+            Header {
+                type_tag: Some(tag),
+                ..
+            },
+            payload,
+        )) => Ok((tag, payload)),
+        _ => Err(decode::Error::Syntax("missing type tag".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Fsdb};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Created {
+        id: u32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Renamed {
+        id: u32,
+        name: String,
+    }
+
+    #[test]
+    fn test_poly_bucket() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut events = db.poly_bucket("events").expect("fail bucket");
+        events.register::<Created>("created");
+        events.register::<Renamed>("renamed");
+        events.put("1", &Created { id: 7 }).expect("failed to save");
+        let renamed = Renamed {
+            id: 7,
+            name: "x".into(),
+        };
+        events.put("2", &renamed).expect("failed to save");
+
+        assert_eq!(events.type_tag("2").expect("fail tag"), "renamed");
+        let any = events.get("1").expect("fail get");
+        assert_eq!(any.downcast_ref::<Created>(), Some(&Created { id: 7 }));
+        assert_eq!(
+            events.get_as::<Renamed>("2").expect("fail get"),
+            Some(renamed)
+        );
+        assert_eq!(events.get_as::<Created>("2").expect("fail get"), None);
+        assert!(matches!(
+            events.put("3", &5u8),
+            Err(Error::UnknownType { .. })
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::{Poll, Waker};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that run `AsyncBucket` operations. Buckets from an `Fsdb` with
+/// `FsdbOptions::io_threads` set use a pool of their own, others share one
+/// pool per process. Its threads stop once the pool is dropped and its queue
+/// is empty
+pub struct IoPool {
+    queue: Arc<Queue>,
+    counts: Arc<Counts>,
+    threads: usize,
+}
+
+/// Which queue of an `IoPool` an operation waits in. A thread takes the
+/// next interactive operation before any background one, so maintenance
+/// (compaction, backups and so on) waits behind user-facing gets and puts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Priority {
+    #[default]
+    Interactive,
+    Background,
+}
+
+/// How busy an `IoPool` is, from `IoPool::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Threads in the pool
+    pub threads: usize,
+    /// Operations waiting for a thread
+    pub queued: usize,
+    /// Of those, the ones with `Priority::Background`
+    pub queued_background: usize,
+    /// Operations running now
+    pub running: usize,
+    /// Operations finished since the pool started
+    pub completed: u64,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: Mutex<Jobs>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct Jobs {
+    interactive: VecDeque<Job>,
+    background: VecDeque<Job>,
+    // set when the pool is dropped
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Counts {
+    running: AtomicUsize,
+    completed: AtomicU64,
+}
+
+/// A bucket operation running on an `IoPool`, from `AsyncBucket`.
+/// Dropping it doesn't cancel the operation, only its result
+pub struct Blocking<T> {
+    shared: Arc<Mutex<Slot<T>>>,
+}
+
+struct Slot<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl IoPool {
+    /// A pool of `threads` threads (at least one)
+    pub fn new(threads: usize) -> Arc<Self> {
+        let threads = threads.max(1);
+        let queue = Arc::new(Queue::default());
+        let counts = Arc::new(Counts::default());
+        for _ in 0..threads {
+            let queue = queue.clone();
+            thread::spawn(move || worker(&queue));
+        }
+        Arc::new(Self {
+            queue,
+            counts,
+            threads,
+        })
+    }
+    /// The pool shared by buckets without one of their own, with a thread per
+    /// CPU (at least two), started on first use
+    pub fn shared() -> Arc<Self> {
+        static SHARED: OnceLock<Arc<IoPool>> = OnceLock::new();
+        SHARED
+            .get_or_init(|| {
+                let threads = thread::available_parallelism().map_or(4, |n| n.get().max(2));
+                Self::new(threads)
+            })
+            .clone()
+    }
+    /// Queue depth and thread counts
+    pub fn stats(&self) -> PoolStats {
+        let jobs = self.queue.jobs.lock().expect("pool poisoned");
+        PoolStats {
+            threads: self.threads,
+            queued: jobs.interactive.len() + jobs.background.len(),
+            queued_background: jobs.background.len(),
+            running: self.counts.running.load(Ordering::Relaxed),
+            completed: self.counts.completed.load(Ordering::Relaxed),
+        }
+    }
+    /// Run `f` on the pool, as a future of its result
+    pub(crate) fn spawn<T, F>(&self, priority: Priority, f: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Slot {
+            result: None,
+            waker: None,
+        }));
+        let (slot, counts) = (shared.clone(), self.counts.clone());
+        let job: Job = Box::new(move || {
+            counts.running.fetch_add(1, Ordering::Relaxed);
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // counted before waking, so the stats are current once it's awaited
+            counts.running.fetch_sub(1, Ordering::Relaxed);
+            counts.completed.fetch_add(1, Ordering::Relaxed);
+            let mut slot = slot.lock().expect("task slot poisoned");
+            slot.result = Some(result);
+            if let Some(w) = slot.waker.take() {
+                w.wake();
+            }
+        });
+        let mut jobs = self.queue.jobs.lock().expect("pool poisoned");
+        match priority {
+            Priority::Interactive => jobs.interactive.push_back(job),
+            Priority::Background => jobs.background.push_back(job),
+        }
+        self.queue.ready.notify_one();
+        Blocking { shared }
+    }
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<T> {
+        let mut slot = self.shared.lock().expect("task slot poisoned");
+        match slot.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            // the operation panicked, so the awaiting task does too
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for IoPool {
+    fn drop(&mut self) {
+        self.queue.jobs.lock().expect("pool poisoned").closed = true;
+        self.queue.ready.notify_all();
+    }
+}
+
+fn worker(queue: &Queue) {
+    loop {
+        let mut jobs = queue.jobs.lock().expect("pool poisoned");
+        let job = loop {
+            if let Some(job) = jobs.interactive.pop_front() {
+                break job;
+            }
+            if let Some(job) = jobs.background.pop_front() {
+                break job;
+            }
+            // ends once the pool is dropped and the queue is empty
+            if jobs.closed {
+                return;
+            }
+            jobs = queue.ready.wait(jobs).expect("pool poisoned");
+        };
+        drop(jobs);
+        job();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IoPool, Priority};
+    use futures_executor::block_on;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    #[test]
+    fn test_pool_stats() {
+        let pool = IoPool::new(1);
+        let (release, wait) = mpsc::channel::<()>();
+        let first = pool.spawn(Priority::Interactive, move || wait.recv().is_ok());
+        let second = pool.spawn(Priority::Interactive, || 2);
+        // the one thread is held up by the first job
+        while pool.stats().running == 0 {
+            std::thread::yield_now();
+        }
+        let stats = pool.stats();
+        assert_eq!((stats.threads, stats.running, stats.queued), (1, 1, 1));
+        release.send(()).expect("fail send");
+        assert!(block_on(first));
+        assert_eq!(block_on(second), 2);
+        assert_eq!(pool.stats().completed, 2);
+        assert_eq!(pool.stats().queued, 0);
+    }
+
+    #[test]
+    fn test_priority() {
+        let pool = IoPool::new(1);
+        let (release, wait) = mpsc::channel::<()>();
+        let blocker = pool.spawn(Priority::Interactive, move || wait.recv().is_ok());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let jobs: Vec<_> = [
+            Priority::Background,
+            Priority::Interactive,
+            Priority::Background,
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let order = order.clone();
+            pool.spawn(p, move || order.lock().unwrap().push(i))
+        })
+        .collect();
+        assert_eq!(pool.stats().queued_background, 2);
+        release.send(()).expect("fail send");
+        assert!(block_on(blocker));
+        jobs.into_iter().for_each(block_on);
+        // the interactive job jumped the queue
+        assert_eq!(*order.lock().unwrap(), vec![1, 0, 2]);
+    }
+}
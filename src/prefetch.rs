@@ -0,0 +1,89 @@
+use crate::cache;
+use crate::error::WithContext;
+use crate::{Bucket, Context, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs::File;
+use std::io;
+
+// reading ahead
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Hint that these keys are about to be read, so the OS can start loading
+    /// them into its page cache (with `posix_fadvise` on Linux; elsewhere this
+    /// only checks they exist). If the bucket has a read cache, they're read
+    /// into it as well. Missing keys are skipped. Returns the number found
+    pub fn prefetch<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> Result<usize> {
+        let mut found = 0;
+        for key in keys {
+            let key = key.as_ref();
+            let ctx = || Context::new(Op::Get, &self.dir, Some(key));
+            let name = self.maxify(key);
+            let packed = self.state.pack().as_ref().and_then(|p| p.locate(&name));
+            let res = match packed {
+                Some((segment, offset, len)) => {
+                    File::open(segment).and_then(|f| will_need(&f, offset, len as u64))
+                }
+                None if self.state.cache().is_some() => {
+                    cache::read(&self.state, &self.dir.join(&name), &name).map(|_| ())
+                }
+                None => File::open(self.dir.join(&name)).and_then(|f| will_need(&f, 0, 0)),
+            };
+            match res {
+                Ok(()) => found += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e).ctx(ctx),
+            }
+        }
+        Ok(found)
+    }
+}
+
+// ask the OS to read `len` bytes (0 for the rest of the file) from `offset` ahead
+#[cfg(target_os = "linux")]
+fn will_need(f: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the descriptor is open for as long as `f` is borrowed
+    let res = unsafe {
+        libc::posix_fadvise(
+            f.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_WILLNEED,
+        )
+    };
+    // the error is returned, not left in errno
+    match res {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn will_need(_f: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+
+    #[test]
+    fn test_prefetch() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("ahead").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        assert_eq!(b.prefetch(["a", "b", "c"]).expect("fail prefetch"), 2);
+
+        b.enable_read_cache(10);
+        assert_eq!(b.prefetch(vec!["b".to_owned()]).expect("fail prefetch"), 1);
+        let stats = b.cache_stats().expect("no cache stats");
+        assert_eq!((stats.entries, stats.hits), (1, 0));
+        assert_eq!(b.get("b").expect("fail get"), 2);
+        assert_eq!(b.cache_stats().expect("no cache stats").hits, 1);
+
+        let packed = db.bucket::<u32>("packed").expect("fail bucket");
+        packed.enable_packed().expect("fail enable_packed");
+        packed.put("p", 3).expect("failed to save");
+        assert_eq!(packed.prefetch(["p", "q"]).expect("fail prefetch"), 1);
+    }
+}
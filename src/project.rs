@@ -0,0 +1,126 @@
+use crate::error::WithContext;
+use crate::{Bucket, BucketOptions, Context, Op, Result};
+use rmp_serde::decode;
+use serde::{de::DeserializeOwned, Serialize};
+
+// field names are only stored with the values of handles that ask for them
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Store struct fields by name rather than by position, so `get_projection`
+    /// can pick out the fields it needs. Values take a little more space. Values
+    /// are read the same either way. It's recorded with the bucket, for every
+    /// handle opened from then on, and values already stored are rewritten by
+    /// `compact` (see `reconfigure`)
+    pub fn set_named_fields(&mut self, on: bool) -> Result<()> {
+        let options = BucketOptions {
+            max_file_name: self.max_file_name,
+            named_fields: on,
+        };
+        self.reconfigure(options).map(|_| ())
+    }
+    /// Get a key as `P`, a struct with some of `V`'s fields, by name.
+    ///
+    /// For values stored with named fields (see `set_named_fields`) the other
+    /// fields are skipped rather than decoded. Other values are decoded in full
+    /// and then projected, which is no faster than `get`
+    pub fn get_projection<P: DeserializeOwned>(&self, key: &str) -> Result<P> {
+        let _slow = self.slow_guard(Op::Get, &self.dir, Some(key));
+        let ctx = || Context::new(Op::Get, &self.dir, Some(key));
+        let name = self.maxify(key);
+        self.inject_fault(&self.dir, &name, None).ctx(ctx)?;
+        let bytes = self.read_file(&self.dir, &name).ctx(ctx)?;
+        let plain = self.plain(&bytes, ctx)?;
+        let projection = match plain.first() {
+            // JSON always names fields
+            _ if self.json => self.decode_plain(&plain, ctx)?,
+            // fixmap, map 16 and map 32: fields are named
+            Some(0x80..=0x8f | 0xde | 0xdf) => decode::from_slice(&plain).ctx(ctx)?,
+            _ => {
+                let value: V = decode::from_slice(&plain).ctx(ctx)?;
+                serde_json::to_value(&value)
+                    .and_then(serde_json::from_value)
+                    .map_err(|e| decode::Error::Syntax(e.to_string()))
+                    .ctx(ctx)?
+            }
+        };
+        self.record_read(&self.dir, &name);
+        Ok(projection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cipher, Fsdb};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        id: u32,
+        customer: String,
+        notes: Vec<String>,
+        total: f64,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Summary {
+        total: f64,
+        customer: String,
+    }
+
+    // not real encryption, just enough that the payload isn't msgpack
+    struct Flip;
+
+    impl Cipher for Flip {
+        fn encrypt(&self, plain: &[u8]) -> Vec<u8> {
+            plain.iter().map(|b| !b).collect()
+        }
+        fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+            Some(data.iter().map(|b| !b).collect())
+        }
+    }
+
+    fn order() -> Order {
+        Order {
+            id: 7,
+            customer: "ann".into(),
+            notes: vec!["leave at the door".into()],
+            total: 12.5,
+        }
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            total: 12.5,
+            customer: "ann".into(),
+        }
+    }
+
+    #[test]
+    fn test_get_projection() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<Order>("orders").expect("fail bucket");
+        b.put("positional", order()).expect("failed to save");
+        b.set_named_fields(true).expect("fail set_named_fields");
+        b.put("named", order()).expect("failed to save");
+
+        for key in ["positional", "named"] {
+            let s: Summary = b.get_projection(key).expect("fail get_projection");
+            assert_eq!(s, summary());
+            assert_eq!(b.get(key).expect("fail get"), order());
+        }
+        // a plain handle still reads named values
+        let plain = db.bucket::<Order>("orders").expect("fail bucket");
+        assert_eq!(plain.get("named").expect("fail get"), order());
+        assert!(b.get_projection::<Summary>("missing").is_err());
+    }
+
+    #[test]
+    fn test_get_projection_encrypted() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<Order>("orders").expect("fail bucket");
+        b.set_cipher(Flip);
+        b.set_named_fields(true).expect("fail set_named_fields");
+        b.put("a", order()).expect("failed to save");
+        let s: Summary = b.get_projection("a").expect("fail get_projection");
+        assert_eq!(s, summary());
+    }
+}
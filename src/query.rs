@@ -0,0 +1,279 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Cursor, Op, Page, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::io;
+
+/// A filter over a bucket's values, from `Bucket::query`. Fields are named as
+/// in the values' serialized form, with `.` between nested fields (like
+/// `"owner.name"`), and compared as JSON: numbers with numbers, strings with
+/// strings, and so on. A value without the field, or with a field of another
+/// type, only matches `ne`.
+///
+/// A sorted index named after a field (see `Bucket::create_sorted_index`) is
+/// used to narrow down which values to read; otherwise every value is read.
+/// Either way every filter is checked against the values themselves
+pub struct Query<'a, V> {
+    bucket: &'a Bucket<V>,
+    filters: Vec<Filter>,
+    limit: Option<usize>,
+    // the first filter value that couldn't be serialized
+    error: Option<serde_json::Error>,
+}
+
+struct Filter {
+    field: String,
+    cmp: Cmp,
+    value: Value,
+}
+
+#[derive(Clone, Copy)]
+enum Cmp {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Filter {
+    fn matches(&self, v: &Value) -> bool {
+        let Some(field) = self.field.split('.').try_fold(v, |v, f| v.get(f)) else {
+            return matches!(self.cmp, Cmp::Ne);
+        };
+        self.matches_field(field)
+    }
+    fn matches_field(&self, field: &Value) -> bool {
+        let ord = compare(field, &self.value);
+        match self.cmp {
+            Cmp::Eq => ord == Some(Ordering::Equal),
+            Cmp::Ne => ord != Some(Ordering::Equal),
+            Cmp::Gt => ord == Some(Ordering::Greater),
+            Cmp::Ge => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+            Cmp::Lt => ord == Some(Ordering::Less),
+            Cmp::Le => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+        }
+    }
+}
+
+// order two JSON values of the same kind, or None if they can't be compared
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => {
+            if let (Some(x), Some(y)) = (x.as_i64(), y.as_i64()) {
+                Some(x.cmp(&y))
+            } else if let (Some(x), Some(y)) = (x.as_u64(), y.as_u64()) {
+                Some(x.cmp(&y))
+            } else {
+                x.as_f64()?.partial_cmp(&y.as_f64()?)
+            }
+        }
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
+        _ if a == b => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Start a query over this bucket's keys (not its sub-buckets). See `Query`
+    pub fn query(&self) -> Query<'_, V> {
+        Query {
+            bucket: self,
+            filters: Vec::new(),
+            limit: None,
+            error: None,
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Query<'_, V> {
+    /// Keep values whose field equals `value`
+    pub fn eq(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, Cmp::Eq, value)
+    }
+    /// Keep values whose field doesn't equal `value` (or that don't have it)
+    pub fn ne(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, Cmp::Ne, value)
+    }
+    /// Keep values whose field is greater than `value`
+    pub fn gt(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, Cmp::Gt, value)
+    }
+    /// Keep values whose field is greater than or equal to `value`
+    pub fn ge(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, Cmp::Ge, value)
+    }
+    /// Keep values whose field is less than `value`
+    pub fn lt(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, Cmp::Lt, value)
+    }
+    /// Keep values whose field is less than or equal to `value`
+    pub fn le(self, field: &str, value: impl Serialize) -> Self {
+        self.filter(field, Cmp::Le, value)
+    }
+    /// Return at most `n` matches from `run`
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+    /// The keys and values that match every filter, sorted by key
+    pub fn run(self) -> Result<Vec<(String, V)>> {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        self.collect(None, limit)
+    }
+    /// Up to `limit` matches, starting after `cursor` (or from the start). See `Cursor`
+    pub fn page(self, limit: usize, cursor: Option<&Cursor>) -> Result<Page<(String, V)>> {
+        let found = self.collect(cursor.map(Cursor::after), limit.saturating_add(1))?;
+        Ok(Page::new(found, limit, |(k, _)| k))
+    }
+    // up to `limit` matches with keys after `after`
+    fn collect(self, after: Option<&str>, limit: usize) -> Result<Vec<(String, V)>> {
+        let b = self.bucket;
+        let ctx = || Context::new(Op::List, &b.dir, None);
+        if let Some(e) = self.error {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e)).ctx(ctx);
+        }
+        let mut candidates: Option<BTreeSet<String>> = None;
+        for filter in self.filters.iter() {
+            let Some(index) = b.state.indexes().get(&filter.field).cloned() else {
+                continue;
+            };
+            let Some(names) = index.matching(&|k| filter.matches_field(k)) else {
+                continue;
+            };
+            let names: BTreeSet<String> = names.into_iter().collect();
+            candidates = Some(match candidates {
+                Some(c) => c.intersection(&names).cloned().collect(),
+                None => names,
+            });
+        }
+        let candidates = match candidates {
+            Some(c) => c,
+            None => b.fs_keys(&b.dir)?.into_iter().collect(),
+        };
+        let mut found = Vec::new();
+        for key in candidates {
+            if after.is_some_and(|a| key.as_str() <= a) {
+                continue;
+            }
+            if found.len() >= limit {
+                break;
+            }
+            // removed since listing
+            let Some(value) = b.fs_get_opt(&b.dir, &key)? else {
+                continue;
+            };
+            let json = serde_json::to_value(&value)
+                .map_err(io::Error::from)
+                .ctx(|| Context::new(Op::Get, &b.dir, Some(&key)))?;
+            if self.filters.iter().all(|f| f.matches(&json)) {
+                found.push((key, value));
+            }
+        }
+        Ok(found)
+    }
+    fn filter(mut self, field: &str, cmp: Cmp, value: impl Serialize) -> Self {
+        match serde_json::to_value(value) {
+            Ok(value) => self.filters.push(Filter {
+                field: field.to_owned(),
+                cmp,
+                value,
+            }),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Ticket {
+        status: String,
+        created: u64,
+        owner: Owner,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Owner {
+        name: String,
+    }
+
+    fn ticket(status: &str, created: u64, owner: &str) -> Ticket {
+        Ticket {
+            status: status.into(),
+            created,
+            owner: Owner { name: owner.into() },
+        }
+    }
+
+    fn keys(found: Vec<(String, Ticket)>) -> Vec<String> {
+        found.into_iter().map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn test_query() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Ticket>("tickets").expect("fail bucket");
+        b.put("t1", ticket("open", 10, "ann"))
+            .expect("failed to save");
+        b.put("t2", ticket("closed", 20, "bob"))
+            .expect("failed to save");
+        b.put("t3", ticket("open", 30, "bob"))
+            .expect("failed to save");
+        b.put("t4", ticket("open", 40, "ann"))
+            .expect("failed to save");
+
+        let open = b.query().eq("status", "open").run().expect("fail query");
+        assert_eq!(keys(open), vec!["t1", "t3", "t4"]);
+        let recent = b
+            .query()
+            .eq("status", "open")
+            .gt("created", 15)
+            .limit(1)
+            .run()
+            .expect("fail query");
+        assert_eq!(keys(recent), vec!["t3"]);
+        let anns = b.query().eq("owner.name", "ann").le("created", 10);
+        assert_eq!(keys(anns.run().expect("fail query")), vec!["t1"]);
+        // mismatched types never match
+        assert!(b
+            .query()
+            .gt("status", 1)
+            .run()
+            .expect("fail query")
+            .is_empty());
+        assert_eq!(b.query().ne("nope", 1).run().expect("fail query").len(), 4);
+
+        // the same answers through an index
+        b.create_sorted_index("created", |t: &Ticket| t.created)
+            .expect("fail create_sorted_index");
+        let q = b
+            .query()
+            .ge("created", 20)
+            .lt("created", 40)
+            .ne("owner.name", "ann");
+        assert_eq!(keys(q.run().expect("fail query")), vec!["t2", "t3"]);
+
+        let page = b
+            .query()
+            .eq("status", "open")
+            .page(2, None)
+            .expect("fail page");
+        assert_eq!(keys(page.items), vec!["t1", "t3"]);
+        let next = page.next.expect("no next page");
+        let page = b.query().eq("status", "open").page(2, Some(&next));
+        let page = page.expect("fail page");
+        assert_eq!(keys(page.items), vec!["t4"]);
+        assert!(page.next.is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{Bucket, Result, SnapshotIter};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A read-only handle to a bucket, from `Bucket::read_only`.
+/// It has no methods that write, so it can be handed out without risking changes.
+pub struct ReadBucket<V> {
+    inner: Bucket<V>,
+}
+
+impl<V> Clone for ReadBucket<V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// A read-only handle to this bucket, with the same settings as this one
+    pub fn read_only(&self) -> ReadBucket<V> {
+        ReadBucket {
+            inner: self.clone(),
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> ReadBucket<V> {
+    /// Check if a key exists
+    pub fn exists(&self, key: &str) -> bool {
+        self.inner.exists(key)
+    }
+    /// Get a key
+    pub fn get(&self, key: &str) -> Result<V> {
+        self.inner.get(key)
+    }
+    /// Get a key, or `V::default()` if it doesn't exist
+    pub fn get_or_default(&self, key: &str) -> Result<V>
+    where
+        V: Default,
+    {
+        self.inner.get_or_default(key)
+    }
+    /// Read up to `len` raw bytes of a key's stored file, starting at `offset`
+    pub fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.inner.read_range(key, offset, len)
+    }
+    /// List keys in this bucket (or sub-buckets in this bucket)
+    pub fn list(&self) -> Result<Vec<String>> {
+        self.inner.list()
+    }
+    /// List keys as a consistent snapshot. See `Bucket::list_stable`
+    pub fn list_stable(&self) -> Result<Vec<String>> {
+        self.inner.list_stable()
+    }
+    /// Iterate over a point-in-time view of the bucket. See `Bucket::snapshot_iter`
+    pub fn snapshot_iter(&self) -> Result<SnapshotIter<V>> {
+        self.inner.snapshot_iter()
+    }
+    /// Number of keys (and sub-buckets) in this bucket
+    pub fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+    /// Check if this bucket has no keys
+    pub fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+    /// Check if a key exists within sub-bucket
+    pub fn exists_within(&self, key: &str, sub: &str) -> bool {
+        self.inner.exists_within(key, sub)
+    }
+    /// Get a key in a sub-bucket
+    pub fn get_within(&self, key: &str, sub: &str) -> Result<V> {
+        self.inner.get_within(key, sub)
+    }
+    /// List keys in this bucket's sub-bucket
+    pub fn list_within(&self, sub: &str) -> Result<Vec<String>> {
+        self.inner.list_within(sub)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+
+    #[test]
+    fn test_read_only() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u64>("nums").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        let r = b.read_only();
+        assert_eq!(r.get("a").expect("fail get"), 1);
+        b.put("a", 2).expect("failed to save");
+        assert_eq!(r.get("a").expect("fail get"), 2);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// recovering from panics
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Escape hatch for a bucket that may have been left in a bad state by a
+    /// panic (or a crash) in the middle of a write.
+    ///
+    /// fsdb already recovers on its own from panics in this process: a value is
+    /// only ever renamed into place once fully written, a panicked write's temp
+    /// file is removed, key locks are released and don't stay poisoned, and the
+    /// manifest is rebuilt if a panic happened while it was locked. This also
+    /// clears poisoning from every lock on the bucket, rebuilds its manifest (if it
+    /// has one) from the directory, and removes temp files left by writes that
+    /// never finished, such as those of a process that died.
+    ///
+    /// Only call it while nothing else is writing to the bucket, since the temp
+    /// files of writes in progress would be removed too
+    pub fn recover_locks(&self) -> Result<()> {
+        let ctx = || Context::new(Op::Open, &self.dir, None);
+        self.state.clear_poison();
+        if let Some(m) = self.state.manifest().as_mut() {
+            m.rebuild().ctx(ctx)?;
+        }
+        remove_temp_files(&self.dir).ctx(ctx)
+    }
+}
+
+// temp files are hidden, and named `.<name>.<...>.tmp`
+fn remove_temp_files(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && name.ends_with(".tmp") && entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Fsdb, Version};
+    use serde::{Deserialize, Serialize, Serializer};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    // a value that panics while being written, if asked to
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Boom(bool);
+
+    impl Serialize for Boom {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            if self.0 {
+                panic!("boom");
+            }
+            s.serialize_newtype_struct("Boom", &self.0)
+        }
+    }
+
+    fn hidden_files(dir: &std::path::Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .expect("fail read_dir")
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| n.starts_with('.') && n.ends_with(".tmp"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_panic_mid_put() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Boom>("boom").expect("fail bucket");
+        b.enable_manifest().expect("fail manifest");
+        let v1 = b
+            .put_versioned("a", Boom(false), Version::default())
+            .expect("failed to save");
+        // panics while holding the key lock
+        let res = catch_unwind(AssertUnwindSafe(|| b.put_versioned("a", Boom(true), v1)));
+        assert!(res.is_err());
+
+        // the old value is intact, and the key can still be written
+        assert_eq!(b.get("a").expect("fail get"), Boom(false));
+        b.put_versioned("a", Boom(false), v1)
+            .expect("failed to save");
+        assert_eq!(b.list().expect("fail list"), vec!["a"]);
+        assert!(hidden_files(&db.path().join("boom")).is_empty());
+    }
+
+    #[test]
+    fn test_recover_locks() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("recover").expect("fail bucket");
+        b.enable_manifest().expect("fail manifest");
+        b.put("a", 1).expect("failed to save");
+        // a write by a process that died, and a key added behind the manifest's back
+        let dir = db.path().join("recover");
+        std::fs::write(dir.join(".b.123-0.tmp"), b"partial").expect("fail write");
+        std::fs::copy(dir.join("a"), dir.join("c")).expect("fail copy");
+        assert_eq!(b.list().expect("fail list"), vec!["a"]);
+
+        b.recover_locks().expect("fail recover_locks");
+        let mut keys = b.list().expect("fail list");
+        keys.sort();
+        assert_eq!(keys, vec!["a", "c"]);
+        assert!(hidden_files(&dir).is_empty());
+        assert_eq!(b.get("c").expect("fail get"), 1);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::changelog::{self, ChangeRecord, CHANGES_DIR, CHECKPOINTS, VALUES};
+use crate::error::WithContext;
+use crate::meta;
+use crate::snapshot::{link_or_copy, link_tree};
+use crate::state::BucketState;
+use crate::{generation, Context, Error, Fsdb, Op, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+// point-in-time restore
+impl Fsdb {
+    /// Put the database back the way it was at `at`, from the latest change log
+    /// checkpoint before then, replaying the changes logged since. The change log
+    /// must be keeping values (see `ChangeLogOptions::keep_values`), and `at` must
+    /// be within its retention.
+    ///
+    /// Keys are written and removed as though by hand, so indexes, views and so
+    /// on are kept up to date, and the restore is itself logged. Each top-level
+    /// directory is taken to be a bucket. Writes during the restore may be lost
+    pub fn restore_to(&self, at: SystemTime) -> Result<()> {
+        let ctx = || Context::new(Op::Restore, &self.dir, None);
+        let until = changelog::nanos(at);
+        let changes = self.dir.join(CHANGES_DIR);
+        let checkpoints = changelog::numbered(&changes.join(CHECKPOINTS), "").ctx(ctx)?;
+        let Some((base, checkpoint)) = checkpoints.into_iter().rev().find(|(c, _)| *c <= until)
+        else {
+            return Err(Error::Io {
+                ctx: ctx(),
+                source: io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no change log checkpoint that early",
+                ),
+            });
+        };
+        let now = changelog::nanos(SystemTime::now());
+        let work = changes.join(format!(".restore-{}-{}.tmp", std::process::id(), now));
+        let restored = (|| {
+            link_tree(&checkpoint, &work).ctx(ctx)?;
+            for record in self.changes_since(base)? {
+                let record = record?;
+                if record.seq > until {
+                    break;
+                }
+                replay(&changes, &work, &record).ctx(ctx)?;
+            }
+            for name in dir_names(&self.dir)?.union(&dir_names(&work)?) {
+                let bucket = self.dir.join(name);
+                fs::create_dir_all(&bucket).ctx(ctx)?;
+                let state = self.bucket_state(&bucket)?;
+                apply(&state, &bucket, &work.join(name), true).ctx(ctx)?;
+                generation::bump(&bucket).ctx(ctx)?;
+            }
+            Ok(())
+        })();
+        let _ = fs::remove_dir_all(&work);
+        restored
+    }
+}
+
+// the (non-fsdb) directories in `dir`
+fn dir_names(dir: &Path) -> Result<BTreeSet<String>> {
+    let entries = fs::read_dir(dir).ctx(|| Context::new(Op::Restore, dir, None))?;
+    Ok(entries
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|name| !name.starts_with('.'))
+        .collect())
+}
+
+// apply a logged change to the copy of the database in `work`
+fn replay(changes: &Path, work: &Path, record: &ChangeRecord) -> io::Result<()> {
+    let bucket = work.join(&record.bucket);
+    match (record.op, &record.key) {
+        (Op::Put, Some(key)) => {
+            let value = changes.join(VALUES).join(format!("{:020}", record.seq));
+            if !value.exists() {
+                let msg = format!("the value written by change {} wasn't kept", record.seq);
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+            }
+            fs::create_dir_all(&bucket)?;
+            remove_if_exists(&bucket.join(key))?;
+            link_or_copy(&value, &bucket.join(key))
+        }
+        (Op::Remove, Some(key)) => remove_if_exists(&bucket.join(key)),
+        (Op::Clear, _) if bucket.is_dir() => {
+            for entry in fs::read_dir(&bucket)?.flatten() {
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                match entry.file_type()?.is_dir() {
+                    true => fs::remove_dir_all(entry.path())?,
+                    false => fs::remove_file(entry.path())?,
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// make the bucket directory `live` match `target`, moving files out of `target`
+fn apply(state: &BucketState, live: &Path, target: &Path, top_level: bool) -> io::Result<()> {
+    for entry in fs::read_dir(live)?.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let wanted = target.join(&name);
+        if entry.file_type()?.is_dir() {
+            match wanted.is_dir() {
+                true => apply(state, &entry.path(), &wanted, false)?,
+                false => fs::remove_dir_all(entry.path())?,
+            }
+        } else if !wanted.is_file() {
+            let _lock = state.key_lock(&entry.path());
+            match state.uninstall(live, &name, top_level, None) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+    }
+    if top_level {
+        for name in state.packed_keys() {
+            if !target.join(&name).is_file() {
+                let _lock = state.key_lock(&live.join(&name));
+                state.uninstall(live, &name, true, None)?;
+            }
+        }
+    }
+    if !target.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(target)?.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = live.join(&name);
+        if entry.file_type()?.is_dir() {
+            if !path.exists() {
+                fs::create_dir(&path)?;
+                apply(state, &path, &entry.path(), false)?;
+            }
+            continue;
+        }
+        let current = match top_level {
+            true => state.stored_hash(live, &name)?,
+            false => changelog::hash_file(&path)?,
+        };
+        if current == changelog::hash_file(&entry.path())? {
+            continue;
+        }
+        let _lock = state.key_lock(&path);
+        let version = match top_level && meta::tracking(live) {
+            true => Some(state.current_version(live, &name, true)?.next()),
+            false => None,
+        };
+        state.install(live, &name, &entry.path(), version, top_level, None)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ChangeLogOptions, Fsdb};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn keeping(retention: Duration, segment_size: u64) -> ChangeLogOptions {
+        ChangeLogOptions {
+            retention,
+            segment_size,
+            keep_values: true,
+        }
+    }
+
+    fn contents(b: &crate::Bucket<u8>) -> Vec<(String, u8)> {
+        let mut keys = b.list().expect("fail list");
+        keys.sort();
+        keys.into_iter()
+            .map(|k| {
+                let v = b.get(&k).expect("fail get");
+                (k, v)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_restore_to() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        b.put("before", 0).expect("failed to save");
+        db.enable_change_log(keeping(Duration::from_secs(3600), 4 << 20))
+            .expect("fail enable_change_log");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        let then = SystemTime::now();
+        b.put("a", 3).expect("failed to save");
+        b.remove("b").expect("fail remove");
+        b.put("c", 4).expect("failed to save");
+        let others = db.bucket::<u8>("others").expect("fail bucket");
+        others.put("x", 5).expect("failed to save");
+        let now = SystemTime::now();
+        b.clear().expect("fail clear");
+
+        db.restore_to(then).expect("fail restore_to");
+        let expected = vec![("a".into(), 1), ("b".into(), 2), ("before".into(), 0)];
+        assert_eq!(contents(&b), expected);
+        assert!(contents(&others).is_empty());
+
+        // the restore can itself be undone
+        db.restore_to(now).expect("fail restore_to");
+        let expected = vec![("a".into(), 3), ("before".into(), 0), ("c".into(), 4)];
+        assert_eq!(contents(&b), expected);
+        assert_eq!(contents(&others), vec![("x".into(), 5)]);
+
+        assert!(db.restore_to(UNIX_EPOCH).is_err());
+    }
+
+    #[test]
+    fn test_restore_retention() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        db.enable_change_log(keeping(Duration::ZERO, 1))
+            .expect("fail enable_change_log");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        let early = SystemTime::now();
+        for i in 2..5 {
+            b.put("a", i).expect("failed to save");
+        }
+        b.put("b", 9).expect("failed to save");
+        // only the latest checkpoint, and what's needed after it, are kept
+        assert!(db.restore_to(early).is_err());
+        db.restore_to(SystemTime::now()).expect("fail restore_to");
+        assert_eq!(contents(&b), vec![("a".into(), 4), ("b".into(), 9)]);
+    }
+
+    #[test]
+    fn test_restore_packed() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        b.enable_packed().expect("fail enable_packed");
+        b.put("before", 0).expect("failed to save");
+        db.enable_change_log(keeping(Duration::from_secs(3600), 4 << 20))
+            .expect("fail enable_change_log");
+        let b = db.bucket::<u8>("things").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        let then = SystemTime::now();
+        b.put("a", 2).expect("failed to save");
+        b.put("c", 3).expect("failed to save");
+        b.remove("before").expect("fail remove");
+
+        db.restore_to(then).expect("fail restore_to");
+        assert_eq!(contents(&b), vec![("a".into(), 1), ("before".into(), 0)]);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::Bucket;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How to retry filesystem operations that fail with a transient error (such as
+/// `EAGAIN`, `EBUSY`, `EINTR`, timeouts or stale NFS handles), waiting
+/// `initial_backoff` before the first retry and doubling up to `max_backoff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run `f`, retrying it while it fails with a transient error
+    pub(crate) fn run<T>(&self, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match f() {
+                Err(e) if retries < self.max_retries && transient(&e) => {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+pub(crate) fn transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        Interrupted | WouldBlock | TimedOut | ResourceBusy | StaleNetworkFileHandle
+    )
+}
+
+/// Spaces out the values visited by a maintenance scan, from `Bucket::pacer`
+pub(crate) struct Pacer {
+    every: Option<Duration>,
+    next: Instant,
+}
+
+impl Pacer {
+    /// Wait (if needed) before visiting the next value
+    pub(crate) fn tick(&mut self) {
+        let Some(every) = self.every else {
+            return;
+        };
+        let now = Instant::now();
+        if self.next > now {
+            std::thread::sleep(self.next - now);
+        }
+        self.next = self.next.max(now) + every;
+    }
+}
+
+impl<V> Bucket<V> {
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry
+            .or_else(|| self.state.network_fs().then(RetryPolicy::default))
+    }
+}
+
+// resilience settings
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Retry reads, listings and writes through this handle that fail with a
+    /// transient error, as is common on network filesystems
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = Some(policy);
+    }
+    /// Limit maintenance scans through this handle (`check_compat`, `rotate_key`,
+    /// `retain` and `remove_where`) to visiting `per_second` values a second, so
+    /// they don't starve other users of a shared filesystem
+    pub fn set_scan_rate_limit(&mut self, per_second: u32) {
+        self.scan_rate = Some(per_second);
+    }
+    /// Give up on reads, listings and writes through this handle that take longer
+    /// than `timeout` (including retries), with `Error::Timeout`. Each one then runs
+    /// on a watchdog thread, since a blocked filesystem call can't be interrupted:
+    /// a timed out operation carries on in the background, and a write that
+    /// finishes late leaves a temp file behind (see `recover_locks`), never a value
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+    // run a filesystem operation under this handle's retry policy, if it has one.
+    // Buckets in network filesystem mode retry with the default policy otherwise
+    pub(crate) fn retrying<T>(&self, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        match self.retry_policy() {
+            Some(policy) => policy.run(f),
+            None => f(),
+        }
+    }
+    // like `retrying`, but on a watchdog thread if this handle has a timeout
+    pub(crate) fn timed<T: Send + 'static>(
+        &self,
+        mut f: impl FnMut() -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let Some(timeout) = self.timeout else {
+            return self.retrying(f);
+        };
+        let policy = self.retry_policy();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let res = match policy {
+                Some(policy) => policy.run(f),
+                None => f(),
+            };
+            // nobody's listening if it timed out
+            let _ = tx.send(res);
+        });
+        rx.recv_timeout(timeout).unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no response within {:?}", timeout),
+            ))
+        })
+    }
+    pub(crate) fn pacer(&self) -> Pacer {
+        Pacer {
+            every: self.scan_rate.map(|n| Duration::from_secs(1) / n.max(1)),
+            next: Instant::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use crate::{Error, Fsdb};
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        };
+        let mut calls = 0;
+        let res = policy.run(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(io::Error::from(io::ErrorKind::ResourceBusy))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+        calls = 0;
+        let res: io::Result<()> = policy.run(|| {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_scan_rate_limit() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<u8>("paced").expect("fail bucket");
+        b.clear().expect("fail clear");
+        for k in ["a", "b", "c", "d"] {
+            b.put(k, 1).expect("failed to save");
+        }
+        b.set_scan_rate_limit(40);
+        let start = Instant::now();
+        assert_eq!(b.retain(|_, _| true).expect("fail retain"), 0);
+        // the first value is visited straight away, then one every 25ms
+        assert!(start.elapsed() >= Duration::from_millis(75));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<u8>("slow").expect("fail bucket");
+        b.put("fast", 1).expect("failed to save");
+        // opening a fifo blocks until there's a writer, like a hung file server
+        let fifo = db.path().join("slow/hung");
+        let c_path = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        b.set_timeout(Duration::from_millis(50));
+        assert_eq!(b.get("fast").expect("fail get"), 1);
+        let start = Instant::now();
+        let err = b.get("hung").unwrap_err();
+        assert!(matches!(err, Error::Timeout { .. }));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // unblock the watchdog's reader
+        drop(std::fs::OpenOptions::new().write(true).open(&fifo));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::{Bucket, BucketOptions, Context, Error, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+
+// points on the ring per unit of weight, so roots get shares close to their weights
+const POINTS_PER_WEIGHT: u32 = 64;
+
+/// A database spread over several root directories (one per disk, say), from
+/// `Fsdb::multi_root`. Each bucket lives on one root, picked by consistent
+/// hashing of its name, so adding a root moves only the buckets (or keys, for
+/// a `SpreadBucket`) that now hash to it. Roots are placed on the ring by their
+/// path, so open them with the same paths each time
+pub struct MultiRoot {
+    roots: Vec<Fsdb>,
+    // (point, index into roots), sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+/// A bucket whose keys are spread over every root of a `MultiRoot`, from
+/// `MultiRoot::spread_bucket`, for one bucket too big for a single disk
+pub struct SpreadBucket<V> {
+    shards: Vec<Bucket<V>>,
+    ring: Vec<(u64, usize)>,
+}
+
+impl<V> Clone for SpreadBucket<V> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            ring: self.ring.clone(),
+        }
+    }
+}
+
+impl Fsdb {
+    /// Open a database spread evenly over several root directories, creating
+    /// them if needed. See `MultiRoot`
+    pub fn multi_root(dirs: &[&str]) -> Result<MultiRoot> {
+        let weighted: Vec<_> = dirs.iter().map(|d| (*d, 1)).collect();
+        Self::multi_root_weighted(&weighted)
+    }
+    /// Open a database spread over several root directories, each given a
+    /// share of buckets in proportion to its weight (its capacity, say)
+    pub fn multi_root_weighted(roots: &[(&str, u32)]) -> Result<MultiRoot> {
+        if roots.iter().all(|(_, w)| *w == 0) {
+            return Err(Error::Io {
+                ctx: Context::new(Op::Open, roots.first().map_or("", |(d, _)| *d), None),
+                source: io::Error::new(io::ErrorKind::InvalidInput, "no root with a weight"),
+            });
+        }
+        let mut ring = Vec::new();
+        for (i, (dir, weight)) in roots.iter().enumerate() {
+            for n in 0..weight * POINTS_PER_WEIGHT {
+                ring.push((fnv(&format!("{}#{}", dir, n)), i));
+            }
+        }
+        ring.sort_unstable();
+        let roots = roots
+            .iter()
+            .map(|(dir, _)| Fsdb::new(dir))
+            .collect::<Result<_>>()?;
+        Ok(MultiRoot { roots, ring })
+    }
+}
+
+impl MultiRoot {
+    /// The root a bucket (or a `SpreadBucket` key) named `name` lives on
+    pub fn root_for(&self, name: &str) -> &Fsdb {
+        &self.roots[locate(&self.ring, name)]
+    }
+    /// Every root, in the order they were given
+    pub fn roots(&self) -> &[Fsdb] {
+        &self.roots
+    }
+    /// Create or open a bucket, on the root its name hashes to
+    pub fn bucket<V: Serialize + DeserializeOwned>(&self, p: &str) -> Result<Bucket<V>> {
+        self.root_for(p).bucket(p)
+    }
+    /// Create or open a bucket with settings, on the root its name hashes to.
+    /// See `Fsdb::bucket_with`
+    pub fn bucket_with<V: Serialize + DeserializeOwned>(
+        &self,
+        p: &str,
+        options: BucketOptions,
+    ) -> Result<Bucket<V>> {
+        self.root_for(p).bucket_with(p, options)
+    }
+    /// Create or open a bucket on every root, with each key stored on the root
+    /// it hashes to
+    pub fn spread_bucket<V: Serialize + DeserializeOwned>(
+        &self,
+        p: &str,
+    ) -> Result<SpreadBucket<V>> {
+        let shards = self
+            .roots
+            .iter()
+            .map(|root| root.bucket(p))
+            .collect::<Result<_>>()?;
+        Ok(SpreadBucket {
+            shards,
+            ring: self.ring.clone(),
+        })
+    }
+    /// Close every root. See `Fsdb::close`
+    pub fn close(self) -> Result<()> {
+        let mut res = Ok(());
+        for root in self.roots {
+            let r = root.close();
+            if res.is_ok() {
+                res = r;
+            }
+        }
+        res
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> SpreadBucket<V> {
+    /// The bucket on the root a key hashes to
+    pub fn shard(&self, key: &str) -> &Bucket<V> {
+        &self.shards[locate(&self.ring, key)]
+    }
+    /// Save a value
+    pub fn put(&self, key: &str, value: V) -> Result<()> {
+        self.shard(key).put(key, value)
+    }
+    /// Save a borrowed value
+    pub fn put_ref(&self, key: &str, value: &V) -> Result<()> {
+        self.shard(key).put_ref(key, value)
+    }
+    /// Load a value
+    pub fn get(&self, key: &str) -> Result<V> {
+        self.shard(key).get(key)
+    }
+    /// Remove a value
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.shard(key).remove(key)
+    }
+    /// Check if a key exists
+    pub fn exists(&self, key: &str) -> bool {
+        self.shard(key).exists(key)
+    }
+    /// Every key, on every root, sorted
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.list()?);
+        }
+        keys.sort();
+        Ok(keys)
+    }
+    /// Number of keys, on every root
+    pub fn len(&self) -> Result<usize> {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+    /// Check if no root has any keys
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Remove every key, on every root
+    pub fn clear(&self) -> Result<()> {
+        self.shards.iter().try_for_each(|s| s.clear())
+    }
+}
+
+// the first point on the ring at or after the name's hash, wrapping around
+fn locate(ring: &[(u64, usize)], name: &str) -> usize {
+    let hash = fnv(name);
+    let at = ring.partition_point(|(point, _)| *point < hash);
+    ring[at % ring.len()].1
+}
+
+// FNV-1a, stable across processes and versions so names keep their roots, with
+// a final mix, as names differing only at the end barely move its high bits
+fn fnv(s: &str) -> u64 {
+    let mut h = s.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+
+    #[test]
+    fn test_multi_root() {
+        let dbs: Vec<_> = (0..3)
+            .map(|_| Fsdb::temp().expect("fail Fsdb::temp"))
+            .collect();
+        let dirs: Vec<String> = dbs
+            .iter()
+            .map(|db| db.path().to_string_lossy().into_owned())
+            .collect();
+        let paths: Vec<&str> = dirs.iter().map(|d| d.as_str()).collect();
+        let multi = Fsdb::multi_root(&paths).expect("fail multi_root");
+
+        // a bucket lives on one root
+        let b = multi.bucket::<u32>("users").expect("fail bucket");
+        b.put("ann", 1).expect("failed to save");
+        let again = multi.root_for("users").bucket::<u32>("users");
+        assert_eq!(again.expect("fail bucket").get("ann").expect("fail get"), 1);
+
+        // a spread bucket's keys land on every root
+        let s = multi
+            .spread_bucket::<u32>("events")
+            .expect("fail spread_bucket");
+        for i in 0..60 {
+            s.put(&format!("k{}", i), i).expect("failed to save");
+        }
+        assert_eq!(s.len().expect("fail len"), 60);
+        assert_eq!(s.get("k7").expect("fail get"), 7);
+        for db in &dbs {
+            let n = db.bucket::<u32>("events").expect("fail bucket").len();
+            assert!(n.expect("fail len") > 0);
+        }
+        s.remove("k7").expect("fail remove");
+        assert!(!s.exists("k7"));
+
+        // reopened with another root, most keys stay where they were
+        let extra = Fsdb::temp().expect("fail Fsdb::temp");
+        let extra_dir = extra.path().to_string_lossy().into_owned();
+        let more = Fsdb::multi_root_weighted(&[
+            (paths[0], 1),
+            (paths[1], 1),
+            (paths[2], 1),
+            (&extra_dir, 1),
+        ])
+        .expect("fail multi_root");
+        let s2 = more
+            .spread_bucket::<u32>("events")
+            .expect("fail spread_bucket");
+        let found = (0..60).filter(|i| *i != 7 && s2.exists(&format!("k{}", i)));
+        assert!(found.count() > 30);
+
+        assert!(Fsdb::multi_root(&[]).is_err());
+        multi.close().expect("fail close");
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::BucketConfig;
+use crate::error::WithContext;
+use crate::{Bucket, Context, Error, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The source of the io error a write to a sealed bucket fails with, which
+/// `WithContext` turns into `Error::Sealed`
+#[derive(Debug)]
+pub(crate) struct Sealed;
+
+impl fmt::Display for Sealed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("bucket is sealed")
+    }
+}
+
+impl std::error::Error for Sealed {}
+
+// immutable buckets
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Make this bucket immutable, for a finished dataset: every write from
+    /// then on (puts, removes, clears, transactions, compaction and so on)
+    /// fails with `Error::Sealed`. Waits for writes in progress to finish.
+    ///
+    /// The flag is saved with the bucket, so it applies to every handle,
+    /// including ones other processes open later, and write permission is
+    /// taken off its value files, so nothing can change them in place. The
+    /// directory itself stays writable, for the bucket's own bookkeeping (its
+    /// generation, for one). Packed values are guarded by the flag alone
+    pub fn seal(&self) -> Result<()> {
+        let ctx = || Context::new(Op::Bucket, &self.dir, None);
+        let _guard = self.state.exclusive_guard();
+        BucketConfig::update(&self.dir, |config| {
+            config.sealed = true;
+            Ok(())
+        })
+        .ctx(ctx)?;
+        self.state.set_sealed(true);
+        set_values_writable(&self.dir, false).ctx(ctx)
+    }
+    /// Make a sealed bucket writable again. Only does so with `force`, so it
+    /// can't happen by accident: without it, returns `Error::Sealed`
+    pub fn unseal(&self, force: bool) -> Result<()> {
+        let ctx = || Context::new(Op::Bucket, &self.dir, None);
+        if !force {
+            return Err(Error::Sealed { ctx: ctx() });
+        }
+        let _guard = self.state.exclusive_guard();
+        set_values_writable(&self.dir, true).ctx(ctx)?;
+        BucketConfig::update(&self.dir, |config| {
+            config.sealed = false;
+            Ok(())
+        })
+        .ctx(ctx)?;
+        self.state.set_sealed(false);
+        Ok(())
+    }
+    /// Check if this bucket is sealed
+    pub fn is_sealed(&self) -> bool {
+        self.state.sealed()
+    }
+}
+
+// take write permission off every value file under `dir` (or give the owner
+// it back), leaving fsdb's own files, whose names start with '.', alone
+fn set_values_writable(dir: &Path, on: bool) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            set_values_writable(&entry.path(), on)?;
+        } else if file_type.is_file() {
+            set_writable(&entry.path(), on)?;
+        }
+    }
+    Ok(())
+}
+
+// take write permission off a file (or give the owner it back)
+fn set_writable(path: &Path, on: bool) -> io::Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = perms.mode();
+        perms.set_mode(if on { mode | 0o200 } else { mode & !0o222 });
+    }
+    #[cfg(not(unix))]
+    perms.set_readonly(!on);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ErrorKind, Fsdb};
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_seal() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("final").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        b.seal().expect("fail seal");
+        assert!(b.is_sealed());
+
+        let e = b.put("b", 2).expect_err("put to a sealed bucket");
+        assert_eq!(e.code(), "fsdb::sealed");
+        assert_eq!(e.kind(), ErrorKind::ReadOnly);
+        assert!(b.remove("a").is_err());
+        assert!(b.clear().is_err());
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        // value files can't be changed in place, though fsdb's own files can
+        let dir = db.path().join("final");
+        let readonly = |name: &str| {
+            let meta = fs::metadata(dir.join(name)).expect("fail metadata");
+            meta.permissions().readonly()
+        };
+        assert!(readonly("a"));
+        assert!(!readonly(".config"));
+
+        // sealed for every handle, including ones opened later
+        let other = db.bucket::<u32>("final").expect("fail bucket");
+        assert!(other.is_sealed());
+        let mut txn = db.transaction().expect("fail transaction");
+        txn.put(&other, "c", 3).expect("fail stage");
+        assert!(txn.commit().is_err());
+
+        assert!(b.unseal(false).is_err());
+        assert!(b.is_sealed());
+        b.unseal(true).expect("fail unseal");
+        assert!(!readonly("a"));
+        b.put("b", 2).expect("failed to save");
+        assert_eq!(other.get("b").expect("fail get"), 2);
+    }
+
+    #[test]
+    fn test_seal_bookkeeping() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("data").expect("fail bucket");
+        let next = db.bucket::<u32>("data-next").expect("fail bucket");
+        b.seal().expect("fail seal");
+        next.seal().expect("fail seal");
+        // moving an alias between sealed buckets still bumps their generations
+        db.alias("current", "data").expect("fail alias");
+        db.swap_alias("current", "data-next")
+            .expect("fail swap_alias");
+        assert_eq!(b.generation().expect("fail generation"), 1);
+        assert_eq!(next.generation().expect("fail generation"), 1);
+    }
+
+    #[test]
+    fn test_seal_packed() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("packed").expect("fail bucket");
+        b.enable_packed().expect("fail enable_packed");
+        b.set_default_ttl(Duration::from_secs(60))
+            .expect("fail set_default_ttl");
+        b.put("a", 1).expect("failed to save");
+        b.seal().expect("fail seal");
+        let e = b.touch("a").expect_err("touch a sealed bucket");
+        assert_eq!(e.code(), "fsdb::sealed");
+        assert!(b.persist("a").is_err());
+        assert!(b.expire_at("a", SystemTime::now()).is_err());
+        assert_eq!(b.get("a").expect("fail get"), 1);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// File (inside a bucket) holding its change sequence number, once enabled
+pub(crate) const SEQ: &str = ".seq";
+
+const POLL_MIN: Duration = Duration::from_millis(5);
+const POLL_MAX: Duration = Duration::from_millis(100);
+
+/// The bucket's change sequence number, or 0 if it isn't kept
+pub(crate) fn read(dir: &Path) -> io::Result<u64> {
+    match fs::read(dir.join(SEQ)) {
+        Ok(buf) => {
+            let bytes = buf
+                .try_into()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "corrupt sequence file"))?;
+            Ok(u64::from_le_bytes(bytes))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Bump the change sequence number, if the bucket keeps one. Numbers are
+/// timestamps (or one more than the last, if greater), so writers in separate
+/// processes racing to bump it still end up with a new number
+pub(crate) fn bump(dir: &Path) -> io::Result<()> {
+    if !dir.join(SEQ).exists() {
+        return Ok(());
+    }
+    write(dir, next(read(dir)?))
+}
+
+fn next(seq: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    now.max(seq + 1)
+}
+
+fn write(dir: &Path, seq: u64) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.{}.tmp", SEQ, std::process::id()));
+    fs::write(&tmp, seq.to_le_bytes())?;
+    fs::rename(tmp, dir.join(SEQ))
+}
+
+// change sequence numbers, for other processes to notice writes
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Keep a change sequence number for this bucket (in a `.seq` file), which
+    /// every write to a top-level key increases. Any process sharing the directory
+    /// can then check for changes with `change_seq` or `wait_for_change`
+    pub fn enable_change_seq(&self) -> Result<()> {
+        let ctx = || Context::new(Op::Open, &self.dir, None);
+        let _lock = self.state.seq_lock();
+        if self.dir.join(SEQ).exists() {
+            return Ok(());
+        }
+        write(&self.dir, next(0)).ctx(ctx)
+    }
+    /// The current change sequence number, or 0 if the bucket doesn't keep one
+    pub fn change_seq(&self) -> Result<u64> {
+        read(&self.dir).ctx(|| Context::new(Op::Get, &self.dir, None))
+    }
+    /// Wait until the change sequence number is greater than `since`, and return it,
+    /// or return `None` if that doesn't happen within `timeout`. Polls the sequence
+    /// file, backing off from 5ms to 100ms between checks
+    pub fn wait_for_change(&self, since: u64, timeout: Duration) -> Result<Option<u64>> {
+        let deadline = Instant::now() + timeout;
+        let mut pause = POLL_MIN;
+        loop {
+            let seq = self.change_seq()?;
+            if seq > since {
+                return Ok(Some(seq));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            std::thread::sleep(pause.min(deadline - now));
+            pause = (pause * 2).min(POLL_MAX);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use std::time::Duration;
+
+    #[test]
+    fn test_wait_for_change() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("seq").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.enable_change_seq().expect("fail enable");
+        let start = b.change_seq().expect("fail seq");
+        assert!(start > 0);
+        let short = Duration::from_millis(20);
+        assert_eq!(b.wait_for_change(start, short).expect("fail wait"), None);
+
+        let writer = b.clone();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(30));
+            writer.put("a", 1).expect("failed to save");
+        });
+        let seq = b
+            .wait_for_change(start, Duration::from_secs(5))
+            .expect("fail wait")
+            .expect("no change seen");
+        assert!(seq > start);
+        t.join().unwrap();
+        b.remove("a").expect("fail remove");
+        assert!(b.change_seq().expect("fail seq") > seq);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Fsdb, Op, Result};
+use std::fs;
+use std::io;
+
+/// A persistent set of strings, from `Fsdb::set`. Each member is an empty marker
+/// file named after it, so adding, removing and checking a member are single
+/// file operations with nothing to encode, and members written by any thread or
+/// process are seen at once.
+///
+/// Members can hold any characters, but like keys they're limited by the
+/// filesystem's maximum file name length
+pub struct PersistentSet {
+    inner: Bucket<()>,
+}
+
+impl Clone for PersistentSet {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Fsdb {
+    /// Create or open a set. See `PersistentSet`
+    pub fn set(&self, p: &str) -> Result<PersistentSet> {
+        let (dir, state) = self.open_bucket_dir(p, std::any::type_name::<PersistentSet>())?;
+        Ok(PersistentSet {
+            inner: Bucket::with_state(dir, state),
+        })
+    }
+}
+
+impl PersistentSet {
+    /// Add a member. Returns false if it was already there
+    pub fn add(&self, member: &str) -> Result<bool> {
+        let ctx = || Context::new(Op::Put, &self.inner.dir, Some(member));
+        let _guard = self.inner.state.write_guard();
+        let path = self.inner.dir.join(escape(member));
+        let added = match fs::File::create_new(path) {
+            Ok(_) => true,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => false,
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        self.inner.make_durable(&self.inner.dir).ctx(ctx)?;
+        Ok(added)
+    }
+    /// Check if a member is in the set
+    pub fn contains(&self, member: &str) -> bool {
+        self.inner.dir.join(escape(member)).is_file()
+    }
+    /// Remove a member. Returns false if it wasn't there
+    pub fn remove(&self, member: &str) -> Result<bool> {
+        let ctx = || Context::new(Op::Remove, &self.inner.dir, Some(member));
+        let _guard = self.inner.state.write_guard();
+        let removed = match fs::remove_file(self.inner.dir.join(escape(member))) {
+            Ok(()) => true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        self.inner.make_durable(&self.inner.dir).ctx(ctx)?;
+        Ok(removed)
+    }
+    /// Every member, sorted
+    pub fn iter(&self) -> Result<std::vec::IntoIter<String>> {
+        let mut members: Vec<String> = self
+            .inner
+            .fs_keys(&self.inner.dir)?
+            .iter()
+            .map(|name| unescape(name))
+            .collect();
+        members.sort();
+        Ok(members.into_iter())
+    }
+    /// Number of members
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.inner.fs_keys(&self.inner.dir)?.len())
+    }
+    /// Check if the set has no members
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Add every member of `other` to this set. Returns the number that weren't already here
+    pub fn union_with(&self, other: &PersistentSet) -> Result<usize> {
+        let mut added = 0;
+        for member in other.iter()? {
+            if self.add(&member)? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+    /// Remove every member
+    pub fn clear(&self) -> Result<()> {
+        self.inner.fs_clear(&self.inner.dir)
+    }
+}
+
+/// Make any string usable as a file name that fsdb won't mistake for its own:
+/// `%`, path separators and NUL are percent-escaped, as is a leading `.`, and
+/// the empty string becomes `%`
+pub(crate) fn escape(s: &str) -> String {
+    if s.is_empty() {
+        return "%".to_owned();
+    }
+    let mut name = String::with_capacity(s.len());
+    for (i, c) in s.chars().enumerate() {
+        match c {
+            '%' | '/' | '\\' | '\0' => name.push_str(&format!("%{:02X}", c as u32)),
+            '.' if i == 0 => name.push_str("%2E"),
+            c => name.push(c),
+        }
+    }
+    name
+}
+
+/// Undo `escape`
+pub(crate) fn unescape(name: &str) -> String {
+    if name == "%" {
+        return String::new();
+    }
+    let mut s = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(i) = rest.find('%') {
+        s.push_str(&rest[..i]);
+        let escaped = rest
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                s.push(byte as char);
+                rest = &rest[i + 3..];
+            }
+            None => {
+                s.push('%');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    s.push_str(rest);
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, unescape};
+    use crate::Fsdb;
+
+    #[test]
+    fn test_set() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let s = db.set("seen").expect("fail set");
+        assert!(s.add("a").expect("fail add"));
+        assert!(!s.add("a").expect("fail add"));
+        assert!(s.add(".hidden/path%").expect("fail add"));
+        assert!(s.add("").expect("fail add"));
+        assert!(s.contains("a"));
+        assert!(s.contains(".hidden/path%"));
+        assert!(!s.contains("b"));
+        assert_eq!(s.len().expect("fail len"), 3);
+        assert_eq!(
+            s.iter().expect("fail iter").collect::<Vec<_>>(),
+            vec!["", ".hidden/path%", "a"]
+        );
+        assert!(s.remove("a").expect("fail remove"));
+        assert!(!s.remove("a").expect("fail remove"));
+        assert!(!s.contains("a"));
+
+        let other = db.set("more").expect("fail set");
+        other.add("a").expect("fail add");
+        other.add("").expect("fail add");
+        assert_eq!(s.union_with(&other).expect("fail union"), 1);
+        assert_eq!(s.len().expect("fail len"), 3);
+        s.clear().expect("fail clear");
+        assert!(s.is_empty().expect("fail is_empty"));
+    }
+
+    #[test]
+    fn test_escape() {
+        for s in ["", "plain", ".dot", "a/b\\c", "100%", "%41", "a.b", "ünï"] {
+            let name = escape(s);
+            assert!(!name.starts_with('.') && !name.contains('/'));
+            assert_eq!(unescape(&name), s);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::durable::sync_dir;
+use crate::error::WithContext;
+use crate::state::BucketState;
+use crate::{Context, Fsdb, Op, Result};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::PoisonError;
+
+impl Fsdb {
+    /// Shut down cleanly, reporting anything that went wrong rather than leaving
+    /// it to `Drop`:
+    ///
+    /// - background threads started from this `Fsdb` stop: space monitors, and
+    ///   elections, whose leases are released (so `is_leader` turns false)
+    /// - every write-behind buffer on a bucket in this database is flushed, and so
+    ///   are reads recorded by access tracking
+    /// - fsdb's own files (manifests, configs, sequence numbers and version
+    ///   metadata) and every bucket directory are flushed to disk
+    ///
+    /// Everything is attempted even if something fails; the first error is returned
+    pub fn close(self) -> Result<()> {
+        self.maintenance.stop();
+        let mut res = Ok(());
+        let mut keep = |r: Result<()>| {
+            if res.is_ok() {
+                res = r;
+            }
+        };
+        for (dir, state) in BucketState::under(&self.dir) {
+            let buffers = state
+                .buffers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            for buffer in buffers.iter().filter_map(|b| b.upgrade()) {
+                keep(buffer.flush());
+            }
+            if let Some(access) = state.access().as_mut() {
+                keep(access.flush().ctx(|| Context::new(Op::Close, &dir, None)));
+            }
+            keep(sync_metadata(&dir).ctx(|| Context::new(Op::Close, &dir, None)));
+        }
+        keep(sync_dir(&self.dir).ctx(|| Context::new(Op::Close, &self.dir, None)));
+        res
+    }
+}
+
+// flush fsdb's hidden files and directories in a bucket, then the bucket itself
+fn sync_metadata(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            sync_dir(&entry.path())?;
+        } else if ty.is_file() {
+            File::open(entry.path())?.sync_all()?;
+        }
+    }
+    sync_dir(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BufferOptions, Fsdb};
+    use std::time::Duration;
+
+    #[test]
+    fn test_close() {
+        let tmp = Fsdb::temp().expect("fail Fsdb::temp");
+        let path = tmp.path().to_str().expect("non-utf8 temp dir");
+        let db = Fsdb::new(path).expect("fail Fsdb::new");
+        let b = db.bucket::<u32>("closing").expect("fail bucket");
+        b.enable_manifest().expect("fail manifest");
+        let opts = BufferOptions {
+            interval: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let buf = b.buffered(opts);
+        buf.put("a", 1).expect("failed to buffer");
+        let leader = db
+            .elect("closer", "me", Duration::from_secs(60))
+            .expect("fail elect");
+        assert!(leader.is_leader());
+
+        db.close().expect("fail close");
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        // the lease was released, so someone else can lead straight away
+        let start = std::time::Instant::now();
+        let next = tmp
+            .elect("closer", "other", Duration::from_secs(60))
+            .expect("fail elect");
+        assert!(next.is_leader());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!leader.is_leader());
+    }
+}
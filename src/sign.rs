@@ -0,0 +1,81 @@
+use crate::Bucket;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+
+/// Checks signatures on stored values (see `Bucket::set_verifier`)
+pub trait Verifier: Send + Sync {
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool;
+}
+
+/// Signs stored values, so that changes made outside of fsdb are detected on read.
+///
+/// fsdb doesn't ship any cryptography: implement this by wrapping a signature
+/// scheme such as ed25519 from the crate of your choice.
+pub trait Signer: Verifier {
+    fn sign(&self, data: &[u8]) -> Vec<u8>;
+}
+
+// tamper evidence
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Sign every value written through this handle (the signature goes in the value's
+    /// header), and verify signatures on read, returning `Error::SignatureInvalid` for a
+    /// value that is unsigned or doesn't match its signature
+    pub fn set_signer(&mut self, signer: impl Signer + 'static) {
+        self.signer = Some(Arc::new(signer));
+    }
+    /// Verify signatures on read without being able to sign, for handles that
+    /// only hold the public key
+    pub fn set_verifier(&mut self, verifier: impl Verifier + 'static) {
+        self.verifier = Some(Arc::new(verifier));
+    }
+    pub(crate) fn verifying(&self) -> Option<&dyn Verifier> {
+        match (&self.verifier, &self.signer) {
+            (Some(v), _) => Some(&**v),
+            (None, Some(s)) => Some(&**s as &dyn Verifier),
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Signer, Verifier};
+    use crate::{Error, Fsdb};
+
+    // not a real signature: a keyed checksum
+    struct Sum(u8);
+
+    impl Verifier for Sum {
+        fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+            self.sign(data) == signature
+        }
+    }
+
+    impl Signer for Sum {
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            let sum = data.iter().fold(self.0, |acc, b| acc.wrapping_add(*b));
+            vec![sum]
+        }
+    }
+
+    #[test]
+    fn test_signed() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<String>("signed").expect("fail bucket");
+        b.set_signer(Sum(7));
+        b.put("a", "alpha".to_string()).expect("failed to save");
+        assert_eq!(b.get("a").expect("fail get"), "alpha");
+
+        // tamper with the last byte of the stored value
+        let path = db.path().join("signed/a");
+        let mut bytes = std::fs::read(&path).expect("fail read");
+        *bytes.last_mut().expect("empty") ^= 1;
+        std::fs::write(path, bytes).expect("fail write");
+        assert!(matches!(b.get("a"), Err(Error::SignatureInvalid { .. })));
+
+        // unsigned values are rejected too
+        let plain = db.bucket::<String>("signed").expect("fail bucket");
+        plain.put("b", "beta".to_string()).expect("failed to save");
+        assert!(matches!(b.get("b"), Err(Error::SignatureInvalid { .. })));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::{Bucket, Context, Op};
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Called with an operation that took at least the threshold, and how long it took
+pub(crate) type SlowOpHook = dyn Fn(&Context, Duration) + Send + Sync;
+
+// instrumentation
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Call `hook` for every get, put, remove, list or clear through this handle
+    /// that takes `threshold` or longer, with its bucket, key and duration. Use it
+    /// to log the keys (huge values, cold paths on a network mount) that are slow
+    pub fn on_slow_op(
+        &mut self,
+        threshold: Duration,
+        hook: impl Fn(&Context, Duration) + Send + Sync + 'static,
+    ) {
+        self.slow_op = Some((threshold, Arc::new(hook)));
+    }
+}
+
+impl<V> Bucket<V> {
+    // time an operation until the returned guard is dropped
+    pub(crate) fn slow_guard<'a>(
+        &'a self,
+        op: Op,
+        dir: &'a Path,
+        key: Option<&'a str>,
+    ) -> SlowGuard<'a> {
+        SlowGuard {
+            hook: self.slow_op.as_ref(),
+            start: Instant::now(),
+            op,
+            dir,
+            key,
+        }
+    }
+}
+
+pub(crate) struct SlowGuard<'a> {
+    hook: Option<&'a (Duration, Arc<SlowOpHook>)>,
+    start: Instant,
+    op: Op,
+    dir: &'a Path,
+    key: Option<&'a str>,
+}
+
+impl Drop for SlowGuard<'_> {
+    fn drop(&mut self) {
+        let Some((threshold, hook)) = self.hook else {
+            return;
+        };
+        let took = self.start.elapsed();
+        if took >= *threshold {
+            hook(&Context::new(self.op, self.dir, self.key), took);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Fsdb, Op};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_on_slow_op() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<u8>("slow").expect("fail bucket");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        b.on_slow_op(Duration::ZERO, move |ctx, _took| {
+            seen2.lock().unwrap().push((ctx.op, ctx.key.clone()));
+        });
+        b.put("a", 1).expect("failed to save");
+        b.get("a").expect("fail get");
+        b.remove("a").expect("fail remove");
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(
+            seen,
+            vec![
+                (Op::Put, Some("a".to_string())),
+                (Op::Get, Some("a".to_string())),
+                (Op::Remove, Some("a".to_string())),
+            ]
+        );
+
+        // nothing takes an hour
+        let quiet = Arc::new(Mutex::new(0));
+        let quiet2 = quiet.clone();
+        b.on_slow_op(Duration::from_secs(3600), move |_, _| {
+            *quiet2.lock().unwrap() += 1;
+        });
+        b.put("b", 2).expect("failed to save");
+        assert_eq!(*quiet.lock().unwrap(), 0);
+    }
+}
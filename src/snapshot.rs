@@ -0,0 +1,256 @@
+use crate::attrs;
+use crate::error::WithContext;
+use crate::{Bucket, Context, Error, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Directory (inside a bucket) holding snapshots
+pub(crate) const SNAPSHOTS: &str = ".snapshots";
+
+/// Hard-link `src` to `dst`, falling back to a copy if the filesystem can't link
+pub(crate) fn link_or_copy(src: &Path, dst: &Path) -> io::Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Recursively link (or copy) the files under `src` into `dst`, skipping fsdb's own files
+pub(crate) fn link_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)?.flatten() {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            link_tree(&entry.path(), &dst.join(&name))?;
+        } else if ty.is_file() {
+            link_or_copy(&entry.path(), &dst.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+// copy the files under `src` into `dst` like `link_tree`, but as new files
+// (with their extended attributes), so they're modified now and the copies in
+// `src` are left as they were
+fn copy_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)?.flatten() {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            copy_tree(&entry.path(), &dst.join(&name))?;
+        } else if ty.is_file() {
+            fs::copy(entry.path(), dst.join(&name))?;
+            if let Some(xattr) = attrs::get_xattr(&entry.path())? {
+                attrs::set_xattr(&dst.join(&name), &xattr)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// named snapshots of a whole bucket
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Save the current contents of this bucket (including sub-buckets) as a named
+    /// snapshot under `.snapshots/name`. Files are hard-linked, so this is cheap and
+    /// takes no extra space until keys are overwritten; where the filesystem doesn't
+    /// support hard links they are copied instead.
+    pub fn snapshot(&self, name: &str) -> Result<()> {
+        let ctx = || Context::new(Op::Snapshot, &self.dir, Some(name));
+        let dir = self.snapshot_dir(name)?;
+        let snapshots = self.dir.join(SNAPSHOTS);
+        fs::create_dir_all(&snapshots).ctx(ctx)?;
+        let _guard = self.state.exclusive_guard();
+        fs::create_dir(&dir).ctx(ctx)?;
+        if let Err(e) = link_tree(&self.dir, &dir).and_then(|()| self.copy_packed(&dir)) {
+            let _ = fs::remove_dir_all(&dir);
+            return Err(e).ctx(ctx);
+        }
+        Ok(())
+    }
+    /// Replace the contents of this bucket with a snapshot taken by `snapshot`
+    pub fn restore_snapshot(&self, name: &str) -> Result<()> {
+        let ctx = || Context::new(Op::Snapshot, &self.dir, Some(name));
+        let dir = self.snapshot_dir(name)?;
+        if !dir.is_dir() {
+            return Err(Error::NoSuchBucket { ctx: ctx() });
+        }
+        let _guard = self.state.exclusive_guard();
+        self.clear_locked(&self.dir)?;
+        // copied rather than linked, so restored keys count as changed for
+        // incremental backups
+        copy_tree(&dir, &self.dir).ctx(ctx)?;
+        if let Some(m) = self.state.manifest().as_mut() {
+            for key in self.fs_list(&self.dir)? {
+                m.insert(&key).ctx(ctx)?;
+            }
+        }
+        self.rebuild_indexes_locked()?;
+        self.fill_views()
+    }
+    /// Names of this bucket's snapshots
+    pub fn list_snapshots(&self) -> Result<Vec<String>> {
+        let dir = self.dir.join(SNAPSHOTS);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        self.fs_list(&dir)
+    }
+    /// Delete a snapshot
+    pub fn remove_snapshot(&self, name: &str) -> Result<()> {
+        let dir = self.snapshot_dir(name)?;
+        fs::remove_dir_all(dir).ctx(|| Context::new(Op::Snapshot, &self.dir, Some(name)))
+    }
+    fn snapshot_dir(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            let source = io::Error::new(io::ErrorKind::InvalidInput, "invalid snapshot name");
+            return Err(Error::Io {
+                ctx: Context::new(Op::Snapshot, &self.dir, Some(name)),
+                source,
+            });
+        }
+        Ok(self.dir.join(SNAPSHOTS).join(name))
+    }
+}
+
+/// Iterator over a point-in-time view of a bucket, from `Bucket::snapshot_iter`
+pub struct SnapshotIter<V> {
+    bucket: Bucket<V>,
+    dir: PathBuf,
+    keys: std::vec::IntoIter<String>,
+}
+
+impl<V: Serialize + DeserializeOwned> SnapshotIter<V> {
+    pub(crate) fn new(bucket: &Bucket<V>) -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = format!(
+            ".iter-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = bucket.dir.join(SNAPSHOTS).join(id);
+        let ctx = || Context::new(Op::List, &bucket.dir, None);
+        let keys = {
+            // hold off writers so the links are all from the same moment
+            let _guard = bucket.state.exclusive_guard();
+            let keys = bucket.fs_keys(&bucket.dir)?;
+            fs::create_dir_all(&dir).ctx(ctx)?;
+            let pack = bucket.state.pack();
+            for key in keys.iter() {
+                // packed values are copied out of their segment
+                let res = match pack.as_ref().map(|p| p.get(key)).transpose() {
+                    Ok(Some(Some(bytes))) => fs::write(dir.join(key), bytes),
+                    Ok(_) => link_or_copy(&bucket.dir.join(key), &dir.join(key)),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    let _ = fs::remove_dir_all(&dir);
+                    return Err(e).ctx(ctx);
+                }
+            }
+            keys
+        };
+        Ok(Self {
+            bucket: bucket.clone(),
+            dir,
+            keys: keys.into_iter(),
+        })
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Iterator for SnapshotIter<V> {
+    type Item = Result<(String, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.keys.next()?;
+        let ctx = || Context::new(Op::Get, &self.dir, Some(&key));
+        let res = fs::read(self.dir.join(&key))
+            .ctx(ctx)
+            .and_then(|bytes| self.bucket.decode(&bytes, ctx));
+        Some(res.map(|v| (key, v)))
+    }
+}
+
+impl<V> Drop for SnapshotIter<V> {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SNAPSHOTS;
+    use crate::{Fsdb, Metadata};
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_snapshot_iter() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u64>("nums").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.put("a", 1).expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        let iter = b.snapshot_iter().expect("fail snapshot");
+        b.put("a", 10).expect("failed to save");
+        b.put("c", 3).expect("failed to save");
+        let mut items: Vec<(String, u64)> = iter.map(|r| r.expect("fail item")).collect();
+        items.sort();
+        assert_eq!(items, vec![("a".to_string(), 1), ("b".to_string(), 2)]);
+        assert_eq!(b.get("a").expect("fail get"), 10);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u64>("nums").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.remove_snapshot("nightly").ok();
+        b.put("a", 1).expect("failed to save");
+        b.put_within("x", 7, "sub").expect("failed to save");
+        b.snapshot("nightly").expect("fail snapshot");
+        b.put("a", 2).expect("failed to save");
+        b.put("b", 3).expect("failed to save");
+        assert_eq!(b.list_snapshots().expect("fail list"), vec!["nightly"]);
+        b.restore_snapshot("nightly").expect("fail restore");
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        assert!(!b.exists("b"));
+        assert_eq!(b.get_within("x", "sub").expect("fail get"), 7);
+    }
+
+    #[test]
+    fn test_restore_snapshot_indexes() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u64>("nums").expect("fail bucket");
+        b.create_sorted_index("value", |v| *v)
+            .expect("fail create_sorted_index");
+        let tagged = Metadata {
+            tags: vec!["odd".into()],
+            ..Metadata::default()
+        };
+        b.put_with_meta("a", 1, tagged).expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        b.snapshot("s").expect("fail snapshot");
+        let saved = db.path().join("nums").join(SNAPSHOTS).join("s").join("a");
+        let modified = || fs::metadata(&saved).and_then(|m| m.modified());
+        let before = modified().expect("fail mtime");
+        b.remove("a").expect("fail remove");
+
+        std::thread::sleep(Duration::from_millis(20));
+        b.restore_snapshot("s").expect("fail restore");
+        assert_eq!(b.top_n("value", 2).expect("fail top_n"), vec!["b", "a"]);
+        assert_eq!(b.list_by_tag("odd").expect("fail list_by_tag"), vec!["a"]);
+        // the snapshot's own files are left alone
+        assert_eq!(modified().expect("fail mtime"), before);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Error, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Called when a write runs out of space, with the number of bytes it needs
+pub(crate) type DiskFullHook = dyn Fn(u64) -> bool + Send + Sync;
+
+/// Watches the space available to a database, from `Fsdb::monitor_space`.
+/// Dropping it (or closing the `Fsdb`) stops the background thread
+pub struct SpaceMonitor {
+    stop: Option<Sender<()>>,
+    checker: Option<JoinHandle<()>>,
+}
+
+impl Fsdb {
+    /// Total size in bytes of every file in the database, including fsdb's own
+    /// files (manifests, version metadata and so on)
+    pub fn disk_usage(&self) -> Result<u64> {
+        dir_size(&self.dir).ctx(|| Context::new(Op::Space, &self.dir, None))
+    }
+    /// Bytes available to this process on the filesystem holding the database
+    /// (from `statvfs`). Not supported on non-unix platforms
+    pub fn available_space(&self) -> Result<u64> {
+        let stats = fs_stats(&self.dir).ctx(|| Context::new(Op::Space, &self.dir, None))?;
+        Ok(stats.available)
+    }
+    /// Check the available space every `every`, and call `on_low` with it when it
+    /// drops below `min_available` bytes. It's called again only after the space
+    /// recovers and then drops below the threshold again
+    pub fn monitor_space(
+        &self,
+        min_available: u64,
+        every: Duration,
+        mut on_low: impl FnMut(u64) + Send + 'static,
+    ) -> Result<SpaceMonitor> {
+        // fail now, rather than silently in the background, if it can't be checked
+        self.available_space()?;
+        let dir = self.dir.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (done, finished) = mpsc::channel::<()>();
+        self.add_task(stop.clone(), finished);
+        let maintenance = self.maintenance.clone();
+        let checker = std::thread::spawn(move || {
+            let _done = done;
+            let mut low = false;
+            loop {
+                let stats = match maintenance.pass() {
+                    Some(_pass) => fs_stats(&dir).ok(),
+                    None => None,
+                };
+                if let Some(stats) = stats {
+                    let now_low = stats.available < min_available;
+                    if now_low && !low {
+                        on_low(stats.available);
+                    }
+                    low = now_low;
+                }
+                if stopped.recv_timeout(every) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+            }
+        });
+        Ok(SpaceMonitor {
+            stop: Some(stop),
+            checker: Some(checker),
+        })
+    }
+}
+
+impl Drop for SpaceMonitor {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(checker) = self.checker.take() {
+            let _ = checker.join();
+        }
+    }
+}
+
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)?.flatten() {
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else if ty.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Free space on the filesystem holding a path
+pub(crate) struct FsStats {
+    /// Bytes available to unprivileged users
+    pub(crate) available: u64,
+    /// Inodes available to unprivileged users
+    pub(crate) available_inodes: u64,
+}
+
+#[cfg(unix)]
+pub(crate) fn fs_stats(path: &Path) -> io::Result<FsStats> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is a valid C string, and buf is only read after statvfs fills it in
+    let res = unsafe { libc::statvfs(c_path.as_ptr(), buf.as_mut_ptr()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let buf = unsafe { buf.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(FsStats {
+        available: buf.f_bavail as u64 * buf.f_frsize as u64,
+        available_inodes: buf.f_favail as u64,
+    })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn fs_stats(_path: &Path) -> io::Result<FsStats> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space is only available on unix",
+    ))
+}
+
+// running out of space
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Reserve space for each value before writing it (with `fallocate` on Linux),
+    /// so a full disk is detected before any data is written. Elsewhere this does nothing
+    pub fn set_preallocate(&mut self, on: bool) {
+        self.preallocate = on;
+    }
+    /// Call `hook` when a `put` through this handle fails with `Error::DiskFull`,
+    /// with the number of bytes it needs, to evict or purge data. If the hook returns
+    /// true (it freed some space), the write is tried once more.
+    ///
+    /// The hook runs without holding any of fsdb's locks, so it can remove keys from
+    /// this or any other bucket. The failed write never leaves a partial file behind
+    pub fn on_disk_full(&mut self, hook: impl Fn(u64) -> bool + Send + Sync + 'static) {
+        self.disk_full_hook = Some(Arc::new(hook));
+    }
+    // run the disk full hook, if any. True if the write should be retried
+    pub(crate) fn reclaim_space(&self, needed: u64) -> bool {
+        self.disk_full_hook
+            .as_ref()
+            .is_some_and(|hook| hook(needed))
+    }
+}
+
+/// Hold back puts while the filesystem is nearly full (set in `FsdbOptions`), so
+/// there's room left for deletes, compaction and other processes. Space is
+/// checked (with `statvfs`) before every put, and when it's below either
+/// threshold the put returns `Error::BackPressure`, or first waits up to `wait`
+/// for space to be freed. Platforms without `statvfs` aren't throttled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteThrottle {
+    /// Bytes that must stay available
+    pub min_available: u64,
+    /// Inodes (files) that must stay available
+    pub min_inodes: u64,
+    /// How long a put waits for space before giving up
+    pub wait: Option<Duration>,
+}
+
+impl WriteThrottle {
+    // wait (if allowed) until there's room to write to `dir`, or say why there isn't
+    fn admit(&self, dir: &Path) -> std::result::Result<(), String> {
+        let deadline = self.wait.map(|w| Instant::now() + w);
+        loop {
+            let Ok(stats) = fs_stats(dir) else {
+                return Ok(());
+            };
+            let reason = if stats.available < self.min_available {
+                format!(
+                    "{} bytes available, below the minimum of {}",
+                    stats.available, self.min_available
+                )
+            } else if stats.available_inodes < self.min_inodes {
+                format!(
+                    "{} inodes available, below the minimum of {}",
+                    stats.available_inodes, self.min_inodes
+                )
+            } else {
+                return Ok(());
+            };
+            let now = Instant::now();
+            match deadline {
+                Some(deadline) if now < deadline => {
+                    std::thread::sleep((deadline - now).min(Duration::from_millis(50)))
+                }
+                _ => return Err(reason),
+            }
+        }
+    }
+}
+
+impl<V> Bucket<V> {
+    // apply the bucket's write throttle (if any) before a put
+    pub(crate) fn throttle(&self, dir: &Path, key: &str) -> Result<()> {
+        let Some(throttle) = self.state.write_throttle() else {
+            return Ok(());
+        };
+        throttle.admit(dir).map_err(|reason| Error::BackPressure {
+            ctx: Context::new(Op::Put, dir, Some(key)),
+            reason,
+        })
+    }
+}
+
+// reserve `len` bytes for a new file, failing with `ENOSPC` if they aren't available
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(f: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if len == 0 {
+        return Ok(());
+    }
+    // SAFETY: the descriptor is open for as long as `f` is borrowed
+    let res = unsafe { libc::fallocate(f.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if res == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // the filesystem can't preallocate, so just write
+        Some(libc::EOPNOTSUPP) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn preallocate(_f: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Fsdb, FsdbOptions, WriteThrottle};
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_disk_usage() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let before = db.disk_usage().expect("fail disk_usage");
+        let b = db.bucket::<Vec<u8>>("big").expect("fail bucket");
+        b.put("a", vec![1; 5000]).expect("failed to save");
+        let after = db.disk_usage().expect("fail disk_usage");
+        assert!(after >= before + 5000);
+        assert!(db.available_space().expect("fail available_space") > 0);
+    }
+
+    #[test]
+    fn test_monitor_space() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let (tx, rx) = mpsc::channel();
+        let monitor = db
+            .monitor_space(u64::MAX, Duration::from_millis(10), move |n| {
+                let _ = tx.send(n);
+            })
+            .expect("fail monitor_space");
+        rx.recv_timeout(Duration::from_secs(1))
+            .expect("no low space callback");
+        // only once while the space stays low
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+        drop(monitor);
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<Vec<u8>>("big").expect("fail bucket");
+        b.set_preallocate(true);
+        b.put("a", vec![7; 10_000]).expect("failed to save");
+        b.put("a", vec![]).expect("failed to save");
+        assert_eq!(b.get("a").expect("fail get"), Vec::<u8>::new());
+        let len = std::fs::metadata(db.path().join("big/a"))
+            .expect("fail metadata")
+            .len();
+        assert!(len < 100);
+    }
+
+    #[test]
+    fn test_write_throttle() {
+        let throttled = Fsdb::temp().expect("fail Fsdb::temp");
+        let path = throttled.path().to_str().expect("non-utf8 temp dir");
+        let options = FsdbOptions {
+            write_throttle: Some(WriteThrottle {
+                min_available: u64::MAX,
+                wait: Some(Duration::from_millis(20)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let db = Fsdb::with_options(path, options).expect("fail Fsdb::with_options");
+        let b = db.bucket::<u8>("full").expect("fail bucket");
+        let started = Instant::now();
+        let err = b.put("a", 1).unwrap_err();
+        assert!(matches!(err, Error::BackPressure { .. }));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert!(!b.exists("a"));
+
+        let roomy = WriteThrottle {
+            min_available: 1,
+            min_inodes: 1,
+            wait: None,
+        };
+        // reopening with a different throttle replaces it
+        let options = FsdbOptions {
+            write_throttle: Some(roomy),
+            ..Default::default()
+        };
+        let db = Fsdb::with_options(path, options).expect("fail Fsdb::with_options");
+        let b = db.bucket::<u8>("full").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_on_disk_full() {
+        use crate::testing::FlakyBackend;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<u8>("full").expect("fail bucket");
+        let flaky = FlakyBackend::new().disk_full_after(0);
+        b.set_flaky_backend(flaky.clone());
+        let err = b.put("a", 1).unwrap_err();
+        assert!(matches!(err, Error::DiskFull { .. }));
+
+        // the hook "evicts" by healing the backend
+        let needed = Arc::new(AtomicU64::new(0));
+        let needed2 = needed.clone();
+        b.on_disk_full(move |n| {
+            needed2.store(n, Ordering::SeqCst);
+            flaky.heal();
+            true
+        });
+        b.put("a", 1).expect("failed to save");
+        assert!(needed.load(Ordering::SeqCst) > 0);
+        assert_eq!(b.get("a").expect("fail get"), 1);
+        assert_eq!(b.list().expect("fail list"), vec!["a"]);
+    }
+}
@@ -0,0 +1,367 @@
+use crate::access::AccessLog;
+use crate::buffered::Flush;
+use crate::bus::Subscribers;
+use crate::cache::ReadCache;
+use crate::changelog::{self, ChangeLog};
+use crate::durable::{Durability, GroupSync};
+use crate::error::Op;
+use crate::foreign::Reference;
+use crate::index::Index;
+use crate::manifest::Manifest;
+use crate::meta::{self, Version};
+use crate::pack::Pack;
+use crate::seal::Sealed;
+use crate::seq;
+use crate::space::WriteThrottle;
+use crate::tags;
+use crate::view::View;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{
+    Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
+use std::time::Duration;
+
+/// In-process state shared by every handle to the same bucket directory,
+/// even across separate `Fsdb` instances.
+///
+/// Its locks ignore poisoning: a panic while holding one releases it, and leaves
+/// nothing half updated, since the bucket lock, key locks and sequence lock
+/// guard no data and the rest hold settings and lists that are only ever
+/// replaced, added to or removed from whole. A panic while the manifest is
+/// locked rebuilds it from the directory. See also `Bucket::recover_locks`
+#[derive(Default)]
+pub(crate) struct BucketState {
+    // writers hold this shared, so they don't block each other,
+    // while list_stable holds it exclusively to get a consistent view
+    lock: RwLock<()>,
+    // loaded when the bucket has a manifest file
+    manifest: Mutex<Option<Manifest>>,
+    // loaded when the bucket has packed values
+    pack: Mutex<Option<Pack>>,
+    // striped per-key locks, held while a key's value and metadata are updated together
+    key_locks: [Mutex<()>; KEY_LOCK_STRIPES],
+    // in-process subscribers to writes, from Fsdb::subscribe
+    pub subscribers: Subscribers,
+    // held while the change sequence file is read and rewritten
+    seq_lock: Mutex<()>,
+    // set once the bucket is opened through an Fsdb in network filesystem mode
+    network_fs: AtomicBool,
+    // set when the bucket's files are named after hashes of their keys
+    hashed_keys: AtomicBool,
+    // set while the bucket is sealed, see Bucket::seal
+    sealed: AtomicBool,
+    // how long puts are kept for, see Bucket::set_default_ttl
+    default_ttl: Mutex<Option<Duration>>,
+    // set when the bucket is opened through an Fsdb with a write throttle
+    write_throttle: Mutex<Option<WriteThrottle>>,
+    // set when the bucket is opened through an Fsdb with durable writes
+    durability: Mutex<Durability>,
+    // shares directory flushes between writers, for Durability::GroupCommit
+    pub group_sync: GroupSync,
+    // write-behind buffers on this bucket, flushed by Fsdb::close
+    pub buffers: Mutex<Vec<Weak<dyn Flush>>>,
+    // loaded when the bucket has an access file
+    access: Mutex<Option<AccessLog>>,
+    // set by Bucket::enable_read_cache
+    cache: Mutex<Option<ReadCache>>,
+    // sorted indexes by name, from Bucket::create_sorted_index
+    indexes: Mutex<HashMap<String, Arc<dyn Index>>>,
+    // values in other buckets referencing this one's keys, from Bucket::references
+    references: Mutex<Vec<Arc<dyn Reference>>>,
+    // views of this bucket by their directory, from Bucket::create_view
+    views: Mutex<HashMap<PathBuf, Arc<dyn View>>>,
+    // set when the bucket is opened through an Fsdb with a change log
+    change_log: Mutex<Option<Arc<ChangeLog>>>,
+}
+
+const KEY_LOCK_STRIPES: usize = 32;
+
+type Registry = HashMap<PathBuf, Weak<BucketState>>;
+
+fn registry() -> MutexGuard<'static, Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+impl BucketState {
+    /// Look up (or create) the state for a bucket directory
+    pub(crate) fn get(dir: &Path) -> Arc<BucketState> {
+        let key = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        let mut reg = registry();
+        if let Some(state) = reg.get(&key).and_then(Weak::upgrade) {
+            return state;
+        }
+        reg.retain(|_, w| w.strong_count() > 0);
+        let state = Arc::new(BucketState::default());
+        reg.insert(key, Arc::downgrade(&state));
+        state
+    }
+    /// The state of every open bucket inside `dir`, with its directory
+    pub(crate) fn under(dir: &Path) -> Vec<(PathBuf, Arc<BucketState>)> {
+        let root = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        registry()
+            .iter()
+            .filter(|(path, _)| path.starts_with(&root))
+            .filter_map(|(path, w)| Some((path.clone(), w.upgrade()?)))
+            .collect()
+    }
+    pub(crate) fn write_guard(&self) -> RwLockReadGuard<'_, ()> {
+        self.lock.read().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn exclusive_guard(&self) -> RwLockWriteGuard<'_, ()> {
+        self.lock.write().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn key_lock(&self, path: &Path) -> MutexGuard<'_, ()> {
+        let mut h = DefaultHasher::new();
+        path.hash(&mut h);
+        let stripe = h.finish() as usize % KEY_LOCK_STRIPES;
+        self.key_locks[stripe]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn manifest(&self) -> MutexGuard<'_, Option<Manifest>> {
+        self.manifest.lock().unwrap_or_else(|poisoned| {
+            self.manifest.clear_poison();
+            let mut manifest = poisoned.into_inner();
+            if let Some(m) = manifest.as_mut() {
+                // if this fails too, keep the old one rather than dropping the manifest
+                let _ = m.rebuild();
+            }
+            manifest
+        })
+    }
+    pub(crate) fn pack(&self) -> MutexGuard<'_, Option<Pack>> {
+        // appends update the index only once they're written
+        self.pack.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn access(&self) -> MutexGuard<'_, Option<AccessLog>> {
+        // a panic while it's locked can at worst lose a read
+        self.access.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn cache(&self) -> MutexGuard<'_, Option<ReadCache>> {
+        // entries are checked against the file before use, so a panic can't leave a stale one
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn indexes(&self) -> MutexGuard<'_, HashMap<String, Arc<dyn Index>>> {
+        self.indexes.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn references(&self) -> MutexGuard<'_, Vec<Arc<dyn Reference>>> {
+        self.references
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn views(&self) -> MutexGuard<'_, HashMap<PathBuf, Arc<dyn View>>> {
+        self.views.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Clear poisoning from every lock, see `Bucket::recover_locks`
+    pub(crate) fn clear_poison(&self) {
+        self.lock.clear_poison();
+        self.manifest.clear_poison();
+        self.key_locks.iter().for_each(Mutex::clear_poison);
+        self.seq_lock.clear_poison();
+        self.default_ttl.clear_poison();
+        self.write_throttle.clear_poison();
+        self.durability.clear_poison();
+        self.buffers.clear_poison();
+        self.pack.clear_poison();
+        self.access.clear_poison();
+        self.cache.clear_poison();
+        self.indexes.clear_poison();
+        self.references.clear_poison();
+        self.views.clear_poison();
+        self.change_log.clear_poison();
+    }
+    pub(crate) fn network_fs(&self) -> bool {
+        self.network_fs.load(Ordering::Relaxed)
+    }
+    /// Switch this bucket to network filesystem mode, for every handle to it
+    pub(crate) fn set_network_fs(&self) {
+        self.network_fs.store(true, Ordering::Relaxed);
+        if let Some(m) = self.manifest().as_mut() {
+            m.network = true;
+        }
+    }
+    pub(crate) fn hashed_keys(&self) -> bool {
+        self.hashed_keys.load(Ordering::Relaxed)
+    }
+    pub(crate) fn set_hashed_keys(&self) {
+        self.hashed_keys.store(true, Ordering::Relaxed);
+    }
+    pub(crate) fn sealed(&self) -> bool {
+        self.sealed.load(Ordering::Relaxed)
+    }
+    pub(crate) fn set_sealed(&self, on: bool) {
+        self.sealed.store(on, Ordering::Relaxed);
+    }
+    /// Fail a write to a sealed bucket, with an error that becomes `Error::Sealed`
+    pub(crate) fn writable(&self) -> io::Result<()> {
+        match self.sealed() {
+            true => Err(io::Error::new(io::ErrorKind::PermissionDenied, Sealed)),
+            false => Ok(()),
+        }
+    }
+    pub(crate) fn default_ttl(&self) -> Option<Duration> {
+        *self
+            .default_ttl
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    pub(crate) fn set_default_ttl(&self, ttl: Option<Duration>) {
+        *self
+            .default_ttl
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = ttl;
+    }
+    pub(crate) fn write_throttle(&self) -> Option<WriteThrottle> {
+        *self
+            .write_throttle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Throttle writes through every handle to this bucket
+    pub(crate) fn set_write_throttle(&self, throttle: WriteThrottle) {
+        *self
+            .write_throttle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(throttle);
+    }
+    pub(crate) fn durability(&self) -> Durability {
+        *self
+            .durability
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Make writes through every handle to this bucket durable
+    pub(crate) fn set_durability(&self, durability: Durability) {
+        *self
+            .durability
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = durability;
+    }
+
+    pub(crate) fn change_log(&self) -> Option<Arc<ChangeLog>> {
+        self.change_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+    /// Log writes through every handle to this bucket
+    pub(crate) fn set_change_log(&self, log: Arc<ChangeLog>) {
+        *self
+            .change_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(log);
+    }
+
+    /// Move a fully written temp file into place as `dir/name`, then stamp its
+    /// version and record it in the manifest (for keys at the top level of the bucket).
+    /// The caller holds the key lock.
+    pub(crate) fn install(
+        &self,
+        dir: &Path,
+        name: &str,
+        tmp: &Path,
+        version: Option<Version>,
+        top_level: bool,
+        actor: Option<&str>,
+    ) -> io::Result<()> {
+        let _guard = self.write_guard();
+        self.writable()?;
+        let retag = match top_level && tags::indexing(dir) {
+            true => Some((self.stored_tags(dir, name)?, tags::file_tags(tmp)?)),
+            false => None,
+        };
+        let log = self.change_log();
+        let before = match &log {
+            Some(_) if top_level => self.stored_hash(dir, name)?,
+            Some(_) => changelog::hash_file(&dir.join(name))?,
+            None => None,
+        };
+        fs::rename(tmp, dir.join(name))?;
+        // a packed value it replaces (see `Bucket::enable_packed`)
+        if top_level {
+            self.unpack(name)?;
+        }
+        if let Some(log) = log {
+            let after = changelog::hash_file(&dir.join(name))?;
+            log.record(dir, Some(name), Op::Put, before, after, actor)?;
+        }
+        if let Some(version) = version {
+            meta::stamp(dir, name, version)?;
+        }
+        if let Some((old, new)) = retag {
+            tags::retag(dir, name, &old, &new)?;
+        }
+        if top_level {
+            self.update_indexes(name, Some(&dir.join(name)));
+            if let Some(m) = self.manifest().as_mut() {
+                m.insert(name)?;
+            }
+            self.bump_seq(dir)?;
+            self.update_views(name, Some(&dir.join(name)))?;
+        }
+        Ok(())
+    }
+    /// Remove `dir/name` (or its packed value) along with its metadata and manifest entry. The
+    /// caller holds the key lock.
+    pub(crate) fn uninstall(
+        &self,
+        dir: &Path,
+        name: &str,
+        top_level: bool,
+        actor: Option<&str>,
+    ) -> io::Result<()> {
+        if top_level && self.uninstall_packed(dir, name, actor)? {
+            return Ok(());
+        }
+        let _guard = self.write_guard();
+        self.writable()?;
+        let old_tags = match top_level && tags::indexing(dir) {
+            true => tags::file_tags(&dir.join(name))?,
+            false => Vec::new(),
+        };
+        let log = self.change_log();
+        let before = match &log {
+            Some(_) => changelog::hash_file(&dir.join(name))?,
+            None => None,
+        };
+        fs::remove_file(dir.join(name))?;
+        if let Some(log) = log {
+            log.record(dir, Some(name), Op::Remove, before, None, actor)?;
+        }
+        meta::remove(dir, name)?;
+        tags::retag(dir, name, &old_tags, &[])?;
+        if top_level {
+            if let Some(access) = self.access().as_mut() {
+                access.forget(name);
+            }
+            if let Some(cache) = self.cache().as_mut() {
+                cache.remove(name);
+            }
+            self.update_indexes(name, None);
+            if let Some(m) = self.manifest().as_mut() {
+                m.remove(name)?;
+            }
+            self.bump_seq(dir)?;
+            self.update_views(name, None)?;
+        }
+        Ok(())
+    }
+    /// Bump the bucket's change sequence number, if it keeps one
+    pub(crate) fn bump_seq(&self, dir: &Path) -> io::Result<()> {
+        let _lock = self.seq_lock();
+        seq::bump(dir)
+    }
+    pub(crate) fn seq_lock(&self) -> MutexGuard<'_, ()> {
+        self.seq_lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,78 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::Write;
+
+// tabular exports, for value types that are flat structs
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Write every key and value in this bucket to `w` as CSV, sorted by key.
+    /// The first column is the key, followed by one column per field of `V`.
+    /// Fails if `V` has nested fields. Sub-buckets are not included
+    pub fn export_csv<W: Write>(&self, w: W) -> Result<()> {
+        let mut out = csv::Writer::from_writer(w);
+        let mut keys = self.fs_keys(&self.dir)?;
+        keys.sort();
+        let mut wrote_header = false;
+        for key in keys {
+            let ctx = || Context::new(Op::Get, &self.dir, Some(&key));
+            let v = self.fs_get(&self.dir, &key)?;
+            let (header, row) = csv_record(&v).map_err(std::io::Error::from).ctx(ctx)?;
+            if !wrote_header {
+                out.write_record(std::iter::once("key").chain(header.iter()))
+                    .map_err(std::io::Error::from)
+                    .ctx(ctx)?;
+                wrote_header = true;
+            }
+            out.write_record(std::iter::once(key.as_str()).chain(row.iter()))
+                .map_err(std::io::Error::from)
+                .ctx(ctx)?;
+        }
+        out.flush().ctx(|| Context::new(Op::List, &self.dir, None))
+    }
+}
+
+// the csv crate can't write a header for a (key, struct) tuple, so write the
+// struct on its own (with its header) and read both rows back
+fn csv_record<T: Serialize>(
+    value: &T,
+) -> std::result::Result<(csv::StringRecord, csv::StringRecord), csv::Error> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.serialize(value)?;
+    let bytes = w.into_inner().map_err(|e| e.into_error())?;
+    let mut r = csv::Reader::from_reader(bytes.as_slice());
+    let header = r.headers()?.clone();
+    let row = r
+        .records()
+        .next()
+        .unwrap_or_else(|| Ok(Default::default()))?;
+    Ok((header, row))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct Row {
+        n: u8,
+        name: String,
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Row>("rows").expect("fail bucket");
+        b.clear().expect("fail clear");
+        let row = |n, name: &str| Row {
+            n,
+            name: name.to_owned(),
+        };
+        b.put("b", row(2, "x, y")).expect("failed to save");
+        b.put("a", row(1, "z")).expect("failed to save");
+        let mut out = Vec::new();
+        b.export_csv(&mut out).expect("fail export");
+        let expected = "key,n,name\na,1,z\nb,2,\"x, y\"\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::attrs::get_xattr;
+use crate::error::WithContext;
+use crate::{header, set, Bucket, Context, Metadata, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Directory (inside a bucket) indexing keys by tag: `.tags/<tag>/<key>`
+const TAGS_DIR: &str = ".tags";
+
+// tag queries
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Keys with `tag` in their `Metadata::tags`.
+    ///
+    /// Tags are indexed from the first time a key is stored with any (through
+    /// `put_with_meta`), and from then on the index is kept up to date by every
+    /// write. Keys tagged before that aren't indexed until they're written again
+    pub fn list_by_tag(&self, tag: &str) -> Result<Vec<String>> {
+        let ctx = || Context::new(Op::List, &self.dir, None);
+        let dir = tag_dir(&self.dir, tag);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).ctx(ctx),
+        };
+        let mut keys: Vec<String> = entries
+            .flatten()
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Tags are indexed once the index directory exists
+pub(crate) fn indexing(dir: &Path) -> bool {
+    dir.join(TAGS_DIR).is_dir()
+}
+
+/// Start indexing tags in a bucket
+pub(crate) fn start_indexing(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir.join(TAGS_DIR))
+}
+
+// tags can hold any characters, but directory names can't start with `.` or hold `/`
+fn tag_dir(dir: &Path, tag: &str) -> PathBuf {
+    dir.join(TAGS_DIR).join(set::escape(tag))
+}
+
+/// The tags of the value stored in a file (from its extended attributes or
+/// header). A missing file has none
+pub(crate) fn file_tags(path: &Path) -> io::Result<Vec<String>> {
+    let bytes = match fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    if let Some(xattr) = get_xattr(path)? {
+        let meta: Metadata = rmp_serde::from_slice(&xattr).map_err(|e| invalid(&e.to_string()))?;
+        return Ok(meta.tags);
+    }
+    value_tags(&bytes)
+}
+
+/// The tags in a stored value's header
+pub(crate) fn value_tags(bytes: &[u8]) -> io::Result<Vec<String>> {
+    let (header, _) = header::split(bytes).ok_or_else(|| invalid("corrupt value header"))?;
+    Ok(header.attrs.map(|m| m.tags).unwrap_or_default())
+}
+
+fn invalid(e: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_owned())
+}
+
+/// Move key `name` in the index from its `old` tags to its `new` ones
+pub(crate) fn retag(dir: &Path, name: &str, old: &[String], new: &[String]) -> io::Result<()> {
+    for tag in old.iter().filter(|t| !new.contains(t)) {
+        let tdir = tag_dir(dir, tag);
+        match fs::remove_file(tdir.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        // tidy up tags nobody has any more; fails harmlessly if it's not empty
+        let _ = fs::remove_dir(tdir);
+    }
+    for tag in new.iter().filter(|t| !old.contains(t)) {
+        let tdir = tag_dir(dir, tag);
+        fs::create_dir_all(&tdir)?;
+        fs::write(tdir.join(name), b"")?;
+    }
+    Ok(())
+}
+
+/// Empty the index, keeping it on
+pub(crate) fn clear(dir: &Path) -> io::Result<()> {
+    let tdir = dir.join(TAGS_DIR);
+    if !tdir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(&tdir)?.flatten() {
+        fs::remove_dir_all(entry.path())?;
+    }
+    Ok(())
+}
+
+/// Rebuild the index from the tags of keys `names`, read by `tags_of`,
+/// turning it on if any has tags
+pub(crate) fn rebuild(
+    dir: &Path,
+    names: &[String],
+    tags_of: impl Fn(&str) -> io::Result<Vec<String>>,
+) -> io::Result<()> {
+    clear(dir)?;
+    for name in names {
+        let tags = tags_of(name)?;
+        if !tags.is_empty() {
+            start_indexing(dir)?;
+            retag(dir, name, &[], &tags)?;
+        }
+    }
+    Ok(())
+}
+
+/// Entries missing from the index or left over in it, as (tag, key), given
+/// the keys `names` stored in the bucket and their tags, read by `tags_of`
+pub(crate) fn stale(
+    dir: &Path,
+    names: &[String],
+    tags_of: impl Fn(&str) -> io::Result<Vec<String>>,
+) -> io::Result<Vec<(String, String)>> {
+    let mut expected = BTreeSet::new();
+    for name in names {
+        for tag in tags_of(name)? {
+            expected.insert((set::escape(&tag), name.clone()));
+        }
+    }
+    let mut indexed = BTreeSet::new();
+    let tdir = dir.join(TAGS_DIR);
+    if tdir.is_dir() {
+        for tag in fs::read_dir(&tdir)?.flatten() {
+            for key in fs::read_dir(tag.path())?.flatten() {
+                if let (Ok(t), Ok(k)) =
+                    (tag.file_name().into_string(), key.file_name().into_string())
+                {
+                    indexed.insert((t, k));
+                }
+            }
+        }
+    }
+    Ok(expected
+        .symmetric_difference(&indexed)
+        .map(|(tag, key)| (set::unescape(tag), key.clone()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Fsdb, Metadata};
+
+    fn tagged(tags: &[&str]) -> Metadata {
+        Metadata {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_list_by_tag() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("tasks").expect("fail bucket");
+        b.put_with_meta("a", 1, tagged(&["urgent", "home"]))
+            .expect("failed to save");
+        b.put_with_meta("b", 2, tagged(&["urgent", ".hidden/odd"]))
+            .expect("failed to save");
+        b.put("c", 3).expect("failed to save");
+        assert_eq!(b.list_by_tag("urgent").expect("fail list"), vec!["a", "b"]);
+        assert_eq!(b.list_by_tag(".hidden/odd").expect("fail list"), vec!["b"]);
+        assert!(b.list_by_tag("nope").expect("fail list").is_empty());
+        // tags don't show up as keys
+        let mut keys = b.list().expect("fail list");
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+
+        // retagging, a plain put and a remove all update the index
+        b.put_with_meta("a", 1, tagged(&["home"]))
+            .expect("failed to save");
+        b.put("b", 2).expect("failed to save");
+        assert!(b.list_by_tag("urgent").expect("fail list").is_empty());
+        assert_eq!(b.list_by_tag("home").expect("fail list"), vec!["a"]);
+        b.remove("a").expect("fail remove");
+        assert!(b.list_by_tag("home").expect("fail list").is_empty());
+
+        b.put_with_meta("c", 3, tagged(&["home"]))
+            .expect("failed to save");
+        b.clear().expect("fail clear");
+        assert!(b.list_by_tag("home").expect("fail list").is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{Fsdb, Result};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A database in its own temporary directory, which is deleted when this is
+/// dropped, from `Fsdb::temp`. Derefs to `Fsdb`
+pub struct TempFsdb {
+    db: Fsdb,
+}
+
+impl Fsdb {
+    /// Create a database in a new, uniquely named directory under the system temp
+    /// directory, for tests. See `TempFsdb`
+    pub fn temp() -> Result<TempFsdb> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let dir = std::env::temp_dir().join(format!(
+            "fsdb-{}-{}-{}",
+            nanos,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let db = Fsdb::new(&dir.to_string_lossy())?;
+        Ok(TempFsdb { db })
+    }
+}
+
+impl TempFsdb {
+    /// The database's directory
+    pub fn path(&self) -> &Path {
+        &self.db.dir
+    }
+}
+
+impl Deref for TempFsdb {
+    type Target = Fsdb;
+    fn deref(&self) -> &Fsdb {
+        &self.db
+    }
+}
+
+impl DerefMut for TempFsdb {
+    fn deref_mut(&mut self) -> &mut Fsdb {
+        &mut self.db
+    }
+}
+
+impl Drop for TempFsdb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.db.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+
+    #[test]
+    fn test_temp() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let other = Fsdb::temp().expect("fail Fsdb::temp");
+        assert_ne!(db.path(), other.path());
+        let b = db.bucket::<u8>("hi").expect("fail bucket");
+        b.put("a", 1).expect("failed to save");
+        let dir = db.path().to_owned();
+        assert!(dir.join("hi/a").is_file());
+        drop(b);
+        drop(db);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,282 @@
+//! Helpers for testing applications built on fsdb: fault injection, and
+//! proptest strategies with a model-checking harness
+
+use crate::Bucket;
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Injects failures into a bucket's file operations (reads, writes and removes),
+/// so applications can test their recovery paths. Attach it with
+/// `Bucket::set_flaky_backend`; clones share the same operation count and faults.
+///
+/// Operations are numbered from 1 in the order they happen, and each fault
+/// applies to every operation after the given count:
+///
+/// - `disk_full_after`: writes fail with `ENOSPC` (`Error::DiskFull`)
+/// - `partial_write_after`: the next write stops half way, leaving a truncated
+///   temp file behind, and fails. Later writes succeed
+/// - `crash_after`: the operation fails as if the process died during it (a write
+///   leaves a truncated temp file), and so does every one after it until `heal`
+#[derive(Clone, Default)]
+pub struct FlakyBackend(Arc<Mutex<Plan>>);
+
+#[derive(Default)]
+struct Plan {
+    ops: usize,
+    disk_full_after: Option<usize>,
+    partial_write_after: Option<usize>,
+    crash_after: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fault {
+    DiskFull,
+    PartialWrite,
+    Crash,
+}
+
+impl FlakyBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Fail writes after `ops` operations, as if the disk filled up
+    pub fn disk_full_after(self, ops: usize) -> Self {
+        self.plan().disk_full_after = Some(ops);
+        self
+    }
+    /// Cut short the first write after `ops` operations
+    pub fn partial_write_after(self, ops: usize) -> Self {
+        self.plan().partial_write_after = Some(ops);
+        self
+    }
+    /// Fail every operation after `ops` operations, as if the process crashed
+    pub fn crash_after(self, ops: usize) -> Self {
+        self.plan().crash_after = Some(ops);
+        self
+    }
+    /// Number of operations so far
+    pub fn ops(&self) -> usize {
+        self.plan().ops
+    }
+    /// Remove every fault, so operations succeed again (like restarting after a crash)
+    pub fn heal(&self) {
+        let mut plan = self.plan();
+        *plan = Plan {
+            ops: plan.ops,
+            ..Default::default()
+        };
+    }
+    fn plan(&self) -> std::sync::MutexGuard<'_, Plan> {
+        self.0.lock().expect("flaky backend poisoned")
+    }
+    // count an operation, and pick the fault (if any) it should hit
+    fn next_op(&self, write: bool) -> Option<Fault> {
+        let mut plan = self.plan();
+        plan.ops += 1;
+        let past = |after: Option<usize>| after.is_some_and(|n| plan.ops > n);
+        if past(plan.crash_after) {
+            return Some(Fault::Crash);
+        }
+        if !write {
+            return None;
+        }
+        if past(plan.disk_full_after) {
+            return Some(Fault::DiskFull);
+        }
+        if past(plan.partial_write_after) {
+            plan.partial_write_after = None;
+            return Some(Fault::PartialWrite);
+        }
+        None
+    }
+}
+
+// fault injection
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Inject failures into operations through this handle. See `FlakyBackend`
+    pub fn set_flaky_backend(&mut self, backend: FlakyBackend) {
+        self.flaky = Some(backend);
+    }
+}
+
+impl<V> Bucket<V> {
+    // called before each operation on `dir/name`, with the bytes about to be written (if any)
+    pub(crate) fn inject_fault(
+        &self,
+        dir: &Path,
+        name: &str,
+        write: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let Some(fault) = self.flaky.as_ref().and_then(|f| f.next_op(write.is_some())) else {
+            return Ok(());
+        };
+        if let (Some(bytes), Fault::PartialWrite | Fault::Crash) = (write, fault) {
+            let tmp = dir.join(format!(".{}.flaky.tmp", name));
+            fs::write(tmp, &bytes[..bytes.len() / 2])?;
+        }
+        Err(match fault {
+            Fault::DiskFull => io::Error::new(
+                io::ErrorKind::StorageFull,
+                "injected fault: no space left on device",
+            ),
+            Fault::PartialWrite => io::Error::other("injected fault: partial write"),
+            Fault::Crash => io::Error::other("injected fault: crashed"),
+        })
+    }
+}
+
+/// One step of a random workload, from `workload`
+#[derive(Debug, Clone)]
+pub enum WorkloadOp<V> {
+    Put(String, V),
+    Get(String),
+    Exists(String),
+    Remove(String),
+    List,
+    Clear,
+}
+
+/// Keys drawn from a small set of short names, so workloads often reuse them
+pub fn key() -> impl Strategy<Value = String> {
+    "[a-e]{1,2}"
+}
+
+/// Workloads of up to `max_len` random operations, with values from `V`'s
+/// `Arbitrary` implementation
+pub fn workload<V>(max_len: usize) -> impl Strategy<Value = Vec<WorkloadOp<V>>>
+where
+    V: Arbitrary + Clone + Debug + 'static,
+{
+    let op = prop_oneof![
+        4 => (key(), any::<V>()).prop_map(|(k, v)| WorkloadOp::Put(k, v)),
+        2 => key().prop_map(WorkloadOp::Get),
+        1 => key().prop_map(WorkloadOp::Exists),
+        2 => key().prop_map(WorkloadOp::Remove),
+        1 => Just(WorkloadOp::List),
+        1 => Just(WorkloadOp::Clear),
+    ];
+    proptest::collection::vec(op, 0..=max_len)
+}
+
+/// Run `ops` against `bucket` (which is cleared first) and against an in-memory
+/// `BTreeMap`, failing at the first operation where their results differ
+pub fn check_against_model<V>(
+    bucket: &Bucket<V>,
+    ops: &[WorkloadOp<V>],
+) -> Result<(), TestCaseError>
+where
+    V: Serialize + DeserializeOwned + Clone + PartialEq + Debug,
+{
+    let fail = |i: usize, op: &WorkloadOp<V>, msg: String| {
+        TestCaseError::fail(format!("op {} ({:?}): {}", i, op, msg))
+    };
+    bucket
+        .clear()
+        .map_err(|e| fail(0, &WorkloadOp::Clear, e.to_string()))?;
+    let mut model = BTreeMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            WorkloadOp::Put(k, v) => {
+                bucket
+                    .put(k, v.clone())
+                    .map_err(|e| fail(i, op, e.to_string()))?;
+                model.insert(k.clone(), v.clone());
+            }
+            WorkloadOp::Get(k) => match (bucket.get(k), model.get(k)) {
+                (Ok(got), Some(want)) if got == *want => (),
+                (Err(_), None) => (),
+                (got, want) => {
+                    return Err(fail(i, op, format!("got {:?}, model has {:?}", got, want)))
+                }
+            },
+            WorkloadOp::Exists(k) => {
+                let (got, want) = (bucket.exists(k), model.contains_key(k));
+                if got != want {
+                    return Err(fail(i, op, format!("got {}, model has {}", got, want)));
+                }
+            }
+            WorkloadOp::Remove(k) => {
+                let (got, want) = (bucket.remove(k).is_ok(), model.remove(k).is_some());
+                if got != want {
+                    return Err(fail(
+                        i,
+                        op,
+                        format!("removed: {}, model had it: {}", got, want),
+                    ));
+                }
+            }
+            WorkloadOp::List => {
+                let mut got = bucket.list().map_err(|e| fail(i, op, e.to_string()))?;
+                got.sort();
+                let want: Vec<&String> = model.keys().collect();
+                if got.iter().collect::<Vec<_>>() != want {
+                    return Err(fail(i, op, format!("got {:?}, model has {:?}", got, want)));
+                }
+            }
+            WorkloadOp::Clear => {
+                bucket.clear().map_err(|e| fail(i, op, e.to_string()))?;
+                model.clear();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_against_model, workload, FlakyBackend};
+    use crate::Fsdb;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_flaky_backend() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<String>("flaky").expect("fail bucket");
+        b.clear().expect("fail clear");
+        let flaky = FlakyBackend::new().partial_write_after(1).crash_after(3);
+        b.set_flaky_backend(flaky.clone());
+
+        b.put("a", "one".into()).expect("failed to save");
+        assert!(b.put("a", "two".into()).is_err());
+        // the old value survives a partial write
+        assert_eq!(b.get("a").expect("fail get"), "one");
+        assert!(b.get("a").is_err());
+        assert!(b.remove("a").is_err());
+        assert_eq!(flaky.ops(), 5);
+
+        flaky.heal();
+        assert_eq!(b.get("a").expect("fail get"), "one");
+        assert_eq!(b.list().expect("fail list"), vec!["a"]);
+    }
+
+    #[test]
+    fn test_disk_full() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let mut b = db.bucket::<u8>("full").expect("fail bucket");
+        b.clear().expect("fail clear");
+        b.set_flaky_backend(FlakyBackend::new().disk_full_after(0));
+        let err = b.put("a", 1).unwrap_err();
+        assert!(matches!(err, crate::Error::DiskFull { .. }));
+        assert!(err.to_string().contains("no space left"));
+        assert!(!b.exists("a"));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        #[test]
+        fn test_model(ops in workload::<(u8, String)>(40)) {
+            let db = Fsdb::temp().expect("fail Fsdb::temp");
+            let b = db.bucket::<(u8, String)>("model").expect("fail bucket");
+            check_against_model(&b, &ops)?;
+            b.enable_manifest().expect("fail manifest");
+            check_against_model(&b, &ops)?;
+        }
+    }
+}
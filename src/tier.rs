@@ -0,0 +1,267 @@
+use crate::changelog::hash_file;
+use crate::error::WithContext;
+use crate::offload::Offload;
+use crate::{Bucket, Context, ErrorKind, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// A bucket kept on two databases, from `Fsdb::tiered_bucket`: a hot one on
+/// fast storage (an SSD, say) and a cold one on slow, bigger storage (a hard
+/// disk or NFS). Values are written to the hot tier, and ones unused for
+/// `max_idle` are moved to the cold tier by `migrate`, or in the background by
+/// `Fsdb::migrate_cold_every`. Reads look in the hot tier first, then the cold
+/// one; a value read from the cold tier stays there until it's written again
+pub struct TieredBucket<V> {
+    hot: Bucket<V>,
+    pub(crate) cold: Bucket<V>,
+    max_idle: Duration,
+    // the bucket's name, which prefixes the names of offloaded objects
+    pub(crate) name: String,
+    // see `set_offload`
+    pub(crate) offload: Option<Offload>,
+}
+
+impl<V> Clone for TieredBucket<V> {
+    fn clone(&self) -> Self {
+        Self {
+            hot: self.hot.clone(),
+            cold: self.cold.clone(),
+            max_idle: self.max_idle,
+            name: self.name.clone(),
+            offload: self.offload.clone(),
+        }
+    }
+}
+
+/// Moves idle values of a `TieredBucket` to its cold tier in the background,
+/// from `Fsdb::migrate_cold_every`. Dropping it (or closing the `Fsdb`) stops
+/// the background thread
+pub struct TierMigrator {
+    stop: Option<Sender<()>>,
+    migrator: Option<JoinHandle<()>>,
+}
+
+impl Fsdb {
+    /// Create or open a bucket with this database as its hot tier and `cold`
+    /// as its cold tier. Access tracking is turned on for the hot tier, so a
+    /// value's last use is its last read or write. See `TieredBucket`
+    pub fn tiered_bucket<V: Serialize + DeserializeOwned>(
+        &self,
+        p: &str,
+        cold: &Fsdb,
+        max_idle: Duration,
+    ) -> Result<TieredBucket<V>> {
+        let hot = self.bucket(p)?;
+        hot.enable_access_tracking()?;
+        Ok(TieredBucket {
+            hot,
+            cold: cold.bucket(p)?,
+            max_idle,
+            name: p.to_owned(),
+            offload: None,
+        })
+    }
+    /// Every `every`, move values of a tiered bucket that have been idle too
+    /// long to its cold tier, and offload ones idle there to its object store
+    /// if it has one. See `TieredBucket::migrate` and `TieredBucket::offload`
+    pub fn migrate_cold_every<V>(&self, bucket: &TieredBucket<V>, every: Duration) -> TierMigrator
+    where
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let bucket = bucket.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (done, finished) = mpsc::channel::<()>();
+        self.add_task(stop.clone(), finished);
+        let maintenance = self.maintenance.clone();
+        let migrator = std::thread::spawn(move || {
+            let _done = done;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                let Some(_pass) = maintenance.pass() else {
+                    continue;
+                };
+                // tried again next time
+                let _ = bucket.migrate();
+                let _ = bucket.offload();
+            }
+        });
+        TierMigrator {
+            stop: Some(stop),
+            migrator: Some(migrator),
+        }
+    }
+}
+
+impl Drop for TierMigrator {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(migrator) = self.migrator.take() {
+            let _ = migrator.join();
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> TieredBucket<V> {
+    /// The hot tier
+    pub fn hot(&self) -> &Bucket<V> {
+        &self.hot
+    }
+    /// The cold tier
+    pub fn cold(&self) -> &Bucket<V> {
+        &self.cold
+    }
+    /// Save a value, to the hot tier
+    pub fn put(&self, key: &str, value: V) -> Result<()> {
+        self.put_ref(key, &value)
+    }
+    /// Save a borrowed value, to the hot tier
+    pub fn put_ref(&self, key: &str, value: &V) -> Result<()> {
+        self.hot.put_ref(key, value)?;
+        // an older copy in the cold tier would come back once this one is removed
+        ignore_missing(self.cold.remove(key))?;
+        self.discard_offloaded(key)
+    }
+    /// Load a value, from whichever tier has it
+    pub fn get(&self, key: &str) -> Result<V> {
+        match self.hot.get(key) {
+            Err(e) if e.kind() == ErrorKind::NotFound => match self.cold.get(key) {
+                Err(e) if e.kind() == ErrorKind::NotFound => match self.get_offloaded(key)? {
+                    Some(value) => Ok(value),
+                    None => Err(e),
+                },
+                res => res,
+            },
+            res => res,
+        }
+    }
+    /// Remove a value from both tiers, and the object store
+    pub fn remove(&self, key: &str) -> Result<()> {
+        let missing = |r: &Result<()>| matches!(r, Err(e) if e.kind() == ErrorKind::NotFound);
+        let offloaded = self.is_offloaded(key);
+        match (self.hot.remove(key), self.cold.remove(key)) {
+            (hot, cold) if missing(&hot) && missing(&cold) && !offloaded => hot,
+            (hot, cold) => ignore_missing(hot)
+                .and(ignore_missing(cold))
+                .and(self.discard_offloaded(key)),
+        }
+    }
+    /// Check if either tier (or the object store) has a key
+    pub fn exists(&self, key: &str) -> bool {
+        self.hot.exists(key) || self.cold.exists(key) || self.is_offloaded(key)
+    }
+    /// Keys in either tier or the object store, sorted
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut keys = self.hot.list()?;
+        keys.extend(self.cold.list()?);
+        keys.extend(self.list_offloaded()?);
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+    /// Move every value of the hot tier that hasn't been read or written for
+    /// `max_idle` to the cold tier. Returns the number moved. A value written
+    /// while it's being moved stays in the hot tier
+    pub fn migrate(&self) -> Result<usize> {
+        let ctx = || Context::new(Op::Copy, &self.hot.dir, None);
+        let cutoff = SystemTime::now() - self.max_idle;
+        let mut moved = 0;
+        let mut pacer = self.hot.pacer();
+        for name in self.hot.fs_keys(&self.hot.dir)? {
+            pacer.tick();
+            let path = self.hot.dir.join(&name);
+            let written = match fs::metadata(&path) {
+                Ok(meta) => meta.modified().ctx(ctx)?,
+                // removed since listing
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).ctx(ctx),
+            };
+            let used = self
+                .hot
+                .last_read(&name)
+                .map_or(written, |t| t.max(written));
+            if used > cutoff {
+                continue;
+            }
+            let Some(before) = hash_file(&path).ctx(ctx)? else {
+                continue;
+            };
+            let value = match self.hot.get(&name) {
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                res => res?,
+            };
+            self.cold.put(&name, value)?;
+            // rewritten in the meantime, so it's in use after all
+            if hash_file(&path).ctx(ctx)? != Some(before) {
+                ignore_missing(self.cold.remove(&name))?;
+                continue;
+            }
+            ignore_missing(self.hot.remove(&name))?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+}
+
+pub(crate) fn ignore_missing(res: Result<()>) -> Result<()> {
+    match res {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use std::time::Duration;
+
+    #[test]
+    fn test_tiered_bucket() {
+        let hot = Fsdb::temp().expect("fail Fsdb::temp");
+        let cold = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = hot
+            .tiered_bucket::<u32>("logs", &cold, Duration::from_millis(50))
+            .expect("fail tiered_bucket");
+        b.put("old", 1).expect("failed to save");
+        std::thread::sleep(Duration::from_millis(100));
+        b.put("new", 2).expect("failed to save");
+        assert_eq!(b.migrate().expect("fail migrate"), 1);
+        assert!(!b.hot().exists("old"));
+        assert_eq!(b.cold().get("old").expect("fail get"), 1);
+
+        // reads find either tier
+        assert_eq!(b.get("old").expect("fail get"), 1);
+        assert_eq!(b.get("new").expect("fail get"), 2);
+        assert_eq!(b.list().expect("fail list"), vec!["new", "old"]);
+
+        // a write brings it back to the hot tier
+        b.put("old", 3).expect("failed to save");
+        assert!(!b.cold().exists("old"));
+        assert_eq!(b.get("old").expect("fail get"), 3);
+
+        b.remove("old").expect("fail remove");
+        assert!(!b.exists("old"));
+        assert!(b.remove("old").is_err());
+    }
+
+    #[test]
+    fn test_migrate_cold_every() {
+        let hot = Fsdb::temp().expect("fail Fsdb::temp");
+        let cold = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = hot
+            .tiered_bucket::<u32>("logs", &cold, Duration::ZERO)
+            .expect("fail tiered_bucket");
+        b.put("a", 1).expect("failed to save");
+        let _migrator = hot.migrate_cold_every(&b, Duration::from_millis(5));
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while b.hot().exists("a") {
+            assert!(std::time::Instant::now() < deadline, "never migrated");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(b.get("a").expect("fail get"), 1);
+    }
+}
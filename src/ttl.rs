@@ -0,0 +1,279 @@
+use crate::config::BucketConfig;
+use crate::error::WithContext;
+use crate::header::{self, Header};
+use crate::{attrs, meta, Bucket, Context, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+// expiring values
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Have every value put from now on expire `ttl` after it's written, for
+    /// cache-style buckets. An expired value reads as missing (`get` returns
+    /// `ErrorKind::NotFound`), though `exists` and `list` still count it until
+    /// `purge_expired` removes it. Use `put_persistent` to keep a value for good.
+    ///
+    /// The setting is saved with the bucket, so it applies to every handle.
+    /// Values already stored keep their expiry (or lack of one)
+    pub fn set_default_ttl(&self, ttl: Duration) -> Result<()> {
+        self.save_default_ttl(Some(ttl))
+    }
+    /// Stop values put from now on expiring, see `set_default_ttl`
+    pub fn clear_default_ttl(&self) -> Result<()> {
+        self.save_default_ttl(None)
+    }
+    fn save_default_ttl(&self, ttl: Option<Duration>) -> Result<()> {
+        let ctx = || Context::new(Op::Bucket, &self.dir, None);
+        let _guard = self.state.write_guard();
+        BucketConfig::update(&self.dir, |config| {
+            config.default_ttl = ttl;
+            Ok(())
+        })
+        .ctx(ctx)?;
+        self.state.set_default_ttl(ttl);
+        Ok(())
+    }
+    /// Create a key that never expires, whatever the bucket's default ttl
+    pub fn put_persistent(&self, key: &str, value: V) -> Result<()> {
+        self.put_persistent_ref(key, &value)
+    }
+    /// Create a key that never expires, from a borrowed value
+    pub fn put_persistent_ref(&self, key: &str, value: &V) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.dir, Some(key));
+        let payload = self.payload(value, ctx)?;
+        let bytes = self.seal_expiring(Some(key), payload, None, None);
+        self.fs_put_bytes(&self.dir, key, &bytes)?;
+        self.publish_put(key, value);
+        Ok(())
+    }
+    /// Mark a key as used now, without rewriting its value: its expiry is
+    /// pushed back to the bucket's default ttl from now, if it has one, and
+    /// its file is stored again, so its modification time is now. Snapshots
+    /// and copies sharing the old file keep theirs. An expired key is missing
+    pub fn touch(&self, key: &str) -> Result<()> {
+        let Some(ttl) = self.state.default_ttl() else {
+            return self.bump_modified(key);
+        };
+        let expires_at = SystemTime::now() + ttl;
+        self.rewrite_header(key, |h| {
+            // a value put with `put_persistent` stays that way
+            if h.expires_at.is_some() {
+                h.expires_at = Some(expires_at);
+            }
+        })
+    }
+    /// Have a key expire at `when`, without rewriting its value. An expired
+    /// key is missing, so can't be brought back this way
+    pub fn expire_at(&self, key: &str, when: SystemTime) -> Result<()> {
+        self.rewrite_header(key, |h| h.expires_at = Some(when))
+    }
+    /// Stop a key expiring, without rewriting its value
+    pub fn persist(&self, key: &str) -> Result<()> {
+        self.rewrite_header(key, |h| h.expires_at = None)
+    }
+    // store a key's file again, rather than setting its modification time, as
+    // it may be linked from snapshots, copies and the change log. Packed values
+    // have no file of their own
+    fn bump_modified(&self, key: &str) -> Result<()> {
+        if self.state.is_packed(&self.maxify(key)) {
+            return Ok(());
+        }
+        self.rewrite_header(key, |_| ())
+    }
+    // store a key's value again with its header changed by `f`, keeping its
+    // version and extended attributes, the way `put` would
+    fn rewrite_header(&self, key: &str, f: impl FnOnce(&mut Header)) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.dir, Some(key));
+        let name = self.maxify(key);
+        let path = self.dir.join(&name);
+        let _lock = self.state.key_lock(&path);
+        let data = match self.state.packed(&name).ctx(ctx)? {
+            Some(data) => data,
+            None => self.retrying(|| fs::read(&path)).ctx(ctx)?,
+        };
+        let (mut header, payload) = header::split(&data)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupt value header"))
+            .ctx(ctx)?;
+        if header.expired() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "expired")).ctx(ctx);
+        }
+        f(&mut header);
+        let sealed = header::wrap(&header, payload.to_vec());
+        let version = match meta::tracking(&self.dir) {
+            true => Some(
+                self.state
+                    .current_version(&self.dir, &name, true)
+                    .ctx(ctx)?,
+            ),
+            false => None,
+        };
+        // a packed value has no file, so no extended attributes
+        let xattr = attrs::get_xattr(&path).ctx(ctx)?;
+        let packed = match xattr {
+            Some(_) => false,
+            None => self
+                .state
+                .install_packed(&self.dir, &name, &sealed, version, self.actor())
+                .ctx(ctx)?,
+        };
+        if !packed {
+            let tmp = self
+                .retrying(|| {
+                    crate::write_temp(
+                        &self.dir,
+                        &name,
+                        &sealed,
+                        self.state.network_fs(),
+                        self.preallocate,
+                    )
+                })
+                .ctx(ctx)?;
+            if let Some(xattr) = &xattr {
+                attrs::set_xattr(&tmp, xattr).ctx(ctx)?;
+            }
+            self.state
+                .install(&self.dir, &name, &tmp, version, true, self.actor())
+                .ctx(ctx)?;
+        }
+        if self.state.subscribers.active() {
+            let value = self.decode(&sealed, ctx)?;
+            self.publish_put(key, &value);
+        }
+        Ok(())
+    }
+    /// Remove every expired value. Returns the number removed
+    pub fn purge_expired(&self) -> Result<usize> {
+        let mut removed = 0;
+        let mut pacer = self.pacer();
+        for name in self.fs_keys(&self.dir)? {
+            pacer.tick();
+            let bytes = match self.read_file(&self.dir, &name) {
+                Ok(bytes) => bytes,
+                // removed since listing
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).ctx(|| Context::new(Op::Remove, &self.dir, Some(&name))),
+            };
+            if header::split(&bytes).is_some_and(|(h, _)| h.expired()) {
+                self.fs_remove(&self.dir, &name)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BucketEvent, ChangeLogOptions, ErrorKind, Fsdb, Op};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_default_ttl() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("cache").expect("fail bucket");
+        b.put("old", 1).expect("failed to save");
+        b.set_default_ttl(Duration::from_millis(50))
+            .expect("fail set_default_ttl");
+        b.put("a", 2).expect("failed to save");
+        b.put_persistent("p", 3).expect("failed to save");
+        // saved with the bucket
+        let again = db.bucket::<u32>("cache").expect("fail bucket");
+        again.put("b", 4).expect("failed to save");
+        assert_eq!(b.get("a").expect("fail get"), 2);
+
+        std::thread::sleep(Duration::from_millis(100));
+        let err = b.get("a").expect_err("expired value read");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(b.get("b").is_err());
+        assert_eq!(b.get("old").expect("fail get"), 1);
+        assert_eq!(b.get("p").expect("fail get"), 3);
+        assert_eq!(b.purge_expired().expect("fail purge_expired"), 2);
+        let mut keys = b.list().expect("fail list");
+        keys.sort();
+        assert_eq!(keys, vec!["old", "p"]);
+
+        // a persistent value stays that way when touched
+        b.touch("p").expect("fail touch");
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(b.get("p").expect("fail get"), 3);
+
+        b.clear_default_ttl().expect("fail clear_default_ttl");
+        b.put("c", 5).expect("failed to save");
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(b.get("c").expect("fail get"), 5);
+    }
+
+    #[test]
+    fn test_touch_and_expire_at() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u32>("sessions").expect("fail bucket");
+        b.set_default_ttl(Duration::from_millis(150))
+            .expect("fail set_default_ttl");
+        b.put("a", 1).expect("failed to save");
+        for _ in 0..4 {
+            std::thread::sleep(Duration::from_millis(60));
+            b.touch("a").expect("fail touch");
+        }
+        // kept alive past its original expiry
+        assert_eq!(b.get("a").expect("fail get"), 1);
+
+        b.expire_at("a", SystemTime::now()).expect("fail expire_at");
+        assert_eq!(b.get("a").expect_err("expired").kind(), ErrorKind::NotFound);
+        assert!(b.touch("a").is_err());
+
+        b.put("b", 2).expect("failed to save");
+        b.persist("b").expect("fail persist");
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(b.get("b").expect("fail get"), 2);
+
+        // without a ttl, only the modification time changes
+        let plain = db.bucket::<u32>("plain").expect("fail bucket");
+        plain.put("k", 1).expect("failed to save");
+        plain.snapshot("s").expect("fail snapshot");
+        let modified = |path: &str| {
+            std::fs::metadata(db.path().join(path))
+                .and_then(|m| m.modified())
+                .expect("fail mtime")
+        };
+        let before = modified("plain/k");
+        std::thread::sleep(Duration::from_millis(20));
+        plain.touch("k").expect("fail touch");
+        assert!(modified("plain/k") > before);
+        // the snapshot's hard link is left alone
+        assert_eq!(modified("plain/.snapshots/s/k"), before);
+        assert_eq!(plain.get("k").expect("fail get"), 1);
+        assert!(plain.touch("missing").is_err());
+    }
+
+    #[test]
+    fn test_touch_packed() {
+        let mut db = Fsdb::temp().expect("fail Fsdb::temp");
+        db.enable_change_log(ChangeLogOptions::default())
+            .expect("fail enable_change_log");
+        let b = db.bucket::<u32>("sessions").expect("fail bucket");
+        b.enable_packed().expect("fail enable_packed");
+        b.enable_change_seq().expect("fail enable_change_seq");
+        b.set_default_ttl(Duration::from_secs(60))
+            .expect("fail set_default_ttl");
+        b.put("a", 1).expect("failed to save");
+        let sub = db.subscribe::<u32>("sessions").expect("fail subscribe");
+        let seq = b.change_seq().expect("fail change_seq");
+
+        b.persist("a").expect("fail persist");
+        assert!(b.change_seq().expect("fail change_seq") > seq);
+        let event = sub.try_recv().expect("fail recv");
+        let put = BucketEvent::Put {
+            key: "a".into(),
+            value: 1,
+        };
+        assert_eq!(event, Some(put));
+        let ops: Vec<_> = db
+            .changes_since(0)
+            .expect("fail changes_since")
+            .map(|c| c.expect("fail read change").op)
+            .collect();
+        assert_eq!(ops, vec![Op::Put, Op::Put]);
+        assert_eq!(b.get("a").expect("fail get"), 1);
+    }
+}
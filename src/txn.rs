@@ -0,0 +1,495 @@
+use crate::bus::{self, Notice};
+use crate::error::WithContext;
+use crate::meta;
+use crate::state::BucketState;
+use crate::{Bucket, Context, Op, Result};
+use rmp_serde::{decode, encode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory (inside the database) where transactions are staged
+pub(crate) const TXN_DIR: &str = ".txn";
+const JOURNAL: &str = "journal";
+
+// transactions of this process that failed part way through being applied.
+// Their owner is alive, so `recover` only finishes them because they're listed
+static UNFINISHED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Serialize, Deserialize)]
+enum JournalOp {
+    // move the staged file into place as bucket/name
+    Put {
+        bucket: PathBuf,
+        name: String,
+        staged: String,
+        // who it's done for, from `Bucket::with_context`
+        #[serde(default)]
+        actor: Option<String>,
+    },
+    Remove {
+        bucket: PathBuf,
+        name: String,
+        #[serde(default)]
+        actor: Option<String>,
+    },
+}
+
+/// A point within a transaction that it can be rolled back to, from `Txn::savepoint`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+/// A set of writes across one or more buckets that are applied all together or not at all.
+///
+/// Values are staged in the database's `.txn` directory as they are added. `commit`
+/// writes a journal listing every operation before applying any of them, so a crash
+/// part way through is finished the next time the database is opened. Dropping a
+/// `Txn` without committing discards it.
+pub struct Txn {
+    dir: PathBuf,
+    ops: Vec<JournalOp>,
+    // published to subscribers on commit, one per op (None if nobody was subscribed)
+    notices: Vec<Option<(Arc<BucketState>, Notice)>>,
+    done: bool,
+}
+
+impl Txn {
+    pub(crate) fn begin(db_dir: &Path) -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let id = format!(
+            "{}-{}-{}",
+            nanos,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = db_dir.join(TXN_DIR).join(id);
+        fs::create_dir_all(&dir).ctx(|| Context::new(Op::Open, &dir, None))?;
+        Ok(Self {
+            dir,
+            ops: Vec::new(),
+            notices: Vec::new(),
+            done: false,
+        })
+    }
+
+    /// Stage a value to be written to `bucket` on commit
+    pub fn put<V: Serialize + DeserializeOwned>(
+        &mut self,
+        bucket: &Bucket<V>,
+        key: &str,
+        value: V,
+    ) -> Result<()> {
+        self.put_ref(bucket, key, &value)
+    }
+
+    /// Stage a borrowed value to be written to `bucket` on commit
+    pub fn put_ref<V: Serialize + DeserializeOwned>(
+        &mut self,
+        bucket: &Bucket<V>,
+        key: &str,
+        value: &V,
+    ) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &bucket.dir, Some(key));
+        let bytes = bucket.encode(key, value, ctx)?;
+        let staged = self.ops.len().to_string();
+        let mut f = fs::File::create(self.dir.join(&staged)).ctx(ctx)?;
+        f.write_all(&bytes).ctx(ctx)?;
+        f.sync_all().ctx(ctx)?;
+        let name = bucket.maxify(key);
+        let notice = bus::put_notice(&bucket.state, &name, value);
+        self.notices.push(notice.map(|n| (bucket.state.clone(), n)));
+        self.ops.push(JournalOp::Put {
+            bucket: bucket.dir.clone(),
+            name,
+            staged,
+            actor: bucket.actor().map(str::to_owned),
+        });
+        Ok(())
+    }
+
+    /// Stage the removal of a key from `bucket` on commit
+    pub fn remove<V: Serialize + DeserializeOwned>(&mut self, bucket: &Bucket<V>, key: &str) {
+        let name = bucket.maxify(key);
+        let notice = bucket
+            .state
+            .subscribers
+            .active()
+            .then(|| (bucket.state.clone(), Notice::Remove(name.clone())));
+        self.notices.push(notice);
+        self.ops.push(JournalOp::Remove {
+            bucket: bucket.dir.clone(),
+            name,
+            actor: bucket.actor().map(str::to_owned),
+        });
+    }
+
+    /// Number of staged operations
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Check if nothing has been staged
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Mark the current point in the transaction, to roll back to later
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.ops.len())
+    }
+
+    /// Discard everything staged since `sp` was taken, keeping what came before it.
+    /// Rolling back to a savepoint that was itself rolled back past does nothing
+    pub fn rollback_to(&mut self, sp: Savepoint) -> Result<()> {
+        if sp.0 >= self.ops.len() {
+            return Ok(());
+        }
+        self.notices.truncate(sp.0);
+        for op in self.ops.drain(sp.0..) {
+            if let JournalOp::Put { staged, .. } = op {
+                let path = self.dir.join(staged);
+                fs::remove_file(&path).ctx(|| Context::new(Op::Remove, &path, None))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply every staged operation. Removals are checked against references
+    /// declared with `Bucket::references` first, which can stage more operations
+    pub fn commit(mut self) -> Result<()> {
+        // earlier transactions still being applied go first
+        if let Some(txn_dir) = self.dir.parent() {
+            if unfinished_in(txn_dir) {
+                recover_in(txn_dir).ctx(|| Context::new(Op::Commit, txn_dir, None))?;
+            }
+        }
+        self.enforce_references()?;
+        // fail before the commit point, rather than part way through applying it
+        for op in &self.ops {
+            let (JournalOp::Put { bucket, name, .. } | JournalOp::Remove { bucket, name, .. }) = op;
+            BucketState::get(bucket)
+                .writable()
+                .ctx(|| Context::new(Op::Commit, bucket, Some(name)))?;
+        }
+        let ctx = || Context::new(Op::Commit, &self.dir, None);
+        let journal = encode::to_vec(&self.ops).ctx(ctx)?;
+        let tmp = self.dir.join(format!("{}.tmp", JOURNAL));
+        let mut f = fs::File::create(&tmp).ctx(ctx)?;
+        f.write_all(&journal).ctx(ctx)?;
+        f.sync_all().ctx(ctx)?;
+        // the rename is the commit point
+        fs::rename(&tmp, self.dir.join(JOURNAL)).ctx(ctx)?;
+        self.done = true;
+        if let Err(e) = replay(&self.dir, &self.ops) {
+            // finished by the next commit, or the next time the database is opened
+            unfinished().push(self.dir.clone());
+            return Err(e).ctx(ctx);
+        }
+        for (state, notice) in self.notices.drain(..).flatten() {
+            state.subscribers.publish(notice);
+        }
+        fs::remove_dir_all(&self.dir).ctx(ctx)
+    }
+
+    // run the on-delete actions for every removal, including those they stage
+    fn enforce_references(&mut self) -> Result<()> {
+        let mut i = 0;
+        while i < self.ops.len() {
+            if let JournalOp::Remove { bucket, name, .. } = &self.ops[i] {
+                let (bucket, name) = (bucket.clone(), name.clone());
+                BucketState::get(&bucket).on_remove(self, &name)?;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    // whether the last op staged for `dir/name` removes it
+    pub(crate) fn removes(&self, dir: &Path, name: &str) -> bool {
+        matches!(self.last_op(dir, name), Some(JournalOp::Remove { .. }))
+    }
+
+    // the value staged for `bucket/name`, if the last op staged for it is a put
+    pub(crate) fn staged_value<V: Serialize + DeserializeOwned>(
+        &self,
+        bucket: &Bucket<V>,
+        name: &str,
+    ) -> Result<Option<V>> {
+        let Some(JournalOp::Put { staged, .. }) = self.last_op(&bucket.dir, name) else {
+            return Ok(None);
+        };
+        let path = self.dir.join(staged);
+        let ctx = || Context::new(Op::Get, &bucket.dir, Some(name));
+        let bytes = fs::read(path).ctx(ctx)?;
+        Ok(Some(bucket.decode(&bytes, ctx)?))
+    }
+
+    fn last_op(&self, dir: &Path, name: &str) -> Option<&JournalOp> {
+        self.ops.iter().rev().find(|op| match op {
+            JournalOp::Put {
+                bucket, name: n, ..
+            }
+            | JournalOp::Remove {
+                bucket, name: n, ..
+            } => bucket == dir && n == name,
+        })
+    }
+
+    /// Discard every staged operation
+    pub fn rollback(mut self) -> Result<()> {
+        self.done = true;
+        fs::remove_dir_all(&self.dir).ctx(|| Context::new(Op::Remove, &self.dir, None))
+    }
+}
+
+impl Drop for Txn {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+// apply journaled ops. Safe to run more than once: staged files that are
+// already gone were moved into place by an earlier attempt
+fn replay(dir: &Path, ops: &[JournalOp]) -> io::Result<()> {
+    for op in ops {
+        match op {
+            JournalOp::Put {
+                bucket,
+                name,
+                staged,
+                actor,
+            } => {
+                let staged = dir.join(staged);
+                if !staged.exists() {
+                    continue;
+                }
+                let state = BucketState::get(bucket);
+                let _lock = state.key_lock(&bucket.join(name));
+                let version = if meta::tracking(bucket) {
+                    Some(meta::current_version(bucket, name)?.next())
+                } else {
+                    None
+                };
+                state.install(bucket, name, &staged, version, true, actor.as_deref())?;
+            }
+            JournalOp::Remove {
+                bucket,
+                name,
+                actor,
+            } => {
+                let state = BucketState::get(bucket);
+                let _lock = state.key_lock(&bucket.join(name));
+                match state.uninstall(bucket, name, true, actor.as_deref()) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finish committed transactions left behind by a crash, and throw away uncommitted ones.
+/// Transactions whose process is still running (on this machine) are left to it, unless
+/// they failed part way through being applied
+pub(crate) fn recover(db_dir: &Path) -> io::Result<()> {
+    let dir = db_dir.join(TXN_DIR);
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    recover_in(&dir)
+}
+
+fn recover_in(dir: &Path) -> io::Result<()> {
+    let entries: Vec<_> = fs::read_dir(dir)?.flatten().collect();
+    // taken, so only one thread finishes each
+    let mut own = Vec::new();
+    unfinished().retain(|txn| match txn.parent() == Some(dir) {
+        true => {
+            own.push(txn.clone());
+            false
+        }
+        false => true,
+    });
+    let mut txns: Vec<PathBuf> = entries
+        .iter()
+        .filter(|e| own.contains(&e.path()) || !owner_alive(&e.file_name().to_string_lossy()))
+        .map(|e| e.path())
+        .collect();
+    // ids start with a timestamp, so this replays in the order transactions began
+    txns.sort();
+    for txn in txns {
+        if let Err(e) = finish(&txn) {
+            // tried again next time
+            unfinished().extend(own);
+            return Err(e);
+        }
+        own.retain(|t| *t != txn);
+    }
+    Ok(())
+}
+
+// apply a transaction's journal, if it was committed, and remove it
+fn finish(txn: &Path) -> io::Result<()> {
+    if let Ok(journal) = fs::read(txn.join(JOURNAL)) {
+        let ops: Vec<JournalOp> = decode::from_slice(&journal)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        replay(txn, &ops)?;
+    }
+    fs::remove_dir_all(txn)
+}
+
+fn unfinished() -> MutexGuard<'static, Vec<PathBuf>> {
+    UNFINISHED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// whether a transaction in `txn_dir` failed part way through being applied
+fn unfinished_in(txn_dir: &Path) -> bool {
+    unfinished().iter().any(|txn| txn.parent() == Some(txn_dir))
+}
+
+// whether the process that began transaction `id` (nanos-pid-counter) is running
+fn owner_alive(id: &str) -> bool {
+    match id.split('-').nth(1).and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) => pid == std::process::id() || process_alive(pid),
+        None => false,
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// can't tell, so leave the transaction be
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TXN_DIR;
+    use crate::Fsdb;
+    use std::fs;
+
+    #[test]
+    fn test_txn() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let nums = db.bucket::<u64>("nums").expect("fail bucket");
+        let names = db.bucket::<String>("names").expect("fail bucket");
+        nums.clear().expect("fail clear");
+        nums.put("gone", 0).expect("failed to save");
+
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put(&nums, "one", 1).expect("fail stage");
+        tx.put(&names, "one", "uno".to_string())
+            .expect("fail stage");
+        tx.remove(&nums, "gone");
+        assert!(!nums.exists("one"));
+        tx.commit().expect("fail commit");
+        assert_eq!(nums.get("one").expect("fail get"), 1);
+        assert_eq!(names.get("one").expect("fail get"), "uno");
+        assert!(!nums.exists("gone"));
+
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put(&nums, "two", 2).expect("fail stage");
+        drop(tx);
+        assert!(!nums.exists("two"));
+    }
+
+    #[test]
+    fn test_savepoint() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let nums = db.bucket::<u64>("nums").expect("fail bucket");
+        nums.clear().expect("fail clear");
+
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put(&nums, "a", 1).expect("fail stage");
+        let sp = tx.savepoint();
+        tx.put(&nums, "b", 2).expect("fail stage");
+        tx.put(&nums, "c", 3).expect("fail stage");
+        tx.rollback_to(sp).expect("fail rollback");
+        assert_eq!(tx.len(), 1);
+        tx.put(&nums, "d", 4).expect("fail stage");
+        tx.commit().expect("fail commit");
+
+        let mut list = nums.list().expect("fail list");
+        list.sort();
+        assert_eq!(list, vec!["a", "d"]);
+    }
+
+    #[test]
+    fn test_recover() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let nums = db.bucket::<u64>("nums").expect("fail bucket");
+        let path = db.path().to_str().expect("fail path").to_owned();
+
+        // another handle opening the database leaves a live transaction alone
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put(&nums, "live", 1).expect("fail stage");
+        let other = Fsdb::new(&path).expect("fail Fsdb::new");
+        tx.commit().expect("fail commit");
+        assert_eq!(nums.get("live").expect("fail get"), 1);
+
+        // one left by a process that's gone is thrown away
+        let dead = db.path().join(TXN_DIR).join("1-999999999-0");
+        fs::create_dir_all(&dead).expect("fail create_dir");
+        fs::write(dead.join("0"), b"staged").expect("fail write");
+        drop(other);
+        Fsdb::new(&path).expect("fail Fsdb::new");
+        assert!(!dead.exists());
+    }
+
+    #[test]
+    fn test_recover_unfinished() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let path = db.path().to_str().expect("fail path").to_owned();
+        let a = db.bucket::<u64>("a").expect("fail bucket");
+        let b = db.bucket::<u64>("b").expect("fail bucket");
+        let b_dir = db.path().join("b");
+        let txns = || fs::read_dir(db.path().join(TXN_DIR)).map_or(0, |d| d.count());
+
+        // fails part way through: the second bucket has gone
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put(&a, "x", 1).expect("fail stage");
+        tx.put(&b, "y", 2).expect("fail stage");
+        fs::remove_dir_all(&b_dir).expect("fail remove_dir_all");
+        assert!(tx.commit().is_err());
+        assert_eq!(a.get("x").expect("fail get"), 1);
+        assert_eq!(txns(), 1);
+
+        // finished by the next commit, first
+        fs::create_dir(&b_dir).expect("fail create_dir");
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put(&a, "z", 3).expect("fail stage");
+        tx.commit().expect("fail commit");
+        assert_eq!(b.get("y").expect("fail get"), 2);
+        assert_eq!(txns(), 0);
+
+        // or the next time the database is opened
+        let mut tx = db.transaction().expect("fail transaction");
+        tx.put(&b, "w", 4).expect("fail stage");
+        fs::remove_dir_all(&b_dir).expect("fail remove_dir_all");
+        assert!(tx.commit().is_err());
+        fs::create_dir(&b_dir).expect("fail create_dir");
+        Fsdb::new(&path).expect("fail Fsdb::new");
+        assert_eq!(b.get("w").expect("fail get"), 4);
+        assert_eq!(txns(), 0);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::error::WithContext;
+use crate::meta;
+use crate::state::BucketState;
+use crate::{Bucket, Context, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Directory (inside a bucket) where resumable uploads are staged
+pub(crate) const UPLOADS: &str = ".uploads";
+const DATA: &str = "data";
+const COMMITTED: &str = "committed";
+
+/// An in-progress write of a large value, from `Bucket::put_resumable`.
+///
+/// Each chunk is made durable before `write_chunk` returns, so after a crash
+/// `put_resumable` with the same key picks up where it left off (see `offset`).
+/// Nothing is visible under the key until `commit`.
+///
+/// The uploaded bytes become the stored file as-is, so they should be an encoded
+/// value (as produced by the same bucket type), or a raw blob read with `read_range`.
+pub struct Upload {
+    bucket: PathBuf,
+    name: String,
+    dir: PathBuf,
+    state: Arc<BucketState>,
+    actor: Option<Arc<str>>,
+    chunks: usize,
+    offset: u64,
+}
+
+impl Upload {
+    pub(crate) fn open<V: Serialize + DeserializeOwned>(
+        bucket: &Bucket<V>,
+        key: &str,
+    ) -> Result<Self> {
+        let name = bucket.maxify(key);
+        let dir = bucket.dir.join(UPLOADS).join(&name);
+        let ctx = || Context::new(Op::Put, &bucket.dir, Some(key));
+        fs::create_dir_all(&dir).ctx(ctx)?;
+        let mut chunks = 0;
+        let mut offset = 0;
+        // chunks are named by index; a crash mid-chunk leaves only a ".part" file
+        while let Ok(m) = fs::metadata(dir.join(chunks.to_string())) {
+            chunks += 1;
+            offset += m.len();
+        }
+        Ok(Self {
+            bucket: bucket.dir.clone(),
+            name,
+            dir,
+            state: bucket.state.clone(),
+            actor: bucket.actor.clone(),
+            chunks,
+            offset,
+        })
+    }
+
+    /// Number of bytes safely written so far
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Durably append a chunk of bytes
+    pub fn write_chunk(&mut self, data: &[u8]) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.bucket, Some(&self.name));
+        let part = self.dir.join(format!("{}.part", self.chunks));
+        let mut f = fs::File::create(&part).ctx(ctx)?;
+        f.write_all(data).ctx(ctx)?;
+        f.sync_all().ctx(ctx)?;
+        fs::rename(&part, self.dir.join(self.chunks.to_string())).ctx(ctx)?;
+        self.chunks += 1;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Assemble the chunks and atomically store them under the key
+    pub fn commit(self) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.bucket, Some(&self.name));
+        let data = self.dir.join(DATA);
+        let committed = self.dir.join(COMMITTED);
+        if !committed.exists() {
+            self.assemble(&data).ctx(ctx)?;
+            fs::File::create(&committed).ctx(ctx)?;
+        }
+        // committed with no data means it was installed before a crash
+        if data.exists() {
+            let _lock = self.state.key_lock(&self.bucket.join(&self.name));
+            let version = if meta::tracking(&self.bucket) {
+                Some(
+                    meta::current_version(&self.bucket, &self.name)
+                        .ctx(ctx)?
+                        .next(),
+                )
+            } else {
+                None
+            };
+            self.state
+                .install(
+                    &self.bucket,
+                    &self.name,
+                    &data,
+                    version,
+                    true,
+                    self.actor.as_deref(),
+                )
+                .ctx(ctx)?;
+        }
+        fs::remove_dir_all(&self.dir).ctx(ctx)
+    }
+
+    /// Throw away the upload and its chunks
+    pub fn abort(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)
+            .ctx(|| Context::new(Op::Remove, &self.bucket, Some(&self.name)))
+    }
+
+    fn assemble(&self, data: &Path) -> io::Result<()> {
+        let mut out = fs::File::create(data)?;
+        for i in 0..self.chunks {
+            let mut chunk = fs::File::open(self.dir.join(i.to_string()))?;
+            io::copy(&mut chunk, &mut out)?;
+        }
+        out.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+
+    #[test]
+    fn test_resumable() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<String>("blobs").expect("fail bucket");
+        b.remove("big").ok();
+        let bytes = rmp_serde::to_vec(&"hello world".to_string()).expect("fail encode");
+        let (first, rest) = bytes.split_at(4);
+
+        let mut up = b.put_resumable("big").expect("fail upload");
+        up.write_chunk(first).expect("fail chunk");
+        drop(up);
+        assert!(!b.exists("big"));
+
+        // resume after "crashing"
+        let mut up = b.put_resumable("big").expect("fail upload");
+        assert_eq!(up.offset(), 4);
+        up.write_chunk(rest).expect("fail chunk");
+        up.commit().expect("fail commit");
+        assert_eq!(b.get("big").expect("fail get"), "hello world");
+    }
+
+    #[test]
+    fn test_read_range() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<Vec<u8>>("blobs").expect("fail bucket");
+        let mut up = b.put_resumable("blob").expect("fail upload");
+        up.write_chunk(b"0123456789").expect("fail chunk");
+        up.commit().expect("fail commit");
+        assert_eq!(b.read_range("blob", 2, 3).expect("fail read"), b"234");
+        assert_eq!(b.read_range("blob", 8, 10).expect("fail read"), b"89");
+    }
+}
@@ -0,0 +1,253 @@
+use crate::state::BucketState;
+use crate::{Bucket, Context, Error, Fsdb, Op, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A bucket derived from another's values, kept up to date by `BucketState`
+/// as the source bucket's top-level keys are written
+pub(crate) trait View: Send + Sync {
+    /// `name` was written (with its file now at `file`), or removed if `None`
+    fn update(&self, name: &str, file: Option<&Path>) -> io::Result<()>;
+    fn clear(&self) -> io::Result<()>;
+}
+
+type FilterFn<V> = dyn Fn(&V) -> bool + Send + Sync;
+type MapFn<V, W> = dyn Fn(&V) -> W + Send + Sync;
+
+struct Materialized<V, W> {
+    // the source bucket's keys, but not its state, which would keep itself alive
+    source: Bucket<V>,
+    view: Bucket<W>,
+    filter: Box<FilterFn<V>>,
+    map: Box<MapFn<V, W>>,
+}
+
+impl<V, W> View for Materialized<V, W>
+where
+    V: Serialize + DeserializeOwned + Send + Sync,
+    W: Serialize + DeserializeOwned + Send + Sync,
+{
+    fn update(&self, name: &str, file: Option<&Path>) -> io::Result<()> {
+        let value = match file {
+            Some(file) => {
+                let bytes = fs::read(file)?;
+                let ctx = || Context::new(Op::Get, file, None);
+                Some(self.source.decode(&bytes, ctx).map_err(io::Error::other)?)
+            }
+            None => None,
+        };
+        match value.filter(|v| (self.filter)(v)) {
+            Some(v) => self.view.put(name, (self.map)(&v)),
+            None => match self.view.remove(name) {
+                Err(Error::Io { source, .. }) if source.kind() == io::ErrorKind::NotFound => Ok(()),
+                r => r,
+            },
+        }
+        .map_err(io::Error::other)
+    }
+    fn clear(&self) -> io::Result<()> {
+        self.view.clear().map_err(io::Error::other)
+    }
+}
+
+impl BucketState {
+    // feed a write or remove of a top-level key to the bucket's views
+    pub(crate) fn update_views(&self, name: &str, file: Option<&Path>) -> io::Result<()> {
+        let views: Vec<_> = self.views().values().cloned().collect();
+        views.iter().try_for_each(|view| view.update(name, file))
+    }
+    pub(crate) fn clear_views(&self) -> io::Result<()> {
+        let views: Vec<_> = self.views().values().cloned().collect();
+        views.iter().try_for_each(|view| view.clear())
+    }
+}
+
+// materialized views
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Maintain `view` as `map` of this bucket's values that pass `filter`,
+    /// under the same keys, so reading it doesn't mean scanning this bucket.
+    ///
+    /// `view` is rebuilt from this bucket now, then updated by writes through
+    /// every handle to this bucket in this process, like `create_sorted_index`.
+    /// Write to `view` only through its source. If a view can't be updated the
+    /// write that triggered it still happens, but returns the error: call
+    /// `rebuild_view` to catch up
+    pub fn create_view<W>(
+        &self,
+        view: &Bucket<W>,
+        filter: impl Fn(&V) -> bool + Send + Sync + 'static,
+        map: impl Fn(&V) -> W + Send + Sync + 'static,
+    ) -> Result<()>
+    where
+        V: Send + Sync + 'static,
+        W: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        if view.dir == self.dir {
+            return Err(self.view_error(view, "can't be its own source"));
+        }
+        let mut source = self.clone();
+        source.state = Arc::new(BucketState::default());
+        let materialized = Arc::new(Materialized {
+            source,
+            view: view.clone(),
+            filter: Box::new(filter),
+            map: Box::new(map),
+        });
+        // hold off writers, so none is missed between the scan and registering the view
+        let _guard = self.state.exclusive_guard();
+        // views read values from their files
+        if self.state.pack().is_some() {
+            return Err(self.view_error(view, "can't have a packed source"));
+        }
+        self.fill_view(materialized.as_ref())?;
+        let key = view.dir.clone();
+        self.state.views().insert(key, materialized);
+        Ok(())
+    }
+    /// Rebuild a view from this bucket's values on disk
+    pub fn rebuild_view<W>(&self, view: &Bucket<W>) -> Result<()> {
+        let registered = self.state.views().get(&view.dir).cloned();
+        let Some(registered) = registered else {
+            return Err(self.view_error(view, "doesn't exist"));
+        };
+        let _guard = self.state.exclusive_guard();
+        self.fill_view(registered.as_ref())
+    }
+    /// Stop maintaining a view. Its bucket is left as it is
+    pub fn drop_view<W>(&self, view: &Bucket<W>) {
+        self.state.views().remove(&view.dir);
+    }
+    // refill every view of this bucket, with it locked exclusively
+    pub(crate) fn fill_views(&self) -> Result<()> {
+        let views: Vec<_> = self.state.views().values().cloned().collect();
+        views
+            .iter()
+            .try_for_each(|view| self.fill_view(view.as_ref()))
+    }
+    fn fill_view(&self, view: &dyn View) -> Result<()> {
+        let ctx = || Context::new(Op::Put, &self.dir, None);
+        view.clear()
+            .map_err(|source| Error::Io { ctx: ctx(), source })?;
+        for name in self.fs_keys(&self.dir)? {
+            match view.update(&name, Some(&self.dir.join(&name))) {
+                // removed since it was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                r => r.map_err(|source| Error::Io {
+                    ctx: Context::new(Op::Put, &self.dir, Some(&name)),
+                    source,
+                })?,
+            }
+        }
+        Ok(())
+    }
+    fn view_error<W>(&self, view: &Bucket<W>, problem: &str) -> Error {
+        Error::Io {
+            ctx: Context::new(Op::Bucket, &self.dir, None),
+            source: io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("view {:?} {}", view.dir, problem),
+            ),
+        }
+    }
+}
+
+impl Fsdb {
+    /// Open bucket `name` as a view of bucket `from`: the values in `from` that
+    /// pass `filter`, through `map`. See `Bucket::create_view`, which this calls
+    /// with a plain handle to `from`
+    pub fn view<V, W>(
+        &self,
+        name: &str,
+        from: &str,
+        filter: impl Fn(&V) -> bool + Send + Sync + 'static,
+        map: impl Fn(&V) -> W + Send + Sync + 'static,
+    ) -> Result<Bucket<W>>
+    where
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+        W: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let view = self.bucket::<W>(name)?;
+        self.bucket::<V>(from)?.create_view(&view, filter, map)?;
+        Ok(view)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Fsdb;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        open: bool,
+        total: u32,
+    }
+
+    fn order(open: bool, total: u32) -> Order {
+        Order { open, total }
+    }
+
+    fn contents(b: &crate::Bucket<u32>) -> Vec<(String, u32)> {
+        let mut keys = b.list().expect("fail list");
+        keys.sort();
+        keys.into_iter()
+            .map(|k| {
+                let v = b.get(&k).expect("fail get");
+                (k, v)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_view() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let orders = db.bucket::<Order>("orders").expect("fail bucket");
+        orders.put("a", order(true, 10)).expect("failed to save");
+        orders.put("b", order(false, 20)).expect("failed to save");
+
+        let open = db
+            .view("open_orders", "orders", |o: &Order| o.open, |o| o.total)
+            .expect("fail view");
+        assert_eq!(contents(&open), vec![("a".to_string(), 10)]);
+
+        // writes through any handle keep it up to date
+        let other = db.bucket::<Order>("orders").expect("fail bucket");
+        other.put("b", order(true, 25)).expect("failed to save");
+        other.put("a", order(false, 10)).expect("failed to save");
+        other.put("c", order(true, 5)).expect("failed to save");
+        assert_eq!(
+            contents(&open),
+            vec![("b".to_string(), 25), ("c".to_string(), 5)]
+        );
+        other.remove("c").expect("fail remove");
+        assert_eq!(contents(&open), vec![("b".to_string(), 25)]);
+        other.clear().expect("fail clear");
+        assert!(contents(&open).is_empty());
+
+        orders.drop_view(&open);
+        orders.put("d", order(true, 1)).expect("failed to save");
+        assert!(contents(&open).is_empty());
+        assert!(orders.rebuild_view(&open).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_view() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let orders = db.bucket::<Order>("orders").expect("fail bucket");
+        let big = db.bucket::<u32>("big").expect("fail bucket");
+        orders
+            .create_view(&big, |o| o.total > 10, |o| o.total)
+            .expect("fail create_view");
+        orders.put("a", order(true, 50)).expect("failed to save");
+        // a stray write to the view is undone by rebuilding it
+        big.put("stray", 1).expect("failed to save");
+        orders.rebuild_view(&big).expect("fail rebuild_view");
+        assert_eq!(contents(&big), vec![("a".to_string(), 50)]);
+        assert!(orders
+            .create_view(&orders, |_| true, |o| o.clone())
+            .is_err());
+    }
+}
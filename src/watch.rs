@@ -0,0 +1,209 @@
+use crate::error::WithContext;
+use crate::{Bucket, Context, Op, Result};
+use futures_core::Stream;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Poll, Waker};
+
+/// What happened to a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Put,
+    Remove,
+}
+
+/// A change to a key in a watched bucket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: String,
+    pub kind: ChangeKind,
+}
+
+/// Options for `Bucket::changes_with`
+#[derive(Debug, Clone)]
+pub struct ChangeOptions {
+    /// Most events held before the watcher waits for the stream to be polled
+    pub capacity: usize,
+    /// Merge events for a key that is already waiting to be read into one,
+    /// carrying its latest kind
+    pub coalesce: bool,
+}
+
+impl Default for ChangeOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            coalesce: false,
+        }
+    }
+}
+
+/// Stream of changes to a bucket's keys, from any process, returned by `Bucket::changes`.
+/// Sub-buckets are not watched. The stream ends only when dropped
+pub struct Changes {
+    _watcher: RecommendedWatcher,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    space: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<ChangeEvent>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+// async change notifications
+impl<V: Serialize + DeserializeOwned> Bucket<V> {
+    /// Watch this bucket for keys being written or removed. See `Changes`
+    pub fn changes(&self) -> Result<Changes> {
+        self.changes_with(ChangeOptions::default())
+    }
+    /// Watch this bucket like `changes`, with a queue capacity and coalescing.
+    /// When the queue is full, the watcher waits for the stream to be polled
+    pub fn changes_with(&self, opts: ChangeOptions) -> Result<Changes> {
+        let ctx = || Context::new(Op::Watch, &self.dir, None);
+        let shared = Arc::new(Shared::default());
+        let sh = shared.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            if let Ok(event) = res {
+                for change in to_changes(&event) {
+                    sh.push(change, &opts);
+                }
+            }
+        })
+        .map_err(std::io::Error::other)
+        .ctx(ctx)?;
+        watcher
+            .watch(&self.dir, RecursiveMode::NonRecursive)
+            .map_err(std::io::Error::other)
+            .ctx(ctx)?;
+        Ok(Changes {
+            _watcher: watcher,
+            shared,
+        })
+    }
+}
+
+impl Shared {
+    fn push(&self, change: ChangeEvent, opts: &ChangeOptions) {
+        let mut q = self.queue.lock().expect("change queue poisoned");
+        if opts.coalesce {
+            if let Some(queued) = q.events.iter_mut().find(|e| e.key == change.key) {
+                queued.kind = change.kind;
+                return;
+            }
+        }
+        while q.events.len() >= opts.capacity.max(1) && !q.closed {
+            q = self.space.wait(q).expect("change queue poisoned");
+        }
+        if q.closed {
+            return;
+        }
+        q.events.push_back(change);
+        if let Some(w) = q.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+// turn a filesystem event into changes to keys. Temp files, metadata and
+// sub-buckets are skipped
+fn to_changes(event: &Event) -> Vec<ChangeEvent> {
+    let removed = match event.kind {
+        EventKind::Remove(_) => true,
+        EventKind::Create(_) | EventKind::Modify(_) => false,
+        _ => return Vec::new(),
+    };
+    event
+        .paths
+        .iter()
+        .filter_map(|path| {
+            let key = path.file_name()?.to_str()?;
+            if key.starts_with('.') || path.is_dir() {
+                return None;
+            }
+            let kind = if removed || !path.exists() {
+                ChangeKind::Remove
+            } else {
+                ChangeKind::Put
+            };
+            Some(ChangeEvent {
+                key: key.to_owned(),
+                kind,
+            })
+        })
+        .collect()
+}
+
+impl Stream for Changes {
+    type Item = ChangeEvent;
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<ChangeEvent>> {
+        let mut q = self.shared.queue.lock().expect("change queue poisoned");
+        match q.events.pop_front() {
+            Some(e) => {
+                self.shared.space.notify_one();
+                Poll::Ready(Some(e))
+            }
+            None => {
+                q.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Changes {
+    fn drop(&mut self) {
+        // release a watcher thread waiting for space
+        self.shared
+            .queue
+            .lock()
+            .expect("change queue poisoned")
+            .closed = true;
+        self.shared.space.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Fsdb;
+    use futures_executor::block_on_stream;
+
+    #[test]
+    fn test_changes() {
+        let db = Fsdb::temp().expect("fail Fsdb::temp");
+        let b = db.bucket::<u8>("watched").expect("fail bucket");
+        b.clear().expect("fail clear");
+        let opts = ChangeOptions {
+            coalesce: true,
+            ..Default::default()
+        };
+        let mut changes = block_on_stream(b.changes_with(opts).expect("fail watch"));
+        b.put("a", 1).expect("failed to save");
+        let put = ChangeEvent {
+            key: "a".into(),
+            kind: ChangeKind::Put,
+        };
+        assert_eq!(changes.next(), Some(put));
+        b.remove("a").expect("fail remove");
+        let removed = ChangeEvent {
+            key: "a".into(),
+            kind: ChangeKind::Remove,
+        };
+        // there may be leftover events from the write before the removal
+        assert!(changes.any(|e| e == removed));
+    }
+}